@@ -1,26 +1,1054 @@
-#![feature(exact_size_is_empty)]
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::{bail, Result};
-use sora::PluginManager;
+use anyhow::{bail, Context as _, Result};
+use sora::{
+    Config, Dispatcher, Environment, GraphFormat, Lockfile, NativeLoaderOptions, Phase,
+    PluginManager, Registry, SigningKey, Snapshot, public_key_from_pem, verify_signature,
+};
+
+const USAGE: &str = "\
+usage: sora run [--parallel] [--threads N] [--watch] [--config FILE]
+                [--only NAME,...] [--skip NAME,...] [--repeat N] [--interval DURATION]
+                [--output text|json] [--dry-run] [<path>...] [-- <arg>...]
+       sora daemon [--interval DURATION] [<run options>] [<path>...] [-- <arg>...]
+       sora list <path>...
+       sora graph [--format dot|mermaid] <path>...
+       sora validate <path>...
+       sora new [--path DIR | --git URL] <name>
+       sora install [--registry LOCATION] [--dir DIR] <name>[@<version>]
+       sora outdated [--registry LOCATION] [<path>...]
+       sora lock <path>...
+       sora sign --key KEY <library>...
+       sora verify --key KEY <library>...
+
+Each path is a plugin folder, a single plugin library or a bundle.
+The arguments after `--` are passed to the plugins that `run` and `daemon`
+dispatch.
+
+Plugins are installed into ./plugins from the registry in $SORA_REGISTRY,
+a folder path or file URL, unless --dir and --registry say otherwise.
+`sora outdated` checks the plugins in ./plugins unless paths are given.
+
+`sora new` makes the plugin depend on the sora release of this binary,
+or with --path or --git on the sora in the folder DIR or the git
+repository at URL. Plugins only load into hosts built against the same
+sora, with the same Rust compiler.
+
+`sora lock` records the plugins in ./sora.lock, and every other command
+then only loads plugins that match it.
+
+`sora sign` writes a detached signature next to each library, which
+`sora verify` and managers that trust the key check. KEY is an Ed25519
+key in a PEM file, from `openssl genpkey -algorithm ed25519`; verify also
+takes the public key alone.
+
+Every command accepts -v/--verbose to report loading and per-plugin
+timings, and -q/--quiet to only report errors.";
+
+/// Where `sora install` places plugins, unless `--dir` is given.
+const PLUGIN_DIR: &str = "plugins";
+
+/// How often `sora run --watch` checks the plugins for changes.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often `sora daemon` checks for signals while it waits.
+const DAEMON_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How much the binary reports on stderr. Errors are always reported.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Normal,
+        _ => Verbosity::Verbose,
+    }
+}
+
+/// Reports progress on stderr, unless `-q` is given.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if verbosity() >= Verbosity::Normal {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Reports details on stderr, if `-v` is given.
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if verbosity() >= Verbosity::Verbose {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+enum Command {
+    Run(RunOptions),
+    Daemon(RunOptions),
+    List { paths: Vec<PathBuf> },
+    Graph { paths: Vec<PathBuf>, format: GraphFormat },
+    Validate { paths: Vec<PathBuf> },
+    New { name: String, sora: Sora },
+    Install { name: String, version: Option<String>, registry: Option<String>, dir: PathBuf },
+    Outdated { paths: Vec<PathBuf>, registry: Option<String> },
+    Lock { paths: Vec<PathBuf> },
+    Sign { paths: Vec<PathBuf>, key: PathBuf },
+    Verify { paths: Vec<PathBuf>, key: PathBuf },
+}
+
+/// The sora that `sora new` makes the plugin depend on.
+enum Sora {
+    /// The release of this binary, from crates.io.
+    Release,
+    Path(PathBuf),
+    Git(String),
+}
+
+struct RunOptions {
+    paths: Vec<PathBuf>,
+    config: Option<String>,
+    parallel: bool,
+    threads: Option<usize>,
+    watch: bool,
+    only: Option<Vec<String>>,
+    skip: Vec<String>,
+    /// How many times to dispatch, `0` meaning until interrupted.
+    repeat: u64,
+    /// The time between the start of two consecutive dispatches.
+    interval: Duration,
+    /// Print a JSON [`DispatchReport`](sora::DispatchReport) after every
+    /// dispatch.
+    json: bool,
+    /// Print the [`Plan`](sora::Plan) instead of dispatching.
+    dry_run: bool,
+    /// What plugins get from [`RunContext::args`](sora::RunContext::args).
+    args: Vec<String>,
+}
+
+impl Command {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let command =
+            args.next().with_context(|| format!("a command must be specified.\n{USAGE}"))?;
+
+        let mut paths = Vec::new();
+        let mut parallel = false;
+        let mut threads = None;
+        let mut watch = false;
+        let mut config = None;
+        let mut only: Option<Vec<String>> = None;
+        let mut skip = Vec::new();
+        let mut repeat = 1;
+        let mut interval = Duration::ZERO;
+        let mut json = false;
+        let mut dry_run = false;
+        let mut format = GraphFormat::Dot;
+        let mut registry = None;
+        let mut dir = PathBuf::from(PLUGIN_DIR);
+        let mut plugin_args = Vec::new();
+        let mut key = None;
+        let mut sora = Sora::Release;
+        let runs = matches!(command.as_str(), "run" | "daemon");
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--parallel" if runs => parallel = true,
+                "--watch" if runs => watch = true,
+                "--dry-run" if command == "run" => dry_run = true,
+                "--only" if runs => {
+                    let names = args.next().context("--only requires a value")?;
+                    only.get_or_insert_default().extend(names.split(',').map(str::to_owned));
+                }
+                "--skip" if runs => {
+                    let names = args.next().context("--skip requires a value")?;
+                    skip.extend(names.split(',').map(str::to_owned));
+                }
+                "--repeat" if runs => {
+                    let value = args.next().context("--repeat requires a value")?;
+                    repeat =
+                        value.parse().with_context(|| format!("invalid repeat count `{value}`"))?;
+                }
+                "--interval" if runs => {
+                    interval =
+                        parse_duration(&args.next().context("--interval requires a value")?)?;
+                }
+                "--output" if runs => {
+                    json = match args.next().context("--output requires a value")?.as_str() {
+                        "text" => false,
+                        "json" => true,
+                        output => bail!("unknown output `{output}`, expected `text` or `json`"),
+                    };
+                }
+                "--config" if runs => {
+                    config = Some(args.next().context("--config requires a value")?);
+                }
+                "--threads" if runs => {
+                    let value = args.next().context("--threads requires a value")?;
+                    let value =
+                        value.parse().with_context(|| format!("invalid thread count `{value}`"))?;
+                    threads = Some(value);
+                }
+                "--format" if command == "graph" => {
+                    format = match args.next().context("--format requires a value")?.as_str() {
+                        "dot" => GraphFormat::Dot,
+                        "mermaid" => GraphFormat::Mermaid,
+                        format => {
+                            bail!("unknown graph format `{format}`, expected `dot` or `mermaid`")
+                        }
+                    };
+                }
+                "--registry" if matches!(command.as_str(), "install" | "outdated") => {
+                    registry = Some(args.next().context("--registry requires a value")?);
+                }
+                "--key" if matches!(command.as_str(), "sign" | "verify") => {
+                    key = Some(PathBuf::from(args.next().context("--key requires a value")?));
+                }
+                "--path" | "--git" if command == "new" => {
+                    if !matches!(sora, Sora::Release) {
+                        bail!("--path and --git cannot be used together");
+                    }
+                    let value = args.next().with_context(|| format!("{arg} requires a value"))?;
+                    sora = match arg.as_str() {
+                        "--path" => Sora::Path(PathBuf::from(value)),
+                        _ => Sora::Git(value),
+                    };
+                }
+                "--dir" if command == "install" => {
+                    dir = PathBuf::from(args.next().context("--dir requires a value")?);
+                }
+                "--" if runs => plugin_args.extend(args.by_ref()),
+                option if option.starts_with('-') => bail!("unknown option `{option}`\n{USAGE}"),
+                _ => paths.push(PathBuf::from(arg)),
+            }
+        }
+
+        if command == "new" {
+            let [name] = <[_; 1]>::try_from(paths)
+                .map_err(|_| anyhow::anyhow!("a plugin name must be specified.\n{USAGE}"))?;
+            return Ok(Self::New { name: name.to_string_lossy().into_owned(), sora });
+        }
+
+        if command == "install" {
+            let [plugin] = <[_; 1]>::try_from(paths)
+                .map_err(|_| anyhow::anyhow!("a plugin must be specified.\n{USAGE}"))?;
+            let plugin = plugin.to_string_lossy();
+            let (name, version) = match plugin.split_once('@') {
+                Some((name, version)) => (name, Some(version.to_owned())),
+                None => (&*plugin, None),
+            };
+            return Ok(Self::Install { name: name.to_owned(), version, registry, dir });
+        }
+
+        if command == "outdated" {
+            if paths.is_empty() {
+                paths.push(PathBuf::from(PLUGIN_DIR));
+            }
+            return Ok(Self::Outdated { paths, registry });
+        }
+
+        if paths.is_empty() && !(runs && config.is_some()) {
+            bail!("a plugin folder path must be specified.\n{USAGE}");
+        }
+
+        match command.as_str() {
+            "run" | "daemon" => {
+                if command == "daemon" && (watch || repeat != 1 || json) {
+                    bail!("--watch, --repeat and --output cannot be used with daemon");
+                }
+
+                let options = RunOptions {
+                    paths,
+                    config,
+                    parallel,
+                    threads,
+                    watch,
+                    only,
+                    skip,
+                    repeat,
+                    interval,
+                    json,
+                    dry_run,
+                    args: plugin_args,
+                };
+                match command.as_str() {
+                    "run" => Ok(Self::Run(options)),
+                    _ => Ok(Self::Daemon(options)),
+                }
+            }
+            "list" => Ok(Self::List { paths }),
+            "graph" => Ok(Self::Graph { paths, format }),
+            "validate" => Ok(Self::Validate { paths }),
+            "lock" => Ok(Self::Lock { paths }),
+            "sign" | "verify" => {
+                let key = key.with_context(|| format!("a key must be specified.\n{USAGE}"))?;
+                match command.as_str() {
+                    "sign" => Ok(Self::Sign { paths, key }),
+                    _ => Ok(Self::Verify { paths, key }),
+                }
+            }
+            command => bail!("unknown command `{command}`\n{USAGE}"),
+        }
+    }
+}
+
+/// Sets the verbosity from the `-v` and `-q` options anywhere among `args`
+/// before `--`, and leaves out those options. Everything after `--` is for
+/// the plugins.
+fn take_verbosity(args: impl Iterator<Item = String>) -> impl Iterator<Item = String> {
+    let mut plugin_args = false;
+    args.filter(move |arg| {
+        let verbosity = match arg.as_str() {
+            _ if plugin_args => return true,
+            "--" => {
+                plugin_args = true;
+                return true;
+            }
+            "-q" | "--quiet" => Verbosity::Quiet,
+            "-v" | "--verbose" => Verbosity::Verbose,
+            _ => return true,
+        };
+        VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+        false
+    })
+}
 
 fn main() -> Result<()> {
-    let mut args = std::env::args().skip(1);
+    match Command::parse(take_verbosity(std::env::args().skip(1)))? {
+        Command::Run(options) => run(&options),
+        Command::Daemon(options) => daemon(&options),
+        Command::List { paths } => list(&paths),
+        Command::Graph { paths, format } => graph(&paths, format),
+        Command::Validate { paths } => validate(&paths),
+        Command::New { name, sora } => new(&name, sora),
+        Command::Install { name, version, registry, dir } => {
+            install(&name, version.as_deref(), registry, &dir)
+        }
+        Command::Outdated { paths, registry } => outdated(&paths, registry),
+        Command::Lock { paths } => lock(&paths),
+        Command::Sign { paths, key } => sign(&paths, &key),
+        Command::Verify { paths, key } => verify(&paths, &key),
+    }
+}
+
+fn run(options: &RunOptions) -> Result<()> {
+    let config = resolve_config(options)?;
+
+    if options.dry_run {
+        let plan = dispatcher(&config, options, &Snapshot::default())?.plan();
+        match options.json {
+            true => println!("{}", plan.to_json()),
+            false => print!("{plan}"),
+        }
+        return Ok(());
+    }
+
+    // The state of the plugins, carried over from one load to the next.
+    let mut state = Snapshot::default();
+    if !options.watch {
+        return dispatch(&config, options, &mut state);
+    }
+
+    loop {
+        let snapshot = snapshot(&config.directories)?;
 
-    let path = match args.next() {
-        Some(path) if args.is_empty() => path,
-        Some(_) => bail!("only one plugin folder path must be specified."),
-        None => bail!("a plugin folder path must be specified."),
+        // Keep watching after a failed load, the next change may fix it.
+        if let Err(error) = dispatch(&config, options, &mut state) {
+            eprintln!("Error: {error:?}");
+        }
+
+        info!("Watching for changes...");
+        wait_for_change(&config.directories, snapshot)?;
+    }
+}
+
+/// Reads the config file, if any, and applies the command line on top.
+fn resolve_config(options: &RunOptions) -> Result<Config> {
+    let mut config = match &options.config {
+        Some(path) => Config::read(path).with_context(|| format!("cannot load {path}"))?,
+        None => Config::default(),
     };
 
+    // Command line arguments take precedence over the config file.
+    if !options.paths.is_empty() {
+        config.directories = options.paths.clone();
+    }
+    config.parallel |= options.parallel;
+    config.threads = options.threads.or(config.threads);
+
+    if config.directories.is_empty() {
+        bail!("no plugins are specified on the command line or in the config file");
+    }
+    if options.threads.is_some() && !config.parallel {
+        bail!("--threads can only be used with --parallel");
+    }
+
+    Ok(config)
+}
+
+/// Looks up the libraries plugins link to next to them, since plugins may
+/// ship those.
+fn native_options(paths: &[PathBuf]) -> NativeLoaderOptions {
+    paths.iter().fold(NativeLoaderOptions::new(), |options, path| match path.is_file() {
+        true => options.search_path(path.parent().unwrap_or(Path::new("."))),
+        false => options.search_path(path),
+    })
+}
+
+/// The lockfile in the current directory, if there is one.
+fn lockfile() -> Result<Option<Lockfile>> {
+    let path = Path::new(Lockfile::FILE_NAME);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let lockfile =
+        Lockfile::read(path).with_context(|| format!("cannot load {}", path.display()))?;
+    debug!("Checking plugins against {}", path.display());
+    Ok(Some(lockfile))
+}
+
+/// Loads every plugin folder and library in `paths` into one manager,
+/// checking them against the lockfile, if any.
+fn load(paths: &[PathBuf]) -> Result<PluginManager> {
+    let start = Instant::now();
     let mut manager = PluginManager::new();
+    manager.set_native_options(native_options(paths));
+    if let Some(lockfile) = lockfile()? {
+        manager.set_lockfile(lockfile);
+    }
+    for path in paths {
+        debug!("Loading {}", path.display());
+    }
+
+    // A broken library is skipped rather than keeping every other plugin
+    // from running.
+    let outcome = unsafe { manager.load_all(paths) };
+    for (path, error) in &outcome.failed {
+        eprintln!("Warning: skipping {}: {error}", path.display());
+    }
+    debug!("Loaded {} plugin(s) in {:?}", outcome.loaded.len(), start.elapsed());
+
+    Ok(manager)
+}
+
+/// Loads the plugins of `config`, restores their `state`, and schedules
+/// those that pass the filters.
+fn dispatcher(
+    config: &Config,
+    options: &RunOptions,
+    state: &Snapshot,
+) -> Result<Dispatcher<impl Send + Sync>> {
+    let manager = load(&config.directories)?;
+    // A plugin whose state no longer loads, for example because its format
+    // changed, starts afresh rather than failing the reload.
+    if let Err(error) = manager.restore(state) {
+        eprintln!("Warning: {error}");
+    }
+
+    let mut dispatcher = manager
+        .into_dispatcher_builder()
+        .num_threads(config.threads.unwrap_or(0))
+        .environment(environment(config))
+        .args(options.args.iter().cloned());
+    if let Some(enabled) = config.enabled.clone() {
+        dispatcher =
+            dispatcher.filter(move |plugin| enabled.iter().any(|name| name == plugin.name()));
+    }
+    if let Some(only) = options.only.clone() {
+        dispatcher = dispatcher.filter(move |plugin| only.iter().any(|name| name == plugin.name()));
+    }
+    if !options.skip.is_empty() {
+        let skip = options.skip.clone();
+        dispatcher =
+            dispatcher.filter(move |plugin| !skip.iter().any(|name| name == plugin.name()));
+    }
+
+    Ok(dispatcher.build())
+}
+
+/// The environment of the plugins: the version of sora, and the settings of
+/// each plugin in the config, keyed `plugins.<name>.<key>`.
+fn environment(config: &Config) -> Environment {
+    let mut environment = config.plugin_environment();
+    environment.insert("sora.version", env!("CARGO_PKG_VERSION"));
+    environment
+}
+
+/// Loads and dispatches the plugins, starting them from `state` and leaving
+/// theirs in it, even when a dispatch fails.
+///
+/// [`Phase::Startup`] is dispatched first and [`Phase::Shutdown`] last, even
+/// after a failed dispatch, with the dispatches of [`Phase::Update`] in
+/// between.
+fn dispatch(config: &Config, options: &RunOptions, state: &mut Snapshot) -> Result<()> {
+    let dispatcher = dispatcher(config, options, state)?;
+    let result = dispatch_once(&dispatcher, config, options, Phase::Startup, false)
+        .and_then(|()| repeat(&dispatcher, config, options));
+    let result = result.and(dispatch_once(&dispatcher, config, options, Phase::Shutdown, false));
+    *state = dispatcher.snapshot();
+    result
+}
+
+/// Dispatches [`Phase::Update`] `--repeat` times, `--interval` apart.
+fn repeat(
+    dispatcher: &Dispatcher<impl Send + Sync>,
+    config: &Config,
+    options: &RunOptions,
+) -> Result<()> {
+    for iteration in 1.. {
+        let start = Instant::now();
+
+        dispatch_once(dispatcher, config, options, Phase::Update, false)?;
+
+        if iteration == options.repeat {
+            break;
+        }
+        std::thread::sleep(options.interval.saturating_sub(start.elapsed()));
+    }
+
+    Ok(())
+}
+
+/// Dispatches the plugins of `phase` once. With `catch_panics`, or whenever
+/// a report is needed, a panicking plugin is reported as an error instead
+/// of unwinding.
+fn dispatch_once(
+    dispatcher: &Dispatcher<impl Send + Sync>,
+    config: &Config,
+    options: &RunOptions,
+    phase: Phase,
+    catch_panics: bool,
+) -> Result<()> {
+    match (config.parallel, catch_panics || options.json || verbosity() == Verbosity::Verbose) {
+        (true, false) => dispatcher.dispatch_phase_par(phase),
+        (false, false) => dispatcher.dispatch_phase(phase),
+        (parallel, true) => {
+            let report = match parallel {
+                true => dispatcher.dispatch_phase_par_report(phase),
+                false => dispatcher.dispatch_phase_report(phase),
+            };
+
+            for (index, stage) in report.stages.iter().enumerate() {
+                for plugin in stage {
+                    debug!(
+                        "stage {index}: {} {:?} in {:?}",
+                        plugin.name, plugin.status, plugin.duration
+                    );
+                }
+            }
+            debug!("{phase:?} finished in {:?}", report.duration);
+
+            // Without plugins to start or shut down, there is one report per
+            // dispatch of the updates.
+            let empty = report.stages.iter().all(Vec::is_empty);
+            if options.json && (phase == Phase::Update || !empty) {
+                println!("{}", report.to_json());
+            }
+            if !report.is_success() {
+                bail!("one or more plugins panicked");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Dispatches [`Phase::Startup`], then [`Phase::Update`] right away, and
+/// again every `--interval` and whenever SIGHUP is received, until SIGINT or
+/// SIGTERM, which cancel the running plugins and dispatch
+/// [`Phase::Shutdown`].
+#[cfg(unix)]
+fn daemon(options: &RunOptions) -> Result<()> {
+    let config = resolve_config(options)?;
+    let dispatcher = dispatcher(&config, options, &Snapshot::default())?;
+    signals::install();
+
+    // A failed dispatch does not stop the daemon.
+    let dispatch = |phase| {
+        if let Err(error) = dispatch_once(&dispatcher, &config, options, phase, true) {
+            eprintln!("Error: {error:?}");
+        }
+    };
+
+    std::thread::scope(|scope| {
+        // Plugins that are running when the signal arrives learn of it
+        // through `RunContext::is_cancelled`.
+        let watcher = scope.spawn(|| {
+            while !signals::SHUTDOWN.load(Ordering::Relaxed) {
+                std::thread::sleep(DAEMON_POLL_INTERVAL);
+            }
+            dispatcher.cancel();
+        });
+
+        dispatch(Phase::Startup);
+        loop {
+            let start = Instant::now();
+
+            dispatch(Phase::Update);
+
+            loop {
+                if signals::SHUTDOWN.load(Ordering::Relaxed) {
+                    info!("Shutting down");
+                    // Only the update is cancelled: shutdown plugins and
+                    // their retries run to completion.
+                    let _ = watcher.join();
+                    dispatcher.uncancel();
+                    dispatch(Phase::Shutdown);
+                    return Ok(());
+                }
+                if signals::HANGUP.swap(false, Ordering::Relaxed) {
+                    info!("Received SIGHUP, dispatching");
+                    break;
+                }
+                if !options.interval.is_zero() && start.elapsed() >= options.interval {
+                    break;
+                }
+
+                std::thread::sleep(DAEMON_POLL_INTERVAL);
+            }
+        }
+    })
+}
+
+#[cfg(not(unix))]
+fn daemon(_: &RunOptions) -> Result<()> {
+    bail!("daemon mode is only supported on Unix");
+}
+
+#[cfg(unix)]
+mod signals {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    pub static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+    pub static HANGUP: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn handle(signal: libc::c_int) {
+        match signal {
+            libc::SIGHUP => HANGUP.store(true, Ordering::Relaxed),
+            _ => SHUTDOWN.store(true, Ordering::Relaxed),
+        }
+    }
+
+    pub fn install() {
+        let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        for signal in [libc::SIGHUP, libc::SIGINT, libc::SIGTERM] {
+            unsafe { libc::signal(signal, handler) };
+        }
+    }
+}
+
+/// Parses durations such as `500ms`, `2s` or `1m`.
+fn parse_duration(value: &str) -> Result<Duration> {
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse().with_context(|| format!("invalid duration `{value}`"))?;
+
+    match unit {
+        "ms" => Ok(Duration::from_millis(amount)),
+        "s" => Ok(Duration::from_secs(amount)),
+        "m" => Ok(Duration::from_secs(amount.saturating_mul(60))),
+        _ => bail!("invalid duration `{value}`, expected a unit of `ms`, `s` or `m`"),
+    }
+}
+
+/// The modification time of every file in `paths`, looking inside
+/// folders.
+fn snapshot(paths: &[PathBuf]) -> Result<BTreeMap<PathBuf, SystemTime>> {
+    let mut snapshot = BTreeMap::new();
+    let mut insert = |path: &Path| -> Result<()> {
+        let metadata = path.metadata()?;
+        if metadata.is_file() {
+            snapshot.insert(path.to_owned(), metadata.modified()?);
+        }
+        Ok(())
+    };
+
+    for path in paths {
+        if path.is_file() {
+            insert(path)?;
+            continue;
+        }
+
+        let entries =
+            std::fs::read_dir(path).with_context(|| format!("cannot read {}", path.display()))?;
+        for entry in entries {
+            insert(&entry?.path())?;
+        }
+    }
+
+    Ok(snapshot)
+}
+
+/// Blocks until the contents of `paths` differ from `previous` and have
+/// stopped changing for one [`WATCH_INTERVAL`], so that a library is not
+/// loaded while it is still being written.
+fn wait_for_change(paths: &[PathBuf], previous: BTreeMap<PathBuf, SystemTime>) -> Result<()> {
+    let mut last = previous.clone();
 
-    for entry in std::fs::read_dir(path)? {
-        let entry = entry?;
-        unsafe { manager.load_plugin(entry.path())? };
+    loop {
+        std::thread::sleep(WATCH_INTERVAL);
+
+        let current = snapshot(paths)?;
+        if current == last && current != previous {
+            return Ok(());
+        }
+        last = current;
     }
+}
 
+fn list(paths: &[PathBuf]) -> Result<()> {
+    let manager = load(paths)?;
+    let metadata: HashMap<_, _> = manager
+        .metadata()
+        .iter()
+        .map(|metadata| (metadata.name.clone(), metadata.clone()))
+        .collect();
     let dispatcher = manager.into_dispatcher();
-    dispatcher.dispatch_par();
+
+    let rows: Vec<[String; 5]> = dispatcher
+        .plugins()
+        .enumerate()
+        .map(|(index, plugin)| {
+            let metadata = &metadata[plugin.name()];
+            [
+                (index + 1).to_string(),
+                metadata.name.clone(),
+                metadata.version.clone().unwrap_or_else(|| "-".to_owned()),
+                metadata.dependencies.join(", "),
+                metadata.description.clone().unwrap_or_default(),
+            ]
+        })
+        .collect();
+
+    print_table(["ORDER", "NAME", "VERSION", "DEPENDENCIES", "DESCRIPTION"], &rows);
+
+    Ok(())
+}
+
+fn graph(paths: &[PathBuf], format: GraphFormat) -> Result<()> {
+    let manager = load(paths)?;
+    print!("{}", manager.into_dispatcher().graph(format));
+
+    Ok(())
+}
+
+/// Loads `paths` and reports every problem found, without running any
+/// plugin.
+fn validate(paths: &[PathBuf]) -> Result<()> {
+    let mut manager = PluginManager::new();
+    manager.set_native_options(native_options(paths).check_dependencies());
+    if let Some(lockfile) = lockfile()? {
+        manager.set_lockfile(lockfile);
+    }
+    // The plugins that did load are checked as well.
+    let outcome = unsafe { manager.load_all(paths) };
+    let mut problems: Vec<_> =
+        outcome.failed.iter().map(|(path, error)| format!("{}: {error}", path.display())).collect();
+
+    if let Err(errors) = manager.validate() {
+        problems.extend(errors.iter().map(ToString::to_string));
+    }
+    for warning in manager.warnings() {
+        eprintln!("warning: {warning}");
+    }
+
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("{problem}");
+        }
+        bail!("found {} problem(s)", problems.len());
+    }
+
+    println!("ok");
+    Ok(())
+}
+
+/// Creates a plugin crate named `name` in the current directory.
+fn new(name: &str, sora: Sora) -> Result<()> {
+    if name.is_empty()
+        || name.starts_with(|c: char| c.is_ascii_digit())
+        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("`{name}` is not a valid crate name");
+    }
+
+    let dir = Path::new(name);
+    if dir.exists() {
+        bail!("{} already exists", dir.display());
+    }
+
+    // TOML literal strings need no escaping, but cannot hold `'`.
+    let literal = |value: &str| match value.contains(['\'', '\n']) {
+        true => bail!("`{value}` cannot be written to Cargo.toml"),
+        false => Ok(format!("'{value}'")),
+    };
+    let version = env!("CARGO_PKG_VERSION");
+    let (dependency, source) = match sora {
+        Sora::Release => (format!("\"{version}\""), format!("sora {version}")),
+        Sora::Path(path) => {
+            // The plugin is created in a new folder, so relative paths would
+            // no longer lead to sora.
+            let path = path
+                .canonicalize()
+                .with_context(|| format!("cannot find sora in {}", path.display()))?;
+            if !path.join("Cargo.toml").is_file() {
+                bail!("{} is not a crate", path.display());
+            }
+            let display = path.display().to_string();
+            (format!("{{ path = {} }}", literal(&display)?), format!("the sora in {display}"))
+        }
+        Sora::Git(url) => (format!("{{ git = {} }}", literal(&url)?), format!("the sora at {url}")),
+    };
+
+    // `my-plugin` becomes `MyPlugin`, which is also the plugin's name.
+    let type_name: String = name
+        .split(['-', '_'])
+        .flat_map(|word| {
+            let mut chars = word.chars();
+            chars.next().map(|c| c.to_ascii_uppercase()).into_iter().chain(chars)
+        })
+        .collect();
+
+    let cargo_toml = format!(
+        r#"[package]
+name = "{name}"
+version = "0.1.0"
+edition = "2021"
+
+[lib]
+crate-type = ["cdylib"]
+
+[dependencies]
+sora = {dependency}
+"#
+    );
+
+    let lib_rs = format!(
+        r#"#[derive(Default)]
+pub struct {type_name} {{}}
+
+impl sora::Plugin for {type_name} {{
+    fn dependencies(&self) -> &[&str] {{
+        &[]
+    }}
+
+    fn version(&self) -> Option<&str> {{
+        Some(env!("CARGO_PKG_VERSION"))
+    }}
+
+    fn run(&self, _: &sora::RunContext) {{
+        println!("Hello from {type_name}!");
+    }}
+}}
+
+sora::export_plugin!({type_name}::default());
+"#
+    );
+
+    std::fs::create_dir_all(dir.join("src"))?;
+    std::fs::write(dir.join("Cargo.toml"), cargo_toml)?;
+    std::fs::write(dir.join("src/lib.rs"), lib_rs)?;
+    std::fs::write(dir.join(".gitignore"), "/target\n")?;
+
+    println!("Created plugin `{type_name}` in {}", dir.display());
+    println!("Build it with `cargo build --release` and run it with `sora run target/release`");
+    println!(
+        "It depends on {source}, and only loads into hosts built against the same sora, with the \
+         same Rust compiler"
+    );
 
     Ok(())
 }
+
+/// Opens `registry`, or the one in `$SORA_REGISTRY`.
+fn open_registry(registry: Option<String>) -> Result<Registry> {
+    let registry = match registry.or_else(|| std::env::var("SORA_REGISTRY").ok()) {
+        Some(registry) => registry,
+        None => bail!("no registry is specified with --registry or $SORA_REGISTRY"),
+    };
+    Registry::open(&registry).with_context(|| format!("cannot open registry {registry}"))
+}
+
+/// Installs the plugin `name` from `registry` into `dir`.
+fn install(name: &str, version: Option<&str>, registry: Option<String>, dir: &Path) -> Result<()> {
+    let registry = open_registry(registry)?;
+    let release = registry.resolve(name, version)?;
+    debug!("Installing {} {} from {}", release.name, release.version, release.bundle.display());
+    let path = registry.install(release, dir)?;
+    println!("Installed {} {} to {}", release.name, release.version, path.display());
+
+    Ok(())
+}
+
+/// Locks the plugins in `paths` to their current libraries.
+fn lock(paths: &[PathBuf]) -> Result<()> {
+    let mut manager = PluginManager::new();
+    manager.set_native_options(native_options(paths));
+    let outcome = unsafe { manager.load_all(paths) };
+    if let Some((path, error)) = outcome.failed.first() {
+        bail!("cannot lock {}: {error}", path.display());
+    }
+
+    let lockfile = manager.lock()?;
+    lockfile.write(Lockfile::FILE_NAME)?;
+    println!("Locked {} plugin(s) in {}", lockfile.plugins.len(), Lockfile::FILE_NAME);
+
+    Ok(())
+}
+
+/// Signs every library in `paths` with the private key in `key`.
+fn sign(paths: &[PathBuf], key: &Path) -> Result<()> {
+    let pem =
+        std::fs::read_to_string(key).with_context(|| format!("cannot read {}", key.display()))?;
+    let key =
+        SigningKey::from_pem(&pem).with_context(|| format!("invalid key {}", key.display()))?;
+
+    for path in paths {
+        let library =
+            std::fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+        let signature_path = signature_path(path);
+        std::fs::write(&signature_path, key.sign(&library))
+            .with_context(|| format!("cannot write {}", signature_path.display()))?;
+        println!("Signed {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Checks the signature of every library in `paths` against the public key
+/// in `key`, or that of the private key in it.
+fn verify(paths: &[PathBuf], key: &Path) -> Result<()> {
+    let pem =
+        std::fs::read_to_string(key).with_context(|| format!("cannot read {}", key.display()))?;
+    let public_key = match SigningKey::from_pem(&pem) {
+        Ok(private_key) => private_key.public_key(),
+        Err(_) => {
+            public_key_from_pem(&pem).with_context(|| format!("invalid key {}", key.display()))?
+        }
+    };
+
+    let mut invalid = 0;
+    for path in paths {
+        let library =
+            std::fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+        let signature = std::fs::read(signature_path(path)).unwrap_or_default();
+        match verify_signature(&public_key, &library, &signature) {
+            true => println!("{}: ok", path.display()),
+            false => {
+                eprintln!("{}: no valid signature", path.display());
+                invalid += 1;
+            }
+        }
+    }
+
+    if invalid > 0 {
+        bail!("{invalid} library(s) without a valid signature");
+    }
+    Ok(())
+}
+
+/// Where the detached signature of the library at `path` is kept.
+fn signature_path(path: &Path) -> PathBuf {
+    let mut signature_path = path.as_os_str().to_owned();
+    signature_path.push(".sig");
+    PathBuf::from(signature_path)
+}
+
+/// Lists the plugins in `paths` that have a newer release in `registry`.
+fn outdated(paths: &[PathBuf], registry: Option<String>) -> Result<()> {
+    let registry = open_registry(registry)?;
+    let updates = load(paths)?.check_updates(&registry);
+    if updates.is_empty() {
+        info!("All plugins are up to date");
+        return Ok(());
+    }
+
+    let rows: Vec<[String; 3]> =
+        updates.into_iter().map(|update| [update.name, update.installed, update.latest]).collect();
+    print_table(["NAME", "INSTALLED", "LATEST"], &rows);
+
+    Ok(())
+}
+
+fn print_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) {
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let print_row = |cells: [&str; N]| {
+        let line: Vec<_> =
+            cells.iter().zip(widths).map(|(cell, width)| format!("{cell:width$}")).collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+
+    print_row(header);
+    for row in rows {
+        print_row(row.each_ref().map(String::as_str));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use sora::{Config, FnPlugin, PluginManager};
+
+    use super::{Command, Verbosity, environment, take_verbosity, verbosity};
+
+    #[test]
+    fn plugin_args() {
+        let args = ["run", "-v", "plugins", "--", "-v", "-q", "--"].map(str::to_owned);
+        let Ok(Command::Run(options)) = Command::parse(take_verbosity(args.into_iter())) else {
+            panic!("`run` does not parse");
+        };
+
+        assert_eq!(options.paths, [std::path::PathBuf::from("plugins")]);
+        assert_eq!(options.args, ["-v", "-q", "--"]);
+        assert!(verbosity() == Verbosity::Verbose);
+    }
+
+    #[test]
+    fn new() {
+        let args = ["new", "--git", "https://example.com/sora", "my-plugin"].map(str::to_owned);
+        let Ok(Command::New { name, sora: super::Sora::Git(url) }) =
+            Command::parse(args.into_iter())
+        else {
+            panic!("`new --git` does not parse");
+        };
+        assert_eq!(name, "my-plugin");
+        assert_eq!(url, "https://example.com/sora");
+
+        let args = ["new", "--path", ".", "--git", "https://example.com/sora", "my-plugin"];
+        assert!(Command::parse(args.map(str::to_owned).into_iter()).is_err());
+    }
+
+    #[test]
+    fn plugin_settings() {
+        let config = Config::parse("[plugins.Hello]\ngreeting = \"hi\"").unwrap();
+        let greeting = Arc::new(Mutex::new(None));
+        let mut manager = PluginManager::new();
+        let read = Arc::clone(&greeting);
+        manager
+            .register(Box::new(FnPlugin::new("Hello", &[], move |context| {
+                let greeting = context.environment().get("plugins.Hello.greeting");
+                *read.lock().unwrap() = greeting.map(str::to_owned);
+            })))
+            .unwrap();
+
+        manager.into_dispatcher_builder().environment(environment(&config)).build().dispatch();
+        assert_eq!(greeting.lock().unwrap().as_deref(), Some("hi"));
+    }
+}
@@ -2,13 +2,17 @@
 pub struct Hello {}
 
 impl sora::Plugin for Hello {
-    fn run(&self) {
+    fn version(&self) -> Option<&str> {
+        Some(env!("CARGO_PKG_VERSION"))
+    }
+
+    fn description(&self) -> Option<&str> {
+        Some("Greets the world")
+    }
+
+    fn run(&self, _: &sora::RunContext) {
         println!("Hello, World!");
     }
 }
 
-#[no_mangle]
-#[allow(improper_ctypes_definitions)]
-pub extern "C" fn create_plugin() -> *mut dyn sora::Plugin {
-    Box::into_raw(Box::new(Hello::default()))
-}
+sora::export_plugin!(Hello::default());
@@ -0,0 +1,96 @@
+use std::path::PathBuf;
+
+use sora::{Drift, LazyPlugin, PluginLoadError, PluginManager};
+
+/// The cdylib is built next to the test executable, in `target/*/deps`.
+fn library() -> PathBuf {
+    let exe = std::env::current_exe().unwrap();
+    let name =
+        format!("{}hello_world{}", std::env::consts::DLL_PREFIX, std::env::consts::DLL_SUFFIX);
+    exe.parent().unwrap().join(name)
+}
+
+#[test]
+fn load() {
+    let mut manager = PluginManager::new();
+    unsafe { manager.load_plugin(library()).unwrap() };
+    assert_eq!(manager.metadata()[0].api_version, Some(1));
+    assert_eq!(manager.metadata()[0].path, library().canonicalize().ok());
+
+    // The report owns the plugin names, so it outlives the library.
+    let report = manager.into_dispatcher().dispatch_report();
+    let names: Vec<_> = report.plugins().map(|plugin| plugin.name.as_str()).collect();
+
+    assert_eq!(names, ["Hello"]);
+    assert!(report.is_success());
+}
+
+#[test]
+fn load_all() {
+    let missing = library().with_file_name("missing");
+    let mut manager = PluginManager::new();
+    let outcome = unsafe { manager.load_all([missing.clone(), library()]) };
+
+    assert_eq!(outcome.loaded, ["Hello"]);
+    assert_eq!(outcome.failed.len(), 1);
+    assert_eq!(outcome.failed[0].0, missing);
+    assert!(!outcome.is_success());
+    assert!(manager.plugin("Hello").is_some());
+}
+
+#[test]
+fn load_bytes() {
+    let bytes = std::fs::read(library()).unwrap();
+    let mut manager = PluginManager::new();
+    unsafe { manager.load_bytes("hello-world", &bytes, None).unwrap() };
+    assert!(manager.into_dispatcher().dispatch_report().is_success());
+
+    let mut manager = PluginManager::new();
+    manager.trust_key([0; 32]);
+    let error = unsafe { manager.load_bytes("hello-world", &bytes, None) }.unwrap_err();
+    assert!(matches!(error, PluginLoadError::SignatureInvalid(_)));
+}
+
+#[test]
+fn load_lazy() {
+    let mut manager = PluginManager::new();
+    unsafe { manager.load_plugin_lazy(library(), LazyPlugin::new("Hello", &[])).unwrap() };
+    assert_eq!(manager.metadata()[0].api_version, Some(1));
+    assert!(manager.into_dispatcher().dispatch_report().is_success());
+
+    // The library creates `Hello`, not the plugin that was declared.
+    let mut manager = PluginManager::new();
+    unsafe { manager.load_plugin_lazy(library(), LazyPlugin::new("Goodbye", &[])).unwrap() };
+    assert!(!manager.into_dispatcher().dispatch_report().is_success());
+}
+
+#[test]
+fn entry_points() {
+    let mut manager = PluginManager::new();
+    manager.set_entry_points(["missing", "create_plugin"]);
+    unsafe { manager.load_plugin(library()).unwrap() };
+
+    let mut manager = PluginManager::new();
+    manager.set_entry_points(["missing"]);
+    assert!(unsafe { manager.load_plugin(library()) }.is_err());
+}
+
+#[test]
+fn lockfile() {
+    let mut manager = PluginManager::new();
+    unsafe { manager.load_plugin(library()).unwrap() };
+    let lockfile = manager.lock().unwrap();
+    assert_eq!(lockfile.plugins["Hello"].version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+
+    let mut manager = PluginManager::new();
+    manager.set_lockfile(lockfile.clone());
+    unsafe { manager.load_plugin(library()).unwrap() };
+
+    let mut drifted = lockfile;
+    drifted.plugins.get_mut("Hello").unwrap().sha256 = [0; 32];
+    let mut manager = PluginManager::new();
+    manager.set_lockfile(drifted);
+    let error = unsafe { manager.load_plugin(library()) }.unwrap_err();
+    assert!(matches!(error, PluginLoadError::Drift { drift: Drift::Digest { .. }, .. }));
+    assert!(manager.metadata().is_empty());
+}
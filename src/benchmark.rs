@@ -0,0 +1,150 @@
+//! Repeated timing of a dispatch, as returned by
+//! [`Dispatcher::benchmark`].
+
+use std::time::{Duration, Instant};
+
+use crate::Dispatcher;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Benchmark {
+    pub iterations: usize,
+    pub stages: Vec<StageBenchmark>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageBenchmark {
+    pub timing: Timing,
+    pub plugins: Vec<PluginBenchmark>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginBenchmark {
+    pub name: String,
+    pub timing: Timing,
+}
+
+/// Statistics over the durations of every iteration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    pub min: Duration,
+    pub mean: Duration,
+    /// The 99th percentile, by the nearest-rank method.
+    pub p99: Duration,
+}
+
+impl Timing {
+    fn new(samples: &mut [Duration]) -> Self {
+        samples.sort_unstable();
+
+        let rank = (samples.len() * 99).div_ceil(100);
+        Self {
+            min: samples[0],
+            mean: samples.iter().sum::<Duration>() / samples.len() as u32,
+            p99: samples[rank - 1],
+        }
+    }
+}
+
+impl<L> Dispatcher<L> {
+    /// Runs every plugin `iterations` times, one after another as
+    /// [`dispatch`](Self::dispatch) does, and summarizes how long each
+    /// plugin and each stage took.
+    ///
+    /// # Panics
+    ///
+    /// If `iterations` is zero, or a plugin panics.
+    pub fn benchmark(&self, iterations: usize) -> Benchmark {
+        assert!(iterations > 0, "cannot benchmark zero iterations");
+
+        let stages = self.stages();
+        let mut stage_samples = vec![Vec::with_capacity(iterations); stages.len()];
+        let mut plugin_samples: Vec<Vec<_>> =
+            stages.iter().map(|stage| vec![Vec::with_capacity(iterations); stage.len()]).collect();
+
+        for _ in 0..iterations {
+            self.state.begin();
+            for (index, stage) in stages.iter().enumerate() {
+                let start = Instant::now();
+                for (samples, &slot) in plugin_samples[index].iter_mut().zip(stage) {
+                    let start = Instant::now();
+                    let plugin = self.at(slot);
+                    plugin.run(&self.state.context(&**plugin));
+                    samples.push(start.elapsed());
+                }
+                stage_samples[index].push(start.elapsed());
+            }
+        }
+
+        let stages = stages
+            .iter()
+            .zip(&mut stage_samples)
+            .zip(&mut plugin_samples)
+            .map(|((stage, stage_samples), plugin_samples)| StageBenchmark {
+                timing: Timing::new(stage_samples),
+                plugins: stage
+                    .iter()
+                    .zip(plugin_samples)
+                    .map(|(&slot, samples)| PluginBenchmark {
+                        name: self.at(slot).name().to_owned(),
+                        timing: Timing::new(samples),
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        Benchmark { iterations, stages }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Timing;
+    use crate::{Plugin, PluginManager, RunContext, register_static_plugins};
+
+    #[test]
+    fn timing() {
+        let mut samples: Vec<_> = (1..=200).rev().map(Duration::from_millis).collect();
+        let timing = Timing::new(&mut samples);
+
+        assert_eq!(timing.min, Duration::from_millis(1));
+        assert_eq!(timing.mean, Duration::from_micros(100_500));
+        assert_eq!(timing.p99, Duration::from_millis(198));
+    }
+
+    #[test]
+    fn benchmark() {
+        struct A;
+        impl Plugin for A {
+            fn run(&self, _: &RunContext) {}
+        }
+
+        struct B;
+        impl Plugin for B {
+            fn dependencies(&self) -> &[&str] {
+                &["A"]
+            }
+
+            fn run(&self, _: &RunContext) {}
+        }
+
+        let mut manager = PluginManager::new();
+        register_static_plugins!(manager, A, B).unwrap();
+        let benchmark = manager.into_dispatcher().benchmark(10);
+
+        assert_eq!(benchmark.iterations, 10);
+        let names: Vec<_> = benchmark
+            .stages
+            .iter()
+            .map(|stage| {
+                stage.plugins.iter().map(|plugin| plugin.name.as_str()).collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(names, [["A"], ["B"]]);
+
+        for stage in &benchmark.stages {
+            assert!(stage.timing.min <= stage.timing.mean && stage.timing.mean <= stage.timing.p99);
+        }
+    }
+}
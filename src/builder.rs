@@ -0,0 +1,210 @@
+use std::any::Any;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::{
+    Integrity, LoadPolicy, Loader, Native, NativeLoaderOptions, Plugin, PluginLoadError,
+    PluginManager, Resources, canonical,
+};
+
+/// Collects everything a [`PluginManager`] should load and loads it in one
+/// go with [`build`](Self::build).
+///
+/// ```ignore
+/// let manager = unsafe {
+///     PluginManager::builder()
+///         .directory("plugins")
+///         .manifest("vendor/physics")
+///         .plugin(Render::default())
+///         .load_policy(LoadPolicy::deny(["Telemetry"]))
+///         .build()?
+/// };
+/// ```
+pub struct PluginManagerBuilder<L: Loader = Native> {
+    sources: Vec<Source>,
+    integrity: Integrity,
+    policy: LoadPolicy,
+    entry_points: Vec<String>,
+    native_options: NativeLoaderOptions,
+    resources: Resources,
+    loader: L,
+}
+
+enum Source {
+    Library(PathBuf),
+    Directory(PathBuf),
+    Manifest(PathBuf),
+    Plugin(Box<dyn Plugin>),
+}
+
+impl<L: Loader + Default> PluginManagerBuilder<L> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<L: Loader> PluginManagerBuilder<L> {
+    /// A builder whose manager loads libraries with `loader`. See
+    /// [`PluginManager::with_loader`].
+    pub fn with_loader(loader: L) -> Self {
+        Self {
+            sources: <_>::default(),
+            integrity: <_>::default(),
+            policy: <_>::default(),
+            entry_points: <_>::default(),
+            native_options: <_>::default(),
+            resources: <_>::default(),
+            loader,
+        }
+    }
+
+    /// Loads the library at `path`. See [`PluginManager::load_plugin`].
+    pub fn library(mut self, path: impl Into<PathBuf>) -> Self {
+        self.sources.push(Source::Library(path.into()));
+        self
+    }
+
+    /// Loads every library in `dir`. See [`PluginManager::load_dir_par`].
+    pub fn directory(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.sources.push(Source::Directory(dir.into()));
+        self
+    }
+
+    /// Loads the plugin described by the manifest in `dir`. See
+    /// [`PluginManager::load_manifest`].
+    pub fn manifest(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.sources.push(Source::Manifest(dir.into()));
+        self
+    }
+
+    /// Registers an in-process plugin. See [`PluginManager::register`].
+    pub fn plugin(mut self, plugin: impl Plugin) -> Self {
+        self.sources.push(Source::Plugin(Box::new(plugin)));
+        self
+    }
+
+    /// See [`PluginManager::set_load_policy`].
+    pub fn load_policy(mut self, policy: LoadPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// See [`PluginManager::set_entry_points`].
+    pub fn entry_points<S: Into<String>>(mut self, entries: impl IntoIterator<Item = S>) -> Self {
+        self.entry_points = entries.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// See [`PluginManager::set_native_options`].
+    pub fn native_options(mut self, options: NativeLoaderOptions) -> Self {
+        self.native_options = options;
+        self
+    }
+
+    /// Provides `resource` to the plugins, which all sources are loaded
+    /// after. See [`PluginManager::resources_mut`].
+    pub fn resource<T: Any + Send + Sync>(mut self, resource: Arc<T>) -> Self {
+        self.resources.insert(resource);
+        self
+    }
+
+    /// See [`PluginManager::trust_key`].
+    pub fn trust_key(mut self, public_key: [u8; 32]) -> Self {
+        self.integrity.trusted_keys.push(public_key);
+        self
+    }
+
+    /// See [`PluginManager::pin_sha256`].
+    pub fn pin_sha256(mut self, path: impl AsRef<Path>, sha256: [u8; 32]) -> Self {
+        self.integrity.pinned.insert(canonical(path.as_ref()), sha256);
+        self
+    }
+}
+
+impl<L: Loader + Sync> PluginManagerBuilder<L>
+where
+    L::Library: Send,
+{
+    /// Loads all sources in the order they were added.
+    ///
+    /// Loading continues past failures, so that the returned error lists
+    /// every source that could not be loaded.
+    ///
+    /// # Safety
+    ///
+    /// Users of this API must specify the correct type of the function or
+    /// variable loaded.
+    pub unsafe fn build(self) -> Result<PluginManager<L>, BuildError> {
+        let mut manager = PluginManager {
+            integrity: self.integrity,
+            policy: self.policy,
+            entry_points: self.entry_points,
+            native_options: self.native_options,
+            resources: self.resources,
+            ..PluginManager::with_loader(self.loader)
+        };
+
+        let mut errors = Vec::new();
+        for source in self.sources {
+            let (path, result) = match source {
+                Source::Library(path) => {
+                    let result = manager.load_plugin(&path);
+                    (Some(path), result)
+                }
+                Source::Directory(dir) => match manager.load_dir_par(&dir) {
+                    Ok(failures) => {
+                        errors
+                            .extend(failures.into_iter().map(|(path, error)| (Some(path), error)));
+                        continue;
+                    }
+                    Err(error) => (Some(dir), Err(error)),
+                },
+                Source::Manifest(dir) => {
+                    let result = manager.load_manifest(&dir);
+                    (Some(dir), result)
+                }
+                Source::Plugin(plugin) => (None, manager.register(plugin)),
+            };
+
+            if let Err(error) = result {
+                errors.push((path, error));
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(manager),
+            false => Err(BuildError { errors }),
+        }
+    }
+}
+
+impl<L: Loader + Default> Default for PluginManagerBuilder<L> {
+    fn default() -> Self {
+        Self::with_loader(L::default())
+    }
+}
+
+/// Every failure encountered by [`PluginManagerBuilder::build`], with the
+/// path of the source that failed, if any.
+#[derive(Debug)]
+pub struct BuildError {
+    pub errors: Vec<(Option<PathBuf>, PluginLoadError)>,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to load {} plugin source(s)", self.errors.len())?;
+
+        for (path, error) in &self.errors {
+            match path {
+                Some(path) => write!(f, "\n  {}: {error}", path.display())?,
+                None => write!(f, "\n  {error}")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for BuildError {}
@@ -0,0 +1,394 @@
+//! Plugins shipped as a single `.sora` file, with a library for every
+//! platform they support.
+
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use ahash::AHashMap;
+
+use crate::sha2::Sha256;
+use crate::{Loader, Manifest, PluginLoadError, PluginManager, Result, hex};
+
+#[derive(Debug, thiserror::Error)]
+pub enum BundleError {
+    #[error("cannot extract bundle: {0}")]
+    Io(#[from] io::Error),
+    #[error("not a valid tar archive")]
+    Corrupt,
+    #[error("entry `{0}` would be extracted outside of the bundle")]
+    UnsafePath(String),
+    #[error("entry `{0}` is neither a file nor a folder")]
+    UnsupportedEntry(String),
+    #[error("no library for {target}; the bundle has {}", list(available))]
+    NoArtifact { target: String, available: Vec<String> },
+    #[error("the bundle cache {} is not a folder only the current user can write to", .0.display())]
+    InsecureCache(PathBuf),
+}
+
+fn list(targets: &[String]) -> String {
+    match targets.is_empty() {
+        true => "none".to_owned(),
+        false => targets.join(", "),
+    }
+}
+
+impl<L: Loader> PluginManager<L> {
+    /// Loads the plugin in the bundle at `path`: an uncompressed tar
+    /// archive holding a [`Manifest`], and a folder per platform with the
+    /// library for it, named after the target
+    /// [`bundle_target`](crate::bundle_target) returns.
+    ///
+    /// ```text
+    /// sora-plugin.toml
+    /// x86_64-linux/libhello.so
+    /// x86_64-linux/libhello.so.sig
+    /// aarch64-macos/libhello.dylib
+    /// assets/greeting.txt
+    /// ```
+    ///
+    /// The bundle is extracted into the [cache](Self::set_bundle_cache),
+    /// in a folder named after its SHA-256 digest, unless an earlier load
+    /// already did and the folder still holds exactly the files of the
+    /// bundle. Otherwise it is extracted afresh. The library for the current
+    /// platform is then loaded as [`load_manifest`](Self::load_manifest)
+    /// does, so it is verified against the `sha256` of the manifest and
+    /// against the trusted keys with the `.sig` file beside it.
+    ///
+    /// # Safety
+    ///
+    /// Users of this API must specify the correct type of the function or
+    /// variable loaded.
+    pub unsafe fn load_bundle(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let error = |error| PluginLoadError::Bundle(path.to_owned(), error);
+
+        let bytes = std::fs::read(path).map_err(|e| error(e.into()))?;
+        let digest = hex(&Sha256::digest(&bytes));
+        let dir = self.bundle_cache().map_err(error)?.join(&digest[..16]);
+        let entries = entries(&bytes).map_err(error)?;
+        if !is_extracted(&entries, &dir) {
+            if dir.exists() {
+                std::fs::remove_dir_all(&dir).map_err(|e| error(e.into()))?;
+            }
+            extract(&entries, &dir).map_err(error)?;
+        }
+
+        let target = bundle_target();
+        let library_dir = dir.join(&target);
+        if !library_dir.is_dir() {
+            let mut available: Vec<_> = std::fs::read_dir(&dir)
+                .map_err(|e| error(e.into()))?
+                .filter_map(|entry| {
+                    let entry = entry.ok()?;
+                    let name = entry.file_name().into_string().ok()?;
+                    (entry.path().is_dir() && name.contains('-')).then_some(name)
+                })
+                .collect();
+            available.sort();
+            return Err(error(BundleError::NoArtifact { target, available }));
+        }
+
+        unsafe { self.load_described(&dir.join(Manifest::FILE_NAME), &library_dir) }
+    }
+
+    /// Extracts bundles into `dir` rather than into a folder of the current
+    /// user in the temporary folder, `sora-bundles-{uid}` on Unix. Libraries
+    /// are opened from there, so it must not be on a file system mounted
+    /// `noexec`, and no other user may be able to write to it.
+    pub fn set_bundle_cache(&mut self, dir: impl Into<PathBuf>) {
+        self.bundle_cache = Some(dir.into());
+    }
+
+    fn bundle_cache(&self) -> std::result::Result<PathBuf, BundleError> {
+        match &self.bundle_cache {
+            Some(dir) => Ok(dir.clone()),
+            None => default_cache(),
+        }
+    }
+}
+
+/// Creates the bundle cache of the current user in the temporary folder,
+/// which others can write to, unless it exists. Either way, it must belong
+/// to the user alone, or another user could plant libraries in it.
+#[cfg(unix)]
+fn default_cache() -> std::result::Result<PathBuf, BundleError> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    let uid = unsafe { libc::getuid() };
+    let dir = std::env::temp_dir().join(format!("sora-bundles-{uid}"));
+    match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Err(error) if error.kind() != io::ErrorKind::AlreadyExists => return Err(error.into()),
+        _ => {}
+    }
+
+    let metadata = std::fs::symlink_metadata(&dir)?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(BundleError::InsecureCache(dir));
+    }
+    Ok(dir)
+}
+
+/// The temporary folder is already private to the user elsewhere.
+#[cfg(not(unix))]
+fn default_cache() -> std::result::Result<PathBuf, BundleError> {
+    Ok(std::env::temp_dir().join("sora-bundles"))
+}
+
+/// The folder of a bundle that holds the library for the current platform,
+/// such as `x86_64-linux` or `aarch64-macos`.
+pub fn bundle_target() -> String {
+    format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS)
+}
+
+/// Whether `dir` holds the files among `entries`, and nothing else.
+fn is_extracted(entries: &[Entry<'_>], dir: &Path) -> bool {
+    // A later entry for the same path replaces an earlier one.
+    let files: AHashMap<PathBuf, &[u8]> = entries
+        .iter()
+        .filter_map(|entry| Some((Path::new(&entry.path).components().collect(), entry.data?)))
+        .collect();
+
+    let mut found = 0;
+    let matches = walk(dir, &mut PathBuf::new(), &mut |relative, path| {
+        found += 1;
+        files.get(relative).is_some_and(|&data| std::fs::read(path).is_ok_and(|read| read == data))
+    });
+    matches && found == files.len()
+}
+
+/// Calls `file` with the path relative to `dir` and the full path of every
+/// file under `dir`, until it returns `false`. Returns whether every file
+/// was accepted, and `false` if `dir` holds anything but files and folders.
+fn walk(dir: &Path, relative: &mut PathBuf, file: &mut impl FnMut(&Path, &Path) -> bool) -> bool {
+    let Ok(entries) = std::fs::read_dir(dir) else { return false };
+    for entry in entries {
+        let Ok(entry) = entry else { return false };
+        let Ok(file_type) = entry.file_type() else { return false };
+        relative.push(entry.file_name());
+        let accepted = match () {
+            () if file_type.is_dir() => walk(&entry.path(), relative, file),
+            () if file_type.is_file() => file(relative, &entry.path()),
+            () => false,
+        };
+        relative.pop();
+        if !accepted {
+            return false;
+        }
+    }
+    true
+}
+
+/// Extracts the tar archive of `entries` into `dir`, which must not exist.
+/// The archive is extracted next to it first, so that `dir` never holds
+/// part of it.
+fn extract(entries: &[Entry<'_>], dir: &Path) -> std::result::Result<(), BundleError> {
+    let mut partial = dir.as_os_str().to_owned();
+    partial.push(format!(".partial-{}", std::process::id()));
+    let partial = PathBuf::from(partial);
+    let _ = std::fs::remove_dir_all(&partial);
+    std::fs::create_dir_all(&partial)?;
+
+    for entry in entries {
+        let path = partial.join(&entry.path);
+        match entry.data {
+            None => std::fs::create_dir_all(path)?,
+            Some(data) => {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(path, data)?;
+            }
+        }
+    }
+
+    if let Err(error) = std::fs::rename(&partial, dir) {
+        std::fs::remove_dir_all(&partial)?;
+        // Another process extracted the same bundle meanwhile.
+        if !dir.is_dir() {
+            return Err(error.into());
+        }
+    }
+
+    Ok(())
+}
+
+/// An entry of a tar archive.
+struct Entry<'a> {
+    path: String,
+    /// The contents of a file, or `None` for a folder.
+    data: Option<&'a [u8]>,
+}
+
+/// The entries of the ustar archive in `bytes`, in order.
+fn entries(mut bytes: &[u8]) -> std::result::Result<Vec<Entry<'_>>, BundleError> {
+    const BLOCK: usize = 512;
+
+    let mut entries = Vec::new();
+    loop {
+        let header = bytes.get(..BLOCK).ok_or(BundleError::Corrupt)?;
+        if header.iter().all(|&byte| byte == 0) {
+            return Ok(entries);
+        }
+
+        let checksum = octal(&header[148..156]).ok_or(BundleError::Corrupt)?;
+        let sum: usize = header
+            .iter()
+            .enumerate()
+            .map(|(index, &byte)| if (148..156).contains(&index) { b' ' } else { byte })
+            .map(usize::from)
+            .sum();
+        if sum != checksum {
+            return Err(BundleError::Corrupt);
+        }
+
+        let name = string(&header[..100])?;
+        let path = match &header[257..262] == b"ustar" {
+            true => match string(&header[345..500])? {
+                "" => name.to_owned(),
+                prefix => format!("{prefix}/{name}"),
+            },
+            false => name.to_owned(),
+        };
+        let is_safe = Path::new(&path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if !is_safe {
+            return Err(BundleError::UnsafePath(path));
+        }
+        // Archives made with `tar -C dir -cf bundle.sora .` start every path
+        // with `./`.
+        let path = path.split('/').filter(|part| !matches!(*part, "" | ".")).collect::<Vec<_>>();
+        let path = path.join("/");
+
+        let size = octal(&header[124..136]).ok_or(BundleError::Corrupt)?;
+        let data = bytes.get(BLOCK..BLOCK.checked_add(size).ok_or(BundleError::Corrupt)?);
+        let data = data.ok_or(BundleError::Corrupt)?;
+        match header[156] {
+            b'0' | b'\0' => entries.push(Entry { path, data: Some(data) }),
+            b'5' => entries.push(Entry { path, data: None }),
+            _ => return Err(BundleError::UnsupportedEntry(path)),
+        }
+
+        let next = BLOCK + size.next_multiple_of(BLOCK);
+        bytes = bytes.get(next..).ok_or(BundleError::Corrupt)?;
+    }
+}
+
+/// A NUL-terminated field of a header.
+fn string(field: &[u8]) -> std::result::Result<&str, BundleError> {
+    let end = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    std::str::from_utf8(&field[..end]).map_err(|_| BundleError::Corrupt)
+}
+
+/// A number of a header, in octal, padded with spaces or NUL bytes.
+fn octal(field: &[u8]) -> Option<usize> {
+    let digits = std::str::from_utf8(field).ok()?.trim_matches([' ', '\0']);
+    usize::from_str_radix(digits, 8).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BundleError;
+
+    /// A ustar archive of `files`.
+    pub(crate) fn tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = Vec::new();
+        for (name, data) in files {
+            let mut header = [0; 512];
+            header[..name.len()].copy_from_slice(name.as_bytes());
+            header[100..107].copy_from_slice(b"0000644");
+            header[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+            header[148..156].fill(b' ');
+            header[156] = b'0';
+            header[257..263].copy_from_slice(b"ustar\0");
+            header[263..265].copy_from_slice(b"00");
+            let sum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+            header[148..155].copy_from_slice(format!("{sum:06o}\0").as_bytes());
+
+            tar.extend(header);
+            tar.extend(*data);
+            tar.resize(tar.len().next_multiple_of(512), 0);
+        }
+        tar.resize(tar.len() + 1024, 0);
+        tar
+    }
+
+    #[test]
+    fn entries() {
+        let archive = tar(&[("sora-plugin.toml", b"name = \"A\"\n"), ("assets/a.txt", b"")]);
+        let entries = super::entries(&archive).unwrap();
+        let paths: Vec<_> = entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["sora-plugin.toml", "assets/a.txt"]);
+        assert_eq!(entries[0].data, Some(&b"name = \"A\"\n"[..]));
+
+        let error = super::entries(&tar(&[("../escape", b"")])).err().unwrap();
+        assert!(matches!(error, BundleError::UnsafePath(path) if path == "../escape"));
+
+        let mut corrupt = archive.clone();
+        corrupt[0] = b'x';
+        assert!(matches!(super::entries(&corrupt), Err(BundleError::Corrupt)));
+        assert!(matches!(super::entries(&archive[..600]), Err(BundleError::Corrupt)));
+    }
+
+    #[test]
+    fn current_dir() {
+        let archive = tar(&[("./sora-plugin.toml", b"name = \"A\"\n"), ("./assets/a.txt", b"")]);
+        let entries = super::entries(&archive).unwrap();
+        let paths: Vec<_> = entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["sora-plugin.toml", "assets/a.txt"]);
+
+        let dir = std::env::temp_dir().join(format!("sora-current-dir-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        super::extract(&entries, &dir).unwrap();
+        assert!(super::is_extracted(&entries, &dir));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn default_cache() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = super::default_cache().unwrap();
+        let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    #[test]
+    fn load_bundle() {
+        let cache = std::env::temp_dir().join(format!("sora-bundles-{}", std::process::id()));
+        let path = cache.join("empty.sora");
+        std::fs::create_dir_all(&cache).unwrap();
+        let manifest = b"name = \"A\"\nversion = \"1.0.0\"\n";
+        let archive = tar(&[("sora-plugin.toml", manifest), ("riscv64-plan9/libhello.so", b"")]);
+        std::fs::write(&path, archive).unwrap();
+
+        let mut manager = crate::PluginManager::new();
+        manager.set_bundle_cache(&cache);
+        let error = unsafe { manager.load_bundle(&path) }.unwrap_err();
+
+        // A tampered extraction is replaced on the next load.
+        let extracted = std::fs::read_dir(&cache)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.is_dir())
+            .unwrap();
+        std::fs::write(extracted.join("sora-plugin.toml"), "name = \"B\"\n").unwrap();
+        std::fs::create_dir(extracted.join(super::bundle_target())).unwrap();
+        unsafe { manager.load_bundle(&path) }.unwrap_err();
+        let restored = std::fs::read(extracted.join("sora-plugin.toml")).unwrap();
+        let planted = extracted.join(super::bundle_target()).exists();
+        std::fs::remove_dir_all(&cache).unwrap();
+        assert_eq!(restored, manifest);
+        assert!(!planted);
+
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "invalid plugin bundle {}: no library for {}; the bundle has riscv64-plan9",
+                path.display(),
+                super::bundle_target()
+            )
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use std::ffi::{CStr, OsStr, c_char, c_void};
+use std::path::Path;
+
+use libloading::Library;
+
+use crate::{HostApi, LoadResult, Loader, Plugin, PluginLoadError, RunContext, host, native};
+
+/// The functions a plugin written against the C ABI provides. Every
+/// function receives the `data` pointer of the [`RawPlugin`] it belongs to.
+///
+/// ```c
+/// struct sora_plugin_vtable {
+///     const char *(*name)(void *data);
+///     size_t (*deps_count)(void *data);
+///     const char *(*deps_at)(void *data, size_t index);
+///     void (*run)(void *data);
+///     void (*destroy)(void *data);
+/// };
+/// ```
+///
+/// Strings must be UTF-8, NUL-terminated, and live as long as the plugin.
+/// `run` may be called from several threads at once. `destroy` may be null
+/// if the plugin owns nothing.
+#[repr(C)]
+pub struct PluginVTable {
+    pub name: unsafe extern "C" fn(*mut c_void) -> *const c_char,
+    pub deps_count: unsafe extern "C" fn(*mut c_void) -> usize,
+    pub deps_at: unsafe extern "C" fn(*mut c_void, usize) -> *const c_char,
+    pub run: unsafe extern "C" fn(*mut c_void),
+    pub destroy: Option<unsafe extern "C" fn(*mut c_void)>,
+}
+
+/// A plugin instance returned by the entry point of a C ABI library.
+///
+/// ```c
+/// struct sora_plugin {
+///     void *data;
+///     const struct sora_plugin_vtable *vtable;
+/// };
+///
+/// struct sora_plugin sora_create_plugin(const struct sora_host_api *host);
+/// ```
+///
+/// `host` is described by [`HostApi`](crate::HostApi). A null `vtable`
+/// means the plugin could not be created.
+#[repr(C)]
+pub struct RawPlugin {
+    pub data: *mut c_void,
+    pub vtable: *const PluginVTable,
+}
+
+type CreatePluginFn = unsafe extern "C" fn(&'static HostApi) -> RawPlugin;
+
+/// Loads plugins that implement the C ABI described by [`PluginVTable`], so
+/// that they can be written in C, C++, Zig, or any other language that can
+/// export C functions.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CAbi;
+
+impl CAbi {
+    /// The entry points tried by [`CAbi::load`], in order.
+    pub const DEFAULT_ENTRY_POINTS: &'static [&'static str] = &["sora_create_plugin"];
+}
+
+impl Loader for CAbi {
+    type Library = Library;
+    type Error = PluginLoadError;
+
+    fn check_file(&self, path: &Path) -> Result<(), PluginLoadError> {
+        native::check_file(path)
+    }
+
+    unsafe fn load(&self, filename: impl AsRef<OsStr>) -> LoadResult<Self> {
+        self.load_entries(filename, Self::DEFAULT_ENTRY_POINTS)
+    }
+
+    unsafe fn load_entry(&self, filename: impl AsRef<OsStr>, entry: &str) -> LoadResult<Self> {
+        self.load_entries(filename, &[entry])
+    }
+
+    unsafe fn load_entries(
+        &self,
+        filename: impl AsRef<OsStr>,
+        entries: &[&str],
+    ) -> LoadResult<Self> {
+        let entries = match entries {
+            [] => Self::DEFAULT_ENTRY_POINTS,
+            entries => entries,
+        };
+
+        let path = Path::new(filename.as_ref());
+        let library = native::open(path)?;
+        let mut error = None;
+        for entry in entries {
+            match unsafe { library.get::<CreatePluginFn>(entry.as_bytes()) } {
+                Ok(create_plugin) => {
+                    let plugin = unsafe { CPlugin::new(create_plugin(host::current())) }.map_err(
+                        |(plugin, message)| PluginLoadError::Abi {
+                            path: path.to_owned(),
+                            plugin: plugin.map(str::to_owned),
+                            message,
+                        },
+                    )?;
+                    return Ok((library, Box::new(plugin)));
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+
+        Err(native::symbol_error(path, entries, error.unwrap()))
+    }
+}
+
+/// Adapts a [`RawPlugin`] to the [`Plugin`] trait.
+///
+/// The strings point into the plugin, so they are only handed out borrowed
+/// from it.
+struct CPlugin {
+    raw: RawPlugin,
+    name: &'static str,
+    dependencies: Box<[&'static str]>,
+}
+
+// SAFETY: the C ABI requires plugins to be thread-safe.
+unsafe impl Send for CPlugin {}
+unsafe impl Sync for CPlugin {}
+
+impl CPlugin {
+    /// Takes ownership of `raw` and reads its name and dependencies. Fails
+    /// with the name, if it could be read, and what is wrong.
+    unsafe fn new(
+        raw: RawPlugin,
+    ) -> std::result::Result<Self, (Option<&'static str>, &'static str)> {
+        let Some(vtable) = (unsafe { raw.vtable.as_ref() }) else {
+            return Err((None, "plugin could not be created"));
+        };
+
+        // Destroys `raw` if reading the strings fails.
+        let mut plugin = Self { raw, name: "", dependencies: Box::default() };
+
+        plugin.name = unsafe { c_str((vtable.name)(plugin.raw.data)) }
+            .ok_or((None, "name is not a valid UTF-8 string"))?;
+        plugin.dependencies = (0..unsafe { (vtable.deps_count)(plugin.raw.data) })
+            .map(|index| unsafe { c_str((vtable.deps_at)(plugin.raw.data, index)) })
+            .collect::<Option<_>>()
+            .ok_or((Some(plugin.name), "dependency is not a valid UTF-8 string"))?;
+
+        Ok(plugin)
+    }
+
+    fn vtable(&self) -> &PluginVTable {
+        unsafe { &*self.raw.vtable }
+    }
+}
+
+impl Plugin for CPlugin {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn dependencies(&self) -> &[&str] {
+        &self.dependencies
+    }
+
+    fn run(&self, _: &RunContext) {
+        unsafe { (self.vtable().run)(self.raw.data) };
+    }
+}
+
+impl Drop for CPlugin {
+    fn drop(&mut self) {
+        if let Some(destroy) = self.vtable().destroy {
+            unsafe { destroy(self.raw.data) };
+        }
+    }
+}
+
+unsafe fn c_str(ptr: *const c_char) -> Option<&'static str> {
+    match ptr.is_null() {
+        true => None,
+        false => unsafe { CStr::from_ptr(ptr) }.to_str().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{c_char, c_void};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::{CPlugin, PluginVTable, RawPlugin};
+    use crate::{Plugin, RunContext};
+
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    static DESTROYED: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "C" fn name(_: *mut c_void) -> *const c_char {
+        c"Greeter".as_ptr()
+    }
+
+    unsafe extern "C" fn invalid_name(_: *mut c_void) -> *const c_char {
+        c"\xff".as_ptr()
+    }
+
+    unsafe extern "C" fn deps_count(_: *mut c_void) -> usize {
+        2
+    }
+
+    unsafe extern "C" fn deps_at(_: *mut c_void, index: usize) -> *const c_char {
+        [c"A".as_ptr(), c"B".as_ptr()][index]
+    }
+
+    unsafe extern "C" fn run(data: *mut c_void) {
+        RUNS.fetch_add(data as usize, Ordering::SeqCst);
+    }
+
+    unsafe extern "C" fn destroy(_: *mut c_void) {
+        DESTROYED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn c_plugin() {
+        static VTABLE: PluginVTable =
+            PluginVTable { name, deps_count, deps_at, run, destroy: Some(destroy) };
+        static INVALID: PluginVTable =
+            PluginVTable { name: invalid_name, deps_count, deps_at, run, destroy: Some(destroy) };
+
+        let plugin = unsafe { CPlugin::new(RawPlugin { data: 3 as _, vtable: &VTABLE }) }.unwrap();
+        assert_eq!(plugin.name(), "Greeter");
+        assert_eq!(plugin.dependencies(), ["A", "B"]);
+
+        plugin.run(&RunContext::detached());
+        assert_eq!(RUNS.load(Ordering::SeqCst), 3);
+
+        drop(plugin);
+        assert_eq!(DESTROYED.load(Ordering::SeqCst), 1);
+
+        let error = unsafe { CPlugin::new(RawPlugin { data: 0 as _, vtable: &INVALID }) };
+        assert!(matches!(error, Err((None, "name is not a valid UTF-8 string"))));
+        assert_eq!(DESTROYED.load(Ordering::SeqCst), 2);
+
+        let error = unsafe { CPlugin::new(RawPlugin { data: 0 as _, vtable: std::ptr::null() }) };
+        assert!(matches!(error, Err((None, "plugin could not be created"))));
+    }
+}
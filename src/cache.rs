@@ -0,0 +1,144 @@
+//! Schedules saved between runs, so that a host that starts with the same
+//! plugins skips scheduling them. See [`DispatcherBuilder::plan_cache`].
+//!
+//! [`DispatcherBuilder::plan_cache`]: crate::DispatcherBuilder::plan_cache
+
+use std::fmt::Write as _;
+use std::path::Path;
+
+use crate::schedule::Schedule;
+use crate::sha2::Sha256;
+use crate::{Plugin, hex, memory};
+
+/// The first line of a cache file, changed whenever its format or the way
+/// plugins are scheduled changes.
+const HEADER: &str = concat!("sora plan cache 2 ", env!("CARGO_PKG_VERSION"));
+
+/// Digests everything the schedules of `plugins`, in slot order, depend
+/// on. `kept` tells whether a dependency is waited for.
+pub(crate) fn key<'a>(
+    plugins: impl IntoIterator<Item = &'a dyn Plugin>,
+    kept: impl Fn(&str) -> bool,
+) -> [u8; 32] {
+    let mut key = String::new();
+    for plugin in plugins {
+        let _ = write!(key, "{:?} {:?}", plugin.name(), plugin.version());
+        for dependency in plugin.dependencies() {
+            let _ = write!(key, " {dependency:?}{}", if kept(dependency) { "" } else { "?" });
+        }
+        let _ = writeln!(key, " {:?} {:?} {:?}", plugin.reads(), plugin.writes(), plugin.phases());
+    }
+
+    Sha256::digest(key.as_bytes())
+}
+
+/// The stages of the schedules saved in `path` under `key`, if any, for as
+/// many plugins as `slots`. They are only as trustworthy as the file, so
+/// they must be checked against the dependencies before they are used.
+pub(crate) fn read<const N: usize>(
+    path: &Path,
+    key: &[u8; 32],
+    slots: usize,
+) -> Option<[Vec<Vec<usize>>; N]> {
+    let source = std::fs::read_to_string(path).ok()?;
+    let mut lines = source.lines();
+    if lines.next()? != HEADER || lines.next()? != hex(key) {
+        return None;
+    }
+
+    let schedules: Vec<_> = lines.map(|line| parse(line, slots)).collect::<Option<_>>()?;
+    schedules.try_into().ok()
+}
+
+/// Saves the stages of `schedules` in `path` under `key`. A cache that
+/// cannot be written is only slower, so errors are ignored.
+pub(crate) fn write(path: &Path, key: &[u8; 32], schedules: &[&Schedule]) {
+    let mut cache = format!("{HEADER}\n{}\n", hex(key));
+    for schedule in schedules {
+        let stages: Vec<_> = schedule.stages().iter().map(|stage| join(stage, ",")).collect();
+        let _ = writeln!(cache, "{}", stages.join(";"));
+    }
+
+    // A host that starts at the same time never reads half a cache, and
+    // hosts that write it at the same time each write their own file.
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".{}-{}.tmp", std::process::id(), memory::next()));
+    let written =
+        std::fs::write(&temporary, cache).and_then(|()| std::fs::rename(&temporary, path));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temporary);
+    }
+}
+
+/// Parses the stages written by [`write`], checking that they refer to no
+/// slot past `slots`.
+fn parse(line: &str, slots: usize) -> Option<Vec<Vec<usize>>> {
+    let slot = |slot: &str| slot.parse().ok().filter(|&slot| slot < slots);
+    split(line, ';').map(|stage| split(stage, ',').map(slot).collect()).collect()
+}
+
+/// Splits `list` at `separator`, with no items if it is empty.
+fn split(list: &str, separator: char) -> impl Iterator<Item = &str> {
+    list.split(separator).filter(move |_| !list.is_empty())
+}
+
+fn join(slots: &[usize], separator: &str) -> String {
+    slots.iter().map(usize::to_string).collect::<Vec<_>>().join(separator)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PluginManager;
+    use crate::fixture::Stub;
+
+    fn build(path: &std::path::Path, steps: Vec<Stub>) -> Vec<Vec<String>> {
+        let mut manager = PluginManager::new();
+        for step in steps {
+            manager.register(Box::new(step)).unwrap();
+        }
+        let dispatcher = manager.into_dispatcher_builder().plan_cache(path).build();
+        let stages = dispatcher.plan().stages;
+        stages
+            .iter()
+            .map(|stage| {
+                let mut names: Vec<_> = stage.iter().map(|plugin| plugin.name.clone()).collect();
+                names.sort();
+                names
+            })
+            .collect()
+    }
+
+    #[test]
+    fn plan_cache() {
+        let path = std::env::temp_dir().join(format!("sora-plan-{}", std::process::id()));
+        let steps = || vec![Stub::new("A", &[]), Stub::new("B", &["A"]), Stub::new("C", &["A"])];
+
+        let stages = build(&path, steps());
+        assert_eq!(stages, [vec!["A"], vec!["B", "C"]]);
+        let cache = std::fs::read_to_string(&path).unwrap();
+        let plan = cache.lines().nth(2).unwrap();
+        assert!(matches!(plan, "0;1,2" | "0;2,1"), "{plan}");
+
+        // A cached plan is used as is, even if it differs from the one that
+        // would be computed, as long as the dependencies allow it.
+        let tampered = cache.replace(plan, "0;1;2");
+        std::fs::write(&path, &tampered).unwrap();
+        assert_eq!(build(&path, steps()), [vec!["A"], vec!["B"], vec!["C"]]);
+
+        // Other plugins, or a corrupt cache, are scheduled afresh.
+        let stages = build(&path, vec![Stub::new("A", &[]), Stub::new("B", &[])]);
+        assert_eq!(stages, [vec!["A", "B"]]);
+        for corrupt in ["0;1;9", "0;1;1", "0;1", "1;0;2"] {
+            std::fs::write(&path, tampered.replace("0;1;2", corrupt)).unwrap();
+            assert_eq!(build(&path, steps()), [vec!["A"], vec!["B", "C"]], "{corrupt}");
+            assert_ne!(std::fs::read_to_string(&path).unwrap(), tampered.replace("0;1;2", corrupt));
+        }
+
+        // Every write went through a file of its own, renamed into place.
+        let temporary = format!("sora-plan-{}.", std::process::id());
+        let mut entries = std::fs::read_dir(std::env::temp_dir()).unwrap().map(Result::unwrap);
+        assert!(!entries.any(|entry| entry.file_name().to_string_lossy().starts_with(&temporary)));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+//! State that plugins change while they run.
+
+use std::fmt;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// A value that a plugin can change from [`Plugin::run`](crate::Plugin::run),
+/// which only gets `&self` since the plugins of a stage may run on several
+/// threads at once.
+///
+/// Every access locks the value. A plugin that panics while changing it
+/// does not poison it, as its next run or retry should still get to it, so
+/// changes must leave the value consistent at every point where they may
+/// panic.
+///
+/// ```
+/// use sora::{Plugin, PluginCell, RunContext};
+///
+/// #[derive(Default)]
+/// struct Frames {
+///     count: PluginCell<u64>,
+/// }
+///
+/// impl Plugin for Frames {
+///     fn run(&self, context: &RunContext) {
+///         let count = self.count.with(|count| {
+///             *count += 1;
+///             *count
+///         });
+///         context.output(count);
+///     }
+/// }
+/// ```
+#[derive(Default)]
+pub struct PluginCell<T> {
+    value: Mutex<T>,
+}
+
+impl<T> PluginCell<T> {
+    pub const fn new(value: T) -> Self {
+        Self { value: Mutex::new(value) }
+    }
+
+    /// Calls `f` with the value, which no other thread can access until it
+    /// returns. `f` must not access the cell again, or it deadlocks.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock())
+    }
+
+    pub fn set(&self, value: T) {
+        *self.lock() = value;
+    }
+
+    pub fn replace(&self, value: T) -> T {
+        std::mem::replace(&mut self.lock(), value)
+    }
+
+    /// The value, without locking, for use in
+    /// [`Plugin::run_mut`](crate::Plugin::run_mut) and
+    /// [`Plugin::init`](crate::Plugin::init).
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, T> {
+        self.value.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T: Clone> PluginCell<T> {
+    pub fn get(&self) -> T {
+        self.lock().clone()
+    }
+}
+
+impl<T> From<T> for PluginCell<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for PluginCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PluginCell").field(&*self.lock()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PluginCell;
+    use crate::{Plugin, PluginManager, RunContext};
+
+    struct Counter {
+        count: PluginCell<usize>,
+    }
+
+    impl Plugin for Counter {
+        fn run(&self, context: &RunContext) {
+            context.output(self.count.with(|count| {
+                *count += 1;
+                *count
+            }));
+        }
+    }
+
+    #[test]
+    fn plugin_cell() {
+        let cell = PluginCell::new(vec![1]);
+        cell.with(|values| values.push(2));
+        assert_eq!(cell.get(), [1, 2]);
+        assert_eq!(cell.replace(vec![3]), [1, 2]);
+
+        // A panic leaves the value as it was when it happened.
+        let panicked = std::panic::catch_unwind(|| {
+            cell.with(|values| {
+                values.push(4);
+                panic!("interrupted");
+            })
+        });
+        assert!(panicked.is_err());
+        assert_eq!(format!("{cell:?}"), "PluginCell([3, 4])");
+        cell.set(Vec::new());
+        assert_eq!(cell.into_inner(), Vec::<i32>::new());
+
+        let mut manager = PluginManager::new();
+        manager.register(Box::new(Counter { count: 0.into() })).unwrap();
+        let dispatcher = manager.into_dispatcher();
+        for _ in 0..3 {
+            dispatcher.dispatch_par();
+        }
+        assert_eq!(dispatcher.output::<usize>("Counter").as_deref(), Some(&3));
+    }
+}
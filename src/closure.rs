@@ -0,0 +1,73 @@
+//! Plugins made of a closure, for plugins too small to deserve a type.
+
+use crate::{Loader, Plugin, PluginManager, Result, RunContext};
+
+/// A plugin that runs a closure. See [`PluginManager::register_fn`].
+pub struct FnPlugin<F> {
+    name: String,
+    dependencies: &'static [&'static str],
+    run: F,
+}
+
+impl<F: Fn(&RunContext) + Send + Sync + 'static> FnPlugin<F> {
+    pub fn new(name: impl Into<String>, dependencies: &'static [&'static str], run: F) -> Self {
+        Self { name: name.into(), dependencies, run }
+    }
+}
+
+impl<F: Fn(&RunContext) + Send + Sync + 'static> Plugin for FnPlugin<F> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn dependencies(&self) -> &[&str] {
+        self.dependencies
+    }
+
+    fn run(&self, context: &RunContext) {
+        (self.run)(context)
+    }
+}
+
+impl<L: Loader> PluginManager<L> {
+    /// Registers a plugin called `name` that runs `run` after
+    /// `dependencies`, as [`register`](Self::register) does.
+    ///
+    /// ```ignore
+    /// manager.register_fn("Log", &["Physics"], |context| {
+    ///     println!("{:?}", context.input::<f32>("Physics"));
+    /// })?;
+    /// ```
+    pub fn register_fn(
+        &mut self,
+        name: impl Into<String>,
+        dependencies: &'static [&'static str],
+        run: impl Fn(&RunContext) + Send + Sync + 'static,
+    ) -> Result<()> {
+        self.register(Box::new(FnPlugin::new(name, dependencies, run)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::PluginManager;
+
+    #[test]
+    fn register_fn() {
+        let mut manager = PluginManager::new();
+        manager
+            .register_fn("Render", &["Physics"], |context| {
+                let position = context.input::<f32>("Physics").unwrap();
+                context.output(*position * 2.0);
+            })
+            .unwrap();
+        manager.register_fn("Physics", &[], |context| context.output(1.5f32)).unwrap();
+        assert!(manager.register_fn("Physics", &[], |_| {}).is_err());
+
+        let dispatcher = manager.into_dispatcher();
+        dispatcher.dispatch();
+        assert_eq!(dispatcher.output::<f32>("Render"), Some(Arc::new(3.0)));
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::Environment;
+use crate::toml::{self, Table, Value};
+
+/// Settings for running a set of plugins, read from a `sora.toml` file.
+///
+/// ```toml
+/// directories = ["plugins", "vendor/plugins"]
+/// enabled = ["Hello", "Physics"]
+/// parallel = true
+/// threads = 4
+///
+/// [plugins.Physics]
+/// gravity = -10
+/// ```
+///
+/// Relative directories are resolved against the directory containing the
+/// file. When `enabled` is omitted, every plugin runs. `threads` only has an
+/// effect together with `parallel`. The `[plugins.<name>]` tables reach the
+/// plugins through [`plugin_environment`](Self::plugin_environment).
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Config {
+    pub directories: Vec<PathBuf>,
+    pub enabled: Option<Vec<String>>,
+    pub parallel: bool,
+    pub threads: Option<usize>,
+    /// The `[plugins.<name>]` tables, keyed by plugin name.
+    pub plugins: BTreeMap<String, Table>,
+}
+
+impl Config {
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        let mut config = Self::parse(&source)?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        for directory in &mut config.directories {
+            *directory = base.join(&*directory);
+        }
+
+        Ok(config)
+    }
+
+    /// The settings of the `[plugins.<name>]` tables, keyed
+    /// `plugins.<name>.<key>`, for plugins to read through
+    /// [`RunContext::environment`](crate::RunContext::environment).
+    ///
+    /// Strings are passed as they are and other values as they are written
+    /// in TOML. The keys of nested tables are joined with `.` too.
+    pub fn plugin_environment(&self) -> Environment {
+        let mut environment = Environment::new();
+        for (name, settings) in &self.plugins {
+            insert_settings(&mut environment, &format!("plugins.{name}"), settings);
+        }
+        environment
+    }
+
+    pub fn parse(source: &str) -> Result<Self, ConfigError> {
+        let mut table = toml::parse(source)
+            .map_err(|error| ConfigError::Syntax { line: error.line, message: error.message })?;
+
+        let config = Self {
+            directories: take_strings(&mut table, "directories")?
+                .unwrap_or_default()
+                .into_iter()
+                .map(PathBuf::from)
+                .collect(),
+            enabled: take_strings(&mut table, "enabled")?,
+            parallel: match table.remove("parallel") {
+                None => false,
+                Some(Value::Boolean(parallel)) => parallel,
+                Some(value) => return Err(type_mismatch("parallel", "boolean", &value)),
+            },
+            threads: match table.remove("threads") {
+                None => None,
+                Some(Value::Integer(threads)) => {
+                    Some(threads.try_into().map_err(|_| ConfigError::InvalidField {
+                        field: "threads",
+                        message: "must not be negative".to_owned(),
+                    })?)
+                }
+                Some(value) => return Err(type_mismatch("threads", "integer", &value)),
+            },
+            plugins: match table.remove("plugins") {
+                None => BTreeMap::new(),
+                Some(Value::Table(plugins)) => plugins
+                    .into_iter()
+                    .map(|(name, settings)| match settings {
+                        Value::Table(settings) => Ok((name, settings)),
+                        value => Err(type_mismatch("plugins", "table of tables", &value)),
+                    })
+                    .collect::<Result<_, _>>()?,
+                Some(value) => return Err(type_mismatch("plugins", "table", &value)),
+            },
+        };
+
+        if let Some(key) = table.into_keys().next() {
+            return Err(ConfigError::UnknownField(key));
+        }
+
+        Ok(config)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("cannot read config: {0}")]
+    Io(std::io::Error),
+    #[error("line {line}: {message}")]
+    Syntax { line: usize, message: String },
+    #[error("unknown field `{0}`")]
+    UnknownField(String),
+    #[error("field `{field}` {message}")]
+    InvalidField { field: &'static str, message: String },
+}
+
+fn take_strings(
+    table: &mut Table,
+    field: &'static str,
+) -> Result<Option<Vec<String>>, ConfigError> {
+    match table.remove(field) {
+        None => Ok(None),
+        Some(Value::Array(values)) => values
+            .into_iter()
+            .map(|value| match value {
+                Value::String(string) => Ok(string),
+                value => Err(type_mismatch(field, "array of strings", &value)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Some),
+        Some(value) => Err(type_mismatch(field, "array of strings", &value)),
+    }
+}
+
+fn insert_settings(environment: &mut Environment, prefix: &str, settings: &Table) {
+    for (key, value) in settings {
+        let key = format!("{prefix}.{key}");
+        match value {
+            Value::Table(settings) => insert_settings(environment, &key, settings),
+            Value::String(string) => _ = environment.insert(key, string.as_str()),
+            value => _ = environment.insert(key, render(value)),
+        }
+    }
+}
+
+/// `value` as it is written in TOML.
+fn render(value: &Value) -> String {
+    match value {
+        Value::String(string) => format!("{string:?}"),
+        Value::Integer(integer) => integer.to_string(),
+        Value::Boolean(boolean) => boolean.to_string(),
+        Value::Array(values) => {
+            format!("[{}]", values.iter().map(render).collect::<Vec<_>>().join(", "))
+        }
+        Value::Table(table) => {
+            let entries: Vec<_> =
+                table.iter().map(|(key, value)| format!("{key} = {}", render(value))).collect();
+            format!("{{ {} }}", entries.join(", "))
+        }
+    }
+}
+
+fn type_mismatch(field: &'static str, expected: &str, found: &Value) -> ConfigError {
+    ConfigError::InvalidField {
+        field,
+        message: format!("must be a {expected}, found {}", found.type_name()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::Config;
+    use crate::toml::Value;
+
+    #[test]
+    fn parse() {
+        let config = Config::parse(
+            r#"
+            directories = ["plugins"]
+            enabled = ["Hello"]
+            threads = 2
+
+            [plugins.Hello]
+            greeting = "hi"
+            repeat = 2
+            names = ["a", "b"]
+            [plugins.Hello.window]
+            fullscreen = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.directories, [PathBuf::from("plugins")]);
+        assert_eq!(config.enabled, Some(vec!["Hello".to_owned()]));
+        assert!(!config.parallel);
+        assert_eq!(config.threads, Some(2));
+        assert_eq!(config.plugins["Hello"]["greeting"], Value::String("hi".into()));
+
+        let environment = config.plugin_environment();
+        assert_eq!(environment.get("plugins.Hello.greeting"), Some("hi"));
+        assert_eq!(environment.get("plugins.Hello.repeat"), Some("2"));
+        assert_eq!(environment.get("plugins.Hello.names"), Some(r#"["a", "b"]"#));
+        assert_eq!(environment.get("plugins.Hello.window.fullscreen"), Some("true"));
+
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn invalid() {
+        let error = |source| Config::parse(source).unwrap_err().to_string();
+
+        assert_eq!(error("threads = -1"), "field `threads` must not be negative");
+        assert_eq!(error("parallel = 1"), "field `parallel` must be a boolean, found integer");
+        assert_eq!(error("plugins = 1"), "field `plugins` must be a table, found integer");
+        assert_eq!(error("plugin = 1"), "unknown field `plugin`");
+    }
+}
@@ -0,0 +1,348 @@
+//! State shared with plugins while they run.
+
+use std::any::Any;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use ahash::AHashMap;
+
+use crate::watchdog::{Watch, Watchdog};
+use crate::{Environment, LocalPlugin, Plugin, PluginResult, json};
+
+type Output = Arc<dyn Any + Send + Sync>;
+
+/// Passed to [`Plugin::run`](crate::Plugin::run) by the dispatcher that runs
+/// the plugin.
+///
+/// Besides cancellation, it carries values between plugins: a plugin
+/// publishes its [`output`](Self::output), and the plugins that depend on it
+/// read it as an [`input`](Self::input). Outputs are cleared at the start
+/// of every dispatch.
+///
+/// Plugins also share a blackboard of values by key, which persists across
+/// dispatches. A plugin may only [`read`](Self::read) the keys it declares
+/// in [`Plugin::reads`](crate::Plugin::reads) or
+/// [`Plugin::writes`](crate::Plugin::writes), and only
+/// [`write`](Self::write) the latter.
+///
+/// Settings from the host, such as its version, are in the
+/// [`environment`](Self::environment).
+pub struct RunContext<'a> {
+    plugin: &'a str,
+    dependencies: &'a [&'a str],
+    reads: &'a [&'a str],
+    writes: &'a [&'a str],
+    state: Option<&'a DispatchState>,
+}
+
+impl RunContext<'static> {
+    /// A context that is never cancelled and has no inputs, for running a
+    /// plugin outside of a dispatcher, such as in its tests. Outputs are
+    /// discarded.
+    pub fn detached() -> Self {
+        Self { plugin: "", dependencies: &[], reads: &[], writes: &[], state: None }
+    }
+}
+
+impl RunContext<'_> {
+    /// Whether the host asked plugins to stop, for example because it is
+    /// shutting down. Long-running plugins should check it periodically and
+    /// return early once it is set.
+    pub fn is_cancelled(&self) -> bool {
+        self.state.is_some_and(|state| state.cancelled.load(Ordering::Relaxed))
+    }
+
+    /// The settings the host gave the dispatcher. Empty when detached.
+    pub fn environment(&self) -> &Environment {
+        match self.state {
+            Some(state) => &state.environment,
+            None => const { &Environment::new() },
+        }
+    }
+
+    /// The arguments the host passes to every plugin, such as those after
+    /// `--` on the command line of `sora run`. Empty when detached.
+    pub fn args(&self) -> &[String] {
+        self.state.map_or(&[], |state| &state.args)
+    }
+
+    /// The output of `dependency` in this dispatch, if it published a `T`.
+    ///
+    /// # Panics
+    ///
+    /// If `dependency` is not one of the plugin's dependencies, as it would
+    /// not be guaranteed to have run.
+    pub fn input<T: Any + Send + Sync>(&self, dependency: &str) -> Option<Arc<T>> {
+        assert!(
+            self.dependencies.contains(&dependency),
+            "`{}` reads the output of `{dependency}`, which it does not depend on",
+            self.plugin,
+        );
+
+        self.state?.output(dependency)
+    }
+
+    /// Publishes `value` as the plugin's output, replacing an earlier one.
+    pub fn output<T: Any + Send + Sync>(&self, value: T) {
+        if let Some(state) = self.state {
+            state.outputs.write().unwrap().insert(self.plugin.to_owned(), Arc::new(value));
+        }
+    }
+
+    /// Sets the machine-readable result of this run, such as the findings of
+    /// a scan, replacing an earlier one. Reporting dispatches collect it in
+    /// [`PluginReport::result`](crate::PluginReport::result).
+    ///
+    /// # Panics
+    ///
+    /// If `result` is JSON that does not parse.
+    pub fn set_result(&self, result: PluginResult) {
+        if let PluginResult::Json(json) = &result {
+            if let Err(error) = json::parse(json) {
+                panic!(
+                    "`{}` sets a result that is not JSON: {} at offset {}",
+                    self.plugin, error.message, error.offset,
+                );
+            }
+        }
+
+        if let Some(state) = self.state {
+            state.results.lock().unwrap().insert(self.plugin.to_owned(), result);
+        }
+    }
+
+    /// The value on the blackboard at `key`, if it is a `T`.
+    ///
+    /// # Panics
+    ///
+    /// If the plugin neither reads nor writes `key`.
+    pub fn read<T: Any + Send + Sync>(&self, key: &str) -> Option<Arc<T>> {
+        assert!(
+            self.reads.contains(&key) || self.writes.contains(&key),
+            "`{}` reads `{key}` from the blackboard without declaring it",
+            self.plugin,
+        );
+
+        self.state?.read(key)
+    }
+
+    /// Puts `value` on the blackboard at `key`.
+    ///
+    /// # Panics
+    ///
+    /// If the plugin does not write `key`.
+    pub fn write<T: Any + Send + Sync>(&self, key: &str, value: T) {
+        assert!(
+            self.writes.contains(&key),
+            "`{}` writes `{key}` to the blackboard without declaring it",
+            self.plugin,
+        );
+
+        if let Some(state) = self.state {
+            state.write(key, value);
+        }
+    }
+}
+
+/// What a dispatcher shares with every [`RunContext`] it hands out.
+#[derive(Default)]
+pub(crate) struct DispatchState {
+    cancelled: AtomicBool,
+    outputs: RwLock<AHashMap<String, Output>>,
+    blackboard: RwLock<AHashMap<String, Output>>,
+    results: Mutex<AHashMap<String, PluginResult>>,
+    pub(crate) environment: Environment,
+    pub(crate) args: Vec<String>,
+    pub(crate) watchdog: Option<Watchdog>,
+    /// Whether reporting dispatches capture the output of plugins.
+    pub(crate) capture_output: bool,
+}
+
+impl DispatchState {
+    pub(crate) fn new(
+        environment: Environment,
+        args: Vec<String>,
+        watchdog: Option<Watchdog>,
+        capture_output: bool,
+    ) -> Self {
+        Self { environment, args, watchdog, capture_output, ..Default::default() }
+    }
+
+    /// Has the [`Watchdog`], if any, watch `plugin` while the returned
+    /// guard lives.
+    pub(crate) fn watch(&self, plugin: &str) -> Option<Watch<'_>> {
+        self.watchdog.as_ref()?.run(plugin)
+    }
+
+    pub(crate) fn context<'a>(&'a self, plugin: &'a dyn Plugin) -> RunContext<'a> {
+        RunContext {
+            plugin: plugin.name(),
+            dependencies: plugin.dependencies(),
+            reads: plugin.reads(),
+            writes: plugin.writes(),
+            state: Some(self),
+        }
+    }
+
+    /// Calls `f` with `plugin` and a context for it that does not borrow
+    /// it, so that `f` can run it mutably. The names the context refers to
+    /// are copied for that.
+    pub(crate) fn with_context_mut<T>(
+        &self,
+        plugin: &mut dyn Plugin,
+        f: impl FnOnce(&mut dyn Plugin, &RunContext) -> T,
+    ) -> T {
+        let name = plugin.name().to_owned();
+        let [dependencies, reads, writes] =
+            [plugin.dependencies(), plugin.reads(), plugin.writes()]
+                .map(|names| names.iter().map(|&name| name.to_owned()).collect::<Vec<_>>());
+        fn borrow(names: &[String]) -> Vec<&str> {
+            names.iter().map(String::as_str).collect()
+        }
+        let (dependencies, reads, writes) =
+            (borrow(&dependencies), borrow(&reads), borrow(&writes));
+
+        let context = RunContext {
+            plugin: &name,
+            dependencies: &dependencies,
+            reads: &reads,
+            writes: &writes,
+            state: Some(self),
+        };
+        f(plugin, &context)
+    }
+
+    pub(crate) fn local_context<'a>(&'a self, plugin: &'a dyn LocalPlugin) -> RunContext<'a> {
+        RunContext {
+            plugin: plugin.name(),
+            dependencies: plugin.dependencies(),
+            reads: plugin.reads(),
+            writes: plugin.writes(),
+            state: Some(self),
+        }
+    }
+
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn uncancel(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn output<T: Any + Send + Sync>(&self, plugin: &str) -> Option<Arc<T>> {
+        self.outputs.read().unwrap().get(plugin)?.clone().downcast().ok()
+    }
+
+    pub(crate) fn read<T: Any + Send + Sync>(&self, key: &str) -> Option<Arc<T>> {
+        self.blackboard.read().unwrap().get(key)?.clone().downcast().ok()
+    }
+
+    pub(crate) fn write<T: Any + Send + Sync>(&self, key: &str, value: T) {
+        self.blackboard.write().unwrap().insert(key.to_owned(), Arc::new(value));
+    }
+
+    /// The result `plugin` set since it was last taken, if any.
+    pub(crate) fn take_result(&self, plugin: &str) -> Option<PluginResult> {
+        self.results.lock().unwrap().remove(plugin)
+    }
+
+    /// Forgets the outputs and results of the previous dispatch.
+    pub(crate) fn begin(&self) {
+        self.outputs.write().unwrap().clear();
+        self.results.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DispatchState;
+    use crate::fixture::Stub;
+    use crate::{Environment, PluginResult, RunContext};
+
+    fn physics() -> Stub {
+        Stub::new("Physics", &[]).writes(&["time"])
+    }
+
+    fn render() -> Stub {
+        Stub::new("Render", &["Physics"]).reads(&["time"])
+    }
+
+    #[test]
+    fn outputs() {
+        let state = DispatchState::default();
+        state.context(&physics()).output(9.81_f64);
+
+        let render = render();
+        let context = state.context(&render);
+        assert_eq!(context.input::<f64>("Physics").as_deref(), Some(&9.81));
+        assert_eq!(context.input::<u32>("Physics"), None);
+
+        state.begin();
+        assert_eq!(context.input::<f64>("Physics"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "`Render` reads the output of `Audio`")]
+    fn undeclared_input() {
+        let state = DispatchState::default();
+        state.context(&render()).input::<f64>("Audio");
+    }
+
+    #[test]
+    fn blackboard() {
+        let state = DispatchState::default();
+        state.context(&physics()).write("time", 1.5_f64);
+        state.begin();
+
+        assert_eq!(state.context(&render()).read::<f64>("time").as_deref(), Some(&1.5));
+        assert_eq!(state.context(&physics()).read::<f64>("time").as_deref(), Some(&1.5));
+    }
+
+    #[test]
+    fn environment() {
+        let mut state = DispatchState::default();
+        state.environment.insert("data_dir", "/var/lib/sora");
+
+        assert_eq!(state.context(&physics()).environment().get("data_dir"), Some("/var/lib/sora"));
+        assert_eq!(RunContext::detached().environment().get("data_dir"), None);
+    }
+
+    #[test]
+    fn args() {
+        let args = vec!["--level".to_owned(), "3".to_owned()];
+        let state = DispatchState::new(Environment::new(), args, None, false);
+
+        assert_eq!(state.context(&physics()).args(), ["--level", "3"]);
+        assert!(RunContext::detached().args().is_empty());
+    }
+
+    #[test]
+    fn result() {
+        let state = DispatchState::default();
+        state.context(&physics()).set_result(PluginResult::Json(r#"{"bodies": 3}"#.to_owned()));
+        RunContext::detached().set_result(PluginResult::Bytes(vec![1]));
+
+        let result = state.take_result("Physics");
+        assert_eq!(result, Some(PluginResult::Json(r#"{"bodies": 3}"#.to_owned())));
+        assert_eq!(state.take_result("Physics"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "`Physics` sets a result that is not JSON")]
+    fn invalid_result() {
+        let state = DispatchState::default();
+        state.context(&physics()).set_result(PluginResult::Json("{".to_owned()));
+    }
+
+    #[test]
+    #[should_panic(expected = "`Render` writes `time` to the blackboard without declaring it")]
+    fn undeclared_write() {
+        let state = DispatchState::default();
+        state.context(&render()).write("time", 0.0_f64);
+    }
+}
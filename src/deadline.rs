@@ -0,0 +1,123 @@
+//! Dispatching within a time budget, such as a frame.
+
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use ahash::AHashMap;
+
+use crate::report::Failures;
+use crate::{DispatchReport, Dispatcher, PluginReport, panic_hook};
+
+/// How long each plugin is expected to run, learned from the reporting
+/// dispatches of a [`Dispatcher`].
+#[derive(Default)]
+pub(crate) struct History {
+    /// An exponential moving average of the durations of every plugin that
+    /// ran, by name.
+    durations: Mutex<AHashMap<String, Duration>>,
+}
+
+impl History {
+    /// Takes the durations of the plugins that ran in `reports` into
+    /// account, weighing each new one by a quarter.
+    pub(crate) fn record(&self, reports: &[PluginReport]) {
+        let mut durations = self.durations.lock().unwrap_or_else(PoisonError::into_inner);
+        for report in reports.iter().filter(|report| report.attempts > 0) {
+            durations
+                .entry(report.name.clone())
+                .and_modify(|duration| *duration = (*duration * 3 + report.duration) / 4)
+                .or_insert(report.duration);
+        }
+    }
+
+    /// How long the plugin called `name` is expected to run, zero if it
+    /// never ran.
+    pub(crate) fn estimate(&self, name: &str) -> Duration {
+        let durations = self.durations.lock().unwrap_or_else(PoisonError::into_inner);
+        durations.get(name).copied().unwrap_or_default()
+    }
+}
+
+impl<L> Dispatcher<L> {
+    /// Like [`dispatch_report`](Self::dispatch_report), but only starts a
+    /// stage if it is expected to finish by `deadline`. Once a stage is
+    /// not, neither it nor any stage after it runs, and their plugins are
+    /// reported as [`PluginStatus::Deferred`](crate::PluginStatus::Deferred).
+    ///
+    /// A stage is expected to take as long as its plugins together, each
+    /// as long as it took on average in recent reporting dispatches. A
+    /// plugin that never ran in one is expected to take no time.
+    pub fn dispatch_with_deadline(&self, deadline: Instant) -> DispatchReport {
+        let _hook = panic_hook::Scope::enter();
+        let start = Instant::now();
+        self.state.begin();
+        let mut failures = Failures::new(self.error_policy);
+        let mut deferred = false;
+        let stages = self
+            .stages()
+            .iter()
+            .map(|stage| {
+                let expected: Duration = stage
+                    .iter()
+                    .filter(|&&slot| !failures.skips(slot))
+                    .map(|&slot| self.history.estimate(self.at(slot).name()))
+                    .sum();
+                deferred = deferred || Instant::now() + expected > deadline;
+                if deferred {
+                    return stage
+                        .iter()
+                        .map(|&slot| PluginReport::deferred(self.at(slot).name()))
+                        .collect();
+                }
+
+                let reports: Vec<_> = stage
+                    .iter()
+                    .map(|&slot| failures.run(slot, &**self.at(slot), &self.state))
+                    .collect();
+                failures.record(&self.schedule, stage, &reports);
+                self.history.record(&reports);
+                reports
+            })
+            .collect();
+
+        DispatchReport { stages, duration: start.elapsed(), policy: failures.policy() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::fixture::Stub;
+    use crate::{PluginManager, PluginStatus};
+
+    #[test]
+    fn dispatch_with_deadline() {
+        let mut manager = PluginManager::new();
+        manager.register(Box::new(Stub::new("Input", &[]))).unwrap();
+        let physics = Stub::new("Physics", &["Input"]).sleep(Duration::from_millis(100));
+        manager.register(Box::new(physics)).unwrap();
+        manager.register(Box::new(Stub::new("Render", &["Physics"]))).unwrap();
+        let dispatcher = manager.into_dispatcher();
+
+        let statuses = |deadline| {
+            let report = dispatcher.dispatch_with_deadline(deadline);
+            report.plugins().map(|plugin| plugin.status.clone()).collect::<Vec<_>>()
+        };
+
+        // Nothing is known about Physics yet, so it is expected to take no
+        // time, and overruns the deadline.
+        let deadline = Instant::now() + Duration::from_millis(50);
+        assert_eq!(
+            statuses(deadline),
+            [PluginStatus::Succeeded, PluginStatus::Succeeded, PluginStatus::Deferred]
+        );
+
+        let deadline = Instant::now() + Duration::from_millis(50);
+        assert_eq!(
+            statuses(deadline),
+            [PluginStatus::Succeeded, PluginStatus::Deferred, PluginStatus::Deferred]
+        );
+        assert_eq!(statuses(Instant::now()), vec![PluginStatus::Deferred; 3]);
+    }
+}
@@ -0,0 +1,442 @@
+//! Ed25519 signatures ([RFC 8032]).
+//!
+//! The arithmetic is not constant time. That is fine for verification,
+//! which only processes public data, but signing leaks timing information
+//! about the secret key, so it is only meant for signing on a trusted
+//! machine, as `sora sign` does.
+//!
+//! [RFC 8032]: https://www.rfc-editor.org/rfc/rfc8032
+
+use crate::sha2::Sha512;
+
+/// Verifies `signature` of `message` against `public_key`.
+pub(crate) fn verify(public_key: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> bool {
+    let Some(a) = Point::decompress(public_key) else {
+        return false;
+    };
+
+    let (r, s) = signature.split_at(32);
+    let s = Scalar::from_bytes(s.try_into().unwrap());
+    if !s.is_canonical() {
+        return false;
+    }
+
+    let mut hasher = Sha512::new();
+    hasher.update(r);
+    hasher.update(public_key);
+    hasher.update(message);
+    let h = Scalar::from_wide(&hasher.finalize());
+
+    let check = Point::base().mul(&s).add(&a.neg().mul(&h));
+    check.compress() == *r
+}
+
+/// The public key of the secret key `seed`.
+pub(crate) fn public_key(seed: &[u8; 32]) -> [u8; 32] {
+    Point::base().mul(&expand(seed).0).compress()
+}
+
+/// Signs `message` with the secret key `seed`.
+pub(crate) fn sign(seed: &[u8; 32], message: &[u8]) -> [u8; 64] {
+    let (a, prefix) = expand(seed);
+    let public_key = Point::base().mul(&a).compress();
+
+    let mut hasher = Sha512::new();
+    hasher.update(&prefix);
+    hasher.update(message);
+    let r = Scalar::from_wide(&hasher.finalize());
+    let big_r = Point::base().mul(&r).compress();
+
+    let mut hasher = Sha512::new();
+    hasher.update(&big_r);
+    hasher.update(&public_key);
+    hasher.update(message);
+    let h = Scalar::from_wide(&hasher.finalize());
+
+    let mut signature = [0; 64];
+    signature[..32].copy_from_slice(&big_r);
+    signature[32..].copy_from_slice(&h.mul_add(&a, &r).to_bytes());
+    signature
+}
+
+/// The clamped secret scalar and the nonce prefix derived from `seed`.
+fn expand(seed: &[u8; 32]) -> (Scalar, [u8; 32]) {
+    let mut hasher = Sha512::new();
+    hasher.update(seed);
+    let hash = hasher.finalize();
+
+    let mut a: [u8; 32] = hash[..32].try_into().unwrap();
+    a[0] &= 0xf8;
+    a[31] &= 0x7f;
+    a[31] |= 0x40;
+    (Scalar::from_bytes(&a), hash[32..].try_into().unwrap())
+}
+
+const MASK: u64 = (1 << 51) - 1;
+
+/// An element of GF(2^255 - 19) in radix 2^51.
+#[derive(Clone, Copy)]
+struct Fe([u64; 5]);
+
+impl Fe {
+    const ZERO: Fe = Fe([0; 5]);
+    const ONE: Fe = Fe([1, 0, 0, 0, 0]);
+
+    fn from_bytes(bytes: &[u8; 32]) -> Fe {
+        let word = |i: usize| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+        let [w0, w1, w2, w3] = [word(0), word(1), word(2), word(3)];
+
+        Fe([
+            w0 & MASK,
+            (w0 >> 51 | w1 << 13) & MASK,
+            (w1 >> 38 | w2 << 26) & MASK,
+            (w2 >> 25 | w3 << 39) & MASK,
+            (w3 >> 12) & MASK,
+        ])
+    }
+
+    fn to_bytes(self) -> [u8; 32] {
+        let mut t = self.carry().0;
+
+        // Subtract p if t >= p, i.e. if t + 19 overflows 2^255.
+        let mut q = (t[0] + 19) >> 51;
+        for limb in &t[1..] {
+            q = (limb + q) >> 51;
+        }
+        t[0] += 19 * q;
+        for i in 0..4 {
+            t[i + 1] += t[i] >> 51;
+            t[i] &= MASK;
+        }
+        t[4] &= MASK;
+
+        let words = [
+            t[0] | t[1] << 51,
+            t[1] >> 13 | t[2] << 38,
+            t[2] >> 26 | t[3] << 25,
+            t[3] >> 39 | t[4] << 12,
+        ];
+        let mut bytes = [0; 32];
+        for (chunk, word) in bytes.chunks_exact_mut(8).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn carry(self) -> Fe {
+        let mut t = self.0;
+        for i in 0..4 {
+            t[i + 1] += t[i] >> 51;
+            t[i] &= MASK;
+        }
+        t[0] += 19 * (t[4] >> 51);
+        t[4] &= MASK;
+        t[1] += t[0] >> 51;
+        t[0] &= MASK;
+        Fe(t)
+    }
+
+    fn add(&self, rhs: &Fe) -> Fe {
+        Fe(std::array::from_fn(|i| self.0[i] + rhs.0[i])).carry()
+    }
+
+    fn sub(&self, rhs: &Fe) -> Fe {
+        // Add 2p so that the limbs cannot underflow.
+        const TWO_P: [u64; 5] =
+            [0xfffffffffffda, 0xffffffffffffe, 0xffffffffffffe, 0xffffffffffffe, 0xffffffffffffe];
+        Fe(std::array::from_fn(|i| self.0[i] + TWO_P[i] - rhs.0[i])).carry()
+    }
+
+    fn neg(&self) -> Fe {
+        Fe::ZERO.sub(self)
+    }
+
+    fn mul(&self, rhs: &Fe) -> Fe {
+        let a = self.0.map(u128::from);
+        let b = rhs.0.map(u128::from);
+        let b19 = b.map(|limb| limb * 19);
+
+        let r = [
+            a[0] * b[0] + a[1] * b19[4] + a[2] * b19[3] + a[3] * b19[2] + a[4] * b19[1],
+            a[0] * b[1] + a[1] * b[0] + a[2] * b19[4] + a[3] * b19[3] + a[4] * b19[2],
+            a[0] * b[2] + a[1] * b[1] + a[2] * b[0] + a[3] * b19[4] + a[4] * b19[3],
+            a[0] * b[3] + a[1] * b[2] + a[2] * b[1] + a[3] * b[0] + a[4] * b19[4],
+            a[0] * b[4] + a[1] * b[3] + a[2] * b[2] + a[3] * b[1] + a[4] * b[0],
+        ];
+
+        let mut t = [0u64; 5];
+        let mut carry = 0;
+        for i in 0..5 {
+            let value = r[i] + carry;
+            t[i] = value as u64 & MASK;
+            carry = value >> 51;
+        }
+        let value = u128::from(t[0]) + carry * 19;
+        t[0] = value as u64 & MASK;
+        t[1] += (value >> 51) as u64;
+
+        Fe(t).carry()
+    }
+
+    fn square(&self) -> Fe {
+        self.mul(self)
+    }
+
+    /// Raises to the power given as a little-endian byte string.
+    fn pow(&self, exponent: &[u8; 32]) -> Fe {
+        let mut result = Fe::ONE;
+        for bit in (0..256).rev() {
+            result = result.square();
+            if exponent[bit / 8] >> (bit % 8) & 1 == 1 {
+                result = result.mul(self);
+            }
+        }
+        result
+    }
+
+    fn invert(&self) -> Fe {
+        self.pow(&exponent(0xeb, 0x7f))
+    }
+
+    fn is_zero(&self) -> bool {
+        self.to_bytes() == [0; 32]
+    }
+
+    fn is_negative(&self) -> bool {
+        self.to_bytes()[0] & 1 == 1
+    }
+
+    fn equals(&self, rhs: &Fe) -> bool {
+        self.to_bytes() == rhs.to_bytes()
+    }
+}
+
+/// `0xff`-filled little-endian exponent with the given first and last byte.
+fn exponent(first: u8, last: u8) -> [u8; 32] {
+    let mut bytes = [0xff; 32];
+    bytes[0] = first;
+    bytes[31] = last;
+    bytes
+}
+
+/// The curve constant `d = -121665 / 121666`.
+const D: [u8; 32] = [
+    0xa3, 0x78, 0x59, 0x13, 0xca, 0x4d, 0xeb, 0x75, 0xab, 0xd8, 0x41, 0x41, 0x4d, 0x0a, 0x70, 0x00,
+    0x98, 0xe8, 0x79, 0x77, 0x79, 0x40, 0xc7, 0x8c, 0x73, 0xfe, 0x6f, 0x2b, 0xee, 0x6c, 0x03, 0x52,
+];
+
+/// `2 * d`.
+const D2: [u8; 32] = [
+    0x59, 0xf1, 0xb2, 0x26, 0x94, 0x9b, 0xd6, 0xeb, 0x56, 0xb1, 0x83, 0x82, 0x9a, 0x14, 0xe0, 0x00,
+    0x30, 0xd1, 0xf3, 0xee, 0xf2, 0x80, 0x8e, 0x19, 0xe7, 0xfc, 0xdf, 0x56, 0xdc, 0xd9, 0x06, 0x24,
+];
+
+/// A point on the twisted Edwards curve in extended coordinates.
+#[derive(Clone, Copy)]
+struct Point {
+    x: Fe,
+    y: Fe,
+    z: Fe,
+    t: Fe,
+}
+
+impl Point {
+    const IDENTITY: Point = Point { x: Fe::ZERO, y: Fe::ONE, z: Fe::ONE, t: Fe::ZERO };
+
+    fn base() -> Point {
+        let mut bytes = [0x66; 32];
+        bytes[0] = 0x58;
+        Point::decompress(&bytes).unwrap()
+    }
+
+    fn decompress(bytes: &[u8; 32]) -> Option<Point> {
+        let sign = bytes[31] >> 7 == 1;
+        let y = Fe::from_bytes(bytes);
+
+        let mut canonical = *bytes;
+        canonical[31] &= 0x7f;
+        if y.to_bytes() != canonical {
+            return None;
+        }
+
+        let y2 = y.square();
+        let u = y2.sub(&Fe::ONE);
+        let v = Fe::from_bytes(&D).mul(&y2).add(&Fe::ONE);
+
+        let v3 = v.square().mul(&v);
+        let v7 = v3.square().mul(&v);
+        let mut x = u.mul(&v3).mul(&u.mul(&v7).pow(&exponent(0xfd, 0x0f)));
+
+        let vx2 = v.mul(&x.square());
+        if !vx2.equals(&u) {
+            if !vx2.equals(&u.neg()) {
+                return None;
+            }
+            let sqrt_m1 = Fe([2, 0, 0, 0, 0]).pow(&exponent(0xfb, 0x1f));
+            x = x.mul(&sqrt_m1);
+        }
+
+        if x.is_zero() && sign {
+            return None;
+        }
+        if x.is_negative() != sign {
+            x = x.neg();
+        }
+
+        Some(Point { x, y, z: Fe::ONE, t: x.mul(&y) })
+    }
+
+    fn compress(&self) -> [u8; 32] {
+        let z = self.z.invert();
+        let x = self.x.mul(&z);
+        let mut bytes = self.y.mul(&z).to_bytes();
+        bytes[31] |= (x.is_negative() as u8) << 7;
+        bytes
+    }
+
+    fn neg(&self) -> Point {
+        Point { x: self.x.neg(), y: self.y, z: self.z, t: self.t.neg() }
+    }
+
+    fn add(&self, rhs: &Point) -> Point {
+        let two_d = Fe::from_bytes(&D2);
+
+        let a = self.y.sub(&self.x).mul(&rhs.y.sub(&rhs.x));
+        let b = self.y.add(&self.x).mul(&rhs.y.add(&rhs.x));
+        let c = self.t.mul(&two_d).mul(&rhs.t);
+        let d = self.z.add(&self.z).mul(&rhs.z);
+        let (e, f, g, h) = (b.sub(&a), d.sub(&c), d.add(&c), b.add(&a));
+
+        Point { x: e.mul(&f), y: g.mul(&h), z: f.mul(&g), t: e.mul(&h) }
+    }
+
+    fn mul(&self, scalar: &Scalar) -> Point {
+        let mut result = Point::IDENTITY;
+        for bit in (0..256).rev() {
+            result = result.add(&result);
+            if scalar.bit(bit) {
+                result = result.add(self);
+            }
+        }
+        result
+    }
+}
+
+/// The order of the base point, as little-endian 64-bit words.
+const L: [u64; 4] = [0x5812631a5cf5d3ed, 0x14def9dea2f79cd6, 0, 0x1000000000000000];
+
+/// An integer modulo the group order `L`, as little-endian 64-bit words.
+#[derive(Clone, Copy)]
+struct Scalar([u64; 4]);
+
+impl Scalar {
+    fn from_bytes(bytes: &[u8; 32]) -> Scalar {
+        Scalar(std::array::from_fn(|i| {
+            u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap())
+        }))
+    }
+
+    /// Reduces a 512-bit little-endian integer modulo `L`.
+    fn from_wide(bytes: &[u8; 64]) -> Scalar {
+        let mut r = Scalar([0; 4]);
+        for bit in (0..512).rev() {
+            // r < L < 2^253, so doubling cannot overflow.
+            let mut carry = (bytes[bit / 8] >> (bit % 8) & 1) as u64;
+            for word in &mut r.0 {
+                let next = *word >> 63;
+                *word = *word << 1 | carry;
+                carry = next;
+            }
+            if !r.is_canonical() {
+                r.sub_l();
+            }
+        }
+        r
+    }
+
+    fn to_bytes(self) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        for (chunk, word) in bytes.chunks_exact_mut(8).zip(self.0) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// `self * a + b` modulo `L`, for `a` and `b` below 2^255.
+    fn mul_add(&self, a: &Scalar, b: &Scalar) -> Scalar {
+        let mut wide = [0u64; 8];
+        for (i, x) in self.0.iter().enumerate() {
+            let mut carry = 0u128;
+            for (j, y) in a.0.iter().enumerate() {
+                let sum = wide[i + j] as u128 + *x as u128 * *y as u128 + carry;
+                wide[i + j] = sum as u64;
+                carry = sum >> 64;
+            }
+            wide[i + 4] = carry as u64;
+        }
+
+        let mut carry = 0u128;
+        for (i, word) in wide.iter_mut().enumerate() {
+            let sum = *word as u128 + b.0.get(i).copied().unwrap_or(0) as u128 + carry;
+            *word = sum as u64;
+            carry = sum >> 64;
+        }
+
+        let mut bytes = [0; 64];
+        for (chunk, word) in bytes.chunks_exact_mut(8).zip(wide) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        Scalar::from_wide(&bytes)
+    }
+
+    fn is_canonical(&self) -> bool {
+        for i in (0..4).rev() {
+            if self.0[i] != L[i] {
+                return self.0[i] < L[i];
+            }
+        }
+        false
+    }
+
+    fn sub_l(&mut self) {
+        let mut borrow = false;
+        for (word, l) in self.0.iter_mut().zip(L) {
+            let (value, b1) = word.overflowing_sub(l);
+            let (value, b2) = value.overflowing_sub(borrow as u64);
+            *word = value;
+            borrow = b1 || b2;
+        }
+    }
+
+    fn bit(&self, bit: usize) -> bool {
+        self.0[bit / 64] >> (bit % 64) & 1 == 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sign, verify};
+
+    fn unhex<const N: usize>(hex: &str) -> [u8; N] {
+        std::array::from_fn(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap())
+    }
+
+    #[test]
+    fn rfc8032() {
+        let public_key = unhex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+        let signature = unhex(
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        );
+
+        assert!(verify(&public_key, b"", &signature));
+        let secret = unhex("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60");
+        assert_eq!(super::public_key(&secret), public_key);
+        assert_eq!(sign(&secret, b""), signature);
+        assert!(verify(&public_key, b"library", &sign(&secret, b"library")));
+        assert!(!verify(&public_key, b"\0", &signature));
+
+        let mut tampered = signature;
+        tampered[40] ^= 1;
+        assert!(!verify(&public_key, b"", &tampered));
+    }
+}
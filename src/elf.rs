@@ -0,0 +1,155 @@
+//! Just enough of the ELF format to list the shared libraries a library
+//! needs.
+
+/// What the dynamic section of an ELF file asks of the dynamic linker.
+#[derive(Debug, Default)]
+pub(crate) struct Dynamic {
+    /// The `DT_NEEDED` entries, in order.
+    pub(crate) needed: Vec<String>,
+    /// The folders of `DT_RUNPATH` and `DT_RPATH`, unexpanded.
+    pub(crate) runpath: Vec<String>,
+}
+
+/// The `DT_NEEDED` entries of the ELF file in `bytes`, in order, or `None`
+/// if it is not a valid ELF file. Files without a dynamic section need
+/// nothing.
+pub(crate) fn needed(bytes: &[u8]) -> Option<Vec<String>> {
+    Some(dynamic(bytes)?.needed)
+}
+
+/// The dynamic section of the ELF file in `bytes`, or `None` if it is not a
+/// valid ELF file.
+pub(crate) fn dynamic(bytes: &[u8]) -> Option<Dynamic> {
+    let elf = Elf::new(bytes)?;
+
+    let mut dynamic = Dynamic::default();
+    for section in 0..elf.section_count()? {
+        let (kind, offset, size, link) = elf.section(section)?;
+        if kind != SHT_DYNAMIC {
+            continue;
+        }
+
+        let (_, strings, strings_size, _) = elf.section(link)?;
+        let strings = bytes.get(strings..strings.checked_add(strings_size)?)?;
+        let entry_size = 2 * elf.word_size();
+        for entry in (offset..offset.checked_add(size)?).step_by(entry_size) {
+            let string = |offset: u64| {
+                let string = strings.get(usize::try_from(offset).ok()?..)?;
+                let string = &string[..string.iter().position(|&byte| byte == 0)?];
+                String::from_utf8(string.to_vec()).ok()
+            };
+
+            match (elf.word(entry)?, elf.word(entry + elf.word_size())?) {
+                (DT_NULL, _) => break,
+                (DT_NEEDED, name) => dynamic.needed.push(string(name)?),
+                (DT_RPATH | DT_RUNPATH, path) => {
+                    dynamic.runpath.extend(string(path)?.split(':').map(str::to_owned));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Some(dynamic)
+}
+
+const SHT_DYNAMIC: u32 = 6;
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+
+struct Elf<'a> {
+    bytes: &'a [u8],
+    is_64: bool,
+    is_little_endian: bool,
+}
+
+impl<'a> Elf<'a> {
+    fn new(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.get(..4)? != b"\x7fELF" {
+            return None;
+        }
+
+        let is_64 = match bytes.get(4)? {
+            1 => false,
+            2 => true,
+            _ => return None,
+        };
+        let is_little_endian = match bytes.get(5)? {
+            1 => true,
+            2 => false,
+            _ => return None,
+        };
+
+        Some(Self { bytes, is_64, is_little_endian })
+    }
+
+    fn word_size(&self) -> usize {
+        if self.is_64 { 8 } else { 4 }
+    }
+
+    fn read<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+        let mut bytes: [u8; N] = self.bytes.get(offset..offset.checked_add(N)?)?.try_into().ok()?;
+        if !self.is_little_endian {
+            bytes.reverse();
+        }
+        Some(bytes)
+    }
+
+    fn u16(&self, offset: usize) -> Option<usize> {
+        Some(u16::from_le_bytes(self.read(offset)?).into())
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        Some(u32::from_le_bytes(self.read(offset)?))
+    }
+
+    /// An address, offset or size, which is as wide as the class.
+    fn word(&self, offset: usize) -> Option<u64> {
+        match self.is_64 {
+            true => Some(u64::from_le_bytes(self.read(offset)?)),
+            false => Some(self.u32(offset)?.into()),
+        }
+    }
+
+    fn offset(&self, offset: usize) -> Option<usize> {
+        usize::try_from(self.word(offset)?).ok()
+    }
+
+    fn section_count(&self) -> Option<usize> {
+        self.u16(if self.is_64 { 0x3c } else { 0x30 })
+    }
+
+    /// The type, offset, size and link of section `index`.
+    fn section(&self, index: impl TryInto<usize>) -> Option<(u32, usize, usize, u32)> {
+        let (table, entry_size) = match self.is_64 {
+            true => (self.offset(0x28)?, self.u16(0x3a)?),
+            false => (self.offset(0x20)?, self.u16(0x2e)?),
+        };
+        let header = table.checked_add(index.try_into().ok()?.checked_mul(entry_size)?)?;
+        let word = self.word_size();
+
+        Some((
+            self.u32(header + 4)?,
+            self.offset(header + 8 + 2 * word)?,
+            self.offset(header + 8 + 3 * word)?,
+            self.u32(header + 8 + 4 * word)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn needed() {
+        assert_eq!(super::needed(b"not an ELF file"), None);
+        assert_eq!(super::needed(b"\x7fELF"), None);
+
+        if cfg!(target_os = "linux") {
+            let exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+            let needed = super::needed(&exe).unwrap();
+            assert!(needed.iter().any(|name| name.starts_with("libc.so")), "{needed:?}");
+        }
+    }
+}
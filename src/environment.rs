@@ -0,0 +1,72 @@
+//! Read-only settings the host hands to running plugins.
+
+use std::collections::BTreeMap;
+
+/// String settings that plugins read through
+/// [`RunContext::environment`](crate::RunContext::environment) instead of
+/// the process environment, such as the host's version or the directories
+/// plugins may write to. Their keys are up to the host.
+///
+/// Since the host decides every value, plugins can be run in tests or in a
+/// sandbox with whatever environment suits it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Environment {
+    values: BTreeMap<String, String>,
+}
+
+impl Environment {
+    pub const fn new() -> Self {
+        Self { values: BTreeMap::new() }
+    }
+
+    /// The variables of the process whose names start with `prefix`, keyed
+    /// by the rest of their names. Variables that are not valid UTF-8 are
+    /// skipped.
+    pub fn from_vars(prefix: &str) -> Self {
+        std::env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .filter_map(|(name, value)| Some((name.strip_prefix(prefix)?.to_owned(), value)))
+            .collect()
+    }
+
+    /// Sets `key` to `value`, returning the previous value.
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) -> Option<String> {
+        self.values.insert(key.into(), value.into())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Environment {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Self { values: iter.into_iter().map(|(key, value)| (key.into(), value.into())).collect() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Environment;
+
+    #[test]
+    fn environment() {
+        let mut environment: Environment = [("version", "1.0")].into_iter().collect();
+        assert_eq!(environment.insert("data_dir", "/var/lib/sora"), None);
+        assert_eq!(environment.insert("version", "2.0").as_deref(), Some("1.0"));
+
+        assert_eq!(environment.get("version"), Some("2.0"));
+        assert_eq!(environment.get("missing"), None);
+        assert_eq!(
+            environment.iter().collect::<Vec<_>>(),
+            [("data_dir", "/var/lib/sora"), ("version", "2.0")]
+        );
+
+        let path = Environment::from_vars("PAT");
+        assert_eq!(path.get("H").map(str::to_owned), std::env::var("PATH").ok());
+    }
+}
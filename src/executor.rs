@@ -0,0 +1,267 @@
+//! Where parallel work runs: on a rayon thread pool with the `rayon`
+//! feature, and otherwise on threads spawned with [`std::thread::scope`]
+//! for each batch of work.
+
+#[cfg(not(feature = "rayon"))]
+use std::cell::Cell;
+use std::sync::Arc;
+
+/// Which CPU cores the worker threads of a [`Dispatcher`] run on, for
+/// example to keep them off the cores of the host's own threads. Only
+/// applied on Linux. See [`DispatcherBuilder::affinity`].
+///
+/// [`Dispatcher`]: crate::Dispatcher
+/// [`DispatcherBuilder::affinity`]: crate::DispatcherBuilder::affinity
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Affinity {
+    /// Every worker runs on any of the cores.
+    Mask(Vec<usize>),
+    /// Each worker runs on one of the cores only, in turn: worker `n` on
+    /// the core at `n` modulo their number.
+    Pin(Vec<usize>),
+}
+
+impl Affinity {
+    /// Restricts the current thread, worker `index`, to its cores. Cores
+    /// the machine lacks are left out, and if none is left, the thread
+    /// keeps running anywhere.
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    fn apply(&self, index: usize) {
+        #[cfg(target_os = "linux")]
+        {
+            let cores = match self {
+                Self::Mask(cores) => &cores[..],
+                Self::Pin(cores) if cores.is_empty() => return,
+                Self::Pin(cores) => std::slice::from_ref(&cores[index % cores.len()]),
+            };
+
+            let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            let max = 8 * size_of::<libc::cpu_set_t>();
+            for &core in cores.iter().filter(|&&core| core < max) {
+                unsafe { libc::CPU_SET(core, &mut set) };
+            }
+            unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) };
+        }
+    }
+}
+
+type NameFn = Arc<dyn Fn(usize) -> String + Send + Sync>;
+
+/// How the worker threads of a [`Dispatcher`](crate::Dispatcher) are set
+/// up.
+#[derive(Clone, Default)]
+pub(crate) struct Threads {
+    /// With `0`, as many as the machine has.
+    pub(crate) num_threads: usize,
+    pub(crate) affinity: Option<Affinity>,
+    /// The name of each worker by its index, `sora-worker-{index}` by
+    /// default.
+    pub(crate) name: Option<NameFn>,
+    /// With `None`, that of [`std::thread::Builder`].
+    pub(crate) stack_size: Option<usize>,
+}
+
+impl Threads {
+    fn name(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => name(index),
+            None => format!("sora-worker-{index}"),
+        }
+    }
+
+    #[cfg(not(feature = "rayon"))]
+    fn builder(&self, index: usize) -> std::thread::Builder {
+        let builder = std::thread::Builder::new().name(self.name(index));
+        match self.stack_size {
+            Some(stack_size) => builder.stack_size(stack_size),
+            None => builder,
+        }
+    }
+}
+
+/// The threads of a [`Dispatcher`](crate::Dispatcher).
+pub(crate) struct Executor {
+    #[cfg(feature = "rayon")]
+    pool: rayon::ThreadPool,
+    #[cfg(not(feature = "rayon"))]
+    threads: Threads,
+}
+
+impl Executor {
+    pub(crate) fn new(threads: Threads) -> Self {
+        #[cfg(feature = "rayon")]
+        let executor = {
+            let mut builder = rayon::ThreadPoolBuilder::new().num_threads(threads.num_threads);
+            if let Some(stack_size) = threads.stack_size {
+                builder = builder.stack_size(stack_size);
+            }
+            if let Some(affinity) = threads.affinity.clone() {
+                builder = builder.start_handler(move |index| affinity.apply(index));
+            }
+            let pool = builder.thread_name(move |index| threads.name(index)).build();
+            Self { pool: pool.expect("Invalid configuration") }
+        };
+        #[cfg(not(feature = "rayon"))]
+        let executor = Self { threads };
+
+        executor
+    }
+
+    /// Calls `f` with every item in parallel, and returns the results in
+    /// the order of the items. A panic in `f` is resumed once every item
+    /// has been processed or abandoned.
+    pub(crate) fn map<T: Send, R: Send>(&self, items: Vec<T>, f: impl Fn(T) -> R + Sync) -> Vec<R> {
+        #[cfg(feature = "rayon")]
+        return self.pool.install(|| map(items, &f));
+        #[cfg(not(feature = "rayon"))]
+        return map_scoped(&self.threads, items, f);
+    }
+}
+
+/// Like [`Executor::map`], on rayon's global thread pool with the `rayon`
+/// feature, and otherwise on as many threads as the machine has.
+pub(crate) fn map<T: Send, R: Send>(items: Vec<T>, f: impl Fn(T) -> R + Sync) -> Vec<R> {
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::iter::{IntoParallelIterator as _, ParallelIterator as _};
+        items.into_par_iter().map(&f).collect()
+    }
+    #[cfg(not(feature = "rayon"))]
+    map_scoped(&Threads::default(), items, f)
+}
+
+/// The index of the worker thread running the caller, if any.
+#[cfg(any(test, feature = "profile"))]
+pub(crate) fn current_thread_index() -> Option<usize> {
+    #[cfg(feature = "rayon")]
+    return rayon::current_thread_index();
+    #[cfg(not(feature = "rayon"))]
+    return WORKER.get();
+}
+
+#[cfg(not(feature = "rayon"))]
+thread_local! {
+    static WORKER: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Splits `items` into one run of consecutive items per thread.
+#[cfg(not(feature = "rayon"))]
+fn map_scoped<T: Send, R: Send>(
+    threads: &Threads,
+    items: Vec<T>,
+    f: impl Fn(T) -> R + Sync,
+) -> Vec<R> {
+    let num_threads = match threads.num_threads {
+        0 => std::thread::available_parallelism().map_or(1, std::num::NonZero::get),
+        num_threads => num_threads,
+    };
+    let num_threads = num_threads.min(items.len());
+    if num_threads <= 1 {
+        return items.into_iter().map(f).collect();
+    }
+
+    let chunk_size = items.len().div_ceil(num_threads);
+    let mut items = items.into_iter();
+    let chunks: Vec<Vec<T>> =
+        (0..num_threads).map(|_| items.by_ref().take(chunk_size).collect()).collect();
+
+    let f = &f;
+    let results: Vec<_> = std::thread::scope(|scope| {
+        let workers: Vec<_> = chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| {
+                let worker = threads.builder(index).spawn_scoped(scope, move || {
+                    WORKER.set(Some(index));
+                    if let Some(affinity) = &threads.affinity {
+                        affinity.apply(index);
+                    }
+                    chunk.into_iter().map(f).collect::<Vec<_>>()
+                });
+                worker.expect("cannot spawn a worker thread")
+            })
+            .collect();
+        workers.into_iter().map(|worker| worker.join()).collect()
+    });
+
+    let mut mapped = Vec::new();
+    for result in results {
+        mapped.extend(result.unwrap_or_else(|payload| std::panic::resume_unwind(payload)));
+    }
+    mapped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{Executor, Threads};
+
+    fn executor(num_threads: usize) -> Executor {
+        Executor::new(Threads { num_threads, ..Threads::default() })
+    }
+
+    #[test]
+    fn map() {
+        let squares = executor(3).map((0..10).collect(), |n: u32| n * n);
+        assert_eq!(squares, [0, 1, 4, 9, 16, 25, 36, 49, 64, 81]);
+        assert_eq!(super::map(Vec::new(), |n: u32| n), []);
+
+        let indices = executor(2).map(vec![(); 2], |()| super::current_thread_index());
+        assert!(indices.iter().all(|index| index.is_some_and(|index| index < 2)), "{indices:?}");
+        assert_eq!(super::current_thread_index(), None);
+
+        let panicked = std::panic::catch_unwind(|| {
+            executor(2).map(vec![1, 2, 3], |n| assert_ne!(n, 2));
+        });
+        assert!(panicked.is_err());
+    }
+
+    #[test]
+    fn threads() {
+        let names =
+            executor(2).map(vec![(); 2], |()| std::thread::current().name().map(str::to_owned));
+        let mut names: Vec<_> = names.into_iter().flatten().collect();
+        names.dedup();
+        assert!(names.iter().all(|name| name.starts_with("sora-worker-")), "{names:?}");
+
+        let threads = Threads {
+            num_threads: 2,
+            name: Some(Arc::new(|index| format!("script-{index}"))),
+            stack_size: Some(64 << 20),
+            ..Threads::default()
+        };
+        // Deeper than the default stack of 2 MiB allows.
+        fn depth(n: u32) -> u32 {
+            let frame = std::hint::black_box([0u8; 1024]);
+            if n == 0 { u32::from(frame[0]) } else { depth(n - 1) + 1 }
+        }
+        let results = Executor::new(threads).map(vec![(); 2], |()| {
+            (std::thread::current().name().unwrap().to_owned(), depth(10_000))
+        });
+        assert!(
+            results.iter().all(|(name, depth)| name.starts_with("script-") && *depth == 10_000)
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn affinity() {
+        use super::Affinity;
+
+        fn cores() -> Vec<usize> {
+            let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            unsafe { libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &mut set) };
+            (0..8 * size_of::<libc::cpu_set_t>())
+                .filter(|&core| unsafe { libc::CPU_ISSET(core, &set) })
+                .collect()
+        }
+
+        for affinity in [Affinity::Pin(vec![0, 0]), Affinity::Mask(vec![0, 100_000])] {
+            let threads =
+                Threads { num_threads: 2, affinity: Some(affinity), ..Threads::default() };
+            let cores = Executor::new(threads).map(vec![(); 2], |()| cores());
+            assert_eq!(cores, [[0], [0]]);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! A plugin for the tests of the crate, which declares whatever a test
+//! needs.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use crate::state::BoxError;
+use crate::{Plugin, RunContext};
+
+type RunFn = Box<dyn Fn(&RunContext) + Send + Sync>;
+
+/// Counts its runs, and does nothing else unless given a function to
+/// [`run`](Self::run).
+pub(crate) struct Stub {
+    name: &'static str,
+    dependencies: &'static [&'static str],
+    reads: &'static [&'static str],
+    writes: &'static [&'static str],
+    run: RunFn,
+    runs: AtomicU32,
+    stateful: bool,
+}
+
+impl Stub {
+    pub(crate) fn new(name: &'static str, dependencies: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            dependencies,
+            reads: &[],
+            writes: &[],
+            run: Box::new(|_| {}),
+            runs: AtomicU32::new(0),
+            stateful: false,
+        }
+    }
+
+    pub(crate) fn reads(mut self, keys: &'static [&'static str]) -> Self {
+        self.reads = keys;
+        self
+    }
+
+    pub(crate) fn writes(mut self, keys: &'static [&'static str]) -> Self {
+        self.writes = keys;
+        self
+    }
+
+    pub(crate) fn run(mut self, run: impl Fn(&RunContext) + Send + Sync + 'static) -> Self {
+        self.run = Box::new(run);
+        self
+    }
+
+    /// Sleeps for `duration` whenever it runs.
+    pub(crate) fn sleep(self, duration: Duration) -> Self {
+        self.run(move |_| std::thread::sleep(duration))
+    }
+
+    /// Saves the number of its runs as its state, and restores it.
+    pub(crate) fn stateful(mut self) -> Self {
+        self.stateful = true;
+        self
+    }
+}
+
+impl Plugin for Stub {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn dependencies(&self) -> &[&str] {
+        self.dependencies
+    }
+
+    fn reads(&self) -> &[&str] {
+        self.reads
+    }
+
+    fn writes(&self) -> &[&str] {
+        self.writes
+    }
+
+    fn run(&self, context: &RunContext) {
+        (self.run)(context);
+        self.runs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn save_state(&self) -> Option<Vec<u8>> {
+        self.stateful.then(|| self.runs.load(Ordering::Relaxed).to_le_bytes().to_vec())
+    }
+
+    fn load_state(&self, state: &[u8]) -> Result<(), BoxError> {
+        self.runs.store(u32::from_le_bytes(state.try_into()?), Ordering::Relaxed);
+        Ok(())
+    }
+}
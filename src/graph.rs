@@ -0,0 +1,206 @@
+//! Checks the dependency graph of a [`PluginManager`] and renders the
+//! stages computed by [`DispatcherBuilder::build`].
+//!
+//! [`DispatcherBuilder::build`]: crate::DispatcherBuilder::build
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+
+use ahash::{AHashMap, AHashSet};
+use petgraph::graph::{DiGraph, NodeIndex};
+
+use crate::{Dispatcher, Loader, PluginManager};
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum GraphError {
+    #[error("plugin `{plugin}` depends on `{dependency}`, which is not loaded")]
+    MissingDependency { plugin: String, dependency: String },
+    /// Each plugin depends on the next one, and the last on the first.
+    #[error("dependency cycle: {} -> {}", .0.join(" -> "), .0[0])]
+    Cycle(Vec<String>),
+    #[error("a plugin named `{0}` is already loaded")]
+    Duplicate(String),
+}
+
+impl<L: Loader> PluginManager<L> {
+    /// Checks that every dependency is loaded and that there are no
+    /// dependency cycles, which would make
+    /// [`into_dispatcher`](Self::into_dispatcher) panic.
+    pub fn validate(&self) -> Result<(), Vec<GraphError>> {
+        use petgraph::algo::tarjan_scc;
+
+        let mut errors = Vec::new();
+        let mut graph = DiGraph::<&str, ()>::new();
+        let nodes: Vec<_> =
+            self.plugins.iter().map(|plugin| graph.add_node(plugin.name())).collect();
+
+        for (index, plugin) in self.plugins.iter().enumerate() {
+            for &dependency in plugin.dependencies() {
+                match self.name_of_plugin.get(dependency) {
+                    Some(&dependency) => {
+                        graph.add_edge(nodes[dependency], nodes[index], ());
+                    }
+                    None => errors.push(GraphError::MissingDependency {
+                        plugin: plugin.name().to_owned(),
+                        dependency: dependency.to_owned(),
+                    }),
+                }
+            }
+        }
+
+        let mut components = tarjan_scc(&graph);
+        components.iter_mut().for_each(|component| component.sort_unstable());
+        components.sort_unstable();
+
+        for component in components {
+            if component.len() > 1 || graph.contains_edge(component[0], component[0]) {
+                // Edges point from a dependency to its dependents, so
+                // reverse the cycle to follow the dependencies.
+                let mut cycle = shortest_cycle(&graph, &component);
+                cycle[1..].reverse();
+                errors.push(GraphError::Cycle(
+                    cycle.into_iter().map(|node| graph[node].to_owned()).collect(),
+                ));
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+
+    /// Reports what looks like a mistake in the declarations of the
+    /// plugins but does not keep them from being dispatched, in the order
+    /// the plugins were registered.
+    pub fn warnings(&self) -> Vec<Warning> {
+        let written: AHashSet<_> = self.plugins.iter().flat_map(|plugin| plugin.writes()).collect();
+
+        let mut warnings = Vec::new();
+        for plugin in &self.plugins {
+            let name = plugin.name();
+            let mut seen = AHashSet::new();
+            let mut duplicates = AHashSet::new();
+            for &dependency in plugin.dependencies() {
+                if !seen.insert(dependency) && duplicates.insert(dependency) {
+                    warnings.push(Warning::DuplicateDependency {
+                        plugin: name.to_owned(),
+                        dependency: dependency.to_owned(),
+                    });
+                }
+            }
+
+            for key in plugin.reads().iter().filter(|key| !written.contains(key)) {
+                warnings.push(Warning::UnwrittenKey {
+                    plugin: name.to_owned(),
+                    key: (*key).to_owned(),
+                });
+            }
+        }
+
+        warnings
+    }
+}
+
+/// See [`PluginManager::warnings`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Warning {
+    #[error("plugin `{plugin}` declares its dependency on `{dependency}` more than once")]
+    DuplicateDependency { plugin: String, dependency: String },
+    /// The plugin will never read a value.
+    #[error("plugin `{plugin}` reads `{key}` from the blackboard, which no plugin writes")]
+    UnwrittenKey { plugin: String, key: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// Graphviz DOT, with one cluster per stage.
+    Dot,
+    /// A Mermaid flowchart, with one subgraph per stage.
+    Mermaid,
+}
+
+/// Finds the shortest cycle through the first node of a strongly connected
+/// `component`, starting from that node.
+fn shortest_cycle(graph: &DiGraph<&str, ()>, component: &[NodeIndex]) -> Vec<NodeIndex> {
+    let start = component[0];
+    let mut parent = AHashMap::new();
+    let mut queue = VecDeque::from([start]);
+
+    while let Some(node) = queue.pop_front() {
+        for next in graph.neighbors(node) {
+            if next == start {
+                let mut cycle = vec![node];
+                while let Some(&previous) = parent.get(cycle.last().unwrap()) {
+                    cycle.push(previous);
+                }
+                cycle.reverse();
+                return cycle;
+            }
+
+            if component.contains(&next) && !parent.contains_key(&next) {
+                parent.insert(next, node);
+                queue.push_back(next);
+            }
+        }
+    }
+
+    unreachable!("a strongly connected component always contains a cycle")
+}
+
+impl<L> Dispatcher<L> {
+    /// Renders the dependency graph, grouping plugins by the stage they run
+    /// in.
+    pub fn graph(&self, format: GraphFormat) -> String {
+        let id_of_plugin: AHashMap<_, _> =
+            self.plugins().enumerate().map(|(id, plugin)| (plugin.name(), id)).collect();
+        let edges = self.plugins().flat_map(|plugin| {
+            plugin.dependencies().iter().filter_map(|dependency| {
+                Some((*id_of_plugin.get(dependency)?, id_of_plugin[plugin.name()]))
+            })
+        });
+
+        let mut out = String::new();
+        let mut id = 0;
+
+        match format {
+            GraphFormat::Dot => {
+                out.push_str("digraph plugins {\n");
+                for (index, stage) in self.stages().iter().enumerate() {
+                    writeln!(out, "    subgraph cluster_{index} {{").unwrap();
+                    writeln!(out, "        label = \"stage {index}\";").unwrap();
+                    for plugin in stage.iter().map(|&slot| self.at(slot)) {
+                        writeln!(out, "        n{id} [label = {:?}];", plugin.name()).unwrap();
+                        id += 1;
+                    }
+                    out.push_str("    }\n");
+                }
+                for (from, to) in edges {
+                    writeln!(out, "    n{from} -> n{to};").unwrap();
+                }
+                out.push_str("}\n");
+            }
+            GraphFormat::Mermaid => {
+                out.push_str("flowchart LR\n");
+                for (index, stage) in self.stages().iter().enumerate() {
+                    writeln!(out, "    subgraph stage{index} [\"stage {index}\"]").unwrap();
+                    for plugin in stage.iter().map(|&slot| self.at(slot)) {
+                        writeln!(
+                            out,
+                            "        n{id}[\"{}\"]",
+                            plugin.name().replace('"', "#quot;")
+                        )
+                        .unwrap();
+                        id += 1;
+                    }
+                    out.push_str("    end\n");
+                }
+                for (from, to) in edges {
+                    writeln!(out, "    n{from} --> n{to}").unwrap();
+                }
+            }
+        }
+
+        out
+    }
+}
@@ -0,0 +1,321 @@
+//! Calling back into the host from a plugin library.
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+use std::ffi::c_void;
+use std::fmt::Write as _;
+
+/// What the host offers to plugins through a [`HostApi`].
+///
+/// Every method has a default, so a host only implements what it supports.
+pub trait Host: Send + Sync {
+    /// Writes `message` to the host's log. By default, to standard error.
+    fn log(&self, level: LogLevel, message: &str) {
+        eprintln!("[{level:?}] {message}");
+    }
+
+    /// Looks up a configuration value. The meaning of `key` is up to the
+    /// host.
+    fn config(&self, key: &str) -> Option<&str> {
+        let _ = key;
+        None
+    }
+
+    /// Publishes an event on `topic`.
+    fn publish(&self, topic: &str, payload: &[u8]) {
+        let _ = (topic, payload);
+    }
+
+    /// The capabilities the host declares, such as `"gpu"`. By default,
+    /// none.
+    fn features(&self) -> &Features {
+        const { &Features::new() }
+    }
+}
+
+/// The names of the capabilities a [`Host`] declares, so that plugins can
+/// adapt to them, for example by skipping GPU work on a host without one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Features {
+    names: BTreeSet<String>,
+}
+
+impl Features {
+    pub const fn new() -> Self {
+        Self { names: BTreeSet::new() }
+    }
+
+    /// Declares `name`, returning whether it was not declared yet.
+    pub fn insert(&mut self, name: impl Into<String>) -> bool {
+        self.names.insert(name.into())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.contains(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.names.iter().map(String::as_str)
+    }
+}
+
+impl<S: Into<String>> FromIterator<S> for Features {
+    fn from_iter<I: IntoIterator<Item = S>>(iter: I) -> Self {
+        Self { names: iter.into_iter().map(Into::into).collect() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u32)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// The functions a plugin library can call on the host, passed to the
+/// create function of [`Native`](crate::Native) and [`CAbi`](crate::CAbi)
+/// plugins.
+///
+/// ```c
+/// struct sora_host_api {
+///     const void *data;
+///     void (*log)(const void *data, uint32_t level,
+///                 const char *message, size_t len);
+///     const char *(*config)(const void *data, const char *key,
+///                           size_t key_len, size_t *value_len);
+///     void (*publish)(const void *data, const char *topic,
+///                     size_t topic_len, const uint8_t *payload,
+///                     size_t payload_len);
+///     bool (*has_feature)(const void *data, const char *name,
+///                         size_t len);
+/// };
+/// ```
+///
+/// Every function receives `data`. Strings are UTF-8 and passed with their
+/// length rather than NUL-terminated. `config` returns null if the key is
+/// not set. The table and the strings it returns stay valid for the rest of
+/// the process, so plugins may keep them.
+#[repr(C)]
+pub struct HostApi {
+    pub data: *const c_void,
+    pub log: unsafe extern "C" fn(*const c_void, LogLevel, *const u8, usize),
+    pub config: unsafe extern "C" fn(*const c_void, *const u8, usize, *mut usize) -> *const u8,
+    pub publish: unsafe extern "C" fn(*const c_void, *const u8, usize, *const u8, usize),
+    pub has_feature: unsafe extern "C" fn(*const c_void, *const u8, usize) -> bool,
+}
+
+// SAFETY: `data` points to a `Host`, which is `Send + Sync`.
+unsafe impl Send for HostApi {}
+unsafe impl Sync for HostApi {}
+
+/// The host of managers that were not given one, which only logs.
+static DEFAULT: DefaultHost = DefaultHost;
+
+struct DefaultHost;
+
+impl Host for DefaultHost {}
+
+impl HostApi {
+    /// Creates the table for `host`, which is never freed, as plugins may
+    /// keep the table for as long as they live.
+    pub fn leak(host: impl Host + 'static) -> &'static Self {
+        let host: &'static dyn Host = Box::leak(Box::new(host));
+        Box::leak(Box::new(Self::new(host)))
+    }
+
+    fn new(host: &'static dyn Host) -> Self {
+        Self {
+            data: Box::leak(Box::new(host)) as *const &dyn Host as *const c_void,
+            log: log_trampoline,
+            config: config_trampoline,
+            publish: publish_trampoline,
+            has_feature: has_feature_trampoline,
+        }
+    }
+
+    /// The table of a host that only logs, to standard error.
+    pub fn default_host() -> &'static Self {
+        static API: std::sync::OnceLock<HostApi> = std::sync::OnceLock::new();
+        API.get_or_init(|| Self::new(&DEFAULT))
+    }
+
+    pub fn log(&self, level: LogLevel, message: &str) {
+        unsafe { (self.log)(self.data, level, message.as_ptr(), message.len()) };
+    }
+
+    pub fn config(&self, key: &str) -> Option<&str> {
+        let mut len = 0;
+        let value = unsafe { (self.config)(self.data, key.as_ptr(), key.len(), &mut len) };
+        match value.is_null() {
+            true => None,
+            false => std::str::from_utf8(unsafe { std::slice::from_raw_parts(value, len) }).ok(),
+        }
+    }
+
+    pub fn publish(&self, topic: &str, payload: &[u8]) {
+        unsafe {
+            (self.publish)(self.data, topic.as_ptr(), topic.len(), payload.as_ptr(), payload.len())
+        };
+    }
+
+    /// Whether the host declares the feature `name`. See [`Host::features`].
+    pub fn has_feature(&self, name: &str) -> bool {
+        unsafe { (self.has_feature)(self.data, name.as_ptr(), name.len()) }
+    }
+}
+
+unsafe fn host<'a>(data: *const c_void) -> &'a dyn Host {
+    unsafe { *(data as *const &dyn Host) }
+}
+
+/// Reads a string passed by a plugin, which may not be valid UTF-8.
+unsafe fn string<'a>(ptr: *const u8, len: usize) -> Option<&'a str> {
+    std::str::from_utf8(unsafe { std::slice::from_raw_parts(ptr, len) }).ok()
+}
+
+unsafe extern "C" fn log_trampoline(
+    data: *const c_void,
+    level: LogLevel,
+    message: *const u8,
+    len: usize,
+) {
+    if let Some(message) = unsafe { string(message, len) } {
+        if !capture_log(level, message) {
+            unsafe { host(data) }.log(level, message);
+        }
+    }
+}
+
+unsafe extern "C" fn config_trampoline(
+    data: *const c_void,
+    key: *const u8,
+    key_len: usize,
+    value_len: *mut usize,
+) -> *const u8 {
+    let value = unsafe { string(key, key_len) }.and_then(|key| unsafe { host(data) }.config(key));
+    match value {
+        Some(value) => {
+            unsafe { *value_len = value.len() };
+            value.as_ptr()
+        }
+        None => std::ptr::null(),
+    }
+}
+
+unsafe extern "C" fn publish_trampoline(
+    data: *const c_void,
+    topic: *const u8,
+    topic_len: usize,
+    payload: *const u8,
+    payload_len: usize,
+) {
+    if let Some(topic) = unsafe { string(topic, topic_len) } {
+        let payload = unsafe { std::slice::from_raw_parts(payload, payload_len) };
+        unsafe { host(data) }.publish(topic, payload);
+    }
+}
+
+unsafe extern "C" fn has_feature_trampoline(
+    data: *const c_void,
+    name: *const u8,
+    len: usize,
+) -> bool {
+    unsafe { string(name, len) }.is_some_and(|name| unsafe { host(data) }.features().contains(name))
+}
+
+thread_local! {
+    static CURRENT: Cell<Option<&'static HostApi>> = const { Cell::new(None) };
+    /// What is logged on this thread while [`capture`] runs.
+    static OUTPUT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Runs `f`, and returns what it logged through any [`HostApi`], one
+/// `[level] message` line each, rather than passing it to the host.
+pub(crate) fn capture<T>(f: impl FnOnce() -> T) -> (T, String) {
+    let previous = OUTPUT.replace(Some(String::new()));
+    let result = f();
+    (result, OUTPUT.replace(previous).unwrap_or_default())
+}
+
+/// Adds `message` to the output being captured on this thread, if any,
+/// returning whether it did.
+fn capture_log(level: LogLevel, message: &str) -> bool {
+    OUTPUT.with_borrow_mut(|output| {
+        let Some(output) = output else { return false };
+        let _ = writeln!(output, "[{level:?}] {message}");
+        true
+    })
+}
+
+/// Makes `host` the one [`current`] returns while `f` runs on this thread.
+pub(crate) fn with<T>(host: &'static HostApi, f: impl FnOnce() -> T) -> T {
+    let previous = CURRENT.replace(Some(host));
+    let result = f();
+    CURRENT.set(previous);
+    result
+}
+
+/// The host of the manager loading plugins on this thread, if any, and
+/// otherwise [`HostApi::default_host`].
+pub(crate) fn current() -> &'static HostApi {
+    CURRENT.get().unwrap_or_else(HostApi::default_host)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::{Features, Host, HostApi, LogLevel};
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+        features: Features,
+    }
+
+    impl Host for Recorder {
+        fn log(&self, level: LogLevel, message: &str) {
+            self.events.lock().unwrap().push(format!("{level:?}: {message}"));
+        }
+
+        fn config(&self, key: &str) -> Option<&str> {
+            (key == "greeting").then_some("Hello")
+        }
+
+        fn publish(&self, topic: &str, payload: &[u8]) {
+            self.events.lock().unwrap().push(format!("{topic}: {payload:?}"));
+        }
+
+        fn features(&self) -> &Features {
+            &self.features
+        }
+    }
+
+    #[test]
+    fn host_api() {
+        let recorder: &'static Recorder = Box::leak(Box::new(Recorder {
+            features: ["gpu"].into_iter().collect(),
+            ..<_>::default()
+        }));
+        let api = HostApi::new(recorder);
+
+        api.log(LogLevel::Info, "loaded");
+        api.publish("tick", &[1, 2]);
+        assert_eq!(api.config("greeting"), Some("Hello"));
+        assert_eq!(api.config("missing"), None);
+        assert!(api.has_feature("gpu"));
+        assert!(!api.has_feature("audio"));
+        assert!(!HostApi::default_host().has_feature("gpu"));
+        assert_eq!(*recorder.events.lock().unwrap(), ["Info: loaded", "tick: [1, 2]"]);
+
+        let default = HostApi::default_host();
+        assert!(std::ptr::eq(super::current(), default));
+        super::with(HostApi::leak(Recorder::default()), || {
+            assert!(!std::ptr::eq(super::current(), default));
+        });
+        assert!(std::ptr::eq(super::current(), default));
+    }
+}
@@ -0,0 +1,411 @@
+//! Dispatching plugins in a worker process, so that a plugin that crashes
+//! does not take the host down with it.
+
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, Instant};
+
+use crate::report::Failures;
+use crate::{
+    DispatchReport, Dispatcher, PanicReport, PluginReport, PluginResult, PluginStatus, panic_hook,
+};
+
+impl<L> Dispatcher<L> {
+    /// Like [`dispatch_report`](Self::dispatch_report), but runs the
+    /// plugins one at a time in a worker process forked for the dispatch.
+    /// A plugin that crashes it, for example with a segmentation fault, is
+    /// reported as [`PluginStatus::Crashed`], and a new worker is forked
+    /// for the plugins after it.
+    ///
+    /// Only the states of the plugins make it back from the worker: after
+    /// a plugin runs, what its [`save_state`](crate::Plugin::save_state)
+    /// returns in the worker is handed to
+    /// [`load_state`](crate::Plugin::load_state) of the same plugin in the
+    /// host. Outputs and any other side effects stay in the worker, so
+    /// plugins that pass data to each other must keep it in their state.
+    ///
+    /// As in any process forked from a multithreaded one, only the thread
+    /// that dispatches exists in the worker, so plugins must not wait for
+    /// other threads of the host.
+    pub fn dispatch_isolated(&self) -> DispatchReport {
+        let start = Instant::now();
+        self.state.begin();
+        let mut failures = Failures::new(self.error_policy);
+        let mut stages: Vec<Vec<PluginReport>> = Vec::new();
+        // The stage and the index in it of the plugin the next worker starts
+        // with.
+        let (mut stage, mut index) = (0, 0);
+
+        while stage < self.stages().len() {
+            let done = stages.get(stage).map_or(&[][..], Vec::as_slice);
+            let (mut worker, pid) = fork(|output| self.work(stage, done, &failures, output));
+            let mut started = Instant::now();
+            let mut crashed = false;
+            loop {
+                let slot = self.stages()[stage][index];
+                let Some(report) = decode(&mut worker) else {
+                    crashed = true;
+                    break;
+                };
+                let report = self.load(slot, report);
+                // The worker went on as if the plugin had succeeded.
+                let diverged = matches!(report.status, PluginStatus::Crashed(_));
+                stages.resize_with(stage + 1, Vec::new);
+                stages[stage].push(report);
+                if !self.advance(&mut stage, &mut index, &mut failures, &stages) {
+                    break;
+                }
+                if diverged {
+                    unsafe { libc::kill(pid, libc::SIGKILL) };
+                    break;
+                }
+                started = Instant::now();
+            }
+
+            drop(worker);
+            let exit = wait(pid);
+            if crashed {
+                let plugin = &**self.at(self.stages()[stage][index]);
+                stages.resize_with(stage + 1, Vec::new);
+                stages[stage].push(PluginReport {
+                    name: plugin.name().to_owned(),
+                    duration: started.elapsed(),
+                    attempts: 1,
+                    status: PluginStatus::Crashed(exit),
+                    panic: None,
+                    output: String::new(),
+                    result: None,
+                });
+                self.advance(&mut stage, &mut index, &mut failures, &stages);
+            }
+        }
+
+        DispatchReport { stages, duration: start.elapsed(), policy: failures.policy() }
+    }
+
+    /// Moves on past the plugin at `index` in `stage`, taking note of the
+    /// failures of the stage if it was its last. Returns whether any
+    /// plugin is left.
+    fn advance(
+        &self,
+        stage: &mut usize,
+        index: &mut usize,
+        failures: &mut Failures,
+        reports: &[Vec<PluginReport>],
+    ) -> bool {
+        *index += 1;
+        let plugins = &self.stages()[*stage];
+        if *index == plugins.len() {
+            failures.record(&self.schedule, plugins, &reports[*stage]);
+            *stage += 1;
+            *index = 0;
+        }
+        *stage < self.stages().len()
+    }
+
+    /// Hands the state the plugin in `slot` saved in the worker to the one
+    /// in the host.
+    fn load(
+        &self,
+        slot: usize,
+        (mut report, state): (PluginReport, Option<Vec<u8>>),
+    ) -> PluginReport {
+        let Some(state) = state else { return report };
+        if let Err(error) = self.at(slot).load_state(&state) {
+            report.status = PluginStatus::Crashed(format!("cannot load its state: {error}"));
+        }
+        report
+    }
+
+    /// Runs the plugins of the worker, from the one in `stage` after those
+    /// `done` reports on, writing a report for each to `output`.
+    fn work(
+        &self,
+        stage: usize,
+        done: &[PluginReport],
+        failures: &Failures,
+        output: &mut impl Write,
+    ) -> std::io::Result<()> {
+        let _hook = panic_hook::Scope::enter();
+        let mut failures = failures.clone();
+        let mut reports = done.to_vec();
+        // Output that cannot be captured goes where that of the host does.
+        let mut capture = self.state.capture_output.then(Capture::new).and_then(Result::ok);
+        for plugins in &self.stages()[stage..] {
+            for &slot in &plugins[reports.len()..] {
+                let plugin = &**self.at(slot);
+                let run = || failures.run(slot, plugin, &self.state);
+                let report = match &mut capture {
+                    Some(capture) => {
+                        let (mut report, written) = capture.run(run);
+                        report.output.push_str(&written);
+                        report
+                    }
+                    None => run(),
+                };
+                let state = if report.attempts > 0 { plugin.save_state() } else { None };
+                output.write_all(&encode(&report, state.as_deref()))?;
+                reports.push(report);
+            }
+            failures.record(&self.schedule, plugins, &reports);
+            reports.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Redirects the standard output and error of the worker to a temporary
+/// file while a plugin runs.
+struct Capture {
+    file: File,
+}
+
+impl Capture {
+    fn new() -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("sora-output-{}", std::process::id()));
+        let file = File::options().read(true).write(true).create_new(true).open(&path)?;
+        // The file lives on until it is closed.
+        std::fs::remove_file(&path)?;
+        Ok(Self { file })
+    }
+
+    /// Runs `f`, and returns what was written to the standard output and
+    /// error meanwhile.
+    fn run<T>(&mut self, f: impl FnOnce() -> T) -> (T, String) {
+        let _ = std::io::stdout().flush();
+        let saved = [libc::STDOUT_FILENO, libc::STDERR_FILENO].map(|fd| unsafe { libc::dup(fd) });
+        for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            unsafe { libc::dup2(self.file.as_raw_fd(), fd) };
+        }
+
+        let result = f();
+
+        let _ = std::io::stdout().flush();
+        for (fd, saved) in [libc::STDOUT_FILENO, libc::STDERR_FILENO].into_iter().zip(saved) {
+            unsafe { libc::dup2(saved, fd) };
+            unsafe { libc::close(saved) };
+        }
+
+        let mut written = Vec::new();
+        let _ = self.file.rewind().and_then(|()| self.file.read_to_end(&mut written));
+        let _ = self.file.set_len(0).and_then(|()| self.file.rewind());
+        (result, String::from_utf8_lossy(&written).into_owned())
+    }
+}
+
+/// Forks a worker that runs `work`, and returns what it writes and its
+/// process ID.
+fn fork(work: impl FnOnce(&mut File) -> std::io::Result<()>) -> (BufReader<File>, libc::pid_t) {
+    // Whatever is buffered would be written by the worker as well.
+    let _ = std::io::stdout().flush();
+
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0, "cannot create a pipe");
+    let [input, output] = fds.map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0, "cannot fork a worker: {}", std::io::Error::last_os_error());
+    if pid == 0 {
+        drop(input);
+        let mut output = File::from(output);
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| work(&mut output)));
+        let _ = std::io::stdout().flush();
+        let code = if matches!(result, Ok(Ok(()))) { 0 } else { 1 };
+        unsafe { libc::_exit(code) };
+    }
+
+    drop(output);
+    (BufReader::new(File::from(input)), pid)
+}
+
+/// Waits for the worker `pid` to exit, and describes how it did.
+fn wait(pid: libc::pid_t) -> String {
+    let mut status = 0;
+    if unsafe { libc::waitpid(pid, &mut status, 0) } != pid {
+        return "the worker was lost".to_owned();
+    }
+
+    if libc::WIFSIGNALED(status) {
+        format!("the worker was killed by signal {}", libc::WTERMSIG(status))
+    } else {
+        format!("the worker exited with status {}", libc::WEXITSTATUS(status))
+    }
+}
+
+/// Encodes `report` and the saved `state` of its plugin. Strings and byte
+/// strings are prefixed with their length as a little-endian `u64`,
+/// optional values with a byte that is `1` if they are present, and the
+/// result with a byte that is `0` for none, `1` for JSON and `2` for
+/// bytes.
+fn encode(report: &PluginReport, state: Option<&[u8]>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let push = |bytes: &mut Vec<u8>, value: &[u8]| {
+        bytes.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(value);
+    };
+
+    push(&mut bytes, report.name.as_bytes());
+    bytes.extend_from_slice(&(report.duration.as_nanos() as u64).to_le_bytes());
+    bytes.extend_from_slice(&report.attempts.to_le_bytes());
+    let (kind, message) = match &report.status {
+        PluginStatus::Succeeded => (0, ""),
+        PluginStatus::Panicked(message) => (1, message.as_str()),
+        PluginStatus::Skipped(cause) => (2, cause.as_str()),
+        PluginStatus::Crashed(message) => (3, message.as_str()),
+        PluginStatus::Deferred => (4, ""),
+    };
+    bytes.push(kind);
+    push(&mut bytes, message.as_bytes());
+
+    bytes.push(report.panic.is_some().into());
+    if let Some(panic) = &report.panic {
+        push(&mut bytes, panic.thread.as_bytes());
+        bytes.push(panic.location.is_some().into());
+        if let Some(location) = &panic.location {
+            push(&mut bytes, location.as_bytes());
+        }
+        push(&mut bytes, panic.backtrace.as_bytes());
+    }
+
+    bytes.push(state.is_some().into());
+    if let Some(state) = state {
+        push(&mut bytes, state);
+    }
+    push(&mut bytes, report.output.as_bytes());
+    match &report.result {
+        Some(PluginResult::Json(json)) => {
+            bytes.push(1);
+            push(&mut bytes, json.as_bytes());
+        }
+        Some(PluginResult::Bytes(result)) => {
+            bytes.push(2);
+            push(&mut bytes, result);
+        }
+        None => bytes.push(0),
+    }
+    bytes
+}
+
+/// Decodes what [`encode`] encoded, or returns `None` if the worker exited
+/// before writing all of it.
+fn decode(input: &mut impl Read) -> Option<(PluginReport, Option<Vec<u8>>)> {
+    fn array<const N: usize>(input: &mut impl Read) -> Option<[u8; N]> {
+        let mut bytes = [0; N];
+        input.read_exact(&mut bytes).ok()?;
+        Some(bytes)
+    }
+    fn bytes(input: &mut impl Read) -> Option<Vec<u8>> {
+        let len = usize::try_from(u64::from_le_bytes(array(input)?)).ok()?;
+        let mut bytes = Vec::new();
+        input.take(len as u64).read_to_end(&mut bytes).ok()?;
+        (bytes.len() == len).then_some(bytes)
+    }
+    fn string(input: &mut impl Read) -> Option<String> {
+        String::from_utf8(bytes(input)?).ok()
+    }
+    fn flag(input: &mut impl Read) -> Option<bool> {
+        Some(array::<1>(input)? == [1])
+    }
+
+    let name = string(input)?;
+    let duration = Duration::from_nanos(u64::from_le_bytes(array(input)?));
+    let attempts = u32::from_le_bytes(array(input)?);
+    let [kind] = array(input)?;
+    let message = string(input)?;
+    let status = match kind {
+        0 => PluginStatus::Succeeded,
+        1 => PluginStatus::Panicked(message),
+        2 => PluginStatus::Skipped(message),
+        3 => PluginStatus::Crashed(message),
+        _ => PluginStatus::Deferred,
+    };
+
+    let panic = match flag(input)? {
+        true => Some(PanicReport {
+            thread: string(input)?,
+            location: if flag(input)? { Some(string(input)?) } else { None },
+            backtrace: string(input)?,
+        }),
+        false => None,
+    };
+    let state = if flag(input)? { Some(bytes(input)?) } else { None };
+
+    let output = string(input)?;
+    let result = match array(input)? {
+        [1] => Some(PluginResult::Json(string(input)?)),
+        [2] => Some(PluginResult::Bytes(bytes(input)?)),
+        _ => None,
+    };
+
+    Some((PluginReport { name, duration, attempts, status, panic, output, result }, state))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixture::Stub;
+    use crate::{
+        ErrorPolicy, HostApi, LogLevel, PluginManager, PluginResult, PluginStatus, RunContext,
+    };
+
+    fn run(name: &str, context: &RunContext) {
+        match name {
+            "Crash" => {
+                // The handler of the standard library ignores raised
+                // signals.
+                unsafe { libc::signal(libc::SIGSEGV, libc::SIG_DFL) };
+                unsafe { libc::raise(libc::SIGSEGV) };
+            }
+            "Count" => {
+                HostApi::default_host().log(LogLevel::Info, "counting");
+                unsafe { libc::write(libc::STDOUT_FILENO, b"counted\n".as_ptr().cast(), 8) };
+                context.set_result(PluginResult::Bytes(vec![1, 2]));
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn dispatch_isolated() {
+        let mut manager = PluginManager::new();
+        for (name, dependencies) in [
+            ("Count", &[][..]),
+            ("Crash", &["Count"][..]),
+            ("After", &["Crash"][..]),
+            ("Other", &["Count"][..]),
+        ] {
+            manager
+                .register(Box::new(
+                    Stub::new(name, dependencies).stateful().run(move |context| run(name, context)),
+                ))
+                .unwrap();
+        }
+        let dispatcher = manager
+            .into_dispatcher_builder()
+            .error_policy(ErrorPolicy::SkipDependents)
+            .capture_output(true)
+            .build();
+
+        for count in 1..=2 {
+            let report = dispatcher.dispatch_isolated();
+            let status = |name| {
+                let plugin = report.plugins().find(|plugin| plugin.name == name).unwrap();
+                plugin.status.clone()
+            };
+            assert_eq!(status("Count"), PluginStatus::Succeeded);
+            let signal = format!("the worker was killed by signal {}", libc::SIGSEGV);
+            assert_eq!(status("Crash"), PluginStatus::Crashed(signal));
+            assert_eq!(status("After"), PluginStatus::Skipped("Crash".to_owned()));
+            assert_eq!(status("Other"), PluginStatus::Succeeded);
+            let counted = report.plugins().find(|plugin| plugin.name == "Count").unwrap();
+            assert_eq!(counted.output, "[Info] counting\ncounted\n");
+            assert_eq!(counted.result, Some(PluginResult::Bytes(vec![1, 2])));
+
+            // The host only sees what the plugins ran in the worker through
+            // their states.
+            let state = dispatcher.plugin("Count").unwrap().save_state().unwrap();
+            assert_eq!(state, u32::to_le_bytes(count));
+        }
+    }
+}
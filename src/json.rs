@@ -0,0 +1,246 @@
+//! A parser for the JSON of registry indexes. Numbers are kept as `f64`,
+//! and strings must not hold escaped surrogate pairs.
+
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(BTreeMap<String, Value>),
+}
+
+impl Value {
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Value::Null => "null",
+            Value::Boolean(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ParseError {
+    /// The byte offset at which parsing failed.
+    pub(crate) offset: usize,
+    pub(crate) message: &'static str,
+}
+
+/// Nested arrays and objects deeper than this are rejected rather than
+/// overflowing the stack.
+const MAX_DEPTH: usize = 128;
+
+pub(crate) fn parse(source: &str) -> Result<Value, ParseError> {
+    let mut parser = Parser { source: source.as_bytes(), offset: 0 };
+    let value = parser.value(0)?;
+    parser.skip_whitespace();
+    match parser.offset == source.len() {
+        true => Ok(value),
+        false => Err(parser.error("unexpected characters after value")),
+    }
+}
+
+struct Parser<'a> {
+    source: &'a [u8],
+    offset: usize,
+}
+
+impl Parser<'_> {
+    fn value(&mut self, depth: usize) -> Result<Value, ParseError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("too deeply nested"));
+        }
+
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.object(depth),
+            Some(b'[') => self.array(depth),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(b't') => self.literal("true", Value::Boolean(true)),
+            Some(b'f') => self.literal("false", Value::Boolean(false)),
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(_) => Err(self.error("expected a value")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Value, ParseError> {
+        self.offset += 1;
+        let mut object = BTreeMap::new();
+        self.skip_whitespace();
+        if self.eat(b'}') {
+            return Ok(Value::Object(object));
+        }
+
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a key"));
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            if !self.eat(b':') {
+                return Err(self.error("expected `:`"));
+            }
+            let value = self.value(depth + 1)?;
+            if object.insert(key, value).is_some() {
+                return Err(self.error("duplicate key"));
+            }
+
+            self.skip_whitespace();
+            if self.eat(b'}') {
+                return Ok(Value::Object(object));
+            }
+            if !self.eat(b',') {
+                return Err(self.error("expected `,` or `}`"));
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Value, ParseError> {
+        self.offset += 1;
+        let mut array = Vec::new();
+        self.skip_whitespace();
+        if self.eat(b']') {
+            return Ok(Value::Array(array));
+        }
+
+        loop {
+            array.push(self.value(depth + 1)?);
+            self.skip_whitespace();
+            if self.eat(b']') {
+                return Ok(Value::Array(array));
+            }
+            if !self.eat(b',') {
+                return Err(self.error("expected `,` or `]`"));
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.offset += 1;
+        let mut string = Vec::new();
+        loop {
+            let byte = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+            self.offset += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = match self.peek() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            let digits = self.source.get(self.offset + 1..self.offset + 5);
+                            let code = digits
+                                .and_then(|digits| std::str::from_utf8(digits).ok())
+                                .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error("invalid unicode escape"))?;
+                            self.offset += 4;
+                            code
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    self.offset += 1;
+                    string.extend(escaped.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                0..=0x1f => return Err(self.error("control character in string")),
+                byte => string.push(byte),
+            }
+        }
+
+        // The source is a `str`, and escapes are encoded as UTF-8.
+        Ok(String::from_utf8(string).expect("strings are valid UTF-8"))
+    }
+
+    fn number(&mut self) -> Result<Value, ParseError> {
+        let start = self.offset;
+        while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.peek() {
+            self.offset += 1;
+        }
+
+        std::str::from_utf8(&self.source[start..self.offset])
+            .ok()
+            .and_then(|number| number.parse().ok())
+            .map(Value::Number)
+            .ok_or(ParseError { offset: start, message: "invalid number" })
+    }
+
+    fn literal(&mut self, literal: &str, value: Value) -> Result<Value, ParseError> {
+        match self.source[self.offset..].starts_with(literal.as_bytes()) {
+            true => {
+                self.offset += literal.len();
+                Ok(value)
+            }
+            false => Err(self.error("expected a value")),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.offset += 1;
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        let matches = self.peek() == Some(byte);
+        self.offset += usize::from(matches);
+        matches
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.source.get(self.offset).copied()
+    }
+
+    fn error(&self, message: &'static str) -> ParseError {
+        ParseError { offset: self.offset, message }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ParseError, Value, parse};
+
+    #[test]
+    fn values() {
+        let value = parse(r#" {"a": [1, -2.5e1, true, null], "b": "x\"é\n"} "#).unwrap();
+        let Value::Object(object) = value else { panic!("{value:?}") };
+        assert_eq!(
+            object["a"],
+            Value::Array(vec![
+                Value::Number(1.0),
+                Value::Number(-25.0),
+                Value::Boolean(true),
+                Value::Null
+            ])
+        );
+        assert_eq!(object["b"], Value::String("x\"é\n".to_owned()));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source| parse(source).unwrap_err();
+        assert_eq!(error("[1,]"), ParseError { offset: 3, message: "expected a value" });
+        assert_eq!(
+            error("{\"a\": 1} x"),
+            ParseError { offset: 9, message: "unexpected characters after value" }
+        );
+        assert_eq!(error("{\"a\": 1, \"a\": 2}").message, "duplicate key");
+        assert_eq!(error("\"abc").message, "unterminated string");
+        assert_eq!(error(&"[".repeat(200)).message, "too deeply nested");
+    }
+}
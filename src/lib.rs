@@ -1,238 +1,4137 @@
 #![cfg_attr(test, feature(internal_output_capture))]
 
 use std::any::Any;
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::marker::PhantomData;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::{Duration, Instant};
 
-use ahash::AHashMap;
-use libloading::{Library, Symbol};
-use rayon::{ThreadPool, ThreadPoolBuilder};
+use ahash::{AHashMap, AHashSet};
+use libloading::Library;
 
-pub type Result<T> = std::result::Result<T, PluginLoadError>;
+use crate::context::DispatchState;
+use crate::deadline::History;
+use crate::executor::{Executor, Threads};
+use crate::memory::MemoryFile;
+use crate::report::Failures;
+use crate::schedule::{Access, Schedule, check_stages, order_access, verify_stages};
+use crate::sha2::Sha256;
+use crate::watchdog::Watchdog;
+
+mod benchmark;
+mod builder;
+mod bundle;
+mod cabi;
+mod cache;
+mod cell;
+mod closure;
+mod config;
+mod context;
+mod deadline;
+mod ed25519;
+mod elf;
+mod environment;
+mod executor;
+#[cfg(test)]
+mod fixture;
+mod graph;
+mod host;
+#[cfg(unix)]
+mod isolate;
+mod json;
+mod key;
+mod local;
+mod lock;
+mod manifest;
+mod memory;
+mod metadata;
+mod native;
+mod observer;
+mod panic_hook;
+mod plan;
+#[cfg(feature = "profile")]
+mod profile;
+mod registry;
+mod report;
+mod resources;
+mod retry;
+mod schedule;
+mod sha2;
+mod state;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod toml;
+mod watchdog;
+
+pub use benchmark::{Benchmark, PluginBenchmark, StageBenchmark, Timing};
+pub use builder::{BuildError, PluginManagerBuilder};
+pub use bundle::{BundleError, bundle_target};
+pub use cabi::{CAbi, PluginVTable, RawPlugin};
+pub use cell::PluginCell;
+pub use closure::FnPlugin;
+pub use config::{Config, ConfigError};
+pub use context::RunContext;
+pub use environment::Environment;
+pub use executor::Affinity;
+pub use graph::{GraphError, GraphFormat, Warning};
+pub use host::{Features, Host, HostApi, LogLevel};
+pub use key::{KeyError, SigningKey, public_key_from_pem, verify_signature};
+pub use local::{LocalDispatcher, LocalPlugin};
+pub use lock::{Drift, LockError, LockedPlugin, Lockfile};
+pub use manifest::{Manifest, ManifestError};
+pub use metadata::PluginMetadata;
+pub use native::{LoadPhase, NativeLoaderOptions};
+pub use observer::{DispatchEvent, DispatchObserver};
+pub use plan::{Explanation, Plan, PlanError, PlannedPlugin};
+pub use registry::{Registry, RegistryError, Release, Update};
+pub use report::{
+    DispatchReport, ErrorPolicy, PanicReport, PluginReport, PluginResult, PluginStatus,
+};
+pub use resources::{ResourceError, Resources};
+pub use retry::RetryPolicy;
+pub use schedule::{Scheduler, TopologicalScheduler};
+pub use state::{BoxError, Snapshot, StateError};
+pub use toml::{Table, Value};
+
+pub type Result<T, E = PluginLoadError> = std::result::Result<T, E>;
 
 pub trait Plugin: Any + Send + Sync {
-    fn name(&self) -> &'static str {
+    fn name(&self) -> &str {
         std::any::type_name::<Self>().split("::").last().unwrap()
     }
 
-    fn dependencies(&self) -> &'static [&'static str] {
+    fn dependencies(&self) -> &[&str] {
+        &[]
+    }
+
+    /// The plugin's version, conventionally `MAJOR.MINOR.PATCH`.
+    fn version(&self) -> Option<&str> {
+        None
+    }
+
+    /// A one-line summary of what the plugin does.
+    fn description(&self) -> Option<&str> {
+        None
+    }
+
+    fn authors(&self) -> &[&str] {
+        &[]
+    }
+
+    /// The phases in which the plugin runs. See
+    /// [`Dispatcher::dispatch_phase`].
+    fn phases(&self) -> &[Phase] {
+        &[Phase::Update]
+    }
+
+    /// Labels that select the pipelines the plugin belongs to. See
+    /// [`PluginManager::into_pipelines`].
+    fn tags(&self) -> &[&str] {
         &[]
     }
 
-    fn run(&self);
+    /// The blackboard keys the plugin reads. See [`RunContext::read`].
+    ///
+    /// Access also orders plugins: two plugins that use the same key, one of
+    /// them writing it, never run in the same stage. Unless
+    /// [`dependencies`](Self::dependencies) say otherwise, the order between
+    /// them is arbitrary but fixed, so explicit dependencies are only needed
+    /// to pin it.
+    fn reads(&self) -> &[&str] {
+        &[]
+    }
+
+    /// The blackboard keys the plugin writes. See [`reads`](Self::reads).
+    fn writes(&self) -> &[&str] {
+        &[]
+    }
+
+    /// Called once when the plugin is registered, to take the
+    /// [`Resources`] it needs from the host. Failing rejects the plugin.
+    fn init(&mut self, resources: &Resources) -> std::result::Result<(), ResourceError> {
+        let _ = resources;
+        Ok(())
+    }
+
+    /// Whether and how often the plugin is run again when it panics.
+    fn retry(&self) -> RetryPolicy {
+        RetryPolicy::NEVER
+    }
+
+    /// The plugin's state, to be passed to [`load_state`](Self::load_state)
+    /// of the next instance, for example after the host restarts. The
+    /// encoding is up to the plugin. See [`PluginManager::save_state`].
+    fn save_state(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Restores a state returned by [`save_state`](Self::save_state),
+    /// before the plugin first runs.
+    fn load_state(&self, state: &[u8]) -> std::result::Result<(), BoxError> {
+        let _ = state;
+        Ok(())
+    }
+
+    /// Runs the plugin once. `context` tells it whether the host asked it
+    /// to stop early.
+    fn run(&self, context: &RunContext);
+
+    /// Runs the plugin once with exclusive access to it, when dispatched by
+    /// [`Dispatcher::dispatch_mut`] or [`Dispatcher::dispatch_par_mut`].
+    /// Calls [`run`](Self::run) by default.
+    ///
+    /// No other thread can reach the plugin meanwhile, so it can change its
+    /// state without interior mutability.
+    fn run_mut(&mut self, context: &RunContext) {
+        self.run(context);
+    }
+}
+
+/// A point in the host's lifecycle at which plugins run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    Startup,
+    Update,
+    Shutdown,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 3] = [Phase::Startup, Phase::Update, Phase::Shutdown];
 }
 
+/// The plugins provided by one library.
+pub type Plugins = Vec<Box<dyn Plugin>>;
+
+/// A plugin loaded by `L`, with the library it was loaded from.
+pub type LoadResult<L> = Result<(<L as Loader>::Library, Box<dyn Plugin>), <L as Loader>::Error>;
+
 pub trait Loader {
     type Library;
+    /// The error loading fails with. A [`PluginManager`] reports it as
+    /// [`PluginLoadError::Loader`], unless it is a [`PluginLoadError`].
+    type Error: std::error::Error + Send + Sync + 'static;
 
     /// # Safety
     ///
     /// Users of this API must specify the correct type of the function or
     /// variable loaded.
-    unsafe fn load(filename: impl AsRef<OsStr>) -> Result<(Self::Library, Box<dyn Plugin>)>;
-}
+    unsafe fn load(&self, filename: impl AsRef<OsStr>) -> LoadResult<Self>;
 
-pub struct Native;
+    /// Loads a plugin whose constructor is exported as `entry` rather than
+    /// under the loader's default name. Loaders without named entry points
+    /// ignore `entry`.
+    ///
+    /// # Safety
+    ///
+    /// Users of this API must specify the correct type of the function or
+    /// variable loaded.
+    unsafe fn load_entry(&self, filename: impl AsRef<OsStr>, entry: &str) -> LoadResult<Self> {
+        let _ = entry;
+        self.load(filename)
+    }
 
-impl Loader for Native {
-    type Library = Library;
+    /// Like [`load_entry`](Self::load_entry), but tries each of `entries`
+    /// in order and uses the first one the library exports. With no
+    /// `entries`, this is the same as [`load`](Self::load).
+    ///
+    /// # Safety
+    ///
+    /// Users of this API must specify the correct type of the function or
+    /// variable loaded.
+    unsafe fn load_entries(
+        &self,
+        filename: impl AsRef<OsStr>,
+        entries: &[&str],
+    ) -> LoadResult<Self> {
+        let Some((last, entries)) = entries.split_last() else {
+            return self.load(filename);
+        };
 
-    unsafe fn load(filename: impl AsRef<OsStr>) -> Result<(Self::Library, Box<dyn Plugin>)> {
-        let library = Library::new(filename).map_err(PluginLoadError::Library)?;
-        let create_plugin: Symbol<unsafe fn() -> *mut dyn Plugin> =
-            unsafe { library.get(b"create_plugin").map_err(PluginLoadError::Plugin)? };
-        let plugin = Box::from_raw(create_plugin());
+        for entry in entries {
+            if let Ok(loaded) = self.load_entry(&filename, entry) {
+                return Ok(loaded);
+            }
+        }
 
-        Ok((library, plugin))
+        self.load_entry(filename, last)
     }
-}
 
-pub struct PluginManager<L: Loader = Native> {
-    plugins: Vec<Box<dyn Plugin>>,
-    name_of_plugin: AHashMap<&'static str, usize>,
-    libraries: Vec<L::Library>,
-    marker: PhantomData<L>,
-}
+    /// Checks, before it is opened, that the file at `path` is one this
+    /// loader can load. [`PluginManager::load_plugin`] and
+    /// [`PluginManager::load_dir_par`] call it, but not the loads of
+    /// manifests or of bytes. Accepts every file by default.
+    fn check_file(&self, path: &Path) -> Result<(), Self::Error> {
+        let _ = path;
+        Ok(())
+    }
 
-impl PluginManager {
-    pub fn new() -> Self {
-        Self::default()
+    /// Whether a library is opened from the contents of its file. If so,
+    /// once the contents are checked against a
+    /// [pinned](PluginManager::pin_sha256) checksum or a [trusted
+    /// key](PluginManager::trust_key), a copy of them is opened instead of
+    /// the file, which could be replaced in the meantime. Loaders that find
+    /// libraries by file name return `false`.
+    fn opens_files(&self) -> bool {
+        true
     }
-}
 
-impl<L: Loader> PluginManager<L> {
+    /// Loads every plugin a library provides. Loaders whose libraries hold
+    /// a single plugin use [`load_entries`](Self::load_entries).
+    ///
     /// # Safety
     ///
     /// Users of this API must specify the correct type of the function or
     /// variable loaded.
-    pub unsafe fn load_plugin(&mut self, filename: impl AsRef<OsStr>) -> Result<()> {
-        let (library, plugin) = L::load(filename)?;
+    unsafe fn load_plugins(
+        &self,
+        filename: impl AsRef<OsStr>,
+        entries: &[&str],
+    ) -> Result<(Self::Library, Plugins), Self::Error> {
+        let (library, plugin) = self.load_entries(filename, entries)?;
+        Ok((library, vec![plugin]))
+    }
+}
 
-        self.name_of_plugin.insert(plugin.name(), self.plugins.len());
-        self.plugins.push(plugin);
-        self.libraries.push(library);
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Native;
 
-        Ok(())
+/// The signature of the function exported by [`export_plugin!`].
+#[allow(improper_ctypes_definitions)]
+type CreatePluginFn = unsafe extern "C" fn(&'static HostApi) -> *mut dyn Plugin;
+
+/// The signature of the function exported by [`export_plugins!`].
+#[allow(improper_ctypes_definitions)]
+type CreatePluginsFn = unsafe extern "C" fn(&'static HostApi) -> *mut [Box<dyn Plugin>];
+
+/// Frees a plugin returned by `create_plugin` or `create_plugins`, inside
+/// the library that allocated it.
+#[allow(improper_ctypes_definitions)]
+type DestroyPluginFn = unsafe extern "C" fn(*mut dyn Plugin);
+
+/// Frees the list returned by `create_plugins`, but not the plugins in it.
+#[allow(improper_ctypes_definitions)]
+type DestroyPluginListFn = unsafe extern "C" fn(*mut [Box<dyn Plugin>]);
+
+impl Native {
+    /// The entry points tried by [`Native::load`], in order, after the
+    /// versioned ones. Besides sora's own, they include those of other
+    /// plugin frameworks with the same signature, to ease porting plugins.
+    pub const DEFAULT_ENTRY_POINTS: &'static [&'static str] =
+        &["create_plugin", "plugin_create", "_plugin_entry"];
+}
+
+impl Loader for Native {
+    type Library = Library;
+    type Error = PluginLoadError;
+
+    /// Checks that the file is named like a library of this platform and
+    /// starts like one.
+    fn check_file(&self, path: &Path) -> Result<()> {
+        native::check_file(path)
     }
 
-    pub fn into_dispatcher(mut self) -> Dispatcher<L::Library> {
-        use petgraph::algo::toposort;
-        use petgraph::graph::DiGraph;
+    /// Loads the plugin through the newest of the
+    /// [versioned entry points](VERSIONED_ENTRY_POINTS) the library exports,
+    /// or else the first of [`Native::DEFAULT_ENTRY_POINTS`].
+    unsafe fn load(&self, filename: impl AsRef<OsStr>) -> LoadResult<Self> {
+        self.load_entries(filename, &[])
+    }
 
-        let mut graph = DiGraph::new();
-        let mut node_indices = HashMap::new();
-        let mut node = |graph: &mut DiGraph<&str, ()>, name| {
-            *node_indices.entry(name).or_insert_with(|| graph.add_node(name))
-        };
+    unsafe fn load_entry(&self, filename: impl AsRef<OsStr>, entry: &str) -> LoadResult<Self> {
+        self.load_entries(filename, &[entry])
+    }
 
-        for plugin in &self.plugins {
-            let master = node(&mut graph, plugin.name());
+    /// Opens the library once and resolves the first of `entries` it
+    /// exports. Without `entries`, the versioned entry points are probed
+    /// first, as in [`load`](Self::load).
+    unsafe fn load_entries(
+        &self,
+        filename: impl AsRef<OsStr>,
+        entries: &[&str],
+    ) -> LoadResult<Self> {
+        let path = Path::new(filename.as_ref());
+        let library = native::open(path)?;
+        let plugin = unsafe { create_plugin(&library, path, entries)? };
+        Ok((library, plugin))
+    }
 
-            for &dependency in plugin.dependencies() {
-                let dependency = node(&mut graph, dependency);
+    /// Calls `create_plugins` if the library exports it, and otherwise
+    /// loads a single plugin through `entries`.
+    unsafe fn load_plugins(
+        &self,
+        filename: impl AsRef<OsStr>,
+        entries: &[&str],
+    ) -> Result<(Self::Library, Plugins), Self::Error> {
+        let path = Path::new(filename.as_ref());
+        let library = native::open(path)?;
+        let Ok(create_plugins) = (unsafe { library.get::<CreatePluginsFn>(b"create_plugins") })
+        else {
+            let plugin = unsafe { create_plugin(&library, path, entries)? };
+            return Ok((library, vec![plugin]));
+        };
 
-                graph.add_edge(dependency, master, ());
+        native::set_api_version(check_api_version(&library, path)?);
+        let list = create_plugins(host::current());
+        let destroy_list =
+            unsafe { library.get::<DestroyPluginListFn>(b"destroy_plugin_list") }.ok();
+        let destroy = destroy_plugin(&library);
+        let plugins = match (destroy, &destroy_list) {
+            (None, None) => Box::from_raw(list).into_vec(),
+            _ => {
+                // Move the plugins out, then let the library free the list.
+                let plugins = (*list)
+                    .iter()
+                    .map(|plugin| Foreign::boxed(Box::into_raw(std::ptr::read(plugin)), destroy))
+                    .collect();
+                // A library that frees its plugins itself but not the list
+                // may not share the host's allocator, so the list is leaked.
+                if let Some(destroy_list) = destroy_list {
+                    destroy_list(list);
+                }
+                plugins
             }
-        }
+        };
+
+        Ok((library, plugins))
+    }
+}
 
-        let nodes = toposort(&graph, None).unwrap();
-        let mut stages = Vec::with_capacity(nodes.len());
+/// The versions of the [`Plugin`] interface this build of sora implements,
+/// oldest first.
+///
+/// Libraries built with [`export_plugin!`] or [`export_plugins!`] export
+/// `sora_api_version`, which [`Native`] calls with the versions of the host
+/// before creating any plugin. The library answers with the newest of them
+/// that it implements too, or `0` to refuse to load. When the trait gains
+/// methods, the version is bumped and the host keeps accepting the
+/// versions it still knows how to call, so plugins compiled against an
+/// older sora need not be rebuilt.
+pub const API_VERSIONS: &[u32] = &[1];
 
-        for node in nodes {
-            let index = self.name_of_plugin[graph[node]];
-            let plugin = self.plugins.remove(index);
+/// Negotiates the interface version with `library` and resolves the entry
+/// point to create its plugin through: the first of `entries` it exports,
+/// or without `entries` the newest of the [`VERSIONED_ENTRY_POINTS`] and
+/// then the first of [`Native::DEFAULT_ENTRY_POINTS`]. Returns the version
+/// the plugin is created with.
+unsafe fn entry_point(library: &Library, path: &Path, entries: &[&str]) -> Result<(u32, Create)> {
+    let api_version = unsafe { check_api_version(library, path)? };
 
-            stages.push(vec![plugin]);
+    let mut tried = Vec::new();
+    let entries = match entries {
+        [] => {
+            for &(version, symbol, create) in VERSIONED_ENTRY_POINTS {
+                if let Some(create) = unsafe { create(library, symbol) } {
+                    return Ok((version, create));
+                }
+                tried.push(symbol);
+            }
+            Native::DEFAULT_ENTRY_POINTS
         }
+        entries => entries,
+    };
 
-        Dispatcher {
-            stages,
-            thread_pool: ThreadPoolBuilder::new().build().expect("Invalid configuration"),
-            libraries: self.libraries,
+    let mut error = None;
+    for entry in entries {
+        match unsafe { library.get::<CreatePluginFn>(entry.as_bytes()) } {
+            Ok(create_plugin) => return Ok((api_version, foreign(*create_plugin, library))),
+            Err(e) => error = Some(e),
         }
+        tried.push(entry);
     }
+
+    Err(native::symbol_error(path, &tried, error.unwrap()))
 }
 
-impl<L: Loader> Default for PluginManager<L> {
-    fn default() -> Self {
-        Self {
-            plugins: <_>::default(),
-            name_of_plugin: <_>::default(),
-            libraries: <_>::default(),
-            marker: PhantomData,
+/// Creates the plugin of the open `library` through its
+/// [entry point](entry_point), and reports the interface version to the
+/// manager loading it.
+unsafe fn create_plugin(
+    library: &Library,
+    path: &Path,
+    entries: &[&str],
+) -> Result<Box<dyn Plugin>> {
+    let (api_version, create) = unsafe { entry_point(library, path, entries)? };
+    native::set_api_version(api_version);
+    Ok(create(host::current()))
+}
+
+/// The signature of the function exported by [`export_plugin!`] and
+/// [`export_plugins!`] to negotiate the interface version.
+type ApiVersionFn = unsafe extern "C" fn(*const u32, usize) -> u32;
+
+/// The newest of the versions `offered` by the host that is also one of
+/// `supported`, or `0` if there is none.
+fn negotiate(offered: &[u32], supported: &[u32]) -> u32 {
+    offered.iter().copied().filter(|version| supported.contains(version)).max().unwrap_or(0)
+}
+
+/// The entry point of each interface version that [`Native`] can load,
+/// newest first, with the function that creates a plugin through it.
+/// Libraries that export none of them are loaded through
+/// [`Native::DEFAULT_ENTRY_POINTS`], which predate versioned entry points.
+///
+/// When the [`Plugin`] trait changes, the new version is added in front,
+/// and the functions of the older ones wrap the plugins they create in an
+/// adapter that implements the new trait, so that libraries built against
+/// an older sora keep loading.
+const VERSIONED_ENTRY_POINTS: &[(u32, &str, CreateVersionedFn)] =
+    &[(1, "sora_create_plugin_v1", create_plugin_v1)];
+
+/// Resolves the entry point `symbol`, or returns `None` if the library does
+/// not export it.
+type CreateVersionedFn = unsafe fn(&Library, &str) -> Option<Create>;
+
+/// Creates a plugin for the given host, through an entry point of a library
+/// that must stay loaded for as long as the plugin lives.
+type Create = Box<dyn Fn(&'static HostApi) -> Box<dyn Plugin> + Send + Sync>;
+
+unsafe fn create_plugin_v1(library: &Library, symbol: &str) -> Option<Create> {
+    let create_plugin = unsafe { library.get::<CreatePluginFn>(symbol.as_bytes()) }.ok()?;
+    Some(foreign(*create_plugin, library))
+}
+
+/// Creates plugins through `create_plugin`, freeing them with the
+/// `destroy_plugin` of `library` if it exports one.
+fn foreign(create_plugin: CreatePluginFn, library: &Library) -> Create {
+    let destroy = unsafe { destroy_plugin(library) };
+    Box::new(move |host| unsafe { Foreign::boxed(create_plugin(host), destroy) })
+}
+
+/// Implements `sora_api_version` in plugin libraries.
+///
+/// # Safety
+///
+/// `offered` must point to `len` versions.
+#[doc(hidden)]
+pub unsafe fn __api_version(offered: *const u32, len: usize) -> u32 {
+    negotiate(unsafe { std::slice::from_raw_parts(offered, len) }, API_VERSIONS)
+}
+
+/// Asks the library which interface version it implements. Libraries that
+/// predate the handshake do not export `sora_api_version` and are assumed
+/// to implement the first version.
+unsafe fn check_api_version(library: &Library, path: &Path) -> Result<u32> {
+    let Ok(api_version) = (unsafe { library.get::<ApiVersionFn>(b"sora_api_version") }) else {
+        return Ok(1);
+    };
+
+    match unsafe { api_version(API_VERSIONS.as_ptr(), API_VERSIONS.len()) } {
+        version if API_VERSIONS.contains(&version) => Ok(version),
+        _ => Err(PluginLoadError::ApiVersion { path: path.to_owned(), supported: API_VERSIONS }),
+    }
+}
+
+/// Resolves `destroy_plugin`, if the library exports it.
+unsafe fn destroy_plugin(library: &Library) -> Option<DestroyPluginFn> {
+    unsafe { library.get::<DestroyPluginFn>(b"destroy_plugin") }.ok().map(|destroy| *destroy)
+}
+
+/// A plugin owned by the library that created it.
+///
+/// Plugins from libraries that export `destroy_plugin` are handed back to
+/// it when dropped, so they are freed by the library's allocator rather
+/// than the host's.
+struct Foreign {
+    plugin: *mut dyn Plugin,
+    destroy: DestroyPluginFn,
+}
+
+// SAFETY: `Plugin` requires `Send + Sync`.
+unsafe impl Send for Foreign {}
+unsafe impl Sync for Foreign {}
+
+impl Foreign {
+    /// Takes ownership of `plugin`. Without a `destroy` function, the host
+    /// frees it.
+    unsafe fn boxed(plugin: *mut dyn Plugin, destroy: Option<DestroyPluginFn>) -> Box<dyn Plugin> {
+        match destroy {
+            Some(destroy) => Box::new(Foreign { plugin, destroy }),
+            None => unsafe { Box::from_raw(plugin) },
         }
     }
+
+    fn plugin(&self) -> &dyn Plugin {
+        unsafe { &*self.plugin }
+    }
 }
 
-#[derive(Debug, thiserror::Error)]
-pub enum PluginLoadError {
-    #[error("cannot load library for plugin: {0}")]
-    Library(libloading::Error),
-    #[error("library does not contain a valid plugin")]
-    Plugin(libloading::Error),
+impl Plugin for Foreign {
+    fn name(&self) -> &str {
+        self.plugin().name()
+    }
+
+    fn dependencies(&self) -> &[&str] {
+        self.plugin().dependencies()
+    }
+
+    fn version(&self) -> Option<&str> {
+        self.plugin().version()
+    }
+
+    fn description(&self) -> Option<&str> {
+        self.plugin().description()
+    }
+
+    fn authors(&self) -> &[&str] {
+        self.plugin().authors()
+    }
+
+    fn phases(&self) -> &[Phase] {
+        self.plugin().phases()
+    }
+
+    fn tags(&self) -> &[&str] {
+        self.plugin().tags()
+    }
+
+    fn reads(&self) -> &[&str] {
+        self.plugin().reads()
+    }
+
+    fn writes(&self) -> &[&str] {
+        self.plugin().writes()
+    }
+
+    fn init(&mut self, resources: &Resources) -> std::result::Result<(), ResourceError> {
+        unsafe { &mut *self.plugin }.init(resources)
+    }
+
+    fn retry(&self) -> RetryPolicy {
+        self.plugin().retry()
+    }
+
+    fn save_state(&self) -> Option<Vec<u8>> {
+        self.plugin().save_state()
+    }
+
+    fn load_state(&self, state: &[u8]) -> std::result::Result<(), BoxError> {
+        self.plugin().load_state(state)
+    }
+
+    fn run(&self, context: &RunContext) {
+        self.plugin().run(context);
+    }
+
+    fn run_mut(&mut self, context: &RunContext) {
+        unsafe { &mut *self.plugin }.run_mut(context);
+    }
 }
 
-pub struct Dispatcher<L> {
-    stages: Vec<Vec<Box<dyn Plugin>>>,
-    thread_pool: ThreadPool,
-    #[allow(dead_code)]
-    libraries: Vec<L>,
+impl Drop for Foreign {
+    fn drop(&mut self) {
+        unsafe { (self.destroy)(self.plugin) };
+    }
 }
 
-impl<L> Dispatcher<L> {
-    pub fn dispatch(&self) {
-        self.stages.iter().for_each(|stage| stage.iter().for_each(|plugin| plugin.run()));
+impl Native {
+    /// Opens the library and resolves its entry point as
+    /// [`load_entries`](Loader::load_entries) does, but defers creating the
+    /// plugin until it runs for the first time.
+    ///
+    /// The plugin cannot describe itself before it is created, so what the
+    /// dispatcher needs to schedule it is `declared` by the caller instead.
+    /// Once created, the plugin must have the declared name. It receives
+    /// the host of the manager loading it now, not when it is created, and
+    /// [`Plugin::init`] is never called.
+    ///
+    /// # Safety
+    ///
+    /// Users of this API must specify the correct type of the function or
+    /// variable loaded.
+    pub unsafe fn load_lazy(
+        filename: impl AsRef<OsStr>,
+        entries: &[&str],
+        declared: LazyPlugin,
+    ) -> Result<(Library, Box<dyn Plugin>)> {
+        let path = Path::new(filename.as_ref());
+        let name = declared.name;
+        let library = native::open(path).map_err(|error| error.for_plugin(name))?;
+        let (api_version, create) = unsafe { entry_point(&library, path, entries) }
+            .map_err(|error| error.for_plugin(name))?;
+        native::set_api_version(api_version);
+        let host = host::current();
+        let plugin = Lazy::new(declared, move || create(host));
+
+        Ok((library, Box::new(plugin)))
     }
 }
 
-impl<L: Send + Sync> Dispatcher<L> {
-    pub fn dispatch_par(&self) {
-        use rayon::iter::{IntoParallelRefIterator as _, ParallelIterator as _};
+/// What a lazily loaded plugin declares about itself before it is created,
+/// for the dispatcher to schedule it. See [`Native::load_lazy`].
+///
+/// ```ignore
+/// let declared = LazyPlugin { reads: &["Input"], ..LazyPlugin::new("Physics", &[]) };
+/// ```
+#[derive(Debug, Clone)]
+pub struct LazyPlugin {
+    pub name: &'static str,
+    pub dependencies: &'static [&'static str],
+    pub version: Option<&'static str>,
+    pub phases: &'static [Phase],
+    pub tags: &'static [&'static str],
+    pub reads: &'static [&'static str],
+    pub writes: &'static [&'static str],
+    pub retry: RetryPolicy,
+}
 
-        self.thread_pool.install(|| {
-            for stage in &self.stages {
-                stage.par_iter().for_each(|plugin| plugin.run())
-            }
-        });
+impl LazyPlugin {
+    /// Declares a plugin called `name` that runs after `dependencies`, with
+    /// the defaults of [`Plugin`] for everything else.
+    pub fn new(name: &'static str, dependencies: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            dependencies,
+            version: None,
+            phases: &[Phase::Update],
+            tags: &[],
+            reads: &[],
+            writes: &[],
+            retry: RetryPolicy::NEVER,
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::ffi::OsStr;
-    use std::sync::Arc;
-
-    use crate::{Loader, Plugin, PluginManager, Result};
-
-    #[macro_export]
-    macro_rules! define_plugins {
-        ($($name:ident { run: $run_block:block $(, dependencies: [$($deps:expr),*] )? }),+) => {
-            $(
-                struct $name;
-                impl Plugin for $name {
-                    fn run(&self) {
-                        $run_block
-                    }
+type CreatePlugin = Box<dyn Fn() -> Box<dyn Plugin> + Send + Sync>;
 
-                    fn dependencies(&self) -> &'static [&'static str] {
-                        &[$($($deps),*)?]
-                    }
-                }
-            )+
+/// A plugin that is only instantiated once it is run, or its state is
+/// restored.
+struct Lazy {
+    declared: LazyPlugin,
+    create: CreatePlugin,
+    plugin: OnceLock<Box<dyn Plugin>>,
+}
 
-            pub struct PluginLoader;
+impl Lazy {
+    fn new(
+        declared: LazyPlugin,
+        create: impl Fn() -> Box<dyn Plugin> + Send + Sync + 'static,
+    ) -> Self {
+        Self { declared, create: Box::new(create), plugin: OnceLock::new() }
+    }
 
-            impl Loader for PluginLoader {
-                type Library = ();
+    fn get(&self) -> &dyn Plugin {
+        &**self.plugin.get_or_init(|| {
+            let plugin = (self.create)();
+            let name = self.declared.name;
+            assert!(
+                plugin.name() == name,
+                "plugin `{name}` was loaded lazily, but its library created `{}`",
+                plugin.name()
+            );
+            plugin
+        })
+    }
+}
 
-                unsafe fn load(filename: impl AsRef<OsStr>) -> Result<(Self::Library, Box<dyn Plugin>)> {
-                    let name = filename.as_ref().to_str().unwrap();
-                    let plugin: Box<dyn Plugin> = match name {
-                        $( stringify!($name) => Box::new($name {}), )+
-                        _ => unimplemented!(),
-                    };
+impl Plugin for Lazy {
+    fn name(&self) -> &str {
+        self.declared.name
+    }
 
-                    Ok(((), plugin))
-                }
-            }
-        };
+    fn dependencies(&self) -> &[&str] {
+        self.declared.dependencies
     }
 
-    #[test]
-    fn smoke() {
-        define_plugins! {
-            A {
-                run: {
-                    println!("A");
-                }
-            },
-            B {
-                run: {
-                    println!("B");
-                },
-                dependencies: ["A"]
-            }
-        }
+    fn version(&self) -> Option<&str> {
+        self.declared.version
+    }
 
-        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+    fn phases(&self) -> &[Phase] {
+        self.declared.phases
+    }
 
-        unsafe { manager.load_plugin("B").unwrap() };
-        unsafe { manager.load_plugin("A").unwrap() };
+    fn tags(&self) -> &[&str] {
+        self.declared.tags
+    }
 
-        let dispatcher = manager.into_dispatcher();
+    fn reads(&self) -> &[&str] {
+        self.declared.reads
+    }
 
-        std::io::set_output_capture(Some(Default::default()));
+    fn writes(&self) -> &[&str] {
+        self.declared.writes
+    }
 
-        dispatcher.dispatch();
+    fn retry(&self) -> RetryPolicy {
+        self.declared.retry
+    }
 
-        let captured = std::io::set_output_capture(None);
-        let captured = captured.unwrap();
-        let captured = Arc::try_unwrap(captured).unwrap();
-        let captured = captured.into_inner().unwrap();
-        let captured = String::from_utf8(captured).unwrap();
+    /// A plugin that has not been created yet has no state to save.
+    fn save_state(&self) -> Option<Vec<u8>> {
+        self.plugin.get()?.save_state()
+    }
+
+    fn load_state(&self, state: &[u8]) -> std::result::Result<(), BoxError> {
+        self.get().load_state(state)
+    }
+
+    fn run(&self, context: &RunContext) {
+        self.get().run(context);
+    }
+
+    fn run_mut(&mut self, context: &RunContext) {
+        self.get();
+        self.plugin.get_mut().unwrap().run_mut(context);
+    }
+}
+
+/// A shared reference to a registered plugin.
+///
+/// Handles are cheap to clone and keep the plugin's library loaded, so a
+/// host can hold on to a plugin after the dispatcher that runs it has been
+/// dropped.
+pub struct PluginHandle<L> {
+    // Dropped before the library.
+    plugin: Arc<dyn Plugin>,
+    library: Option<Arc<L>>,
+}
+
+impl<L> PluginHandle<L> {
+    /// Wraps `plugin`, keeping `library` loaded for as long as the handle
+    /// or a clone of it lives. See [`Dispatcher::add_plugin`].
+    pub fn new(plugin: Box<dyn Plugin>, library: Option<Arc<L>>) -> Self {
+        Self { plugin: plugin.into(), library }
+    }
+}
+
+impl<L> PluginHandle<L> {
+    /// The plugin, unless another handle shares it.
+    fn get_mut(&mut self) -> Option<&mut dyn Plugin> {
+        Arc::get_mut(&mut self.plugin)
+    }
+
+    /// Like [`get_mut`](Self::get_mut), for dispatching the plugin mutably.
+    fn exclusive(&mut self) -> &mut dyn Plugin {
+        let name = self.plugin.name().to_owned();
+        self.get_mut().unwrap_or_else(|| {
+            panic!("plugin `{name}` is shared through a `PluginHandle`, so it cannot run mutably")
+        })
+    }
+}
+
+impl<L> Clone for PluginHandle<L> {
+    fn clone(&self) -> Self {
+        Self { plugin: self.plugin.clone(), library: self.library.clone() }
+    }
+}
+
+impl<L> Deref for PluginHandle<L> {
+    type Target = dyn Plugin;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.plugin
+    }
+}
+
+pub struct PluginManager<L: Loader = Native> {
+    plugins: Vec<PluginHandle<L::Library>>,
+    metadata: Vec<PluginMetadata>,
+    name_of_plugin: AHashMap<String, usize>,
+    /// The loaded libraries, with their canonical paths. Only plugins keep
+    /// them loaded, so a library is closed once its last plugin is dropped.
+    libraries: Vec<(Option<PathBuf>, Weak<L::Library>)>,
+    integrity: Integrity,
+    policy: LoadPolicy,
+    entry_points: Vec<String>,
+    host: &'static HostApi,
+    native_options: NativeLoaderOptions,
+    /// Where bundles are extracted. See [`PluginManager::set_bundle_cache`].
+    bundle_cache: Option<PathBuf>,
+    /// See [`PluginManager::set_lockfile`].
+    lockfile: Option<Lockfile>,
+    resources: Resources,
+    loader: L,
+}
+
+impl PluginManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn builder() -> PluginManagerBuilder {
+        PluginManagerBuilder::new()
+    }
+}
+
+/// Registers plugins compiled into the host with a [`PluginManager`].
+///
+/// Static plugins are scheduled together with the plugins loaded from
+/// libraries, and may depend on them or be depended on. Each plugin is
+/// subject to the manager's [`LoadPolicy`]; the macro evaluates to the
+/// first error.
+///
+/// ```ignore
+/// let mut manager = PluginManager::new();
+/// register_static_plugins!(manager, Physics::default(), Render::new())?;
+/// unsafe { manager.load_plugin("libthird_party.so")? };
+/// ```
+#[macro_export]
+macro_rules! register_static_plugins {
+    ($manager:expr, $($plugin:expr),+ $(,)?) => {
+        (|| -> $crate::Result<()> {
+            $( $manager.register(::std::boxed::Box::new($plugin))?; )+
+            Ok(())
+        })()
+    };
+}
+
+/// Exports `sora_create_plugin_v1` from a plugin library, so that
+/// [`Native`] can load it, `destroy_plugin` to free it again, and
+/// `sora_api_version` to agree on the interface version. See
+/// [`API_VERSIONS`].
+///
+/// `create_plugin` is exported too, for hosts that predate versioned entry
+/// points and for manifests that name it.
+///
+/// A closure receives the [`HostApi`] of the loading manager, which the
+/// plugin may keep: `export_plugin!(|host| Hello::new(host))`.
+///
+/// ```ignore
+/// #[derive(Default)]
+/// pub struct Hello {}
+///
+/// impl sora::Plugin for Hello {
+///     fn run(&self, _: &sora::RunContext) {
+///         println!("Hello, World!");
+///     }
+/// }
+///
+/// sora::export_plugin!(Hello::default());
+/// ```
+#[macro_export]
+macro_rules! export_plugin {
+    (|$host:pat_param| $plugin:expr) => {
+        #[no_mangle]
+        #[allow(improper_ctypes_definitions)]
+        pub extern "C" fn sora_create_plugin_v1(
+            host: &'static $crate::HostApi,
+        ) -> *mut dyn $crate::Plugin {
+            let $host = host;
+            ::std::boxed::Box::into_raw(::std::boxed::Box::new($plugin))
+        }
+
+        #[no_mangle]
+        #[allow(improper_ctypes_definitions)]
+        pub extern "C" fn create_plugin(host: &'static $crate::HostApi) -> *mut dyn $crate::Plugin {
+            sora_create_plugin_v1(host)
+        }
+
+        $crate::__export_destroy_plugin!();
+        $crate::__export_api_version!();
+    };
+    ($plugin:expr) => {
+        $crate::export_plugin!(|_| $plugin);
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __export_destroy_plugin {
+    () => {
+        /// # Safety
+        ///
+        /// `plugin` must come from this library's `create_plugin` or
+        /// `create_plugins`.
+        #[no_mangle]
+        #[allow(improper_ctypes_definitions)]
+        pub unsafe extern "C" fn destroy_plugin(plugin: *mut dyn $crate::Plugin) {
+            ::std::mem::drop(unsafe { ::std::boxed::Box::from_raw(plugin) });
+        }
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __export_api_version {
+    () => {
+        /// # Safety
+        ///
+        /// `offered` must point to `len` interface versions.
+        #[no_mangle]
+        pub unsafe extern "C" fn sora_api_version(offered: *const u32, len: usize) -> u32 {
+            unsafe { $crate::__api_version(offered, len) }
+        }
+    };
+}
+
+/// Exports `create_plugins` from a library that bundles several plugins.
+/// When a library exports it, [`Native`] loads every plugin it returns.
+///
+/// Like [`export_plugin!`], it also exports `destroy_plugin` and
+/// `sora_api_version`, together with `destroy_plugin_list`, so the two
+/// macros cannot be used in the same library.
+///
+/// ```ignore
+/// sora::export_plugins!(Physics::default(), Render::new());
+/// sora::export_plugins!(|host| Physics::new(host), Render::new());
+/// ```
+#[macro_export]
+macro_rules! export_plugins {
+    (|$host:pat_param| $($plugin:expr),+ $(,)?) => {
+        #[no_mangle]
+        #[allow(improper_ctypes_definitions)]
+        pub extern "C" fn create_plugins(
+            host: &'static $crate::HostApi,
+        ) -> *mut [::std::boxed::Box<dyn $crate::Plugin>] {
+            let $host = host;
+            let plugins: ::std::boxed::Box<[::std::boxed::Box<dyn $crate::Plugin>]> =
+                ::std::boxed::Box::new([$(::std::boxed::Box::new($plugin) as ::std::boxed::Box<dyn $crate::Plugin>),+]);
+            ::std::boxed::Box::into_raw(plugins)
+        }
+
+        /// # Safety
+        ///
+        /// `list` must come from this library's `create_plugins`, and its
+        /// plugins must have been moved out.
+        #[no_mangle]
+        #[allow(improper_ctypes_definitions)]
+        pub unsafe extern "C" fn destroy_plugin_list(list: *mut [::std::boxed::Box<dyn $crate::Plugin>]) {
+            let list = list as *mut [::std::mem::ManuallyDrop<::std::boxed::Box<dyn $crate::Plugin>>];
+            ::std::mem::drop(unsafe { ::std::boxed::Box::from_raw(list) });
+        }
+
+        $crate::__export_destroy_plugin!();
+        $crate::__export_api_version!();
+    };
+    ($($plugin:expr),+ $(,)?) => {
+        $crate::export_plugins!(|_| $($plugin),+);
+    };
+}
+
+impl<L: Loader> PluginManager<L> {
+    /// A manager that loads libraries with `loader`, for loaders that hold
+    /// configuration or state. Loaders without any can use
+    /// [`default`](Default::default) instead.
+    pub fn with_loader(loader: L) -> Self {
+        Self {
+            plugins: <_>::default(),
+            metadata: <_>::default(),
+            name_of_plugin: <_>::default(),
+            libraries: <_>::default(),
+            integrity: <_>::default(),
+            policy: <_>::default(),
+            entry_points: <_>::default(),
+            host: HostApi::default_host(),
+            native_options: <_>::default(),
+            bundle_cache: <_>::default(),
+            lockfile: <_>::default(),
+            resources: <_>::default(),
+            loader,
+        }
+    }
+
+    pub fn loader(&self) -> &L {
+        &self.loader
+    }
+
+    /// # Safety
+    ///
+    /// Users of this API must specify the correct type of the function or
+    /// variable loaded.
+    pub unsafe fn load_plugin(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = canonical(path.as_ref());
+        let verified = self.integrity.check(&path, None)?.filter(|_| self.loader.opens_files());
+        self.loader.check_file(&path).map_err(|error| loader_error(&path, error))?;
+        let (library, plugins, api_version) = with_verified(&path, verified.as_ref(), |open| {
+            load_library(&self.loader, self.host, &self.native_options, &self.entry_points, open)
+        })?;
+        self.register_loaded(Some(&path), library, plugins, api_version)
+    }
+
+    /// Loads a library from `bytes` rather than from a file, such as one
+    /// downloaded or embedded in the host. `name` identifies it in errors
+    /// and, as the library is written to a file first where the platform
+    /// requires it, in that file's name.
+    ///
+    /// Without a file, there is no path to pin a checksum to or to find a
+    /// signature next to. Once a key is trusted, the Ed25519 `signature` of
+    /// `bytes` must be given. The library is not shared with other loads of
+    /// the same bytes.
+    ///
+    /// # Safety
+    ///
+    /// Users of this API must specify the correct type of the function or
+    /// variable loaded.
+    pub unsafe fn load_bytes(
+        &mut self,
+        name: &str,
+        bytes: &[u8],
+        signature: Option<&[u8]>,
+    ) -> Result<()> {
+        self.integrity.verify(Path::new(name), bytes, None, signature)?;
+        let file = MemoryFile::new(name, bytes).map_err(PluginLoadError::Io)?;
+        let (library, plugins, api_version) = load_library(
+            &self.loader,
+            self.host,
+            &self.native_options,
+            &self.entry_points,
+            file.path(),
+        )?;
+        self.register_loaded(None, library, plugins, api_version)
+    }
+
+    /// Loads the plugin described by the [`Manifest`] in `dir`.
+    ///
+    /// The manifest is validated and the library located before it is
+    /// opened; once created, the plugin's name and dependencies must match
+    /// the ones declared in the manifest.
+    ///
+    /// # Safety
+    ///
+    /// Users of this API must specify the correct type of the function or
+    /// variable loaded.
+    pub unsafe fn load_manifest(&mut self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        unsafe { self.load_described(&dir.join(Manifest::FILE_NAME), dir) }
+    }
+
+    /// Loads the plugin described by the manifest at `path`, from the
+    /// library it names in `library_dir`.
+    unsafe fn load_described(&mut self, path: &Path, library_dir: &Path) -> Result<()> {
+        let error = |error| PluginLoadError::Manifest(path.to_owned(), error);
+
+        let manifest = Manifest::read(path).map_err(error)?;
+        let library_path = manifest.library_path(library_dir).map_err(error)?;
+        let verified = self
+            .integrity
+            .check(&library_path, manifest.sha256)?
+            .filter(|_| self.loader.opens_files());
+        let (loaded, api_version) = with_verified(&library_path, verified.as_ref(), |open| {
+            host::with(self.host, || {
+                native::with(&self.native_options, || self.loader.load_entry(open, &manifest.entry))
+            })
+        });
+        let (library, plugin) = loaded
+            .map_err(|error| loader_error(&library_path, error).for_plugin(&manifest.name))?;
+        manifest.verify(plugin.name(), plugin.dependencies()).map_err(error)?;
+
+        self.register_loaded(Some(&library_path), library, vec![plugin], api_version)?;
+        let metadata = self.metadata.last_mut().unwrap();
+        metadata.version.get_or_insert(manifest.version);
+
+        Ok(())
+    }
+
+    /// Trusts libraries signed with the Ed25519 `public_key`.
+    ///
+    /// Once a key is trusted, every library loaded afterwards must be
+    /// accompanied by a detached signature in a file of the same name with
+    /// an added `.sig` extension, made with one of the trusted keys.
+    /// Libraries without a valid signature are rejected with
+    /// [`PluginLoadError::SignatureInvalid`].
+    pub fn trust_key(&mut self, public_key: [u8; 32]) {
+        self.integrity.trusted_keys.push(public_key);
+    }
+
+    /// Pins the SHA-256 digest of the library at `path`.
+    ///
+    /// Loading `path` afterwards fails with
+    /// [`PluginLoadError::ChecksumMismatch`] unless the file hashes to
+    /// `sha256`.
+    pub fn pin_sha256(&mut self, path: impl AsRef<Path>, sha256: [u8; 32]) {
+        self.integrity.pinned.insert(canonical(path.as_ref()), sha256);
+    }
+
+    /// Sets the symbols to look for when loading a library without a
+    /// manifest, tried in order. By default, the loader picks them, which
+    /// for [`Native`] means [`Native::DEFAULT_ENTRY_POINTS`].
+    pub fn set_entry_points<S: Into<String>>(&mut self, entries: impl IntoIterator<Item = S>) {
+        self.entry_points = entries.into_iter().map(Into::into).collect();
+    }
+
+    /// Sets the host passed to the plugins loaded afterwards. See
+    /// [`HostApi`].
+    ///
+    /// Plugins that are not loaded from a library find the host's
+    /// [`Features`] among the [`resources`](Self::resources) instead.
+    pub fn set_host(&mut self, host: impl Host + 'static) {
+        self.resources.insert(Arc::new(host.features().clone()));
+        self.host = HostApi::leak(host);
+    }
+
+    /// Sets the flags with which [`Native`], [`CAbi`] and
+    /// [`Native::load_lazy`] open the libraries loaded afterwards.
+    pub fn set_native_options(&mut self, options: NativeLoaderOptions) {
+        self.native_options = options;
+    }
+
+    /// Sets the policy deciding which loaded plugins are registered.
+    pub fn set_load_policy(&mut self, policy: LoadPolicy) {
+        self.policy = policy;
+    }
+
+    /// Registers an already constructed plugin that is not backed by a
+    /// library, such as a system built into the host.
+    ///
+    /// The plugin is scheduled together with loaded plugins and is subject
+    /// to the manager's [`LoadPolicy`].
+    pub fn register(&mut self, mut plugin: Box<dyn Plugin>) -> Result<()> {
+        self.check_registrable(&*plugin)?;
+        self.init(&mut *plugin)?;
+        self.insert(PluginHandle::new(plugin, None));
+
+        Ok(())
+    }
+
+    fn check_registrable(&self, plugin: &dyn Plugin) -> Result<()> {
+        if !self.policy.allows(plugin) {
+            return Err(PluginLoadError::Denied(plugin.name().to_owned()));
+        }
+
+        if self.name_of_plugin.contains_key(plugin.name()) {
+            return Err(PluginLoadError::Duplicate(plugin.name().to_owned()));
+        }
+
+        if plugin.dependencies().contains(&plugin.name()) {
+            return Err(PluginLoadError::SelfDependency(plugin.name().to_owned()));
+        }
+
+        Ok(())
+    }
+
+    fn init(&self, plugin: &mut dyn Plugin) -> Result<()> {
+        plugin
+            .init(&self.resources)
+            .map_err(|error| PluginLoadError::Init { plugin: plugin.name().to_owned(), error })
+    }
+
+    /// The values plugins receive in [`Plugin::init`]. Resources have to be
+    /// inserted before the plugins that need them are registered.
+    pub fn resources(&self) -> &Resources {
+        &self.resources
+    }
+
+    pub fn resources_mut(&mut self) -> &mut Resources {
+        &mut self.resources
+    }
+
+    fn insert(&mut self, plugin: PluginHandle<L::Library>) {
+        self.name_of_plugin.insert(plugin.name().to_owned(), self.plugins.len());
+        self.metadata.push(PluginMetadata::of(&*plugin));
+        self.plugins.push(plugin);
+    }
+
+    /// Registers all plugins from the library at `path`, or none of them if
+    /// any is rejected. `api_version` is the interface version the library
+    /// was loaded through, if the loader reported one.
+    ///
+    /// If the library is already loaded, the plugins share it, and `library`
+    /// is dropped. The operating system counts how often a library is
+    /// opened, so this keeps it loaded. Libraries without a `path` are not
+    /// shared.
+    fn register_loaded(
+        &mut self,
+        path: Option<&Path>,
+        library: L::Library,
+        mut plugins: Vec<Box<dyn Plugin>>,
+        api_version: Option<u32>,
+    ) -> Result<()> {
+        // Arguments are dropped in reverse order, so on error the plugins
+        // are dropped before the library is unloaded.
+        for (index, plugin) in plugins.iter().enumerate() {
+            self.check_registrable(&**plugin)?;
+
+            if plugins[..index].iter().any(|other| other.name() == plugin.name()) {
+                return Err(PluginLoadError::Duplicate(plugin.name().to_owned()));
+            }
+        }
+
+        if let (Some(lockfile), Some(path)) = (&self.lockfile, path) {
+            let sha256 = Sha256::digest(&std::fs::read(path).map_err(PluginLoadError::Io)?);
+            for plugin in &plugins {
+                lockfile.check(plugin.name(), plugin.version(), &sha256).map_err(|drift| {
+                    PluginLoadError::Drift {
+                        path: path.to_owned(),
+                        plugin: plugin.name().to_owned(),
+                        drift,
+                    }
+                })?;
+            }
+        }
+
+        for plugin in &mut plugins {
+            self.init(&mut **plugin)?;
+        }
+
+        if plugins.is_empty() {
+            return Ok(());
+        }
+
+        self.libraries.retain(|(_, library)| library.strong_count() > 0);
+        let path = path.map(canonical);
+        let loaded = self.libraries.iter().find(|(other, _)| path.is_some() && *other == path);
+        let library = match loaded.and_then(|(_, library)| library.upgrade()) {
+            Some(loaded) => loaded,
+            None => {
+                let library = Arc::new(library);
+                self.libraries.push((path.clone(), Arc::downgrade(&library)));
+                library
+            }
+        };
+
+        for plugin in plugins {
+            self.insert(PluginHandle::new(plugin, Some(library.clone())));
+            let metadata = self.metadata.last_mut().unwrap();
+            metadata.api_version = api_version;
+            metadata.path.clone_from(&path);
+        }
+
+        Ok(())
+    }
+
+    /// The metadata of every registered plugin, in registration order.
+    ///
+    /// Plugins loaded from a manifest that do not report a version get the
+    /// one declared in the manifest.
+    pub fn metadata(&self) -> &[PluginMetadata] {
+        &self.metadata
+    }
+
+    /// Returns a handle to the plugin called `name`, if it is registered.
+    pub fn plugin(&self, name: &str) -> Option<PluginHandle<L::Library>> {
+        self.name_of_plugin.get(name).map(|&index| self.plugins[index].clone())
+    }
+
+    pub fn into_dispatcher(self) -> Dispatcher<L::Library> {
+        self.into_dispatcher_builder().build()
+    }
+
+    pub fn into_dispatcher_builder(self) -> DispatcherBuilder<L> {
+        DispatcherBuilder {
+            manager: self,
+            threads: Threads::default(),
+            filters: Vec::new(),
+            error_policy: ErrorPolicy::default(),
+            environment: Environment::new(),
+            args: Vec::new(),
+            plan_cache: None,
+            scheduler: Box::new(TopologicalScheduler),
+            watchdog: None,
+            capture_output: false,
+        }
+    }
+
+    /// Creates an independent [`Dispatcher`] for each of `names`, running
+    /// the plugins tagged with that name.
+    ///
+    /// The dispatchers share the loaded plugins and libraries. Dependencies
+    /// on plugins outside a pipeline are ignored, as with
+    /// [`DispatcherBuilder::filter`].
+    pub fn into_pipelines<S: Into<String>>(
+        self,
+        names: impl IntoIterator<Item = S>,
+    ) -> HashMap<String, Dispatcher<L::Library>>
+    where
+        L: Clone,
+    {
+        names
+            .into_iter()
+            .map(Into::into)
+            .map(|name| {
+                let tag = name.clone();
+                let dispatcher = self
+                    .share()
+                    .into_dispatcher_builder()
+                    .filter(move |plugin| plugin.tags().contains(&tag.as_str()))
+                    .build();
+
+                (name, dispatcher)
+            })
+            .collect()
+    }
+
+    /// Returns a manager with the same plugins and libraries.
+    fn share(&self) -> Self
+    where
+        L: Clone,
+    {
+        Self {
+            plugins: self.plugins.clone(),
+            metadata: self.metadata.clone(),
+            name_of_plugin: self.name_of_plugin.clone(),
+            libraries: self.libraries.clone(),
+            ..Self::with_loader(self.loader.clone())
+        }
+    }
+}
+
+impl PluginManager<Native> {
+    /// Loads a plugin that is only created once a dispatcher first runs it,
+    /// through the [entry points](Self::set_entry_points) of the manager.
+    /// See [`Native::load_lazy`].
+    ///
+    /// # Safety
+    ///
+    /// Users of this API must specify the correct type of the function or
+    /// variable loaded.
+    pub unsafe fn load_plugin_lazy(
+        &mut self,
+        filename: impl AsRef<OsStr>,
+        declared: LazyPlugin,
+    ) -> Result<()> {
+        let path = canonical(Path::new(&filename));
+        let verified = self.integrity.check(&path, None)?;
+        self.loader.check_file(&path).map_err(|error| error.for_plugin(declared.name))?;
+        let entries: Vec<_> = self.entry_points.iter().map(String::as_str).collect();
+        let (loaded, api_version) = with_verified(&path, verified.as_ref(), |open| {
+            host::with(self.host, || {
+                native::with(&self.native_options, || Native::load_lazy(open, &entries, declared))
+            })
+        });
+        let (library, plugin) = loaded?;
+        self.register_loaded(Some(&path), library, vec![plugin], api_version)
+    }
+}
+
+impl<L: Loader + Sync> PluginManager<L>
+where
+    L::Library: Send,
+{
+    /// Loads every library and bundle in `paths`, and every entry of those
+    /// that are folders, as [`load_plugin`](PluginManager::load_plugin) and
+    /// [`load_dir_par`](Self::load_dir_par) do.
+    ///
+    /// A path that fails to load does not stop the others: the plugins that
+    /// load are registered, and the failures are returned with them.
+    ///
+    /// # Safety
+    ///
+    /// Users of this API must specify the correct type of the function or
+    /// variable loaded.
+    pub unsafe fn load_all<P: AsRef<Path>>(
+        &mut self,
+        paths: impl IntoIterator<Item = P>,
+    ) -> LoadOutcome {
+        let registered = self.metadata.len();
+        let mut failed = Vec::new();
+        for path in paths {
+            let path = path.as_ref();
+            match path.is_dir() {
+                true => match unsafe { self.load_dir_par(path) } {
+                    Ok(failures) => failed.extend(failures),
+                    Err(error) => failed.push((path.to_owned(), error)),
+                },
+                false => {
+                    let result = match is_bundle(path) {
+                        true => unsafe { self.load_bundle(path) },
+                        false => unsafe { self.load_plugin(path) },
+                    };
+                    if let Err(error) = result {
+                        failed.push((path.to_owned(), error));
+                    }
+                }
+            }
+        }
+
+        let loaded = self.metadata[registered..].iter().map(|metadata| metadata.name.clone());
+        LoadOutcome { loaded: loaded.collect(), failed }
+    }
+
+    /// Loads every entry of `dir` concurrently.
+    /// Detached `.sig` signature files are skipped, and `.sora` bundles are
+    /// loaded with [`load_bundle`](Self::load_bundle) once the libraries
+    /// are.
+    ///
+    /// Plugins are registered in path order, independent of the order in
+    /// which the libraries finish loading. Entries that fail to load are
+    /// returned together with their error; all other plugins are still
+    /// registered.
+    ///
+    /// # Safety
+    ///
+    /// Users of this API must specify the correct type of the function or
+    /// variable loaded.
+    pub unsafe fn load_dir_par(
+        &mut self,
+        dir: impl AsRef<Path>,
+    ) -> Result<Vec<(PathBuf, PluginLoadError)>> {
+        let mut paths = std::fs::read_dir(dir)
+            .and_then(|entries| {
+                entries.map(|entry| Ok(entry?.path())).collect::<std::io::Result<Vec<_>>>()
+            })
+            .map_err(PluginLoadError::Io)?;
+        paths.retain(|path| path.extension() != Some(OsStr::new("sig")));
+        paths.sort();
+        let (bundles, paths): (Vec<_>, Vec<_>) =
+            paths.into_iter().partition(|path| is_bundle(path));
+
+        let loader = &self.loader;
+        let integrity = &self.integrity;
+        let host = self.host;
+        let native_options = &self.native_options;
+        let entry_points = &self.entry_points;
+        let loaded = executor::map(paths, |path| {
+            let result = integrity.check(&path, None).and_then(|verified| unsafe {
+                loader.check_file(&path).map_err(|error| loader_error(&path, error))?;
+                let verified = verified.filter(|_| loader.opens_files());
+                with_verified(&path, verified.as_ref(), |open| {
+                    load_library(loader, host, native_options, entry_points, open)
+                })
+            });
+            (path, result)
+        });
+
+        let mut errors = Vec::new();
+        for (path, result) in loaded {
+            match result {
+                Ok((library, plugins, api_version)) => {
+                    if let Err(error) =
+                        self.register_loaded(Some(&path), library, plugins, api_version)
+                    {
+                        errors.push((path, error));
+                    }
+                }
+                Err(error) => errors.push((path, error)),
+            }
+        }
+        for path in bundles {
+            if let Err(error) = unsafe { self.load_bundle(&path) } {
+                errors.push((path, error));
+            }
+        }
+
+        Ok(errors)
+    }
+}
+
+/// Loads `filename` with `loader` and the configured entry points, if any,
+/// passing `host` to the plugins.
+unsafe fn load_library<L: Loader>(
+    loader: &L,
+    host: &'static HostApi,
+    native_options: &NativeLoaderOptions,
+    entry_points: &[String],
+    filename: impl AsRef<OsStr>,
+) -> Result<(L::Library, Plugins, Option<u32>)> {
+    let entries: Vec<_> = entry_points.iter().map(String::as_str).collect();
+    let (loaded, api_version) = host::with(host, || {
+        native::with(native_options, || loader.load_plugins(&filename, &entries))
+    });
+    let (library, plugins) = loaded.map_err(|error| loader_error(&filename, error))?;
+    Ok((library, plugins, api_version))
+}
+
+/// Calls `load` with the file to open for the library at `path`: the
+/// `verified` copy of it, if there is one, opened as if it were at `path`.
+fn with_verified<T>(
+    path: &Path,
+    verified: Option<&MemoryFile>,
+    load: impl FnOnce(&Path) -> T,
+) -> T {
+    match verified {
+        Some(file) => native::with_original(path, || load(file.path())),
+        None => load(path),
+    }
+}
+
+/// Reports an error of a [`Loader`] with `filename`, unless it already is a
+/// [`PluginLoadError`].
+fn loader_error(filename: impl AsRef<OsStr>, error: impl Into<BoxError>) -> PluginLoadError {
+    match error.into().downcast() {
+        Ok(error) => *error,
+        Err(source) => PluginLoadError::Loader { path: filename.as_ref().into(), source },
+    }
+}
+
+/// Decides which plugins a [`PluginManager`] registers.
+///
+/// The policy is consulted once a plugin has been created, so it can
+/// inspect the plugin's name and dependencies. Rejected plugins are dropped
+/// together with their library, and loading them fails with
+/// [`PluginLoadError::Denied`].
+#[derive(Default)]
+pub enum LoadPolicy {
+    #[default]
+    AllowAll,
+    /// Only plugins with one of these names are registered.
+    Allowlist(AHashSet<String>),
+    /// Plugins with one of these names are rejected.
+    Denylist(AHashSet<String>),
+    /// Plugins for which the callback returns `false` are rejected.
+    Callback(PolicyCallback),
+}
+
+type PolicyCallback = Box<dyn Fn(&dyn Plugin) -> bool + Send + Sync>;
+
+impl LoadPolicy {
+    pub fn allow<S: Into<String>>(names: impl IntoIterator<Item = S>) -> Self {
+        Self::Allowlist(names.into_iter().map(Into::into).collect())
+    }
+
+    pub fn deny<S: Into<String>>(names: impl IntoIterator<Item = S>) -> Self {
+        Self::Denylist(names.into_iter().map(Into::into).collect())
+    }
+
+    pub fn callback(callback: impl Fn(&dyn Plugin) -> bool + Send + Sync + 'static) -> Self {
+        Self::Callback(Box::new(callback))
+    }
+
+    fn allows(&self, plugin: &dyn Plugin) -> bool {
+        match self {
+            Self::AllowAll => true,
+            Self::Allowlist(names) => names.contains(plugin.name()),
+            Self::Denylist(names) => !names.contains(plugin.name()),
+            Self::Callback(callback) => callback(plugin),
+        }
+    }
+}
+
+/// Integrity requirements checked before a library is opened.
+#[derive(Default)]
+struct Integrity {
+    trusted_keys: Vec<[u8; 32]>,
+    pinned: AHashMap<PathBuf, [u8; 32]>,
+}
+
+impl Integrity {
+    /// Checks the library at `path`, if there are requirements for it.
+    ///
+    /// The file could be replaced once checked, so the bytes that were
+    /// checked are returned in a [`MemoryFile`] to open instead of `path`.
+    /// Without requirements, nothing is read and `None` is returned.
+    fn check(&self, path: &Path, sha256: Option<[u8; 32]>) -> Result<Option<MemoryFile>> {
+        let sha256 = sha256.or_else(|| self.pinned.get(&canonical(path)).copied());
+        if self.trusted_keys.is_empty() && sha256.is_none() {
+            return Ok(None);
+        }
+
+        let library = std::fs::read(path).map_err(PluginLoadError::Io)?;
+        let signature = match self.trusted_keys.is_empty() {
+            true => None,
+            false => {
+                let mut signature_path = path.as_os_str().to_owned();
+                signature_path.push(".sig");
+                std::fs::read(signature_path).ok()
+            }
+        };
+
+        self.verify(path, &library, sha256, signature.as_deref())?;
+        let name = path.file_name().map_or("library".into(), |name| name.to_string_lossy());
+        let file =
+            MemoryFile::named(&name.replace('\\', "_"), &library).map_err(PluginLoadError::Io)?;
+        Ok(Some(file))
+    }
+
+    /// Checks the `library` read from `path` against `sha256`, and its
+    /// detached `signature` against the trusted keys, if there are any.
+    fn verify(
+        &self,
+        path: &Path,
+        library: &[u8],
+        sha256: Option<[u8; 32]>,
+        signature: Option<&[u8]>,
+    ) -> Result<()> {
+        if let Some(expected) = sha256 {
+            let found = Sha256::digest(library);
+            if found != expected {
+                return Err(PluginLoadError::ChecksumMismatch {
+                    path: path.to_owned(),
+                    expected: hex(&expected),
+                    found: hex(&found),
+                });
+            }
+        }
+
+        if !self.trusted_keys.is_empty() {
+            let valid = signature.is_some_and(|signature| {
+                self.trusted_keys.iter().any(|key| verify_signature(key, library, signature))
+            });
+            if !valid {
+                return Err(PluginLoadError::SignatureInvalid(path.to_owned()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn is_bundle(path: &Path) -> bool {
+    path.extension() == Some(OsStr::new("sora"))
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+impl<L: Loader + Default> Default for PluginManager<L> {
+    fn default() -> Self {
+        Self::with_loader(L::default())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PluginLoadError {
+    #[error("cannot {phase} {}{}: {source}", path.display(), for_plugin(plugin))]
+    Library {
+        path: PathBuf,
+        phase: LoadPhase,
+        /// The name of the plugin, if known before it is created.
+        plugin: Option<String>,
+        source: libloading::Error,
+    },
+    #[error("invalid C ABI plugin in {}{}: {message}", path.display(), for_plugin(plugin))]
+    Abi { path: PathBuf, plugin: Option<String>, message: &'static str },
+    #[error("cannot read plugin files: {0}")]
+    Io(std::io::Error),
+    #[error("invalid plugin manifest {}: {1}", .0.display())]
+    Manifest(PathBuf, ManifestError),
+    #[error("invalid plugin bundle {}: {1}", .0.display())]
+    Bundle(PathBuf, BundleError),
+    #[error("{} does not have a valid signature from a trusted key", .0.display())]
+    SignatureInvalid(PathBuf),
+    #[error("SHA-256 of {} is {found}, but {expected} is pinned", path.display())]
+    ChecksumMismatch { path: PathBuf, expected: String, found: String },
+    #[error("{} does not match the lockfile for plugin `{plugin}`: {drift}", path.display())]
+    Drift { path: PathBuf, plugin: String, drift: Drift },
+    #[error("plugin `{0}` is not allowed by the load policy")]
+    Denied(String),
+    #[error("a plugin named `{0}` is already loaded")]
+    Duplicate(String),
+    #[error("plugin `{0}` depends on itself")]
+    SelfDependency(String),
+    #[error("plugin `{plugin}` failed to initialize: {error}")]
+    Init { plugin: String, error: ResourceError },
+    #[error("{} implements none of the plugin interface versions {supported:?}", path.display())]
+    ApiVersion { path: PathBuf, supported: &'static [u32] },
+    #[error("{} needs shared libraries that cannot be found: {}", path.display(), missing.join(", "))]
+    MissingDependencies { path: PathBuf, missing: Vec<String> },
+    /// The [error](Loader::Error) of a loader other than [`Native`] and
+    /// [`CAbi`], which `source` can be downcast to.
+    #[error("cannot load {}: {source}", path.display())]
+    Loader { path: PathBuf, source: BoxError },
+    #[error("{} is not a plugin library: {reason}", path.display())]
+    NotALibrary { path: PathBuf, reason: &'static str },
+}
+
+impl PluginLoadError {
+    /// The file that failed to load, if the error is about one.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Self::Library { path, .. }
+            | Self::Abi { path, .. }
+            | Self::Manifest(path, _)
+            | Self::Bundle(path, _)
+            | Self::SignatureInvalid(path)
+            | Self::ChecksumMismatch { path, .. }
+            | Self::Drift { path, .. }
+            | Self::ApiVersion { path, .. }
+            | Self::MissingDependencies { path, .. }
+            | Self::Loader { path, .. }
+            | Self::NotALibrary { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    /// How far loading the library got before it failed, if it was opened
+    /// at all.
+    pub fn phase(&self) -> Option<LoadPhase> {
+        match self {
+            Self::Library { phase, .. } => Some(phase.clone()),
+            Self::MissingDependencies { .. }
+            | Self::ApiVersion { .. }
+            | Self::NotALibrary { .. } => Some(LoadPhase::Open),
+            Self::Abi { .. } => Some(LoadPhase::Create),
+            _ => None,
+        }
+    }
+
+    /// The name of the plugin that failed to load, if known.
+    pub fn plugin(&self) -> Option<&str> {
+        match self {
+            Self::Library { plugin, .. } | Self::Abi { plugin, .. } => plugin.as_deref(),
+            Self::Denied(plugin)
+            | Self::Duplicate(plugin)
+            | Self::SelfDependency(plugin)
+            | Self::Init { plugin, .. }
+            | Self::Drift { plugin, .. } => Some(plugin),
+            _ => None,
+        }
+    }
+
+    /// Names `plugin` as the one being loaded, unless the error already
+    /// names one.
+    fn for_plugin(mut self, name: &str) -> Self {
+        if let Self::Library { plugin, .. } | Self::Abi { plugin, .. } = &mut self {
+            plugin.get_or_insert_with(|| name.to_owned());
+        }
+        self
+    }
+}
+
+fn for_plugin(plugin: &Option<String>) -> String {
+    match plugin {
+        Some(plugin) => format!(" for plugin `{plugin}`"),
+        None => String::new(),
+    }
+}
+
+/// What [`PluginManager::load_all`] did.
+#[derive(Debug, Default)]
+pub struct LoadOutcome {
+    /// The names of the plugins registered, in order.
+    pub loaded: Vec<String>,
+    /// The libraries that failed to load, or whose plugins could not be
+    /// registered, with the reason.
+    pub failed: Vec<(PathBuf, PluginLoadError)>,
+}
+
+impl LoadOutcome {
+    /// Whether every path loaded.
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Configures the [`Dispatcher`] created from a [`PluginManager`].
+type PluginFilter = Box<dyn Fn(&dyn Plugin) -> bool>;
+
+pub struct DispatcherBuilder<L: Loader> {
+    manager: PluginManager<L>,
+    threads: Threads,
+    filters: Vec<PluginFilter>,
+    error_policy: ErrorPolicy,
+    environment: Environment,
+    args: Vec<String>,
+    plan_cache: Option<PathBuf>,
+    scheduler: Box<dyn Scheduler>,
+    watchdog: Option<Watchdog>,
+    capture_output: bool,
+}
+
+impl<L: Loader> DispatcherBuilder<L> {
+    /// Sets the number of worker threads used by
+    /// [`Dispatcher::dispatch_par`]. With `0`, the default, there are as
+    /// many as the machine has.
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        self.threads.num_threads = num_threads;
+        self
+    }
+
+    /// Names the worker threads used by [`Dispatcher::dispatch_par`] with
+    /// `name` of their index, rather than `sora-worker-{index}`, as
+    /// profilers and debuggers show them.
+    pub fn thread_name(mut self, name: impl Fn(usize) -> String + Send + Sync + 'static) -> Self {
+        self.threads.name = Some(Arc::new(name));
+        self
+    }
+
+    /// Sets the size in bytes of the stack of the worker threads used by
+    /// [`Dispatcher::dispatch_par`], for plugins that recurse deeply, such
+    /// as script interpreters. By default, that of [`std::thread`].
+    pub fn stack_size(mut self, stack_size: usize) -> Self {
+        self.threads.stack_size = Some(stack_size);
+        self
+    }
+
+    /// Sets which CPU cores the worker threads used by
+    /// [`Dispatcher::dispatch_par`] run on. By default, any.
+    pub fn affinity(mut self, affinity: Affinity) -> Self {
+        self.threads.affinity = Some(affinity);
+        self
+    }
+
+    /// Sets what [`Dispatcher::dispatch_report`] and
+    /// [`Dispatcher::dispatch_par_report`] do after a plugin fails. Defaults
+    /// to [`ErrorPolicy::Continue`].
+    pub fn error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Sets the settings plugins read from
+    /// [`RunContext::environment`]. Empty by default.
+    pub fn environment(mut self, environment: Environment) -> Self {
+        self.environment = environment;
+        self
+    }
+
+    /// Sets the arguments plugins get from [`RunContext::args`]. None by
+    /// default.
+    pub fn args(mut self, args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Only dispatches the plugins for which `filter` returns `true`. When
+    /// called more than once, a plugin must pass every filter.
+    ///
+    /// Plugins that are filtered out are dropped, and their dependents run
+    /// without waiting for them.
+    pub fn filter(mut self, filter: impl Fn(&dyn Plugin) -> bool + 'static) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    /// Saves the stages plugins are scheduled in to `path`, and reuses them
+    /// when a dispatcher is later built for the same plugins, rather than
+    /// scheduling them again.
+    ///
+    /// The plugins are the same if they are registered in the same order,
+    /// and report the same names, versions, dependencies, blackboard keys
+    /// and phases. The [scheduler](Self::scheduler) is not part of the key:
+    /// after changing it, delete the cache. Cached stages that leave out a
+    /// plugin, or run one no later than a plugin it waits for, are
+    /// scheduled again.
+    pub fn plan_cache(mut self, path: impl Into<PathBuf>) -> Self {
+        self.plan_cache = Some(path.into());
+        self
+    }
+
+    /// Sets how plugins are grouped into stages. Defaults to
+    /// [`TopologicalScheduler`].
+    pub fn scheduler(mut self, scheduler: impl Scheduler + 'static) -> Self {
+        self.scheduler = Box::new(scheduler);
+        self
+    }
+
+    /// Has reporting dispatches capture what each plugin logs through the
+    /// [`HostApi`] while it runs in [`PluginReport::output`], instead of
+    /// passing it to the [`Host`], so that the output of plugins running in
+    /// parallel can be told apart.
+    ///
+    /// What plugins print to the standard output or error is shared by
+    /// every thread, so it is only captured by
+    /// [`Dispatcher::dispatch_isolated`], which runs them one at a time.
+    pub fn capture_output(mut self, capture_output: bool) -> Self {
+        self.capture_output = capture_output;
+        self
+    }
+
+    /// Starts a thread that calls `overdue` with the name of every plugin
+    /// still running `deadline` after it started, and how long it has run
+    /// so far, for example to log it or count it in a metric. Each run of a
+    /// plugin is flagged at most once.
+    ///
+    /// Plugins cannot be stopped from the outside, so an overdue plugin
+    /// goes on running; `overdue` only tells which stage is stuck.
+    /// `overdue` runs on the watchdog thread, and delays the flagging of
+    /// other plugins while it does.
+    pub fn watchdog(
+        mut self,
+        deadline: Duration,
+        overdue: impl Fn(&str, Duration) + Send + Sync + 'static,
+    ) -> Self {
+        self.watchdog = Some(Watchdog::start(deadline, Box::new(overdue)));
+        self
+    }
+
+    /// # Panics
+    ///
+    /// If the [scheduler](Self::scheduler) leaves out a plugin, or runs one
+    /// no later than a plugin it waits for.
+    pub fn build(mut self) -> Dispatcher<L::Library> {
+        let (plugins, slot_of_plugin) = self.take_plugins();
+
+        // Whether a schedule could not be taken from the plan cache.
+        let scheduled_afresh = Cell::new(false);
+        // Schedules the plugins for which `include` returns `true`, unless
+        // the `cached` stages hold them in an order their dependencies
+        // allow. Dependencies on other loaded plugins are ignored.
+        let plan = |include: &dyn Fn(&dyn Plugin) -> bool, cached: Option<Vec<Vec<usize>>>| {
+            let slot_of_plugin = &slot_of_plugin;
+            let included =
+                |name: &str| slot_of_plugin.get(name).is_some_and(|&slot| include(&*plugins[slot]));
+
+            let (slots, mut scheduled): (Vec<_>, Vec<_>) = plugins
+                .iter()
+                .enumerate()
+                .filter(|(_, plugin)| include(&***plugin))
+                .map(|(slot, plugin)| {
+                    let dependencies: Vec<_> = plugin
+                        .dependencies()
+                        .iter()
+                        .copied()
+                        .filter(|&dependency| {
+                            included(dependency)
+                                || !self.manager.name_of_plugin.contains_key(dependency)
+                        })
+                        .collect();
+
+                    (slot, (plugin.name(), dependencies))
+                })
+                .unzip();
+
+            let access: Vec<_> = slots.iter().map(|&slot| Access::of(&*plugins[slot])).collect();
+            order_access(&mut scheduled, &access);
+
+            let index_of_plugin: AHashMap<_, _> =
+                scheduled.iter().enumerate().map(|(index, (name, _))| (*name, index)).collect();
+            let dependencies: Vec<Vec<_>> = scheduled
+                .iter()
+                .map(|(_, dependencies)| {
+                    dependencies.iter().map(|dependency| index_of_plugin[dependency]).collect()
+                })
+                .collect();
+
+            // The cached stages hold slots, and any that is not scheduled
+            // here is out of range.
+            let mut index_of_slot = vec![usize::MAX; plugins.len()];
+            for (index, &slot) in slots.iter().enumerate() {
+                index_of_slot[slot] = index;
+            }
+            let cached = cached
+                .map(|stages| -> Vec<Vec<_>> {
+                    stages
+                        .iter()
+                        .map(|stage| stage.iter().map(|&slot| index_of_slot[slot]).collect())
+                        .collect()
+                })
+                .filter(|stages| verify_stages(stages, &dependencies).is_ok());
+            let stages = cached.unwrap_or_else(|| {
+                scheduled_afresh.set(true);
+                let included: Vec<_> = slots.iter().map(|&slot| &*plugins[slot]).collect();
+                let stages = self.scheduler.schedule(&included, &dependencies);
+                check_stages(&stages, &dependencies);
+                stages
+            });
+
+            let stages = stages
+                .into_iter()
+                .map(|stage| stage.into_iter().map(|index| slots[index]).collect())
+                .collect();
+            let edges = slots.iter().zip(&scheduled).flat_map(|(&slot, (_, dependencies))| {
+                dependencies.iter().map(move |dependency| (slot_of_plugin[*dependency], slot))
+            });
+
+            Schedule::new(stages, edges)
+        };
+
+        let plan_all = |cached: Option<[Vec<Vec<usize>>; 4]>| {
+            let [all, startup, update, shutdown] =
+                cached.map_or_else(Default::default, |cached| cached.map(Some));
+            let phase = |phase| move |plugin: &dyn Plugin| plugin.phases().contains(&phase);
+            [
+                plan(&|_| true, all),
+                plan(&phase(Phase::Startup), startup),
+                plan(&phase(Phase::Update), update),
+                plan(&phase(Phase::Shutdown), shutdown),
+            ]
+        };
+
+        let [schedule, phases @ ..] = match &self.plan_cache {
+            Some(path) => {
+                let key = cache::key(plugins.iter().map(|plugin| &**plugin), |dependency| {
+                    slot_of_plugin.contains_key(dependency)
+                        || !self.manager.name_of_plugin.contains_key(dependency)
+                });
+                let schedules = plan_all(cache::read(path, &key, plugins.len()));
+                if scheduled_afresh.get() {
+                    cache::write(path, &key, &schedules.each_ref());
+                }
+                schedules
+            }
+            None => plan_all(None),
+        };
+
+        self.finish(plugins, slot_of_plugin, schedule, phases)
+    }
+
+    /// Builds a dispatcher that schedules nothing yet, for
+    /// [`Dispatcher::plan_from`] to fill in.
+    pub(crate) fn build_unscheduled(mut self) -> Dispatcher<L::Library> {
+        let (plugins, slot_of_plugin) = self.take_plugins();
+        let unscheduled = || Schedule::new(Vec::new(), []);
+        self.finish(plugins, slot_of_plugin, unscheduled(), [(); 3].map(|()| unscheduled()))
+    }
+
+    /// Takes the plugins the filters keep out of the manager, with the slot
+    /// of each by name.
+    fn take_plugins(&mut self) -> (Vec<PluginHandle<L::Library>>, AHashMap<String, usize>) {
+        let filters = &self.filters;
+        let plugins: Vec<_> = self
+            .manager
+            .plugins
+            .drain(..)
+            .filter(|plugin| filters.iter().all(|filter| filter(&**plugin)))
+            .collect();
+        let slot_of_plugin = plugins
+            .iter()
+            .enumerate()
+            .map(|(slot, plugin)| (plugin.name().to_owned(), slot))
+            .collect();
+        (plugins, slot_of_plugin)
+    }
+
+    fn finish(
+        self,
+        plugins: Vec<PluginHandle<L::Library>>,
+        slot_of_plugin: AHashMap<String, usize>,
+        schedule: Schedule,
+        phases: [Schedule; 3],
+    ) -> Dispatcher<L::Library> {
+        Dispatcher {
+            plugins: plugins.into_iter().map(Some).collect(),
+            slot_of_plugin,
+            schedule,
+            phases,
+            state: DispatchState::new(
+                self.environment,
+                self.args,
+                self.watchdog,
+                self.capture_output,
+            ),
+            error_policy: self.error_policy,
+            executor: Executor::new(self.threads),
+            #[cfg(feature = "profile")]
+            profile: Default::default(),
+            libraries: self.manager.libraries.into_iter().map(|(_, library)| library).collect(),
+            history: History::default(),
+        }
+    }
+}
+
+/// What [`Dispatcher::record`] measures.
+#[derive(Clone, Copy)]
+pub(crate) enum SpanKind {
+    Stage,
+    Plugin,
+}
+
+pub struct Dispatcher<L> {
+    /// Every plugin by its slot; removed plugins leave an empty slot.
+    plugins: Vec<Option<PluginHandle<L>>>,
+    slot_of_plugin: AHashMap<String, usize>,
+    /// The plan of [`dispatch`](Self::dispatch). Disabled plugins are not
+    /// scheduled.
+    schedule: Schedule,
+    /// A separate plan per [`Phase`], indexed by the phase.
+    phases: [Schedule; 3],
+    state: DispatchState,
+    error_policy: ErrorPolicy,
+    executor: Executor,
+    #[cfg(feature = "profile")]
+    profile: profile::Profile,
+    /// The libraries the plugins were loaded from, for
+    /// [`leak_libraries`](Self::leak_libraries).
+    libraries: Vec<Weak<L>>,
+    /// How long the plugins took in reporting dispatches, for
+    /// [`dispatch_with_deadline`](Self::dispatch_with_deadline).
+    history: History,
+}
+
+impl<L> Drop for Dispatcher<L> {
+    fn drop(&mut self) {
+        // A plugin's vtable and destructor live in its library, so every
+        // plugin has to be dropped before any library is unloaded. Each
+        // library is closed along with the last handle to its plugins.
+        self.plugins.clear();
+    }
+}
+
+impl<L> Dispatcher<L> {
+    /// Returns the plugins in the order [`dispatch`](Self::dispatch) runs
+    /// them.
+    pub fn plugins(&self) -> impl Iterator<Item = &dyn Plugin> {
+        self.schedule.stages().iter().flatten().map(|&slot| &**self.at(slot))
+    }
+
+    /// Returns a handle to the plugin called `name`, if it is loaded, even
+    /// if it is disabled.
+    pub fn plugin(&self, name: &str) -> Option<PluginHandle<L>> {
+        self.slot_of_plugin.get(name).map(|&slot| self.at(slot).clone())
+    }
+
+    pub(crate) fn at(&self, slot: usize) -> &PluginHandle<L> {
+        self.plugins[slot].as_ref().unwrap()
+    }
+
+    pub(crate) fn stages(&self) -> &[Vec<usize>] {
+        self.schedule.stages()
+    }
+
+    /// Removes the plugin called `name` and returns it. Its dependents no
+    /// longer wait for it.
+    ///
+    /// Only the stages of its transitive dependents are recomputed.
+    pub fn remove(&mut self, name: &str) -> Option<PluginHandle<L>> {
+        let slot = self.slot_of_plugin.remove(name)?;
+        self.unschedule(slot);
+        self.plugins[slot].take()
+    }
+
+    /// Stops running the plugin called `name` until it is
+    /// [enabled](Self::enable) again. Its dependents no longer wait for it.
+    ///
+    /// Returns `false` if no such plugin is loaded or it is already
+    /// disabled.
+    pub fn disable(&mut self, name: &str) -> bool {
+        match self.slot_of_plugin.get(name) {
+            Some(&slot) if self.schedule.contains(slot) => {
+                self.unschedule(slot);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Runs a [disabled](Self::disable) plugin again, in the stages its
+    /// dependencies call for.
+    ///
+    /// Returns `false` if no such plugin is loaded or it is not disabled.
+    pub fn enable(&mut self, name: &str) -> bool {
+        match self.slot_of_plugin.get(name) {
+            Some(&slot) if !self.schedule.contains(slot) => {
+                self.reschedule(slot);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Adds a plugin to the running schedule, after its dependencies and
+    /// before the plugins that depend on it.
+    ///
+    /// The plugin is checked against every loaded plugin, including
+    /// disabled ones: its name must be new, its dependencies loaded, and it
+    /// must not close a dependency cycle.
+    pub fn add_plugin(&mut self, plugin: PluginHandle<L>) -> std::result::Result<(), GraphError> {
+        let name = plugin.name();
+        if self.slot_of_plugin.contains_key(name) {
+            return Err(GraphError::Duplicate(name.to_owned()));
+        }
+
+        if let Some(dependency) = plugin
+            .dependencies()
+            .iter()
+            .find(|dependency| !self.slot_of_plugin.contains_key(**dependency))
+        {
+            return Err(GraphError::MissingDependency {
+                plugin: name.to_owned(),
+                dependency: (*dependency).to_owned(),
+            });
+        }
+
+        let mut path = vec![name];
+        let mut visited = AHashSet::new();
+        if plugin
+            .dependencies()
+            .iter()
+            .any(|dependency| self.depends_on(dependency, name, &mut visited, &mut path))
+        {
+            return Err(GraphError::Cycle(path.into_iter().map(str::to_owned).collect()));
+        }
+
+        let slot = self.plugins.len();
+        self.slot_of_plugin.insert(name.to_owned(), slot);
+        self.plugins.push(Some(plugin));
+        self.reschedule(slot);
+
+        Ok(())
+    }
+
+    /// Whether `from` is `to` or transitively depends on it. If so, `path`
+    /// ends with the plugins leading from `from` to `to`.
+    fn depends_on<'a>(
+        &'a self,
+        from: &'a str,
+        to: &str,
+        visited: &mut AHashSet<&'a str>,
+        path: &mut Vec<&'a str>,
+    ) -> bool {
+        if from == to {
+            return true;
+        }
+
+        let Some(&slot) = self.slot_of_plugin.get(from) else {
+            return false;
+        };
+        if !visited.insert(from) {
+            return false;
+        }
+
+        path.push(from);
+        let plugin = self.at(slot);
+        if plugin
+            .dependencies()
+            .iter()
+            .any(|dependency| self.depends_on(dependency, to, visited, path))
+        {
+            return true;
+        }
+        path.pop();
+
+        false
+    }
+
+    fn unschedule(&mut self, slot: usize) {
+        self.schedule.remove(slot);
+        self.phases.iter_mut().for_each(|schedule| schedule.remove(slot));
+    }
+
+    /// Schedules the plugin in `slot` next to the scheduled plugins it
+    /// depends on or that depend on it.
+    ///
+    /// Plugins whose [`Access`] conflicts with it run after it if they wait
+    /// for it anyway, and before it otherwise.
+    fn reschedule(&mut self, slot: usize) {
+        let plugin = self.at(slot).clone();
+        let link = |schedule: &Schedule| {
+            let mut dependencies: Vec<_> = plugin
+                .dependencies()
+                .iter()
+                .filter_map(|dependency| self.slot_of_plugin.get(*dependency).copied())
+                .filter(|&dependency| schedule.contains(dependency))
+                .collect();
+            let mut dependents: Vec<_> = self
+                .slot_of_plugin
+                .values()
+                .copied()
+                .filter(|&other| schedule.contains(other))
+                .filter(|&other| self.at(other).dependencies().contains(&plugin.name()))
+                .collect();
+
+            let downstream: AHashSet<_> = dependents
+                .iter()
+                .flat_map(|&dependent| {
+                    schedule.downstream(dependent).into_iter().chain([dependent])
+                })
+                .collect();
+            let conflicting = self.slot_of_plugin.values().copied().filter(|&other| {
+                schedule.contains(other)
+                    && Access::of(&**self.at(other)).conflicts(&Access::of(&*plugin))
+            });
+            for other in conflicting {
+                match downstream.contains(&other) {
+                    true if !dependents.contains(&other) => dependents.push(other),
+                    false if !dependencies.contains(&other) => dependencies.push(other),
+                    _ => {}
+                }
+            }
+
+            (dependencies, dependents)
+        };
+
+        let (dependencies, dependents) = link(&self.schedule);
+        let phases = Phase::ALL.map(|phase| {
+            let schedule = &self.phases[phase as usize];
+            plugin.phases().contains(&phase).then(|| link(schedule))
+        });
+
+        self.schedule.insert(slot, dependencies, dependents);
+        for (schedule, links) in self.phases.iter_mut().zip(phases) {
+            if let Some((dependencies, dependents)) = links {
+                schedule.insert(slot, dependencies, dependents);
+            }
+        }
+    }
+
+    /// Keeps the plugin libraries loaded for the rest of the process, even
+    /// after the dispatcher is dropped.
+    ///
+    /// For hosts that never unload plugins, this avoids the hazards of
+    /// unloading a library, such as thread-local destructors that still
+    /// point into it.
+    pub fn leak_libraries(&mut self) {
+        self.libraries.drain(..).filter_map(|library| library.upgrade()).for_each(std::mem::forget);
+    }
+
+    /// Asks the running plugins to stop early, through
+    /// [`RunContext::is_cancelled`]. The dispatcher stays cancelled until
+    /// [`uncancel`](Self::uncancel), so plugins dispatched later see it too.
+    ///
+    /// This takes `&self` so that another thread can call it while a
+    /// dispatch is in progress.
+    pub fn cancel(&self) {
+        self.state.cancel();
+    }
+
+    /// Lets plugins dispatched from now on run to completion again, such as
+    /// those of [`Phase::Shutdown`] after an update was cancelled.
+    pub fn uncancel(&self) {
+        self.state.uncancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.is_cancelled()
+    }
+
+    /// The output that the plugin called `name` published in the last
+    /// dispatch, if it is a `T`. See [`RunContext::output`].
+    pub fn output<T: Any + Send + Sync>(&self, name: &str) -> Option<Arc<T>> {
+        self.state.output(name)
+    }
+
+    /// The value on the blackboard at `key`, if it is a `T`. See
+    /// [`RunContext::read`].
+    pub fn read<T: Any + Send + Sync>(&self, key: &str) -> Option<Arc<T>> {
+        self.state.read(key)
+    }
+
+    /// Puts `value` on the blackboard at `key`, for example to seed it before
+    /// the first dispatch.
+    pub fn write<T: Any + Send + Sync>(&self, key: &str, value: T) {
+        self.state.write(key, value);
+    }
+
+    /// Runs every plugin, regardless of its phases.
+    pub fn dispatch(&self) {
+        self.run(&self.schedule);
+    }
+
+    /// Runs the plugins that belong to `phase`.
+    ///
+    /// Each phase has its own plan: a plugin waits for those of its
+    /// dependencies that belong to the same phase, and ignores the others.
+    pub fn dispatch_phase(&self, phase: Phase) {
+        self.run(&self.phases[phase as usize]);
+    }
+
+    fn run(&self, schedule: &Schedule) {
+        self.state.begin();
+        schedule
+            .stages()
+            .iter()
+            .flatten()
+            .for_each(|&slot| retry::run(&**self.at(slot), &self.state));
+    }
+
+    /// Like [`dispatch`](Self::dispatch), but runs every plugin through
+    /// [`Plugin::run_mut`], with exclusive access to it.
+    ///
+    /// # Panics
+    ///
+    /// If a scheduled plugin is shared through a [`PluginHandle`], such as
+    /// one returned by [`plugin`](Self::plugin), since the dispatcher
+    /// cannot rule out that it is in use elsewhere.
+    pub fn dispatch_mut(&mut self) {
+        self.state.begin();
+        for &slot in self.schedule.stages().iter().flatten() {
+            let plugin = self.plugins[slot].as_mut().unwrap().exclusive();
+            retry::run_mut(plugin, &self.state);
+        }
+    }
+
+    /// Like [`dispatch`](Self::dispatch), but times every plugin and
+    /// catches panics, recording them in the report with a
+    /// [`PanicReport`] instead of printing them. What happens after a panic
+    /// depends on the [`ErrorPolicy`].
+    pub fn dispatch_report(&self) -> DispatchReport {
+        self.dispatch_with_observer(())
+    }
+
+    pub fn error_policy(&self) -> ErrorPolicy {
+        self.error_policy
+    }
+
+    pub fn set_error_policy(&mut self, error_policy: ErrorPolicy) {
+        self.error_policy = error_policy;
+    }
+
+    pub fn environment(&self) -> &Environment {
+        &self.state.environment
+    }
+
+    /// The settings plugins read from [`RunContext::environment`], which
+    /// can change between dispatches.
+    pub fn environment_mut(&mut self) -> &mut Environment {
+        &mut self.state.environment
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.state.args
+    }
+
+    /// Sets the arguments plugins get from [`RunContext::args`] in the
+    /// following dispatches.
+    pub fn set_args(&mut self, args: impl IntoIterator<Item = impl Into<String>>) {
+        self.state.args = args.into_iter().map(Into::into).collect();
+    }
+}
+
+impl<L: Send + Sync> Dispatcher<L> {
+    pub fn dispatch_par(&self) {
+        self.run_par(&self.schedule);
+    }
+
+    /// Like [`dispatch_phase`](Dispatcher::dispatch_phase), but runs the
+    /// plugins within a stage in parallel.
+    pub fn dispatch_phase_par(&self, phase: Phase) {
+        self.run_par(&self.phases[phase as usize]);
+    }
+
+    /// Like [`dispatch_par`](Self::dispatch_par), but runs every plugin
+    /// through [`Plugin::run_mut`], with exclusive access to it. The plugins
+    /// of a stage are distinct, so each thread gets its own.
+    ///
+    /// # Panics
+    ///
+    /// See [`dispatch_mut`](Dispatcher::dispatch_mut).
+    pub fn dispatch_par_mut(&mut self) {
+        self.state.begin();
+        let mut plugins: Vec<_> = self.plugins.iter_mut().map(Option::as_mut).collect();
+        for stage in self.schedule.stages() {
+            let stage: Vec<_> =
+                stage.iter().map(|&slot| plugins[slot].take().unwrap().exclusive()).collect();
+            self.executor.map(stage, |plugin| retry::run_mut(plugin, &self.state));
+        }
+    }
+
+    fn run_par(&self, schedule: &Schedule) {
+        self.state.begin();
+        for (index, stage) in schedule.stages().iter().enumerate() {
+            self.record(
+                SpanKind::Stage,
+                || format!("stage {index}"),
+                || {
+                    self.executor.map(stage.to_vec(), |slot| {
+                        let plugin = self.at(slot);
+                        self.record(
+                            SpanKind::Plugin,
+                            || plugin.name().to_owned(),
+                            || retry::run(&**plugin, &self.state),
+                        );
+                    });
+                },
+            );
+        }
+    }
+
+    /// Runs `f`, recording it as a span with the `profile` feature.
+    #[cfg_attr(not(feature = "profile"), allow(unused_variables))]
+    fn record(&self, kind: SpanKind, name: impl FnOnce() -> String, f: impl FnOnce()) {
+        #[cfg(feature = "profile")]
+        self.profile.record(kind, name(), f);
+        #[cfg(not(feature = "profile"))]
+        f();
+    }
+
+    /// Like [`dispatch_par`](Self::dispatch_par), but produces a report. See
+    /// [`dispatch_report`](Self::dispatch_report).
+    pub fn dispatch_par_report(&self) -> DispatchReport {
+        self.report_par(&self.schedule)
+    }
+
+    /// Like [`dispatch_phase_par`](Self::dispatch_phase_par), but produces
+    /// a report. See [`dispatch_report`](Dispatcher::dispatch_report).
+    pub fn dispatch_phase_par_report(&self, phase: Phase) -> DispatchReport {
+        self.report_par(&self.phases[phase as usize])
+    }
+
+    fn report_par(&self, schedule: &Schedule) -> DispatchReport {
+        let _hook = panic_hook::Scope::enter();
+        let start = Instant::now();
+        self.state.begin();
+        let mut failures = Failures::new(self.error_policy);
+        let stages = schedule
+            .stages()
+            .iter()
+            .map(|stage| {
+                let reports = self
+                    .executor
+                    .map(stage.to_vec(), |slot| failures.run(slot, &**self.at(slot), &self.state));
+                failures.record(schedule, stage, &reports);
+                self.history.record(&reports);
+                reports
+            })
+            .collect();
+
+        DispatchReport { stages, duration: start.elapsed(), policy: failures.policy() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex, OnceLock};
+
+    use crate::sha2::Sha256;
+    use crate::{
+        API_VERSIONS, Dispatcher, ErrorPolicy, Features, FnPlugin, GraphFormat, Host, HostApi,
+        Lazy, LazyPlugin, LoadPhase, LoadPolicy, Loader, LogLevel, Native, Phase, Plugin,
+        PluginHandle, PluginLoadError, PluginManager, PluginManagerBuilder, PluginStatus,
+        ResourceError, Resources, Result, RunContext, Scheduler, define_plugins,
+    };
+
+    fn capture(f: impl FnOnce()) -> String {
+        std::io::set_output_capture(Some(Default::default()));
+
+        f();
+
+        let captured = std::io::set_output_capture(None);
+        let captured = captured.unwrap();
+        let captured = Arc::try_unwrap(captured).unwrap();
+        let captured = captured.into_inner().unwrap();
+        String::from_utf8(captured).unwrap()
+    }
+
+    #[test]
+    fn smoke() {
+        define_plugins! {
+            A {
+                run: {
+                    println!("A");
+                }
+            },
+            B {
+                run: {
+                    println!("B");
+                },
+                dependencies: ["A"]
+            }
+        }
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+
+        unsafe { manager.load_plugin("B").unwrap() };
+        unsafe { manager.load_plugin("A").unwrap() };
+
+        let dispatcher = manager.into_dispatcher();
+
+        assert_eq!(capture(|| dispatcher.dispatch()), "A\nB\n");
+    }
+
+    #[test]
+    fn api_version() {
+        assert_eq!(super::negotiate(&[1, 2, 3], &[2, 3, 4]), 3);
+        assert_eq!(super::negotiate(&[1], &[2]), 0);
+        assert_eq!(super::negotiate(&[], API_VERSIONS), 0);
+
+        let offered = [0, API_VERSIONS[0]];
+        let version = unsafe { super::__api_version(offered.as_ptr(), offered.len()) };
+        assert_eq!(version, API_VERSIONS[0]);
+    }
+
+    #[test]
+    fn dispatch_mut() {
+        struct Counter {
+            count: usize,
+        }
+
+        impl Plugin for Counter {
+            fn run(&self, _: &RunContext) {
+                panic!("dispatched mutably");
+            }
+
+            fn run_mut(&mut self, context: &RunContext) {
+                self.count += 1;
+                context.output(self.count);
+            }
+        }
+
+        let mut manager = PluginManager::new();
+        manager.register(Box::new(Counter { count: 0 })).unwrap();
+        let mut dispatcher = manager.into_dispatcher();
+        let name = "Counter";
+
+        dispatcher.dispatch_mut();
+        dispatcher.dispatch_mut();
+        assert_eq!(dispatcher.output::<usize>(name).as_deref(), Some(&2));
+        dispatcher.dispatch_par_mut();
+        assert_eq!(dispatcher.output::<usize>(name).as_deref(), Some(&3));
+
+        let handle = dispatcher.plugin(name).unwrap();
+        let shared = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            dispatcher.dispatch_mut();
+        }));
+        assert!(shared.is_err());
+        drop(handle);
+    }
+
+    #[test]
+    fn load_error() {
+        // Starts like a library, so only opening it fails.
+        let path = std::env::temp_dir().join(format!(
+            "sora-truncated-{}.{}",
+            std::process::id(),
+            std::env::consts::DLL_EXTENSION
+        ));
+        let exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        std::fs::write(&path, &exe[..4]).unwrap();
+        let mut manager = PluginManager::new();
+        let declared = LazyPlugin::new("Truncated", &[]);
+        let error = unsafe { manager.load_plugin_lazy(&path, declared) }.unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(error.path(), Some(path.as_path()));
+        assert_eq!(error.phase(), Some(LoadPhase::Open));
+        assert_eq!(error.plugin(), Some("Truncated"));
+        assert!(
+            error
+                .to_string()
+                .starts_with(&format!("cannot open {} for plugin `Truncated`: ", path.display()))
+        );
+
+        let path = path.with_extension("txt");
+        let declared = LazyPlugin::new("Text", &[]);
+        let error = unsafe { manager.load_plugin_lazy(&path, declared) }.unwrap_err();
+        assert!(matches!(error, PluginLoadError::NotALibrary { .. }));
+
+        if cfg!(target_os = "linux") {
+            let entries = ["create_plugin", "plugin_create"];
+            let Err(error) = (unsafe { Native.load_entries("libc.so.6", &entries) }) else {
+                panic!("libc is not a plugin");
+            };
+            assert_eq!(error.phase(), Some(LoadPhase::Symbol(entries.map(String::from).into())));
+            assert_eq!(error.plugin(), None);
+            assert!(
+                error.to_string().starts_with(
+                    "cannot find any of `create_plugin`, `plugin_create` in libc.so.6: "
+                )
+            );
+
+            let Err(error) = (unsafe { Native.load("libc.so.6") }) else {
+                panic!("libc is not a plugin");
+            };
+            assert!(error.to_string().starts_with(
+                "cannot find any of `sora_create_plugin_v1`, `create_plugin`, `plugin_create`, \
+                 `_plugin_entry` in libc.so.6: "
+            ));
+        }
+    }
+
+    #[test]
+    fn load_dir_par() {
+        define_plugins! {
+            A {
+                run: {
+                    println!("A");
+                }
+            },
+            B {
+                run: {
+                    println!("B");
+                },
+                dependencies: ["A"]
+            },
+            C {
+                run: {
+                    println!("C");
+                },
+                dependencies: ["B"]
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("sora-load-dir-par-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["C", "A", "Unknown", "B"] {
+            std::fs::write(dir.join(name), []).unwrap();
+        }
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        let errors = unsafe { manager.load_dir_par(&dir).unwrap() };
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, dir.join("Unknown"));
+
+        let dispatcher = manager.into_dispatcher();
+
+        assert_eq!(capture(|| dispatcher.dispatch()), "A\nB\nC\n");
+    }
+
+    #[test]
+    fn multiple_plugins_per_library() {
+        struct A;
+
+        impl Plugin for A {
+            fn run(&self, _: &RunContext) {
+                println!("A");
+            }
+        }
+
+        struct B;
+
+        impl Plugin for B {
+            fn dependencies(&self) -> &'static [&'static str] {
+                &["A"]
+            }
+
+            fn run(&self, _: &RunContext) {
+                println!("B");
+            }
+        }
+
+        #[derive(Default)]
+        struct BundleLoader;
+
+        impl Loader for BundleLoader {
+            type Library = ();
+            type Error = PluginLoadError;
+
+            unsafe fn load(
+                &self,
+                _: impl AsRef<OsStr>,
+            ) -> Result<(Self::Library, Box<dyn Plugin>)> {
+                unreachable!()
+            }
+
+            unsafe fn load_plugins(
+                &self,
+                filename: impl AsRef<OsStr>,
+                _: &[&str],
+            ) -> Result<(Self::Library, crate::Plugins)> {
+                let plugins: Vec<Box<dyn Plugin>> = match filename.as_ref().to_str().unwrap() {
+                    "bundle" => vec![Box::new(B), Box::new(A)],
+                    _ => vec![Box::new(A)],
+                };
+                Ok(((), plugins))
+            }
+        }
+
+        let mut manager: PluginManager<BundleLoader> = PluginManager::default();
+        unsafe { manager.load_plugin("bundle").unwrap() };
+
+        // A library is rejected as a whole.
+        assert!(matches!(
+            unsafe { manager.load_plugin("single") },
+            Err(PluginLoadError::Duplicate(name)) if name == "A"
+        ));
+
+        let dispatcher = manager.into_dispatcher();
+
+        assert_eq!(capture(|| dispatcher.dispatch()), "A\nB\n");
+    }
+
+    #[test]
+    fn loader_instance() {
+        struct A;
+
+        impl Plugin for A {
+            fn run(&self, _: &RunContext) {}
+        }
+
+        #[derive(Debug, PartialEq, thiserror::Error)]
+        #[error("outside of {0}")]
+        struct OutsideRoot(&'static str);
+
+        /// Only loads libraries under `root`, and counts the loads.
+        struct RootedLoader {
+            root: &'static str,
+            loads: AtomicUsize,
+        }
+
+        impl Loader for RootedLoader {
+            type Library = ();
+            type Error = OutsideRoot;
+
+            unsafe fn load(
+                &self,
+                filename: impl AsRef<OsStr>,
+            ) -> Result<((), Box<dyn Plugin>), OutsideRoot> {
+                if !Path::new(filename.as_ref()).starts_with(self.root) {
+                    return Err(OutsideRoot(self.root));
+                }
+                self.loads.fetch_add(1, Ordering::SeqCst);
+                Ok(((), Box::new(A)))
+            }
+        }
+
+        let loader = RootedLoader { root: "plugins", loads: AtomicUsize::new(0) };
+        let mut manager = PluginManager::with_loader(loader);
+        let error = unsafe { manager.load_plugin("elsewhere/A") }.unwrap_err();
+        assert_eq!(error.to_string(), "cannot load elsewhere/A: outside of plugins");
+        let PluginLoadError::Loader { source, .. } = error else { panic!("{error:?}") };
+        assert_eq!(source.downcast_ref(), Some(&OutsideRoot("plugins")));
+        unsafe { manager.load_plugin("plugins/A").unwrap() };
+        assert_eq!(manager.loader().loads.load(Ordering::SeqCst), 1);
+
+        let loader = RootedLoader { root: "vendor", loads: AtomicUsize::new(0) };
+        let builder = PluginManagerBuilder::with_loader(loader).library("vendor/A");
+        let manager = unsafe { builder.build().unwrap() };
+        assert_eq!(manager.loader().loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn lazy() {
+        define_plugins! {
+            A {
+                run: {
+                    println!("A");
+                }
+            }
+        }
+
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        let declared =
+            LazyPlugin { version: Some("1.0"), tags: &["lazy"], ..LazyPlugin::new("A", &[]) };
+        let plugin = Lazy::new(declared, || {
+            CREATED.fetch_add(1, Ordering::SeqCst);
+            Box::new(A)
+        });
+        assert_eq!(plugin.save_state(), None);
+        manager.register(Box::new(plugin)).unwrap();
+        assert_eq!(manager.metadata()[0].version.as_deref(), Some("1.0"));
+
+        let dispatcher = manager.into_dispatcher();
+        assert_eq!(CREATED.load(Ordering::SeqCst), 0);
+
+        assert_eq!(capture(|| dispatcher.dispatch()), "A\n");
+        assert_eq!(capture(|| dispatcher.dispatch()), "A\n");
+        assert_eq!(CREATED.load(Ordering::SeqCst), 1);
+
+        let plugin = Lazy::new(LazyPlugin::new("B", &[]), || Box::new(A));
+        let created =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| plugin.get().name().len()));
+        assert!(created.is_err());
+    }
+
+    #[test]
+    fn signature() {
+        define_plugins! {
+            A {
+                run: {}
+            },
+            B {
+                run: {}
+            },
+            C {
+                run: {}
+            }
+        }
+
+        // RFC 8032, test 1: the signature of an empty message.
+        let unhex = |hex: &str| -> Vec<u8> {
+            (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+                .collect()
+        };
+        let public_key = unhex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+        let signature = unhex(
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        );
+
+        let dir = std::env::temp_dir().join(format!("sora-signature-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("A"), []).unwrap();
+        std::fs::write(dir.join("A.sig"), &signature).unwrap();
+        std::fs::write(dir.join("B"), []).unwrap();
+        std::fs::write(dir.join("C"), [0]).unwrap();
+        std::fs::write(dir.join("C.sig"), &signature).unwrap();
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        manager.trust_key(public_key.try_into().unwrap());
+
+        let results = ["A", "B", "C"].map(|name| unsafe { manager.load_plugin(dir.join(name)) });
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(results[0].is_ok());
+        assert!(
+            matches!(&results[1], Err(PluginLoadError::SignatureInvalid(path)) if path.ends_with("B"))
+        );
+        assert!(
+            matches!(&results[2], Err(PluginLoadError::SignatureInvalid(path)) if path.ends_with("C"))
+        );
+    }
+
+    #[test]
+    fn checksum() {
+        define_plugins! {
+            A {
+                run: {}
+            },
+            B {
+                run: {}
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("sora-checksum-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("A"), []).unwrap();
+        std::fs::write(dir.join("B"), [0]).unwrap();
+
+        // SHA-256 of the empty string.
+        let empty = Sha256::digest(&[]);
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        manager.pin_sha256(dir.join("A"), empty);
+        manager.pin_sha256(dir.join("B"), empty);
+
+        let results = ["A", "B"].map(|name| unsafe { manager.load_plugin(dir.join(name)) });
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(results[0].is_ok());
+        assert!(
+            matches!(&results[1], Err(PluginLoadError::ChecksumMismatch { path, .. }) if path.ends_with("B"))
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn verified_origin() {
+        // An ELF file that needs `libsora-dep.so`, found next to it through
+        // `$ORIGIN`, and nothing else.
+        let strings = b"\0libsora-dep.so\0$ORIGIN\0";
+        let dynamic: Vec<u8> = [(1_u64, 1_u64), (29, 16), (0, 0)]
+            .iter()
+            .flat_map(|&(tag, value)| [tag.to_le_bytes(), value.to_le_bytes()].concat())
+            .collect();
+        let section = |kind: u32, offset: usize, size: usize, link: u32| {
+            let mut header = [0; 64];
+            header[4..8].copy_from_slice(&kind.to_le_bytes());
+            header[24..32].copy_from_slice(&(offset as u64).to_le_bytes());
+            header[32..40].copy_from_slice(&(size as u64).to_le_bytes());
+            header[40..44].copy_from_slice(&link.to_le_bytes());
+            header
+        };
+        let mut elf = vec![0; 64];
+        elf[..7].copy_from_slice(b"\x7fELF\x02\x01\x01");
+        let sections = 64 + dynamic.len() + strings.len();
+        elf[0x28..0x30].copy_from_slice(&(sections as u64).to_le_bytes());
+        elf[0x3a..0x3c].copy_from_slice(&64_u16.to_le_bytes());
+        elf[0x3c..0x3e].copy_from_slice(&3_u16.to_le_bytes());
+        elf.extend(&dynamic);
+        elf.extend(strings);
+        elf.extend(section(0, 0, 0, 0));
+        elf.extend(section(6, 64, dynamic.len(), 2));
+        elf.extend(section(3, 64 + dynamic.len(), strings.len(), 0));
+
+        let dir = std::env::temp_dir().join(format!("sora-origin-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("libplugin.so");
+        let key = crate::SigningKey::from_seed([7; 32]);
+        std::fs::write(&path, &elf).unwrap();
+        std::fs::write(dir.join("libplugin.so.sig"), key.sign(&elf)).unwrap();
+
+        let mut manager = PluginManager::new();
+        manager.trust_key(key.public_key());
+        manager.set_native_options(crate::NativeLoaderOptions::new().check_dependencies());
+        let missing = unsafe { manager.load_plugin(&path) }.unwrap_err();
+        std::fs::write(dir.join("libsora-dep.so"), []).unwrap();
+        // Found, so the load gets as far as opening the library, which is
+        // not one.
+        let open = unsafe { manager.load_plugin(&path) }.unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let path = path.canonicalize().unwrap_or(path);
+        assert!(matches!(
+            missing,
+            PluginLoadError::MissingDependencies { path: found, missing }
+                if found == path && missing == ["libsora-dep.so"]
+        ));
+        assert_eq!(open.phase(), Some(LoadPhase::Open));
+        assert!(matches!(open, PluginLoadError::Library { path: found, .. } if found == path));
+    }
+
+    #[test]
+    fn checksum_replaced() {
+        /// Replaces the library once it is checked, and only loads it as
+        /// it was.
+        #[derive(Default)]
+        struct ReplacingLoader;
+
+        impl Loader for ReplacingLoader {
+            type Library = ();
+            type Error = PluginLoadError;
+
+            fn check_file(&self, path: &Path) -> Result<()> {
+                std::fs::write(path, [0]).map_err(PluginLoadError::Io)
+            }
+
+            unsafe fn load(
+                &self,
+                filename: impl AsRef<OsStr>,
+            ) -> Result<(Self::Library, Box<dyn Plugin>)> {
+                let library = std::fs::read(filename.as_ref()).map_err(PluginLoadError::Io)?;
+                assert!(library.is_empty());
+                Ok(((), Box::new(FnPlugin::new("A", &[], |_| {}))))
+            }
+        }
+
+        let path = std::env::temp_dir().join(format!("sora-replaced-{}", std::process::id()));
+        std::fs::write(&path, []).unwrap();
+
+        let mut manager: PluginManager<ReplacingLoader> = PluginManager::default();
+        manager.pin_sha256(&path, Sha256::digest(&[]));
+        let result = unsafe { manager.load_plugin(&path) };
+        std::fs::remove_file(&path).unwrap();
+
+        result.unwrap();
+    }
+
+    #[test]
+    fn load_policy() {
+        define_plugins! {
+            A {
+                run: {
+                    println!("A");
+                }
+            },
+            B {
+                run: {
+                    println!("B");
+                }
+            },
+            C {
+                run: {
+                    println!("C");
+                },
+                dependencies: ["A"]
+            }
+        }
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        manager.set_load_policy(LoadPolicy::deny(["B"]));
+        unsafe { manager.load_plugin("A").unwrap() };
+        assert!(matches!(
+            unsafe { manager.load_plugin("B") },
+            Err(PluginLoadError::Denied(name)) if name == "B"
+        ));
+
+        manager.set_load_policy(LoadPolicy::callback(|plugin| plugin.dependencies().is_empty()));
+        assert!(matches!(unsafe { manager.load_plugin("C") }, Err(PluginLoadError::Denied(_))));
+        assert!(matches!(
+            unsafe { manager.load_plugin("A") },
+            Err(PluginLoadError::Duplicate(name)) if name == "A"
+        ));
+
+        let dispatcher = manager.into_dispatcher();
+
+        assert_eq!(capture(|| dispatcher.dispatch()), "A\n");
+    }
+
+    #[test]
+    fn static_plugins() {
+        define_plugins! {
+            A {
+                run: {
+                    println!("A");
+                }
+            },
+            B {
+                run: {
+                    println!("B");
+                },
+                dependencies: ["A", "C"]
+            },
+            C {
+                run: {
+                    println!("C");
+                },
+                dependencies: ["A"]
+            }
+        }
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        unsafe { manager.load_plugin("B").unwrap() };
+        register_static_plugins!(manager, A, C).unwrap();
+
+        let dispatcher = manager.into_dispatcher();
+
+        assert_eq!(capture(|| dispatcher.dispatch()), "A\nC\nB\n");
+    }
+
+    #[test]
+    fn metadata() {
+        struct Physics;
+        impl Plugin for Physics {
+            fn version(&self) -> Option<&'static str> {
+                Some("1.2.0")
+            }
+
+            fn description(&self) -> Option<&'static str> {
+                Some("Rigid body simulation")
+            }
+
+            fn authors(&self) -> &'static [&'static str] {
+                &["Ada", "Grace"]
+            }
+
+            fn run(&self, _: &RunContext) {}
+        }
+
+        struct Render;
+        impl Plugin for Render {
+            fn dependencies(&self) -> &'static [&'static str] {
+                &["Physics"]
+            }
+
+            fn run(&self, _: &RunContext) {}
+        }
+
+        let mut manager = PluginManager::new();
+        manager.register(Box::new(Physics)).unwrap();
+        manager.register(Box::new(Render)).unwrap();
+
+        let [physics, render] = manager.metadata() else { panic!() };
+        assert_eq!(physics.name, "Physics");
+        assert_eq!(physics.version.as_deref(), Some("1.2.0"));
+        assert_eq!(physics.description.as_deref(), Some("Rigid body simulation"));
+        assert_eq!(physics.authors, ["Ada", "Grace"]);
+        assert_eq!(render.name, "Render");
+        assert_eq!(render.version, None);
+        assert_eq!(render.dependencies, ["Physics"]);
+        assert_eq!(manager.plugin("Physics").unwrap().version(), Some("1.2.0"));
+    }
+
+    #[test]
+    fn phases() {
+        struct Window;
+        impl Plugin for Window {
+            fn phases(&self) -> &[Phase] {
+                &[Phase::Startup, Phase::Shutdown]
+            }
+
+            fn run(&self, _: &RunContext) {
+                println!("Window");
+            }
+        }
+
+        struct Render;
+        impl Plugin for Render {
+            fn dependencies(&self) -> &[&str] {
+                &["Window"]
+            }
+
+            fn run(&self, _: &RunContext) {
+                println!("Render");
+            }
+        }
+
+        struct Log;
+        impl Plugin for Log {
+            fn dependencies(&self) -> &[&str] {
+                &["Window"]
+            }
+
+            fn phases(&self) -> &[Phase] {
+                &Phase::ALL
+            }
+
+            fn run(&self, _: &RunContext) {
+                println!("Log");
+            }
+        }
+
+        let mut manager = PluginManager::new();
+        register_static_plugins!(manager, Log, Render, Window).unwrap();
+        let dispatcher = manager.into_dispatcher();
+
+        assert_eq!(capture(|| dispatcher.dispatch_phase(Phase::Startup)), "Window\nLog\n");
+        assert_eq!(capture(|| dispatcher.dispatch_phase(Phase::Update)), "Render\nLog\n");
+        assert_eq!(capture(|| dispatcher.dispatch_phase(Phase::Shutdown)), "Window\nLog\n");
+        assert_eq!(capture(|| dispatcher.dispatch()), "Window\nRender\nLog\n");
+
+        let names = |report: crate::DispatchReport| -> Vec<String> {
+            report.stages.into_iter().flatten().map(|plugin| plugin.name).collect()
+        };
+        let mut report = None;
+        capture(|| report = Some(dispatcher.dispatch_phase_report(Phase::Update)));
+        assert_eq!(names(report.unwrap()), ["Render", "Log"]);
+        let mut report = None;
+        capture(|| report = Some(dispatcher.dispatch_phase_par_report(Phase::Shutdown)));
+        assert_eq!(names(report.unwrap()), ["Window", "Log"]);
+    }
+
+    #[test]
+    fn pipelines() {
+        struct Physics;
+        impl Plugin for Physics {
+            fn tags(&self) -> &[&str] {
+                &["simulation"]
+            }
+
+            fn run(&self, _: &RunContext) {
+                println!("Physics");
+            }
+        }
+
+        struct Camera;
+        impl Plugin for Camera {
+            fn dependencies(&self) -> &[&str] {
+                &["Physics"]
+            }
+
+            fn tags(&self) -> &[&str] {
+                &["render", "simulation"]
+            }
+
+            fn run(&self, _: &RunContext) {
+                println!("Camera");
+            }
+        }
+
+        struct Render;
+        impl Plugin for Render {
+            fn dependencies(&self) -> &[&str] {
+                &["Camera"]
+            }
+
+            fn tags(&self) -> &[&str] {
+                &["render"]
+            }
+
+            fn run(&self, _: &RunContext) {
+                println!("Render");
+            }
+        }
+
+        let mut manager = PluginManager::new();
+        register_static_plugins!(manager, Physics, Camera, Render).unwrap();
+        let pipelines = manager.into_pipelines(["render", "simulation", "audio"]);
+
+        assert_eq!(capture(|| pipelines["render"].dispatch()), "Camera\nRender\n");
+        assert_eq!(capture(|| pipelines["simulation"].dispatch()), "Physics\nCamera\n");
+        assert_eq!(pipelines["audio"].plugins().count(), 0);
+    }
+
+    #[test]
+    fn builder() {
+        define_plugins! {
+            A {
+                run: {
+                    println!("A");
+                }
+            },
+            B {
+                run: {
+                    println!("B");
+                },
+                dependencies: ["A"]
+            },
+            C {
+                run: {
+                    println!("C");
+                },
+                dependencies: ["B"]
+            },
+            D {
+                run: {}
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("sora-builder-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("plugins")).unwrap();
+        for name in ["A", "Unknown"] {
+            std::fs::write(dir.join("plugins").join(name), []).unwrap();
+        }
+
+        let error = unsafe {
+            PluginManagerBuilder::<PluginLoader>::new()
+                .directory(dir.join("plugins"))
+                .library("B")
+                .plugin(D)
+                .manifest(dir.join("missing"))
+                .load_policy(LoadPolicy::deny(["D"]))
+                .build()
+                .err()
+                .unwrap()
+        };
+
+        let failures: Vec<_> = error.errors.iter().map(|(path, _)| path.clone()).collect();
+        assert_eq!(
+            failures,
+            [Some(dir.join("plugins").join("Unknown")), None, Some(dir.join("missing"))]
+        );
+
+        let error = unsafe {
+            PluginManagerBuilder::<PluginLoader>::new()
+                .directory(dir.join("plugins").join("A"))
+                .build()
+                .err()
+                .unwrap()
+        };
+        assert!(matches!(error.errors[..], [(Some(_), PluginLoadError::Io(_))]));
+
+        std::fs::remove_file(dir.join("plugins").join("Unknown")).unwrap();
+        let manager = unsafe {
+            PluginManagerBuilder::<PluginLoader>::new()
+                .directory(dir.join("plugins"))
+                .library("B")
+                .plugin(C)
+                .build()
+                .unwrap()
+        };
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let dispatcher = manager.into_dispatcher();
+
+        assert_eq!(capture(|| dispatcher.dispatch()), "A\nB\nC\n");
+    }
+
+    #[test]
+    fn filter() {
+        define_plugins! {
+            A {
+                run: {
+                    println!("A");
+                }
+            },
+            B {
+                run: {
+                    println!("B");
+                },
+                dependencies: ["A"]
+            },
+            C {
+                run: {
+                    println!("C");
+                },
+                dependencies: ["B"]
+            }
+        }
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        for name in ["C", "B", "A"] {
+            unsafe { manager.load_plugin(name).unwrap() };
+        }
+
+        let dispatcher =
+            manager.into_dispatcher_builder().filter(|plugin| plugin.name() != "B").build();
+
+        assert_eq!(dispatcher.plugins().count(), 2);
+        assert_eq!(capture(|| dispatcher.dispatch()), "A\nC\n");
+    }
+
+    /// Runs one plugin per stage, in the order they were registered.
+    struct Sequential;
+
+    impl Scheduler for Sequential {
+        fn schedule(&self, plugins: &[&dyn Plugin], _: &[Vec<usize>]) -> Vec<Vec<usize>> {
+            (0..plugins.len()).map(|index| vec![index]).collect()
+        }
+    }
+
+    #[test]
+    fn scheduler() {
+        define_plugins! {
+            A {
+                run: {
+                    println!("A");
+                }
+            },
+            B {
+                run: {
+                    println!("B");
+                }
+            },
+            C {
+                run: {
+                    println!("C");
+                },
+                dependencies: ["A"]
+            }
+        }
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        for name in ["A", "B", "C"] {
+            unsafe { manager.load_plugin(name).unwrap() };
+        }
+
+        let dispatcher = manager.into_dispatcher_builder().scheduler(Sequential).build();
+        assert_eq!(dispatcher.plan().stages.len(), 3);
+        assert_eq!(capture(|| dispatcher.dispatch()), "A\nB\nC\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "scheduler placed plugin 0 no later than 1, which it waits for")]
+    fn scheduler_order() {
+        define_plugins! {
+            A {
+                run: {}
+            },
+            B {
+                run: {},
+                dependencies: ["A"]
+            }
+        }
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        for name in ["B", "A"] {
+            unsafe { manager.load_plugin(name).unwrap() };
+        }
+
+        manager.into_dispatcher_builder().scheduler(Sequential).build();
+    }
+
+    #[test]
+    fn mutation() {
+        define_plugins! {
+            A {
+                run: {
+                    println!("A");
+                }
+            },
+            B {
+                run: {
+                    println!("B");
+                },
+                dependencies: ["A"]
+            },
+            C {
+                run: {
+                    println!("C");
+                },
+                dependencies: ["B"]
+            }
+        }
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        for name in ["C", "B", "A"] {
+            unsafe { manager.load_plugin(name).unwrap() };
+        }
+
+        let mut dispatcher = manager.into_dispatcher();
+        let stages = |dispatcher: &Dispatcher<()>| dispatcher.dispatch_report().stages.len();
+        assert_eq!(stages(&dispatcher), 3);
+
+        assert!(dispatcher.disable("B"));
+        assert!(!dispatcher.disable("B"));
+        assert_eq!(capture(|| dispatcher.dispatch()), "A\nC\n");
+        assert_eq!(stages(&dispatcher), 1);
+
+        assert!(dispatcher.enable("B"));
+        assert_eq!(capture(|| dispatcher.dispatch()), "A\nB\nC\n");
+        assert_eq!(stages(&dispatcher), 3);
+
+        assert!(dispatcher.remove("A").is_some());
+        assert!(dispatcher.remove("A").is_none());
+        assert!(!dispatcher.enable("A"));
+        assert_eq!(capture(|| dispatcher.dispatch()), "B\nC\n");
+        assert_eq!(stages(&dispatcher), 2);
+    }
+
+    #[test]
+    fn add_plugin() {
+        struct Named(&'static str, &'static [&'static str]);
+        impl Plugin for Named {
+            fn name(&self) -> &str {
+                self.0
+            }
+
+            fn dependencies(&self) -> &[&str] {
+                self.1
+            }
+
+            fn run(&self, _: &RunContext) {
+                println!("{}", self.0);
+            }
+        }
+
+        let handle =
+            |name, dependencies| PluginHandle::new(Box::new(Named(name, dependencies)), None);
+
+        let mut manager = PluginManager::new();
+        register_static_plugins!(manager, Named("A", &[]), Named("B", &["A"])).unwrap();
+        let mut dispatcher = manager.into_dispatcher();
+
+        dispatcher.add_plugin(handle("C", &["A"])).unwrap();
+        assert_eq!(capture(|| dispatcher.dispatch()), "A\nB\nC\n");
+        assert_eq!(dispatcher.dispatch_report().stages.len(), 2);
+
+        let error = |dispatcher: &mut Dispatcher<_>, name, dependencies| {
+            dispatcher.add_plugin(handle(name, dependencies)).unwrap_err().to_string()
+        };
+        assert_eq!(error(&mut dispatcher, "C", &[]), "a plugin named `C` is already loaded");
+        assert_eq!(
+            error(&mut dispatcher, "D", &["Missing"]),
+            "plugin `D` depends on `Missing`, which is not loaded"
+        );
+
+        // Plugins that depended on a removed plugin wait for its successor.
+        dispatcher.remove("A");
+        assert_eq!(error(&mut dispatcher, "A", &["C"]), "dependency cycle: A -> C -> A");
+        dispatcher.add_plugin(handle("A", &[])).unwrap();
+        assert_eq!(capture(|| dispatcher.dispatch()), "A\nB\nC\n");
+    }
+
+    #[test]
+    fn cancel() {
+        struct Worker;
+        impl Plugin for Worker {
+            fn run(&self, context: &RunContext) {
+                while !context.is_cancelled() {
+                    std::thread::yield_now();
+                }
+            }
+        }
+
+        let mut manager = PluginManager::new();
+        manager.register(Box::new(Worker)).unwrap();
+        let dispatcher = manager.into_dispatcher();
+        assert!(!dispatcher.is_cancelled());
+
+        std::thread::scope(|scope| {
+            let worker = scope.spawn(|| dispatcher.dispatch_par());
+            dispatcher.cancel();
+            worker.join().unwrap();
+        });
+
+        assert!(dispatcher.is_cancelled());
+        dispatcher.dispatch();
+
+        dispatcher.uncancel();
+        assert!(!dispatcher.is_cancelled());
+    }
+
+    #[test]
+    fn outputs() {
+        struct Physics;
+        impl Plugin for Physics {
+            fn run(&self, context: &RunContext) {
+                context.output(2_u32);
+            }
+        }
+
+        struct Render;
+        impl Plugin for Render {
+            fn dependencies(&self) -> &[&str] {
+                &["Physics"]
+            }
+
+            fn run(&self, context: &RunContext) {
+                let bodies = context.input::<u32>("Physics").unwrap();
+                context.output(format!("{bodies} bodies"));
+            }
+        }
+
+        let mut manager = PluginManager::new();
+        register_static_plugins!(manager, Render, Physics).unwrap();
+        let dispatcher = manager.into_dispatcher();
+
+        dispatcher.dispatch();
+        assert_eq!(dispatcher.output::<String>("Render").unwrap().as_str(), "2 bodies");
+
+        dispatcher.dispatch_phase(Phase::Startup);
+        assert!(dispatcher.output::<String>("Render").is_none());
+
+        assert!(dispatcher.dispatch_par_report().is_success());
+        assert_eq!(dispatcher.output::<u32>("Physics").as_deref(), Some(&2));
+    }
+
+    #[test]
+    fn blackboard() {
+        struct Spawn;
+        impl Plugin for Spawn {
+            fn writes(&self) -> &[&str] {
+                &["entities"]
+            }
+
+            fn run(&self, context: &RunContext) {
+                let entities = context.read::<u32>("entities").map_or(0, |entities| *entities);
+                context.write("entities", entities + 1);
+            }
+        }
+
+        struct Despawn;
+        impl Plugin for Despawn {
+            fn writes(&self) -> &[&str] {
+                &["entities"]
+            }
+
+            fn run(&self, context: &RunContext) {
+                let entities = *context.read::<u32>("entities").unwrap();
+                context.write("entities", entities - 1);
+            }
+        }
+
+        struct Count;
+        impl Plugin for Count {
+            fn reads(&self) -> &[&str] {
+                &["entities"]
+            }
+
+            fn run(&self, context: &RunContext) {
+                context.read::<u32>("entities");
+            }
+        }
+
+        let mut manager = PluginManager::new();
+        register_static_plugins!(manager, Spawn, Despawn, Count).unwrap();
+        let mut dispatcher = manager.into_dispatcher();
+        assert_eq!(dispatcher.stages().len(), 3);
+
+        dispatcher.write("entities", 1_u32);
+        for _ in 0..10 {
+            dispatcher.dispatch_par();
+        }
+        assert_eq!(dispatcher.read::<u32>("entities").as_deref(), Some(&1));
+
+        dispatcher.disable("Spawn");
+        assert_eq!(dispatcher.stages().len(), 2);
+        dispatcher.enable("Spawn");
+        assert_eq!(dispatcher.stages().len(), 3);
+    }
+
+    #[test]
+    fn resources() {
+        struct Pool(&'static str);
+
+        #[derive(Default)]
+        struct Database {
+            pool: Option<Arc<Pool>>,
+        }
+
+        impl Plugin for Database {
+            fn init(&mut self, resources: &Resources) -> std::result::Result<(), ResourceError> {
+                self.pool = Some(resources.get::<Pool>()?);
+                Ok(())
+            }
+
+            fn run(&self, _: &RunContext) {
+                println!("{}", self.pool.as_ref().unwrap().0);
+            }
+        }
+
+        let mut manager = PluginManager::new();
+        let error = manager.register(Box::new(Database::default())).unwrap_err();
+        assert!(matches!(
+            error,
+            PluginLoadError::Init { ref plugin, error: ResourceError::Missing(_) } if plugin == "Database"
+        ));
+        assert!(manager.plugin("Database").is_none());
+
+        let manager = unsafe {
+            PluginManager::builder()
+                .resource(Arc::new(Pool("postgres")))
+                .plugin(Database::default())
+                .build()
+                .unwrap()
+        };
+        assert!(manager.resources().contains::<Pool>());
+        let dispatcher = manager.into_dispatcher();
+        let output = capture(|| dispatcher.dispatch());
+        assert_eq!(output, "postgres\n");
+    }
+
+    #[test]
+    fn features() {
+        struct Gpu;
+
+        impl Host for Gpu {
+            fn features(&self) -> &Features {
+                static FEATURES: OnceLock<Features> = OnceLock::new();
+                FEATURES.get_or_init(|| ["gpu"].into_iter().collect())
+            }
+        }
+
+        #[derive(Default)]
+        struct Render {
+            gpu: bool,
+        }
+
+        impl Plugin for Render {
+            fn init(&mut self, resources: &Resources) -> std::result::Result<(), ResourceError> {
+                self.gpu = resources.get::<Features>()?.contains("gpu");
+                Ok(())
+            }
+
+            fn run(&self, _: &RunContext) {
+                println!("{}", if self.gpu { "GPU" } else { "CPU" });
+            }
+        }
+
+        let mut manager = PluginManager::new();
+        manager.set_host(Gpu);
+        manager.register(Box::new(Render::default())).unwrap();
+        let dispatcher = manager.into_dispatcher();
+        assert_eq!(capture(|| dispatcher.dispatch()), "GPU\n");
+    }
+
+    #[test]
+    fn dispatch_report() {
+        define_plugins! {
+            A {
+                run: {
+                    panic!("A failed");
+                }
+            },
+            B {
+                run: {},
+                dependencies: ["A"]
+            }
+        }
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        unsafe { manager.load_plugin("A").unwrap() };
+        unsafe { manager.load_plugin("B").unwrap() };
+
+        let dispatcher = manager.into_dispatcher();
+
+        for report in [dispatcher.dispatch_report(), dispatcher.dispatch_par_report()] {
+            let statuses: Vec<_> =
+                report.stages.iter().flatten().map(|plugin| &plugin.status).collect();
+            assert_eq!(
+                statuses,
+                [&PluginStatus::Panicked("A failed".to_owned()), &PluginStatus::Succeeded]
+            );
+            assert_eq!(report.stages.len(), 2);
+
+            let panic = report.stages[0][0].panic.as_ref().unwrap();
+            assert!(panic.location.as_ref().unwrap().starts_with(file!()), "{panic:?}");
+            assert!(!panic.thread.is_empty() && !panic.backtrace.is_empty());
+            assert_eq!(report.stages[1][0].panic, None);
+        }
+    }
+
+    #[test]
+    fn capture_output() {
+        define_plugins! {
+            A {
+                run: {
+                    HostApi::default_host().log(LogLevel::Warn, "A");
+                }
+            },
+            B {
+                run: {
+                    HostApi::default_host().log(LogLevel::Info, "B");
+                    panic!("B failed");
+                }
+            }
+        }
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        unsafe { manager.load_plugin("A").unwrap() };
+        unsafe { manager.load_plugin("B").unwrap() };
+        let dispatcher = manager.into_dispatcher_builder().capture_output(true).build();
+
+        for report in [dispatcher.dispatch_report(), dispatcher.dispatch_par_report()] {
+            let mut outputs: Vec<_> =
+                report.plugins().map(|plugin| (&*plugin.name, &*plugin.output)).collect();
+            outputs.sort();
+            assert_eq!(outputs, [("A", "[Warn] A\n"), ("B", "[Info] B\n")]);
+        }
+    }
+
+    #[test]
+    fn error_policy() {
+        define_plugins! {
+            A {
+                run: {
+                    panic!("A failed");
+                }
+            },
+            B {
+                run: {},
+                dependencies: ["A"]
+            },
+            C {
+                run: {},
+                dependencies: ["B"]
+            },
+            D {
+                run: {}
+            },
+            E {
+                run: {},
+                dependencies: ["D"]
+            }
+        }
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        for name in ["A", "B", "C", "D", "E"] {
+            unsafe { manager.load_plugin(name).unwrap() };
+        }
+        let mut dispatcher =
+            manager.into_dispatcher_builder().error_policy(ErrorPolicy::FailFast).build();
+
+        let skipped = |dispatcher: &Dispatcher<()>| {
+            [dispatcher.dispatch_report(), dispatcher.dispatch_par_report()].map(|report| {
+                assert_eq!(report.policy, dispatcher.error_policy());
+                let mut skipped: Vec<_> = report
+                    .plugins()
+                    .filter_map(|plugin| match &plugin.status {
+                        PluginStatus::Skipped(cause) => {
+                            assert_eq!(cause, "A");
+                            Some(plugin.name.clone())
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                skipped.sort();
+                skipped
+            })
+        };
+
+        assert_eq!(skipped(&dispatcher), [["B", "C", "E"], ["B", "C", "E"]]);
+
+        dispatcher.set_error_policy(ErrorPolicy::SkipDependents);
+        assert_eq!(skipped(&dispatcher), [["B", "C"], ["B", "C"]]);
+
+        dispatcher.set_error_policy(ErrorPolicy::Continue);
+        assert_eq!(skipped(&dispatcher), [[] as [String; 0], []]);
+    }
+
+    #[test]
+    fn graph() {
+        define_plugins! {
+            A {
+                run: {}
+            },
+            B {
+                run: {},
+                dependencies: ["A"]
+            },
+            C {
+                run: {},
+                dependencies: ["A"]
+            },
+            D {
+                run: {},
+                dependencies: ["B", "C"]
+            }
+        }
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        for name in ["D", "C", "B", "A"] {
+            unsafe { manager.load_plugin(name).unwrap() };
+        }
+
+        let dispatcher = manager.into_dispatcher();
+
+        assert_eq!(
+            dispatcher.graph(GraphFormat::Dot),
+            r#"digraph plugins {
+    subgraph cluster_0 {
+        label = "stage 0";
+        n0 [label = "A"];
+    }
+    subgraph cluster_1 {
+        label = "stage 1";
+        n1 [label = "C"];
+        n2 [label = "B"];
+    }
+    subgraph cluster_2 {
+        label = "stage 2";
+        n3 [label = "D"];
+    }
+    n0 -> n1;
+    n0 -> n2;
+    n2 -> n3;
+    n1 -> n3;
+}
+"#
+        );
+        assert!(dispatcher.graph(GraphFormat::Mermaid).contains(
+            r#"    subgraph stage1 ["stage 1"]
+        n1["C"]
+        n2["B"]
+    end"#
+        ));
+    }
+
+    #[test]
+    fn warnings() {
+        struct Fake {
+            name: &'static str,
+            dependencies: &'static [&'static str],
+            reads: &'static [&'static str],
+            writes: &'static [&'static str],
+        }
+
+        impl Plugin for Fake {
+            fn name(&self) -> &str {
+                self.name
+            }
+
+            fn dependencies(&self) -> &[&str] {
+                self.dependencies
+            }
+
+            fn reads(&self) -> &[&str] {
+                self.reads
+            }
+
+            fn writes(&self) -> &[&str] {
+                self.writes
+            }
+
+            fn run(&self, _: &RunContext) {}
+        }
+
+        let mut manager = PluginManager::new();
+        for plugin in [
+            Fake { name: "Physics", dependencies: &[], reads: &["input"], writes: &["time"] },
+            Fake {
+                name: "Render",
+                dependencies: &["Physics", "Physics", "Physics"],
+                reads: &["time", "camera"],
+                writes: &[],
+            },
+        ] {
+            manager.register(Box::new(plugin)).unwrap();
+        }
+
+        let warnings: Vec<_> = manager.warnings().iter().map(ToString::to_string).collect();
+        assert_eq!(
+            warnings,
+            [
+                "plugin `Physics` reads `input` from the blackboard, which no plugin writes",
+                "plugin `Render` declares its dependency on `Physics` more than once",
+                "plugin `Render` reads `camera` from the blackboard, which no plugin writes",
+            ]
+        );
+    }
+
+    #[test]
+    fn validate() {
+        define_plugins! {
+            A {
+                run: {},
+                dependencies: ["C"]
+            },
+            B {
+                run: {},
+                dependencies: ["A", "Missing"]
+            },
+            C {
+                run: {},
+                dependencies: ["B"]
+            },
+            D {
+                run: {},
+                dependencies: ["D"]
+            },
+            E {
+                run: {},
+                dependencies: ["A"]
+            }
+        }
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        for name in ["A", "B", "C", "E"] {
+            unsafe { manager.load_plugin(name).unwrap() };
+        }
+        let error = unsafe { manager.load_plugin("D") }.unwrap_err();
+        assert!(matches!(error, PluginLoadError::SelfDependency(ref plugin) if plugin == "D"));
+        assert!(manager.plugin("D").is_none());
+
+        let errors: Vec<_> =
+            manager.validate().unwrap_err().iter().map(ToString::to_string).collect();
+        assert_eq!(
+            errors,
+            [
+                "plugin `B` depends on `Missing`, which is not loaded",
+                "dependency cycle: A -> C -> B -> A",
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_library() {
+        static CLOSED: AtomicUsize = AtomicUsize::new(0);
+        static LOADS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Library;
+
+        impl Drop for Library {
+            fn drop(&mut self) {
+                CLOSED.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        struct Named(&'static str);
+
+        impl Plugin for Named {
+            fn name(&self) -> &str {
+                self.0
+            }
+
+            fn run(&self, _: &RunContext) {}
+        }
+
+        #[derive(Default)]
+        struct PluginLoader;
+
+        impl Loader for PluginLoader {
+            type Library = Library;
+            type Error = PluginLoadError;
+
+            unsafe fn load(
+                &self,
+                _: impl AsRef<OsStr>,
+            ) -> Result<(Self::Library, Box<dyn Plugin>)> {
+                let name = ["A", "B"][LOADS.fetch_add(1, Ordering::SeqCst)];
+                Ok((Library, Box::new(Named(name))))
+            }
+        }
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        unsafe { manager.load_plugin("shared").unwrap() };
+        unsafe { manager.load_plugin("shared").unwrap() };
+        // The second copy is closed right away, as the plugins share the
+        // first.
+        assert_eq!(CLOSED.load(Ordering::SeqCst), 1);
+
+        let mut dispatcher = manager.into_dispatcher();
+        let a = dispatcher.remove("A").unwrap();
+        let b = dispatcher.remove("B").unwrap();
+        assert!(Arc::ptr_eq(a.library.as_ref().unwrap(), b.library.as_ref().unwrap()));
+
+        drop(dispatcher);
+        drop(a);
+        assert_eq!(CLOSED.load(Ordering::SeqCst), 1);
+        drop(b);
+        assert_eq!(CLOSED.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn drop_order() {
+        static DROPPED: Mutex<Vec<&str>> = Mutex::new(Vec::new());
+
+        struct Library;
+
+        impl Drop for Library {
+            fn drop(&mut self) {
+                DROPPED.lock().unwrap().push("library");
+            }
+        }
+
+        struct A;
+
+        impl Plugin for A {
+            fn run(&self, _: &RunContext) {}
+        }
+
+        impl Drop for A {
+            fn drop(&mut self) {
+                DROPPED.lock().unwrap().push("plugin");
+            }
+        }
+
+        #[derive(Default)]
+        struct PluginLoader;
+
+        impl Loader for PluginLoader {
+            type Library = Library;
+            type Error = PluginLoadError;
+
+            unsafe fn load(
+                &self,
+                _: impl AsRef<OsStr>,
+            ) -> Result<(Self::Library, Box<dyn Plugin>)> {
+                Ok((Library, Box::new(A)))
+            }
+        }
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        unsafe { manager.load_plugin("A").unwrap() };
+
+        drop(manager.into_dispatcher());
+        assert_eq!(*DROPPED.lock().unwrap(), ["plugin", "library"]);
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        unsafe { manager.load_plugin("A").unwrap() };
+
+        let mut dispatcher = manager.into_dispatcher();
+        dispatcher.leak_libraries();
+        drop(dispatcher);
+        assert_eq!(*DROPPED.lock().unwrap(), ["plugin", "library", "plugin"]);
+        DROPPED.lock().unwrap().clear();
+
+        let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+        unsafe { manager.load_plugin("A").unwrap() };
+
+        // A handle keeps both the plugin and its library alive.
+        let dispatcher = manager.into_dispatcher();
+        let handle = dispatcher.plugin("A").unwrap();
+        assert!(dispatcher.plugin("B").is_none());
+        drop(dispatcher);
+        assert!(DROPPED.lock().unwrap().is_empty());
 
-        assert_eq!(captured, "A\nB\n");
+        handle.run(&RunContext::detached());
+        drop(handle);
+        assert_eq!(*DROPPED.lock().unwrap(), ["plugin", "library"]);
     }
 
     #[test]
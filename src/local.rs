@@ -0,0 +1,231 @@
+//! Dispatching plugins that must stay on one thread.
+
+use std::any::Any;
+use std::time::Instant;
+
+use crate::context::DispatchState;
+use crate::schedule::schedule;
+use crate::{
+    DispatchReport, Environment, ErrorPolicy, Loader, Plugin, PluginHandle, PluginManager,
+    PluginReport, RunContext, panic_hook,
+};
+
+/// Like [`Plugin`], but without requiring `Send + Sync`, for plugins bound
+/// to the thread that created them, such as GUI or scripting plugins.
+///
+/// Every [`Plugin`] is also a `LocalPlugin`.
+pub trait LocalPlugin: Any {
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>().split("::").last().unwrap()
+    }
+
+    fn dependencies(&self) -> &[&str] {
+        &[]
+    }
+
+    fn reads(&self) -> &[&str] {
+        &[]
+    }
+
+    fn writes(&self) -> &[&str] {
+        &[]
+    }
+
+    fn run(&self, context: &RunContext);
+}
+
+impl<P: Plugin> LocalPlugin for P {
+    fn name(&self) -> &str {
+        Plugin::name(self)
+    }
+
+    fn dependencies(&self) -> &[&str] {
+        Plugin::dependencies(self)
+    }
+
+    fn reads(&self) -> &[&str] {
+        Plugin::reads(self)
+    }
+
+    fn writes(&self) -> &[&str] {
+        Plugin::writes(self)
+    }
+
+    fn run(&self, context: &RunContext) {
+        Plugin::run(self, context);
+    }
+}
+
+/// A registered plugin scheduled by a [`LocalDispatcher`].
+struct Registered<L>(PluginHandle<L>);
+
+impl<L: 'static> LocalPlugin for Registered<L> {
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    fn dependencies(&self) -> &[&str] {
+        self.0.dependencies()
+    }
+
+    fn reads(&self) -> &[&str] {
+        self.0.reads()
+    }
+
+    fn writes(&self) -> &[&str] {
+        self.0.writes()
+    }
+
+    fn run(&self, context: &RunContext) {
+        self.0.run(context);
+    }
+}
+
+/// Runs [`LocalPlugin`]s one after another on the calling thread.
+///
+/// Unlike [`Dispatcher`](crate::Dispatcher), it cannot be sent to another
+/// thread and has no `dispatch_par`.
+pub struct LocalDispatcher {
+    stages: Vec<Vec<Box<dyn LocalPlugin>>>,
+    state: DispatchState,
+}
+
+impl LocalDispatcher {
+    /// Schedules `plugins` by their dependencies.
+    ///
+    /// # Panics
+    ///
+    /// If the dependencies form a cycle or a dependency is missing.
+    pub fn new(plugins: impl IntoIterator<Item = Box<dyn LocalPlugin>>) -> Self {
+        let mut plugins: Vec<_> = plugins.into_iter().map(Some).collect();
+        let order = schedule(
+            &plugins
+                .iter()
+                .flatten()
+                .map(|plugin| (plugin.name(), plugin.dependencies().to_vec()))
+                .collect::<Vec<_>>(),
+        );
+
+        let stages = order
+            .into_iter()
+            .map(|stage| stage.into_iter().map(|index| plugins[index].take().unwrap()).collect())
+            .collect();
+
+        Self { stages, state: DispatchState::default() }
+    }
+
+    /// Returns the plugins in the order [`dispatch`](Self::dispatch) runs
+    /// them.
+    pub fn plugins(&self) -> impl Iterator<Item = &dyn LocalPlugin> {
+        self.stages.iter().flatten().map(|plugin| &**plugin)
+    }
+
+    /// See [`Dispatcher::cancel`](crate::Dispatcher::cancel).
+    pub fn cancel(&self) {
+        self.state.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.is_cancelled()
+    }
+
+    /// See [`Dispatcher::environment_mut`](crate::Dispatcher::environment_mut).
+    pub fn environment_mut(&mut self) -> &mut Environment {
+        &mut self.state.environment
+    }
+
+    pub fn dispatch(&self) {
+        self.state.begin();
+        self.plugins().for_each(|plugin| plugin.run(&self.context(plugin)));
+    }
+
+    /// See [`Dispatcher::dispatch_report`](crate::Dispatcher::dispatch_report).
+    /// Every plugin runs, as with [`ErrorPolicy::Continue`].
+    pub fn dispatch_report(&self) -> DispatchReport {
+        let _hook = panic_hook::Scope::enter();
+        let start = Instant::now();
+        self.state.begin();
+        let stages = self
+            .stages
+            .iter()
+            .map(|stage| {
+                stage.iter().map(|plugin| {
+                    PluginReport::run_with(plugin.name(), || plugin.run(&self.context(&**plugin)))
+                })
+            })
+            .map(Iterator::collect)
+            .collect();
+
+        DispatchReport { stages, duration: start.elapsed(), policy: ErrorPolicy::Continue }
+    }
+
+    fn context<'a>(&'a self, plugin: &'a dyn LocalPlugin) -> RunContext<'a> {
+        self.state.local_context(plugin)
+    }
+}
+
+impl<L: Loader> PluginManager<L>
+where
+    L::Library: 'static,
+{
+    /// Creates a [`LocalDispatcher`] that runs the registered plugins
+    /// together with `local` ones. Plugins may depend on each other in
+    /// either direction.
+    pub fn into_local_dispatcher(
+        self,
+        local: impl IntoIterator<Item = Box<dyn LocalPlugin>>,
+    ) -> LocalDispatcher {
+        let plugins = self.plugins.into_iter().map(|plugin| Box::new(Registered(plugin)) as Box<_>);
+        LocalDispatcher::new(plugins.chain(local))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::LocalPlugin;
+    use crate::{Plugin, PluginManager, RunContext};
+
+    struct Window {
+        events: Rc<RefCell<Vec<&'static str>>>,
+    }
+
+    impl LocalPlugin for Window {
+        fn dependencies(&self) -> &[&str] {
+            &["Input"]
+        }
+
+        fn run(&self, _: &RunContext) {
+            self.events.borrow_mut().push("Window");
+        }
+    }
+
+    struct Input;
+
+    impl Plugin for Input {
+        fn run(&self, _: &RunContext) {}
+    }
+
+    #[test]
+    fn local_dispatcher() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+
+        let mut manager = PluginManager::new();
+        manager.register(Box::new(Input)).unwrap();
+        let dispatcher =
+            manager.into_local_dispatcher([Box::new(Window { events: events.clone() }) as Box<_>]);
+
+        let names: Vec<_> = dispatcher.plugins().map(|plugin| plugin.name()).collect();
+        assert_eq!(names, ["Input", "Window"]);
+
+        dispatcher.dispatch();
+        assert_eq!(*events.borrow(), ["Window"]);
+
+        let report = dispatcher.dispatch_report();
+        assert!(report.is_success());
+        assert_eq!(report.stages.len(), 2);
+        assert_eq!(*events.borrow(), ["Window", "Window"]);
+    }
+}
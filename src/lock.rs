@@ -0,0 +1,221 @@
+//! Lockfiles, recording the plugin libraries a deployment was tested with.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::manifest::parse_sha256;
+use crate::sha2::Sha256;
+use crate::toml::{self, Value};
+use crate::{Loader, PluginManager, hex};
+
+/// The version and library digest of every plugin, as written to a
+/// `sora.lock` file by [`PluginManager::lock`].
+///
+/// ```toml
+/// [plugins.Hello]
+/// version = "0.1.0"
+/// sha256 = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+/// ```
+///
+/// Once given to [`PluginManager::set_lockfile`], plugins loaded from a
+/// library are only registered if they are locked, report the locked
+/// version, and come from a library with the locked digest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lockfile {
+    pub plugins: BTreeMap<String, LockedPlugin>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockedPlugin {
+    pub version: Option<String>,
+    /// The SHA-256 digest of the library the plugin is loaded from.
+    pub sha256: [u8; 32],
+}
+
+impl Lockfile {
+    pub const FILE_NAME: &'static str = "sora.lock";
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, LockError> {
+        let source = std::fs::read_to_string(path).map_err(LockError::Io)?;
+        Self::parse(&source)
+    }
+
+    pub fn parse(source: &str) -> Result<Self, LockError> {
+        let mut table = toml::parse(source)
+            .map_err(|error| LockError::Syntax { line: error.line, message: error.message })?;
+        let plugins = match table.remove("plugins") {
+            Some(Value::Table(plugins)) => plugins,
+            Some(_) => return Err(LockError::Invalid("`plugins` must be a table".to_owned())),
+            None => Default::default(),
+        };
+        if let Some(key) = table.into_keys().next() {
+            return Err(LockError::Invalid(format!("unknown field `{key}`")));
+        }
+
+        let plugins = plugins
+            .into_iter()
+            .map(|(name, plugin)| {
+                let invalid = |message: &str| LockError::Invalid(format!("`{name}` {message}"));
+                let Value::Table(mut plugin) = plugin else {
+                    return Err(invalid("must be a table"));
+                };
+                let version = match plugin.remove("version") {
+                    Some(Value::String(version)) => Some(version),
+                    Some(_) => return Err(invalid("has a `version` that is not a string")),
+                    None => None,
+                };
+                let sha256 = match plugin.remove("sha256") {
+                    Some(Value::String(sha256)) => parse_sha256(&sha256).ok(),
+                    _ => None,
+                };
+                let sha256 = sha256.ok_or_else(|| invalid("needs a `sha256` of 64 hex digits"))?;
+                if let Some(key) = plugin.into_keys().next() {
+                    return Err(invalid(&format!("has an unknown field `{key}`")));
+                }
+
+                Ok((name, LockedPlugin { version, sha256 }))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { plugins })
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_string())
+    }
+
+    /// Checks the plugin `name`, reporting `version`, against the lock.
+    pub(crate) fn check(
+        &self,
+        name: &str,
+        version: Option<&str>,
+        sha256: &[u8; 32],
+    ) -> Result<(), Drift> {
+        let locked = self.plugins.get(name).ok_or(Drift::Unlocked)?;
+        if locked.version.as_deref() != version {
+            return Err(Drift::Version {
+                locked: locked.version.clone(),
+                found: version.map(str::to_owned),
+            });
+        }
+        if locked.sha256 != *sha256 {
+            return Err(Drift::Digest { locked: hex(&locked.sha256), found: hex(sha256) });
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Lockfile {
+    /// Formats the lockfile as TOML.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# Generated by `sora lock`. Plugins are checked against it when loaded.")?;
+        for (name, plugin) in &self.plugins {
+            writeln!(f, "\n[plugins.{name}]")?;
+            if let Some(version) = &plugin.version {
+                let version = version.replace('\\', "\\\\").replace('"', "\\\"");
+                writeln!(f, "version = \"{version}\"")?;
+            }
+            writeln!(f, "sha256 = \"{}\"", hex(&plugin.sha256))?;
+        }
+        Ok(())
+    }
+}
+
+impl<L: Loader> PluginManager<L> {
+    /// Locks the plugins loaded from a library to their current version and
+    /// the digest of that library. Plugins that were registered directly or
+    /// loaded from memory are left out.
+    pub fn lock(&self) -> Result<Lockfile, LockError> {
+        let mut lockfile = Lockfile::default();
+        for plugin in &self.plugins {
+            let Some(library) = &plugin.library else { continue };
+            let path = self.libraries.iter().find_map(|(path, other)| {
+                std::ptr::eq(other.as_ptr(), Arc::as_ptr(library)).then_some(path.as_ref()?)
+            });
+            let Some(path) = path else { continue };
+
+            // Names become table headers, which only take bare keys.
+            let name = plugin.name();
+            if name.is_empty()
+                || !name.chars().all(|c| c.is_ascii_alphanumeric() || "_-".contains(c))
+            {
+                return Err(LockError::Invalid(format!("`{name}` cannot be locked by name")));
+            }
+
+            let sha256 = Sha256::digest(&std::fs::read(path).map_err(LockError::Io)?);
+            let version = plugin.version().map(str::to_owned);
+            lockfile.plugins.insert(name.to_owned(), LockedPlugin { version, sha256 });
+        }
+
+        Ok(lockfile)
+    }
+
+    /// Checks plugins loaded from a library afterwards against `lockfile`,
+    /// failing with [`PluginLoadError::Drift`](crate::PluginLoadError::Drift)
+    /// if they differ from it.
+    ///
+    /// Plugins are only known once their library is open, so a library that
+    /// drifted has already been opened when it is rejected. To keep it from
+    /// being opened at all, pin its digest with
+    /// [`pin_sha256`](Self::pin_sha256).
+    pub fn set_lockfile(&mut self, lockfile: Lockfile) {
+        self.lockfile = Some(lockfile);
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LockError {
+    #[error("cannot read lockfile: {0}")]
+    Io(std::io::Error),
+    #[error("line {line}: {message}")]
+    Syntax { line: usize, message: String },
+    #[error("invalid lockfile: {0}")]
+    Invalid(String),
+}
+
+/// How a plugin differs from its [`Lockfile`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Drift {
+    #[error("it is not locked")]
+    Unlocked,
+    #[error("it reports version {}, but {} is locked", show(found), show(locked))]
+    Version { locked: Option<String>, found: Option<String> },
+    #[error("the SHA-256 of its library is {found}, but {locked} is locked")]
+    Digest { locked: String, found: String },
+}
+
+fn show(version: &Option<String>) -> &str {
+    version.as_deref().unwrap_or("none")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Drift, LockedPlugin, Lockfile};
+
+    #[test]
+    fn parse_and_check() {
+        let mut lockfile = Lockfile::default();
+        let plugin = LockedPlugin { version: Some("1.0.0".to_owned()), sha256: [7; 32] };
+        lockfile.plugins.insert("Hello".to_owned(), plugin);
+        lockfile.plugins.insert("Bare".to_owned(), LockedPlugin { version: None, sha256: [0; 32] });
+        assert_eq!(Lockfile::parse(&lockfile.to_string()).unwrap(), lockfile);
+
+        assert_eq!(lockfile.check("Hello", Some("1.0.0"), &[7; 32]), Ok(()));
+        assert_eq!(lockfile.check("Other", None, &[7; 32]), Err(Drift::Unlocked));
+        assert_eq!(
+            lockfile.check("Hello", None, &[7; 32]).unwrap_err().to_string(),
+            "it reports version none, but 1.0.0 is locked"
+        );
+        assert!(matches!(lockfile.check("Bare", None, &[7; 32]), Err(Drift::Digest { .. })));
+
+        let error = |source| Lockfile::parse(source).unwrap_err().to_string();
+        assert_eq!(
+            error("[plugins.A]\nversion = \"1.0.0\""),
+            "invalid lockfile: `A` needs a `sha256` of 64 hex digits"
+        );
+        assert_eq!(error("version = 1"), "invalid lockfile: unknown field `version`");
+    }
+}
@@ -0,0 +1,279 @@
+use std::path::{Path, PathBuf};
+
+use crate::toml::{self, Table, Value};
+
+/// Metadata shipped next to a plugin library in a `sora-plugin.toml` file.
+///
+/// ```toml
+/// name = "Hello"
+/// version = "0.1.0"
+/// entry = "create_plugin"
+/// library = "libhello_world.so"
+/// dependencies = []
+/// sha256 = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+/// ```
+///
+/// `entry` defaults to `create_plugin`. When `library` is omitted, the
+/// directory must contain exactly one file with the platform's dynamic
+/// library extension. If `sha256` is given, the library is only loaded if
+/// its digest matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub name: String,
+    pub version: String,
+    pub entry: String,
+    pub library: Option<PathBuf>,
+    pub dependencies: Vec<String>,
+    pub sha256: Option<[u8; 32]>,
+}
+
+impl Manifest {
+    pub const FILE_NAME: &'static str = "sora-plugin.toml";
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, ManifestError> {
+        let source = std::fs::read_to_string(path).map_err(ManifestError::Io)?;
+        Self::parse(&source)
+    }
+
+    pub fn parse(source: &str) -> Result<Self, ManifestError> {
+        let mut table = toml::parse(source)
+            .map_err(|error| ManifestError::Syntax { line: error.line, message: error.message })?;
+
+        let manifest = Self {
+            name: take_string(&mut table, "name")?.ok_or(ManifestError::MissingField("name"))?,
+            version: take_string(&mut table, "version")?
+                .ok_or(ManifestError::MissingField("version"))?,
+            entry: take_string(&mut table, "entry")?.unwrap_or_else(|| "create_plugin".to_owned()),
+            library: take_string(&mut table, "library")?.map(PathBuf::from),
+            dependencies: take_strings(&mut table, "dependencies")?.unwrap_or_default(),
+            sha256: take_string(&mut table, "sha256")?.map(|hex| parse_sha256(&hex)).transpose()?,
+        };
+
+        if let Some(key) = table.into_keys().next() {
+            return Err(ManifestError::UnknownField(key));
+        }
+
+        manifest.validate()?;
+
+        Ok(manifest)
+    }
+
+    /// Resolves the library described by this manifest, relative to the
+    /// directory containing it.
+    pub fn library_path(&self, dir: &Path) -> Result<PathBuf, ManifestError> {
+        if let Some(library) = &self.library {
+            let path = dir.join(library);
+            return match path.is_file() {
+                true => Ok(path),
+                false => Err(ManifestError::LibraryNotFound(path)),
+            };
+        }
+
+        let mut libraries = std::fs::read_dir(dir)
+            .and_then(|entries| {
+                entries.map(|entry| Ok(entry?.path())).collect::<std::io::Result<Vec<_>>>()
+            })
+            .map_err(ManifestError::Io)?;
+        libraries.retain(|path| is_library(path));
+
+        match <[_; 1]>::try_from(libraries) {
+            Ok([path]) => Ok(path),
+            Err(libraries) if libraries.is_empty() => {
+                Err(ManifestError::LibraryNotFound(dir.to_owned()))
+            }
+            Err(libraries) => Err(ManifestError::AmbiguousLibrary(libraries)),
+        }
+    }
+
+    fn validate(&self) -> Result<(), ManifestError> {
+        let invalid = |field, message: &str| {
+            Err(ManifestError::InvalidField { field, message: message.to_owned() })
+        };
+
+        if self.name.is_empty() {
+            return invalid("name", "must not be empty");
+        }
+
+        if !is_version(&self.version) {
+            return invalid("version", "must have the form `MAJOR.MINOR.PATCH`");
+        }
+
+        if self.entry.is_empty()
+            || !self.entry.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return invalid("entry", "must be a valid symbol name");
+        }
+
+        for (index, dependency) in self.dependencies.iter().enumerate() {
+            if *dependency == self.name {
+                return invalid("dependencies", "a plugin cannot depend on itself");
+            }
+
+            if self.dependencies[..index].contains(dependency) {
+                return invalid("dependencies", "dependencies must be unique");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the plugin created from the library matches this
+    /// manifest.
+    pub(crate) fn verify(&self, name: &str, dependencies: &[&str]) -> Result<(), ManifestError> {
+        let mismatch = |field, expected: String, found: String| {
+            Err(ManifestError::Mismatch { field, expected, found })
+        };
+
+        if name != self.name {
+            return mismatch("name", self.name.clone(), name.to_owned());
+        }
+
+        let mut expected: Vec<_> = self.dependencies.iter().map(String::as_str).collect();
+        let mut found = dependencies.to_vec();
+        expected.sort_unstable();
+        found.sort_unstable();
+
+        if expected != found {
+            return mismatch("dependencies", expected.join(", "), found.join(", "));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ManifestError {
+    #[error("cannot read manifest: {0}")]
+    Io(std::io::Error),
+    #[error("line {line}: {message}")]
+    Syntax { line: usize, message: String },
+    #[error("missing field `{0}`")]
+    MissingField(&'static str),
+    #[error("unknown field `{0}`")]
+    UnknownField(String),
+    #[error("field `{field}` {message}")]
+    InvalidField { field: &'static str, message: String },
+    #[error("no plugin library found at {}", .0.display())]
+    LibraryNotFound(PathBuf),
+    #[error("more than one plugin library found: {0:?}")]
+    AmbiguousLibrary(Vec<PathBuf>),
+    #[error("manifest declares {field} `{expected}`, but the plugin reports `{found}`")]
+    Mismatch { field: &'static str, expected: String, found: String },
+}
+
+fn take_string(table: &mut Table, field: &'static str) -> Result<Option<String>, ManifestError> {
+    match table.remove(field) {
+        None => Ok(None),
+        Some(Value::String(string)) => Ok(Some(string)),
+        Some(value) => Err(type_mismatch(field, "string", &value)),
+    }
+}
+
+fn take_strings(
+    table: &mut Table,
+    field: &'static str,
+) -> Result<Option<Vec<String>>, ManifestError> {
+    match table.remove(field) {
+        None => Ok(None),
+        Some(Value::Array(values)) => values
+            .into_iter()
+            .map(|value| match value {
+                Value::String(string) => Ok(string),
+                value => Err(type_mismatch(field, "array of strings", &value)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Some),
+        Some(value) => Err(type_mismatch(field, "array of strings", &value)),
+    }
+}
+
+fn type_mismatch(field: &'static str, expected: &str, found: &Value) -> ManifestError {
+    ManifestError::InvalidField {
+        field,
+        message: format!("must be a {expected}, found {}", found.type_name()),
+    }
+}
+
+pub(crate) fn parse_sha256(hex: &str) -> Result<[u8; 32], ManifestError> {
+    let invalid = || ManifestError::InvalidField {
+        field: "sha256",
+        message: "must be 64 hexadecimal digits".to_owned(),
+    };
+
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut digest = [0; 32];
+    for (byte, i) in digest.iter_mut().zip((0..64).step_by(2)) {
+        *byte = u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(digest)
+}
+
+pub(crate) fn is_version(version: &str) -> bool {
+    let core = version.split(['-', '+']).next().unwrap_or_default();
+    let parts: Vec<_> = core.split('.').collect();
+
+    parts.len() == 3
+        && parts.iter().all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn is_library(path: &Path) -> bool {
+    let suffix = std::env::consts::DLL_SUFFIX;
+    path.is_file() && path.to_str().is_some_and(|path| path.ends_with(suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Manifest, ManifestError};
+
+    #[test]
+    fn parse() {
+        let manifest = Manifest::parse(
+            r#"
+            name = "B"
+            version = "1.2.3-beta"
+            dependencies = ["A"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(manifest.name, "B");
+        assert_eq!(manifest.version, "1.2.3-beta");
+        assert_eq!(manifest.entry, "create_plugin");
+        assert_eq!(manifest.library, None);
+        assert_eq!(manifest.dependencies, ["A"]);
+        assert_eq!(manifest.sha256, None);
+
+        assert!(manifest.verify("B", &["A"]).is_ok());
+        assert!(matches!(
+            manifest.verify("C", &["A"]),
+            Err(ManifestError::Mismatch { field: "name", .. })
+        ));
+    }
+
+    #[test]
+    fn invalid() {
+        let error = |source| Manifest::parse(source).unwrap_err().to_string();
+
+        assert_eq!(error("version = \"1.0.0\""), "missing field `name`");
+        assert_eq!(
+            error("name = \"A\"\nversion = \"1.0\""),
+            "field `version` must have the form `MAJOR.MINOR.PATCH`"
+        );
+        assert_eq!(
+            error("name = \"A\"\nversion = \"1.0.0\"\ndependencies = [\"A\"]"),
+            "field `dependencies` a plugin cannot depend on itself"
+        );
+        assert_eq!(
+            error("name = \"A\"\nversion = \"1.0.0\"\nentry = 1"),
+            "field `entry` must be a string, found integer"
+        );
+        assert_eq!(error("name = \"A\"\nversion = \"1.0.0\"\nkind = \"\""), "unknown field `kind`");
+        assert_eq!(
+            error("name = \"A\"\nversion = \"1.0.0\"\nsha256 = \"abc\""),
+            "field `sha256` must be 64 hexadecimal digits"
+        );
+    }
+}
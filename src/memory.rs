@@ -0,0 +1,142 @@
+//! Plugin libraries that only exist in memory, such as those downloaded or
+//! embedded in the host.
+
+use std::fs::File;
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+
+/// A file holding the bytes of a library, so that it can be opened by path.
+///
+/// On Linux, the file is anonymous, created with `memfd_create`, and opened
+/// through `/proc/self/fd`, unless it is [named](Self::named). Elsewhere,
+/// it is a temporary file only its owner can read, which is removed when
+/// this is dropped. Once a library is
+/// open, the file is no longer needed, though Windows refuses to remove it
+/// until the library is closed.
+pub(crate) struct MemoryFile {
+    path: PathBuf,
+    /// An anonymous file is gone once closed.
+    _file: File,
+    /// The folder of a [named](Self::named) file.
+    dir: Option<PathBuf>,
+}
+
+impl MemoryFile {
+    /// `name` shows up in the file's name, and must not contain `/`, `\` or
+    /// NUL bytes.
+    pub(crate) fn new(name: &str, bytes: &[u8]) -> io::Result<Self> {
+        if name.contains(['/', '\\', '\0']) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid library name"));
+        }
+
+        let (path, mut file) = create(name)?;
+        file.write_all(bytes)?;
+        Ok(Self { path, _file: file, dir: None })
+    }
+
+    /// Like [`new`](Self::new), but the file is called `name`, in a folder
+    /// of its own that only its owner can access, so that the library
+    /// shows up under its own name, such as in backtraces. The folder is
+    /// removed when this is dropped.
+    pub(crate) fn named(name: &str, bytes: &[u8]) -> io::Result<Self> {
+        if name.contains(['/', '\\', '\0']) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid library name"));
+        }
+
+        let dir = std::env::temp_dir().join(format!("sora-{}-{}", std::process::id(), next()));
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(&dir)?;
+
+        let path = dir.join(name);
+        let file = open_new(&path).and_then(|mut file| file.write_all(bytes).map(|()| file));
+        match file {
+            Ok(file) => Ok(Self { path, _file: file, dir: Some(dir) }),
+            Err(error) => {
+                let _ = std::fs::remove_dir_all(&dir);
+                Err(error)
+            }
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn create(name: &str) -> io::Result<(PathBuf, File)> {
+    use std::ffi::CString;
+    use std::os::fd::{AsRawFd as _, FromRawFd as _};
+
+    let name = CString::new(format!("sora:{name}"))?;
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let file = unsafe { File::from_raw_fd(fd) };
+    Ok((PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd())), file))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn create(name: &str) -> io::Result<(PathBuf, File)> {
+    let path = std::env::temp_dir().join(format!("sora-{}-{}-{name}", std::process::id(), next()));
+    let file = open_new(&path)?;
+    Ok((path, file))
+}
+
+/// Creates the file at `path`, which only its owner can access.
+fn open_new(path: &Path) -> io::Result<File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o700);
+    options.open(path)
+}
+
+/// A number not yet used in the names of this process's files.
+pub(crate) fn next() -> usize {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    COUNT.fetch_add(1, Ordering::Relaxed)
+}
+
+impl Drop for MemoryFile {
+    fn drop(&mut self) {
+        match &self.dir {
+            Some(dir) => _ = std::fs::remove_dir_all(dir),
+            None if cfg!(not(target_os = "linux")) => _ = std::fs::remove_file(&self.path),
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryFile;
+
+    #[test]
+    fn memory_file() {
+        let file = MemoryFile::new("plugin.so", b"\x7fELF").unwrap();
+        assert_eq!(std::fs::read(file.path()).unwrap(), b"\x7fELF");
+
+        let path = file.path().to_owned();
+        drop(file);
+        // On Linux, another file may take over the descriptor.
+        if cfg!(not(target_os = "linux")) {
+            assert!(!path.exists());
+        }
+
+        assert!(MemoryFile::new("../plugin.so", b"").is_err());
+
+        let file = MemoryFile::named("plugin.so", b"\x7fELF").unwrap();
+        let path = file.path().to_owned();
+        assert_eq!(path.file_name().unwrap(), "plugin.so");
+        assert_eq!(std::fs::read(&path).unwrap(), b"\x7fELF");
+        drop(file);
+        assert!(!path.parent().unwrap().exists());
+    }
+}
@@ -0,0 +1,38 @@
+//! Descriptive information about loaded plugins, as returned by
+//! [`PluginManager::metadata`](crate::PluginManager::metadata).
+
+use std::path::PathBuf;
+
+use crate::Plugin;
+
+/// What a plugin reports about itself, copied when it is registered so that
+/// it stays available independently of the plugin's library.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginMetadata {
+    pub name: String,
+    pub version: Option<String>,
+    pub description: Option<String>,
+    pub authors: Vec<String>,
+    pub dependencies: Vec<String>,
+    /// The version of the plugin interface its library was loaded through,
+    /// for plugins loaded by [`Native`](crate::Native). See
+    /// [`API_VERSIONS`](crate::API_VERSIONS).
+    pub api_version: Option<u32>,
+    /// The canonical path of the library the plugin was loaded from, if it
+    /// was loaded from a file.
+    pub path: Option<PathBuf>,
+}
+
+impl PluginMetadata {
+    pub(crate) fn of(plugin: &dyn Plugin) -> Self {
+        Self {
+            name: plugin.name().to_owned(),
+            version: plugin.version().map(str::to_owned),
+            description: plugin.description().map(str::to_owned),
+            authors: plugin.authors().iter().copied().map(str::to_owned).collect(),
+            dependencies: plugin.dependencies().iter().copied().map(str::to_owned).collect(),
+            api_version: None,
+            path: None,
+        }
+    }
+}
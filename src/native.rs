@@ -0,0 +1,520 @@
+//! How plugin libraries are opened.
+
+use std::cell::{Cell, RefCell};
+use std::ffi::{OsStr, c_int};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use libloading::Library;
+
+use crate::PluginLoadError;
+
+/// How the operating system opens plugin libraries. See
+/// [`PluginManager::set_native_options`](crate::PluginManager::set_native_options).
+///
+/// The default flags are those of [`Library::new`]: `RTLD_LAZY | RTLD_LOCAL`
+/// for `dlopen`, and none for `LoadLibraryExW`. Each is ignored on the other
+/// platforms.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NativeLoaderOptions {
+    /// The `flags` of `dlopen`.
+    pub unix_flags: c_int,
+    /// The `dwFlags` of `LoadLibraryExW`.
+    pub windows_flags: u32,
+    /// Folders in which the shared libraries that plugins link to are
+    /// looked up, before the system's. See
+    /// [`search_path`](Self::search_path).
+    pub search_paths: Vec<PathBuf>,
+    /// Whether to make sure that the shared libraries plugins link to can
+    /// be found before opening them. See
+    /// [`check_dependencies`](Self::check_dependencies).
+    pub check_dependencies: bool,
+}
+
+impl NativeLoaderOptions {
+    pub const fn new() -> Self {
+        #[cfg(unix)]
+        let unix_flags = libc::RTLD_LAZY | libc::RTLD_LOCAL;
+        #[cfg(not(unix))]
+        let unix_flags = 0;
+
+        Self { unix_flags, windows_flags: 0, search_paths: Vec::new(), check_dependencies: false }
+    }
+
+    /// Before opening a plugin, looks for the shared libraries it directly
+    /// links to, and fails with [`PluginLoadError::MissingDependencies`]
+    /// listing all those that cannot be found, rather than with the first
+    /// one the dynamic linker reports.
+    ///
+    /// Only ELF libraries are checked, and nothing is opened to check them.
+    /// A library counts as found if it is in the plugin's `RUNPATH`, in the
+    /// [search paths](Self::search_path), in `LD_LIBRARY_PATH`, listed in
+    /// `/etc/ld.so.cache` or in one of the default folders of the dynamic
+    /// linker, or already loaded by the process.
+    pub fn check_dependencies(mut self) -> Self {
+        self.check_dependencies = true;
+        self
+    }
+
+    /// Looks up the shared libraries that plugins link to in `dir` too, so
+    /// that plugins can ship them alongside.
+    ///
+    /// Like `LD_LIBRARY_PATH`, which cannot be changed once the process
+    /// runs, but only for the libraries plugins need directly or through
+    /// other libraries in the search paths. On ELF platforms, those found in
+    /// `dir` are opened before the plugin. On Windows, `dir` is added with
+    /// `AddDllDirectory` while the plugin is opened.
+    pub fn search_path(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.search_paths.push(dir.into());
+        self
+    }
+
+    /// Resolves every symbol when the library is opened rather than when it
+    /// is first used (`RTLD_NOW`), so missing symbols fail the load.
+    pub const fn now(mut self) -> Self {
+        #[cfg(unix)]
+        {
+            self.unix_flags = self.unix_flags & !libc::RTLD_LAZY | libc::RTLD_NOW;
+        }
+        self
+    }
+
+    /// Makes the symbols of the library available to the libraries opened
+    /// after it (`RTLD_GLOBAL`), for plugins that share symbols with each
+    /// other.
+    pub const fn global(mut self) -> Self {
+        #[cfg(unix)]
+        {
+            self.unix_flags = self.unix_flags & !libc::RTLD_LOCAL | libc::RTLD_GLOBAL;
+        }
+        self
+    }
+}
+
+impl Default for NativeLoaderOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The step at which loading a plugin library failed. See
+/// [`PluginLoadError::phase`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadPhase {
+    /// Opening the library, or checking it before any plugin is created.
+    Open,
+    /// Looking up the entry points, the names of which were tried in order.
+    Symbol(Vec<String>),
+    /// Creating the plugin through its entry point.
+    Create,
+}
+
+/// Completes "cannot ... {path}".
+impl fmt::Display for LoadPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Open => f.write_str("open"),
+            Self::Symbol(names) => match names.as_slice() {
+                [name] => write!(f, "find `{name}` in"),
+                names => write!(f, "find any of `{}` in", names.join("`, `")),
+            },
+            Self::Create => f.write_str("create a plugin from"),
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<NativeLoaderOptions> = const { RefCell::new(NativeLoaderOptions::new()) };
+    static API_VERSION: Cell<Option<u32>> = const { Cell::new(None) };
+    static ORIGINAL: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+/// Makes [`open`] treat the library it opens while `f` runs on this thread
+/// as a copy of the one at `path`, such as the bytes of it that were
+/// verified. Errors name `path`, and the folders of the `RUNPATH` that are
+/// relative to `$ORIGIN` are looked up next to `path` as search paths,
+/// since the dynamic linker looks next to the copy.
+pub(crate) fn with_original<T>(path: &Path, f: impl FnOnce() -> T) -> T {
+    let previous = ORIGINAL.replace(Some(path.to_owned()));
+    let result = f();
+    ORIGINAL.set(previous);
+    result
+}
+
+/// Makes [`open`] use `options` while `f` runs on this thread, and returns
+/// the interface version the library loaded by `f` was loaded through, if
+/// the loader reported one with [`set_api_version`].
+pub(crate) fn with<T>(options: &NativeLoaderOptions, f: impl FnOnce() -> T) -> (T, Option<u32>) {
+    let previous = CURRENT.replace(options.clone());
+    let previous_version = API_VERSION.replace(None);
+    let result = f();
+    CURRENT.set(previous);
+    (result, API_VERSION.replace(previous_version))
+}
+
+/// Reports the interface version negotiated with the library being loaded.
+pub(crate) fn set_api_version(version: u32) {
+    API_VERSION.set(Some(version));
+}
+
+/// Opens `filename` with the options of the manager loading plugins on
+/// this thread, if any, and otherwise the defaults.
+pub(crate) unsafe fn open(filename: impl AsRef<OsStr>) -> crate::Result<Library> {
+    let filename = filename.as_ref();
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut options = CURRENT.with_borrow(Clone::clone);
+    let original = ORIGINAL.with_borrow(Clone::clone);
+    let path = original.as_deref().unwrap_or(Path::new(filename));
+
+    #[cfg(unix)]
+    if original.is_some() {
+        let dynamic = std::fs::read(filename).ok().and_then(|bytes| crate::elf::dynamic(&bytes));
+        let runpath = dynamic.map(|dynamic| dynamic.runpath).unwrap_or_default();
+        let relative =
+            runpath.iter().filter(|dir| dir.contains("$ORIGIN") || dir.contains("${ORIGIN}"));
+        options.search_paths.extend(relative.map(|dir| expand_origin(dir, path)));
+    }
+
+    #[cfg(unix)]
+    if options.check_dependencies {
+        let missing = unsafe { missing_dependencies(Path::new(filename), path, &options) };
+        if !missing.is_empty() {
+            return Err(PluginLoadError::MissingDependencies { path: path.to_owned(), missing });
+        }
+    }
+
+    #[cfg(unix)]
+    let library = unsafe {
+        // The plugin holds on to its dependencies once it is open, so they
+        // can be closed right after.
+        let _dependencies = preload(filename, &options, &mut Vec::new());
+        libloading::os::unix::Library::open(Some(filename), options.unix_flags).map(Library::from)
+    };
+    #[cfg(windows)]
+    let library = unsafe {
+        let _directories: Vec<_> =
+            options.search_paths.iter().map(windows::DllDirectory::add).collect();
+        let flags = match options.search_paths.is_empty() {
+            true => options.windows_flags,
+            false => options.windows_flags | windows::LOAD_LIBRARY_SEARCH_DEFAULT_DIRS,
+        };
+        libloading::os::windows::Library::load_with_flags(filename, flags).map(Library::from)
+    };
+
+    library.map_err(|source| PluginLoadError::Library {
+        path: path.to_owned(),
+        phase: LoadPhase::Open,
+        plugin: None,
+        source,
+    })
+}
+
+/// The error for a library at `path` that exports none of `names`.
+pub(crate) fn symbol_error(
+    path: &Path,
+    names: &[&str],
+    source: libloading::Error,
+) -> PluginLoadError {
+    PluginLoadError::Library {
+        path: path.to_owned(),
+        phase: LoadPhase::Symbol(names.iter().map(|&name| name.to_owned()).collect()),
+        plugin: None,
+        source,
+    }
+}
+
+/// The first bytes of the libraries of this platform: ELF, Mach-O (thin
+/// in either byte order, or universal), or PE.
+#[cfg(not(any(target_vendor = "apple", windows)))]
+const MAGIC: &[&[u8]] = &[b"\x7fELF"];
+#[cfg(target_vendor = "apple")]
+const MAGIC: &[&[u8]] = &[
+    b"\xfe\xed\xfa\xce",
+    b"\xfe\xed\xfa\xcf",
+    b"\xce\xfa\xed\xfe",
+    b"\xcf\xfa\xed\xfe",
+    b"\xca\xfe\xba\xbe",
+];
+#[cfg(windows)]
+const MAGIC: &[&[u8]] = &[b"MZ"];
+
+/// Checks that `path` is named and starts like a library of this platform,
+/// such as `libphysics.so` or `libphysics.so.1` on Linux.
+pub(crate) fn check_file(path: &Path) -> crate::Result<()> {
+    let not_a_library = |reason| PluginLoadError::NotALibrary { path: path.to_owned(), reason };
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let extension = format!(".{}", std::env::consts::DLL_EXTENSION);
+    let versioned = cfg!(unix) && name.contains(&format!("{extension}."));
+    if !name.ends_with(&extension) && !versioned {
+        return Err(not_a_library("the file name does not end with the library extension"));
+    }
+
+    let mut start = [0; 4];
+    let read = std::fs::File::open(path)
+        .and_then(|mut file| std::io::Read::read(&mut file, &mut start))
+        .map_err(PluginLoadError::Io)?;
+    if !MAGIC.iter().any(|magic| start[..read].starts_with(magic)) {
+        return Err(not_a_library("the file does not start like a library"));
+    }
+
+    Ok(())
+}
+
+/// The names of the libraries that the ELF file `path`, found at
+/// `original`, needs but that cannot be found. They are looked up where
+/// the dynamic linker would, without opening any, so that the constructors
+/// of those found do not run.
+#[cfg(unix)]
+unsafe fn missing_dependencies(
+    path: &Path,
+    original: &Path,
+    options: &NativeLoaderOptions,
+) -> Vec<String> {
+    let Some(dynamic) = std::fs::read(path).ok().and_then(|bytes| crate::elf::dynamic(&bytes))
+    else {
+        return Vec::new();
+    };
+
+    let runpath = dynamic.runpath.iter().map(|dir| expand_origin(dir, original));
+    let library_path = std::env::var_os("LD_LIBRARY_PATH").unwrap_or_default();
+    let dirs: Vec<_> = runpath
+        .chain(options.search_paths.iter().cloned())
+        .chain(std::env::split_paths(&library_path))
+        .chain(DEFAULT_LIBRARY_DIRS.iter().map(PathBuf::from))
+        .collect();
+    let cached = std::fs::read("/etc/ld.so.cache").ok().and_then(|bytes| ld_cache(&bytes));
+
+    let found = |name: &String| {
+        if name.contains('/') {
+            return Path::new(name).is_file();
+        }
+
+        dirs.iter().any(|dir| dir.join(name).is_file())
+            || cached.as_ref().is_some_and(|cached| cached.contains(name))
+            || unsafe { is_loaded(name) }
+    };
+    dynamic.needed.into_iter().filter(|name| !found(name)).collect()
+}
+
+/// The folders the dynamic linker searches after its cache.
+#[cfg(unix)]
+const DEFAULT_LIBRARY_DIRS: &[&str] = &["/lib", "/usr/lib", "/lib64", "/usr/lib64"];
+
+/// The names of the libraries listed in the `ld.so.cache` of glibc in
+/// `bytes`, or `None` if it is not in the format of glibc 2.32 and later,
+/// which may follow an older one.
+#[cfg(unix)]
+fn ld_cache(bytes: &[u8]) -> Option<ahash::AHashSet<String>> {
+    const MAGIC: &[u8] = b"glibc-ld.so.cache1.1";
+    const HEADER: usize = 48;
+    const ENTRY: usize = 24;
+
+    let start = bytes.windows(MAGIC.len()).position(|window| window == MAGIC)?;
+    // String offsets are relative to the start of this format.
+    let cache = &bytes[start..];
+    let u32_at = |offset: usize| {
+        let bytes = cache.get(offset..offset.checked_add(4)?)?;
+        usize::try_from(u32::from_ne_bytes(bytes.try_into().ok()?)).ok()
+    };
+
+    let count = u32_at(MAGIC.len())?;
+    (0..count)
+        .map(|index| {
+            let key = u32_at(HEADER + index * ENTRY + 4)?;
+            let name = cache.get(key..)?;
+            let name = &name[..name.iter().position(|&byte| byte == 0)?];
+            String::from_utf8(name.to_vec()).ok()
+        })
+        .collect()
+}
+
+/// Whether the process has already loaded a library called `name`. Asking
+/// does not load it.
+#[cfg(unix)]
+unsafe fn is_loaded(name: &str) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        let flags = libc::RTLD_LAZY | libc::RTLD_NOLOAD;
+        unsafe { libloading::os::unix::Library::open(Some(name), flags) }.is_ok()
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = name;
+        false
+    }
+}
+
+/// The folder `dir` of the `RUNPATH` of the library at `path`, with
+/// `$ORIGIN` replaced by the folder of `path`.
+#[cfg(unix)]
+fn expand_origin(dir: &str, path: &Path) -> PathBuf {
+    let origin = path.parent().unwrap_or(Path::new("."));
+    let origin = origin.to_str().unwrap_or(".");
+    PathBuf::from(dir.replace("${ORIGIN}", origin).replace("$ORIGIN", origin))
+}
+
+/// Opens the libraries in the search paths that `filename` needs, the ones
+/// they need first. `visited` holds the paths already considered.
+#[cfg(unix)]
+unsafe fn preload(
+    filename: &OsStr,
+    options: &NativeLoaderOptions,
+    visited: &mut Vec<PathBuf>,
+) -> Vec<Library> {
+    let needed = match options.search_paths.is_empty() {
+        true => None,
+        false => std::fs::read(filename).ok().and_then(|bytes| crate::elf::needed(&bytes)),
+    };
+
+    let mut libraries = Vec::new();
+    for name in needed.into_iter().flatten() {
+        let found =
+            options.search_paths.iter().map(|dir| dir.join(&name)).find(|path| path.is_file());
+        let Some(path) = found.filter(|path| !visited.contains(path)) else {
+            continue;
+        };
+        visited.push(path.clone());
+
+        libraries.extend(unsafe { preload(path.as_os_str(), options, visited) });
+        if let Ok(library) =
+            unsafe { libloading::os::unix::Library::open(Some(&path), options.unix_flags) }
+        {
+            libraries.push(library.into());
+        }
+    }
+
+    libraries
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::ffi::c_void;
+    use std::os::windows::ffi::OsStrExt as _;
+    use std::path::Path;
+
+    pub const LOAD_LIBRARY_SEARCH_DEFAULT_DIRS: u32 = 0x0000_1000;
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn AddDllDirectory(directory: *const u16) -> *mut c_void;
+        fn RemoveDllDirectory(cookie: *mut c_void) -> i32;
+    }
+
+    /// A folder added to the DLL search path until dropped.
+    pub struct DllDirectory(*mut c_void);
+
+    impl DllDirectory {
+        pub fn add(dir: impl AsRef<Path>) -> Self {
+            let dir: Vec<u16> = dir.as_ref().as_os_str().encode_wide().chain([0]).collect();
+            Self(unsafe { AddDllDirectory(dir.as_ptr()) })
+        }
+    }
+
+    impl Drop for DllDirectory {
+        fn drop(&mut self) {
+            if !self.0.is_null() {
+                unsafe { RemoveDllDirectory(self.0) };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NativeLoaderOptions;
+
+    #[test]
+    #[cfg(unix)]
+    fn flags() {
+        let options = NativeLoaderOptions::new();
+        assert_eq!(options.unix_flags, libc::RTLD_LAZY | libc::RTLD_LOCAL);
+        assert_eq!(options.clone().now().unix_flags, libc::RTLD_NOW | libc::RTLD_LOCAL);
+        assert_eq!(options.clone().global().unix_flags, libc::RTLD_LAZY | libc::RTLD_GLOBAL);
+        assert_eq!(options.clone().now().global(), options.global().now());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn check_file() {
+        let dir = std::env::temp_dir().join(format!("sora-check-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        for name in ["libphysics.so", "libphysics.so.1", "physics", "notes.so"] {
+            let contents = if name == "notes.so" { &b"notes"[..] } else { &exe };
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+
+        let reason = |name| match super::check_file(&dir.join(name)) {
+            Ok(()) => None,
+            Err(crate::PluginLoadError::NotALibrary { reason, .. }) => Some(reason),
+            Err(error) => panic!("{error}"),
+        };
+        assert_eq!(reason("libphysics.so"), None);
+        assert_eq!(reason("libphysics.so.1"), None);
+        assert_eq!(
+            reason("physics"),
+            Some("the file name does not end with the library extension")
+        );
+        assert_eq!(reason("notes.so"), Some("the file does not start like a library"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn missing_dependencies() {
+        let exe = std::env::current_exe().unwrap();
+        let options = NativeLoaderOptions::new().check_dependencies();
+        assert!(unsafe { super::missing_dependencies(&exe, &exe, &options) }.is_empty());
+
+        // The same executable, but linked to a C library that does not exist.
+        let bytes = std::fs::read(&exe).unwrap();
+        let (from, to) = (b"libc.so.6\0", b"libq.so.6\0");
+        let mut patched = Vec::with_capacity(bytes.len());
+        let mut rest = &bytes[..];
+        while let Some(index) = rest.windows(from.len()).position(|window| window == from) {
+            patched.extend_from_slice(&rest[..index]);
+            patched.extend_from_slice(to);
+            rest = &rest[index + from.len()..];
+        }
+        patched.extend_from_slice(rest);
+
+        let path = std::env::temp_dir().join(format!("sora-missing-{}", std::process::id()));
+        std::fs::write(&path, patched).unwrap();
+        let missing = unsafe { super::missing_dependencies(&path, &path, &options) };
+        let error = super::with(&options, || unsafe { super::open(&path) }).0.unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(missing, ["libq.so.6"]);
+        if let Ok(bytes) = std::fs::read("/etc/ld.so.cache") {
+            assert!(super::ld_cache(&bytes).unwrap().contains("libc.so.6"));
+        }
+        assert_eq!(
+            error.to_string(),
+            format!("{} needs shared libraries that cannot be found: libq.so.6", path.display())
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn preload() {
+        let dir = std::env::temp_dir().join(format!("sora-preload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // Not a library, so it is found but cannot be opened.
+        std::fs::write(dir.join("libc.so.6"), "").unwrap();
+
+        let exe = std::env::current_exe().unwrap();
+        let options = NativeLoaderOptions::new().search_path(&dir);
+        let mut visited = Vec::new();
+        let libraries = unsafe { super::preload(exe.as_os_str(), &options, &mut visited) };
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(libraries.is_empty());
+        assert_eq!(visited, [dir.join("libc.so.6")]);
+
+        let mut visited = Vec::new();
+        let options = NativeLoaderOptions::new();
+        unsafe { super::preload(exe.as_os_str(), &options, &mut visited) };
+        assert!(visited.is_empty());
+    }
+}
@@ -0,0 +1,287 @@
+//! Following a dispatch as it happens.
+
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::Instant;
+
+use crate::report::Failures;
+use crate::schedule::Schedule;
+use crate::{DispatchReport, Dispatcher, Phase, PluginReport, PluginStatus, panic_hook};
+
+/// Receives the progress of
+/// [`Dispatcher::dispatch_with_observer`], for example to drive a progress
+/// bar. Every method does nothing by default.
+pub trait DispatchObserver {
+    /// Stage `index`, of `len` plugins, is about to run.
+    fn stage_started(&mut self, index: usize, len: usize) {
+        let _ = (index, len);
+    }
+
+    fn plugin_started(&mut self, name: &str) {
+        let _ = name;
+    }
+
+    fn plugin_completed(&mut self, report: &PluginReport) {
+        let _ = report;
+    }
+
+    /// The plugin panicked, on its last attempt, or crashed.
+    fn plugin_failed(&mut self, report: &PluginReport) {
+        let _ = report;
+    }
+
+    /// The plugin did not run because of the
+    /// [`ErrorPolicy`](crate::ErrorPolicy).
+    fn plugin_skipped(&mut self, report: &PluginReport) {
+        let _ = report;
+    }
+
+    fn stage_finished(&mut self, index: usize, reports: &[PluginReport]) {
+        let _ = (index, reports);
+    }
+}
+
+/// Observes nothing.
+impl DispatchObserver for () {}
+
+impl<O: DispatchObserver + ?Sized> DispatchObserver for &mut O {
+    fn stage_started(&mut self, index: usize, len: usize) {
+        (**self).stage_started(index, len);
+    }
+
+    fn plugin_started(&mut self, name: &str) {
+        (**self).plugin_started(name);
+    }
+
+    fn plugin_completed(&mut self, report: &PluginReport) {
+        (**self).plugin_completed(report);
+    }
+
+    fn plugin_failed(&mut self, report: &PluginReport) {
+        (**self).plugin_failed(report);
+    }
+
+    fn plugin_skipped(&mut self, report: &PluginReport) {
+        (**self).plugin_skipped(report);
+    }
+
+    fn stage_finished(&mut self, index: usize, reports: &[PluginReport]) {
+        (**self).stage_finished(index, reports);
+    }
+}
+
+/// The progress of [`Dispatcher::dispatch_streaming`], one event per
+/// [`DispatchObserver`] method.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DispatchEvent {
+    StageStarted {
+        index: usize,
+        len: usize,
+    },
+    PluginStarted {
+        name: String,
+    },
+    PluginCompleted(PluginReport),
+    PluginFailed(PluginReport),
+    PluginSkipped(PluginReport),
+    StageFinished {
+        index: usize,
+    },
+    /// The last event, with the report of the whole dispatch.
+    Finished(DispatchReport),
+}
+
+/// Sends every event, ignoring them once the receiver hangs up.
+impl DispatchObserver for Sender<DispatchEvent> {
+    fn stage_started(&mut self, index: usize, len: usize) {
+        let _ = self.send(DispatchEvent::StageStarted { index, len });
+    }
+
+    fn plugin_started(&mut self, name: &str) {
+        let _ = self.send(DispatchEvent::PluginStarted { name: name.to_owned() });
+    }
+
+    fn plugin_completed(&mut self, report: &PluginReport) {
+        let _ = self.send(DispatchEvent::PluginCompleted(report.clone()));
+    }
+
+    fn plugin_failed(&mut self, report: &PluginReport) {
+        let _ = self.send(DispatchEvent::PluginFailed(report.clone()));
+    }
+
+    fn plugin_skipped(&mut self, report: &PluginReport) {
+        let _ = self.send(DispatchEvent::PluginSkipped(report.clone()));
+    }
+
+    fn stage_finished(&mut self, index: usize, _: &[PluginReport]) {
+        let _ = self.send(DispatchEvent::StageFinished { index });
+    }
+}
+
+impl<L> Dispatcher<L> {
+    /// Like [`dispatch_report`](Self::dispatch_report), but tells `observer`
+    /// about every stage and plugin as it starts and finishes.
+    pub fn dispatch_with_observer(&self, observer: impl DispatchObserver) -> DispatchReport {
+        self.observe(&self.schedule, observer)
+    }
+
+    /// Like [`dispatch_phase`](Self::dispatch_phase), but produces a report.
+    /// See [`dispatch_report`](Self::dispatch_report).
+    pub fn dispatch_phase_report(&self, phase: Phase) -> DispatchReport {
+        self.observe(&self.phases[phase as usize], ())
+    }
+
+    fn observe(&self, schedule: &Schedule, mut observer: impl DispatchObserver) -> DispatchReport {
+        let _hook = panic_hook::Scope::enter();
+        let start = Instant::now();
+        self.state.begin();
+        let mut failures = Failures::new(self.error_policy);
+        let stages = schedule
+            .stages()
+            .iter()
+            .enumerate()
+            .map(|(index, stage)| {
+                observer.stage_started(index, stage.len());
+                let reports: Vec<_> = stage
+                    .iter()
+                    .map(|&slot| {
+                        let plugin = &**self.at(slot);
+                        if !failures.skips(slot) {
+                            observer.plugin_started(plugin.name());
+                        }
+
+                        let report = failures.run(slot, plugin, &self.state);
+                        match report.status {
+                            PluginStatus::Succeeded => observer.plugin_completed(&report),
+                            PluginStatus::Panicked(_) | PluginStatus::Crashed(_) => {
+                                observer.plugin_failed(&report)
+                            }
+                            PluginStatus::Skipped(_) | PluginStatus::Deferred => {
+                                observer.plugin_skipped(&report)
+                            }
+                        }
+                        report
+                    })
+                    .collect();
+                failures.record(schedule, stage, &reports);
+                self.history.record(&reports);
+                observer.stage_finished(index, &reports);
+                reports
+            })
+            .collect();
+
+        DispatchReport { stages, duration: start.elapsed(), policy: failures.policy() }
+    }
+}
+
+impl<L: Send + Sync + 'static> Dispatcher<L> {
+    /// Starts [`dispatch_with_observer`](Self::dispatch_with_observer) on a
+    /// new thread and returns the events as they happen. The channel closes
+    /// after [`DispatchEvent::Finished`].
+    pub fn dispatch_streaming(self: &Arc<Self>) -> Receiver<DispatchEvent> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let dispatcher = Arc::clone(self);
+        std::thread::spawn(move || {
+            let report = dispatcher.dispatch_with_observer(sender.clone());
+            let _ = sender.send(DispatchEvent::Finished(report));
+        });
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{DispatchEvent, DispatchObserver};
+    use crate::{ErrorPolicy, Plugin, PluginManager, PluginReport, RunContext};
+
+    #[derive(Default)]
+    struct Events(Vec<String>);
+
+    impl DispatchObserver for Events {
+        fn stage_started(&mut self, index: usize, len: usize) {
+            self.0.push(format!("stage {index} of {len}"));
+        }
+
+        fn plugin_started(&mut self, name: &str) {
+            self.0.push(format!("start {name}"));
+        }
+
+        fn plugin_completed(&mut self, report: &PluginReport) {
+            self.0.push(format!("complete {}", report.name));
+        }
+
+        fn plugin_failed(&mut self, report: &PluginReport) {
+            self.0.push(format!("fail {}", report.name));
+        }
+
+        fn plugin_skipped(&mut self, report: &PluginReport) {
+            self.0.push(format!("skip {}", report.name));
+        }
+
+        fn stage_finished(&mut self, index: usize, _: &[PluginReport]) {
+            self.0.push(format!("end {index}"));
+        }
+    }
+
+    struct A;
+    impl Plugin for A {
+        fn run(&self, _: &RunContext) {
+            panic!("A failed");
+        }
+    }
+
+    struct B;
+    impl Plugin for B {
+        fn dependencies(&self) -> &[&str] {
+            &["A"]
+        }
+
+        fn run(&self, _: &RunContext) {}
+    }
+
+    #[test]
+    fn observer() {
+        let mut manager = PluginManager::new();
+        manager.register(Box::new(A)).unwrap();
+        manager.register(Box::new(B)).unwrap();
+        let mut dispatcher = manager.into_dispatcher();
+
+        let mut events = Events::default();
+        let report = dispatcher.dispatch_with_observer(&mut events);
+        assert_eq!(report.stages.len(), 2);
+        assert_eq!(
+            events.0,
+            [
+                "stage 0 of 1",
+                "start A",
+                "fail A",
+                "end 0",
+                "stage 1 of 1",
+                "start B",
+                "complete B",
+                "end 1"
+            ]
+        );
+
+        dispatcher.set_error_policy(ErrorPolicy::SkipDependents);
+        let mut events = Events::default();
+        dispatcher.dispatch_with_observer(&mut events);
+        assert_eq!(events.0[4..], ["stage 1 of 1", "skip B", "end 1"]);
+    }
+
+    #[test]
+    fn streaming() {
+        let mut manager = PluginManager::new();
+        manager.register(Box::new(A)).unwrap();
+        manager.register(Box::new(B)).unwrap();
+        let dispatcher = Arc::new(manager.into_dispatcher());
+
+        let events: Vec<_> = dispatcher.dispatch_streaming().into_iter().collect();
+        assert_eq!(events.len(), 9);
+        assert_eq!(events[1], DispatchEvent::PluginStarted { name: "A".to_owned() });
+        assert!(matches!(&events[2], DispatchEvent::PluginFailed(report) if report.name == "A"));
+        assert!(matches!(&events[8], DispatchEvent::Finished(report) if !report.is_success()));
+    }
+}
@@ -0,0 +1,76 @@
+//! A panic hook that records where a plugin panicked, installed while a
+//! reporting dispatch runs.
+
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::panic::PanicHookInfo;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::PanicReport;
+
+type Hook = Box<dyn Fn(&PanicHookInfo<'_>) + Send + Sync>;
+
+/// How many scopes are alive, and the hook that was installed before the
+/// first of them.
+static SCOPES: Mutex<(usize, Option<Arc<Hook>>)> = Mutex::new((0, None));
+
+thread_local! {
+    /// Whether this thread is running a plugin whose panics are recorded.
+    static RECORDING: Cell<bool> = const { Cell::new(false) };
+    static PANIC: RefCell<Option<PanicReport>> = const { RefCell::new(None) };
+}
+
+/// Keeps the hook installed while alive. Panics outside of [`record`] are
+/// passed on to the previous hook.
+pub(crate) struct Scope(());
+
+impl Scope {
+    pub(crate) fn enter() -> Self {
+        let mut scopes = SCOPES.lock().unwrap_or_else(PoisonError::into_inner);
+        if scopes.0 == 0 {
+            let previous = Arc::new(std::panic::take_hook());
+            scopes.1 = Some(previous.clone());
+            std::panic::set_hook(Box::new(move |info| match RECORDING.get() {
+                true => PANIC.set(Some(capture(info))),
+                false => previous(info),
+            }));
+        }
+        scopes.0 += 1;
+        Self(())
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let mut scopes = SCOPES.lock().unwrap_or_else(PoisonError::into_inner);
+        scopes.0 -= 1;
+        // The hook cannot be changed while panicking. It stays installed,
+        // and passes panics on.
+        if scopes.0 > 0 || std::thread::panicking() {
+            return;
+        }
+
+        drop(std::panic::take_hook());
+        if let Some(Ok(previous)) = scopes.1.take().map(Arc::try_unwrap) {
+            std::panic::set_hook(previous);
+        }
+    }
+}
+
+/// Runs `f`, and returns where the last panic it caught, if any, happened.
+pub(crate) fn record<T>(f: impl FnOnce() -> T) -> (T, Option<PanicReport>) {
+    let recording = RECORDING.replace(true);
+    let previous = PANIC.take();
+    let result = f();
+    RECORDING.set(recording);
+    (result, PANIC.replace(previous))
+}
+
+fn capture(info: &PanicHookInfo<'_>) -> PanicReport {
+    let thread = std::thread::current();
+    PanicReport {
+        thread: thread.name().map_or_else(|| format!("{:?}", thread.id()), str::to_owned),
+        location: info.location().map(ToString::to_string),
+        backtrace: Backtrace::force_capture().to_string(),
+    }
+}
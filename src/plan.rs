@@ -0,0 +1,481 @@
+//! The stages a dispatcher would run, and why, computed without running
+//! anything.
+
+use std::fmt::{self, Write as _};
+
+use ahash::AHashMap;
+
+use crate::json::{self, Value};
+use crate::report::push_json_string;
+use crate::schedule::{Access, Schedule};
+use crate::{Dispatcher, Loader, Phase, PluginManager};
+
+/// The stages of [`Dispatcher::dispatch`], as returned by
+/// [`Dispatcher::plan`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    pub stages: Vec<Vec<PlannedPlugin>>,
+}
+
+/// Why a plugin runs in its stage, as returned by
+/// [`Dispatcher::explain`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    pub name: String,
+    pub stage: usize,
+    /// The plugins that keep it from running earlier, from one in the first
+    /// stage to the plugin itself. Each waits for the one before it and
+    /// runs in the next stage.
+    pub chain: Vec<String>,
+    /// The plugins that wait for it, directly or not, by stage.
+    pub blocks: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedPlugin {
+    pub name: String,
+    pub version: Option<String>,
+    /// The plugins it waits for: its scheduled dependencies, and the
+    /// plugins ordered before it because they access the same blackboard
+    /// keys.
+    pub after: Vec<String>,
+}
+
+impl<L> Dispatcher<L> {
+    /// The stages [`dispatch`](Self::dispatch) runs, for checking a set of
+    /// plugins without running them. Disabled plugins are left out.
+    pub fn plan(&self) -> Plan {
+        let stages = self.schedule.stages().iter().map(|stage| {
+            stage
+                .iter()
+                .map(|&slot| PlannedPlugin {
+                    name: self.at(slot).name().to_owned(),
+                    version: self.at(slot).version().map(str::to_owned),
+                    after: self
+                        .schedule
+                        .dependencies(slot)
+                        .iter()
+                        .map(|&dependency| self.at(dependency).name().to_owned())
+                        .collect(),
+                })
+                .collect()
+        });
+
+        Plan { stages: stages.collect() }
+    }
+
+    /// Builds a dispatcher for the plugins of `manager` that runs them in
+    /// the stages of `plan`, such as one saved with [`Plan::to_json`] by an
+    /// earlier release, rather than scheduling them anew.
+    ///
+    /// The plan is checked against the plugins: every planned plugin must
+    /// be loaded and run after the planned plugins it depends on and those
+    /// listed in `after`, and plugins whose blackboard access conflicts
+    /// must not share a stage. Loaded plugins left out of the plan are
+    /// [disabled](Self::disable). Plugins are not scheduled otherwise, so
+    /// a plan fails to match plugins that depend on each other rather than
+    /// panicking.
+    pub fn plan_from<M: Loader<Library = L>>(
+        plan: &Plan,
+        manager: PluginManager<M>,
+    ) -> Result<Self, PlanError> {
+        let mut dispatcher = manager.into_dispatcher_builder().build_unscheduled();
+
+        let mut stage_of_slot = AHashMap::new();
+        for (stage, plugins) in plan.stages.iter().enumerate() {
+            for plugin in plugins {
+                let slot = *dispatcher
+                    .slot_of_plugin
+                    .get(&plugin.name)
+                    .ok_or_else(|| PlanError::UnknownPlugin(plugin.name.clone()))?;
+                if stage_of_slot.insert(slot, stage).is_some() {
+                    return Err(PlanError::Duplicate(plugin.name.clone()));
+                }
+            }
+        }
+
+        let mut edges = Vec::new();
+        for plugin in plan.plugins() {
+            let slot = dispatcher.slot_of_plugin[&plugin.name];
+            let handle = dispatcher.at(slot);
+            let dependencies = handle.dependencies().iter().copied().filter(|dependency| {
+                dispatcher
+                    .slot_of_plugin
+                    .get(*dependency)
+                    .is_some_and(|dependency| stage_of_slot.contains_key(dependency))
+            });
+            for dependency in dependencies {
+                if !plugin.after.iter().any(|after| after == dependency) {
+                    return Err(PlanError::Order {
+                        plugin: plugin.name.clone(),
+                        dependency: dependency.to_owned(),
+                    });
+                }
+            }
+
+            for after in &plugin.after {
+                let dependency = dispatcher
+                    .slot_of_plugin
+                    .get(after)
+                    .copied()
+                    .filter(|dependency| {
+                        stage_of_slot
+                            .get(dependency)
+                            .is_some_and(|&stage| stage < stage_of_slot[&slot])
+                    })
+                    .ok_or_else(|| PlanError::Order {
+                        plugin: plugin.name.clone(),
+                        dependency: after.clone(),
+                    })?;
+                edges.push((dependency, slot));
+            }
+        }
+
+        for (stage, plugins) in plan.stages.iter().enumerate() {
+            let slots: Vec<_> =
+                plugins.iter().map(|plugin| dispatcher.slot_of_plugin[&plugin.name]).collect();
+            for (index, &slot) in slots.iter().enumerate() {
+                let access = Access::of(&**dispatcher.at(slot));
+                if let Some(&other) = slots[..index]
+                    .iter()
+                    .find(|&&other| access.conflicts(&Access::of(&**dispatcher.at(other))))
+                {
+                    return Err(PlanError::Conflict {
+                        stage,
+                        plugins: [dispatcher.at(other).name(), dispatcher.at(slot).name()]
+                            .map(str::to_owned),
+                    });
+                }
+            }
+        }
+
+        // Each phase keeps the order of the plan, without its empty stages.
+        let restrict = |include: &dyn Fn(usize) -> bool| {
+            let stages: Vec<Vec<usize>> = plan
+                .stages
+                .iter()
+                .map(|plugins| {
+                    let slots =
+                        plugins.iter().map(|plugin| dispatcher.slot_of_plugin[&plugin.name]);
+                    slots.filter(|&slot| include(slot)).collect()
+                })
+                .filter(|slots: &Vec<_>| !slots.is_empty())
+                .collect();
+            let edges = edges.iter().copied();
+            Schedule::new(stages, edges.filter(|&(from, to)| include(from) && include(to)))
+        };
+        let schedule = restrict(&|_| true);
+        let phases =
+            Phase::ALL.map(|phase| restrict(&|slot| dispatcher.at(slot).phases().contains(&phase)));
+        dispatcher.schedule = schedule;
+        dispatcher.phases = phases;
+
+        Ok(dispatcher)
+    }
+
+    /// Explains why the plugin called `name` runs in its stage. Returns
+    /// `None` if no such plugin is scheduled.
+    pub fn explain(&self, name: &str) -> Option<Explanation> {
+        let slot = *self.slot_of_plugin.get(name).filter(|&&slot| self.schedule.contains(slot))?;
+        let stage = self.schedule.stage(slot);
+
+        // A plugin runs in the stage after the latest of its dependencies.
+        let mut chain = vec![slot];
+        while let Some(&dependency) = self
+            .schedule
+            .dependencies(chain[chain.len() - 1])
+            .iter()
+            .max_by_key(|&&dependency| self.schedule.stage(dependency))
+        {
+            chain.push(dependency);
+        }
+
+        let mut blocks: Vec<_> = self.schedule.downstream(slot).into_iter().collect();
+        blocks.sort_by_key(|&slot| (self.schedule.stage(slot), self.at(slot).name()));
+
+        let name = |slot: usize| self.at(slot).name().to_owned();
+        Some(Explanation {
+            name: name(slot),
+            stage,
+            chain: chain.into_iter().rev().map(name).collect(),
+            blocks: blocks.into_iter().map(name).collect(),
+        })
+    }
+}
+
+impl Plan {
+    pub fn plugins(&self) -> impl Iterator<Item = &PlannedPlugin> {
+        self.stages.iter().flatten()
+    }
+
+    /// Serializes the plan as a single line of JSON, which
+    /// [`from_json`](Self::from_json) reads back.
+    ///
+    /// ```json
+    /// {"stages":[[{"name":"Physics","version":"1.0.0","after":[]}],[{"name":"Render","version":null,"after":["Physics"]}]]}
+    /// ```
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"stages\":[");
+
+        for (index, stage) in self.stages.iter().enumerate() {
+            json.push_str(if index == 0 { "[" } else { ",[" });
+            for (index, plugin) in stage.iter().enumerate() {
+                if index > 0 {
+                    json.push(',');
+                }
+                json.push_str("{\"name\":");
+                push_json_string(&mut json, &plugin.name);
+                json.push_str(",\"version\":");
+                match &plugin.version {
+                    Some(version) => push_json_string(&mut json, version),
+                    None => json.push_str("null"),
+                }
+                json.push_str(",\"after\":[");
+                for (index, name) in plugin.after.iter().enumerate() {
+                    if index > 0 {
+                        json.push(',');
+                    }
+                    push_json_string(&mut json, name);
+                }
+                json.push_str("]}");
+            }
+            json.push(']');
+        }
+
+        json.push_str("]}");
+        json
+    }
+
+    /// Reads a plan written by [`to_json`](Self::to_json).
+    pub fn from_json(source: &str) -> Result<Self, PlanError> {
+        let value = json::parse(source)
+            .map_err(|error| PlanError::Syntax { offset: error.offset, message: error.message })?;
+        let invalid = |message: &str| PlanError::Invalid(message.to_owned());
+        let strings = |value: Value| match value {
+            Value::Array(values) => values
+                .into_iter()
+                .map(|value| match value {
+                    Value::String(string) => Ok(string),
+                    _ => Err(invalid("`after` must hold plugin names")),
+                })
+                .collect(),
+            _ => Err(invalid("`after` must be an array")),
+        };
+
+        let Value::Object(mut plan) = value else { return Err(invalid("must be an object")) };
+        let Some(Value::Array(stages)) = plan.remove("stages") else {
+            return Err(invalid("`stages` must be an array"));
+        };
+        let stages = stages
+            .into_iter()
+            .map(|stage| {
+                let Value::Array(plugins) = stage else {
+                    return Err(invalid("every stage must be an array"));
+                };
+                plugins
+                    .into_iter()
+                    .map(|plugin| {
+                        let Value::Object(mut plugin) = plugin else {
+                            return Err(invalid("every plugin must be an object"));
+                        };
+                        let Some(Value::String(name)) = plugin.remove("name") else {
+                            return Err(invalid("every plugin needs a `name`"));
+                        };
+                        let version = match plugin.remove("version") {
+                            Some(Value::String(version)) => Some(version),
+                            Some(Value::Null) | None => None,
+                            Some(_) => return Err(invalid("`version` must be a string")),
+                        };
+                        let after =
+                            strings(plugin.remove("after").unwrap_or(Value::Array(vec![])))?;
+                        Ok(PlannedPlugin { name, version, after })
+                    })
+                    .collect()
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { stages })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PlanError {
+    #[error("at byte {offset}: {message}")]
+    Syntax { offset: usize, message: &'static str },
+    #[error("invalid plan: {0}")]
+    Invalid(String),
+    #[error("plugin `{0}` is planned but not loaded")]
+    UnknownPlugin(String),
+    #[error("plugin `{0}` is planned more than once")]
+    Duplicate(String),
+    #[error("plugin `{plugin}` is not planned after `{dependency}`")]
+    Order { plugin: String, dependency: String },
+    #[error("plugins `{}` and `{}` share stage {stage} but access the same keys", plugins[0], plugins[1])]
+    Conflict { stage: usize, plugins: [String; 2] },
+}
+
+/// One line per stage, such as `stage 1: Render (after Physics)`.
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, stage) in self.stages.iter().enumerate() {
+            let mut line = format!("stage {index}:");
+            for (position, plugin) in stage.iter().enumerate() {
+                line.push_str(if position == 0 { " " } else { ", " });
+                line.push_str(&plugin.name);
+                if !plugin.after.is_empty() {
+                    write!(line, " (after {})", plugin.after.join(", "))?;
+                }
+            }
+            writeln!(f, "{line}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// For example:
+///
+/// ```text
+/// Render runs in stage 2, after Input -> Physics -> Render
+/// it blocks UI, Present
+/// ```
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.chain.len() {
+            1 => writeln!(f, "{} runs in stage {}, waiting for nothing", self.name, self.stage)?,
+            _ => writeln!(
+                f,
+                "{} runs in stage {}, after {}",
+                self.name,
+                self.stage,
+                self.chain.join(" -> ")
+            )?,
+        }
+        match self.blocks.is_empty() {
+            true => writeln!(f, "it blocks nothing"),
+            false => writeln!(f, "it blocks {}", self.blocks.join(", ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Plan, PlanError};
+    use crate::fixture::Stub;
+    use crate::{Dispatcher, PluginManager};
+
+    #[test]
+    fn plan() {
+        let mut manager = PluginManager::new();
+        for (name, dependencies) in
+            [("Physics", &[][..]), ("Audio", &[]), ("Render", &["Physics", "Audio"])]
+        {
+            manager.register(Box::new(Stub::new(name, dependencies))).unwrap();
+        }
+        let mut dispatcher = manager.into_dispatcher();
+        dispatcher.disable("Audio");
+
+        let plan = dispatcher.plan();
+        assert_eq!(plan.plugins().count(), 2);
+        assert_eq!(plan.to_string(), "stage 0: Physics\nstage 1: Render (after Physics)\n");
+        assert_eq!(
+            plan.to_json(),
+            r#"{"stages":[[{"name":"Physics","version":null,"after":[]}],[{"name":"Render","version":null,"after":["Physics"]}]]}"#
+        );
+        assert_eq!(Plan::from_json(&plan.to_json()).unwrap(), plan);
+    }
+
+    #[test]
+    fn plan_from() {
+        let manager = || {
+            let mut manager = PluginManager::new();
+            for (name, dependencies) in
+                [("Physics", &[][..]), ("Audio", &[]), ("Render", &["Physics"])]
+            {
+                manager.register(Box::new(Stub::new(name, dependencies))).unwrap();
+            }
+            manager
+        };
+
+        // Audio waits for nothing, but is planned last.
+        let json = r#"{"stages":[[{"name":"Physics"}],[{"name":"Render","after":["Physics"]}],
+            [{"name":"Audio","version":null,"after":[]}]]}"#;
+        let plan = Plan::from_json(json).unwrap();
+        let dispatcher = Dispatcher::plan_from(&plan, manager()).unwrap();
+        assert_eq!(dispatcher.plan(), plan);
+        assert_eq!(dispatcher.explain("Render").unwrap().chain, ["Physics", "Render"]);
+
+        let mut partial = plan.clone();
+        partial.stages.pop();
+        let mut dispatcher = Dispatcher::plan_from(&partial, manager()).unwrap();
+        assert!(dispatcher.enable("Audio"));
+
+        let error = |json| Dispatcher::plan_from(&Plan::from_json(json).unwrap(), manager());
+        assert!(matches!(
+            error(r#"{"stages":[[{"name":"Physics"},{"name":"Render"}]]}"#),
+            Err(PlanError::Order { plugin, dependency }) if plugin == "Render" && dependency == "Physics"
+        ));
+        assert!(matches!(
+            error(r#"{"stages":[[{"name":"Render","after":["Physics"]}],[{"name":"Physics"}]]}"#),
+            Err(PlanError::Order { .. })
+        ));
+        assert!(matches!(
+            error(r#"{"stages":[[{"name":"Input"}]]}"#),
+            Err(PlanError::UnknownPlugin(name)) if name == "Input"
+        ));
+
+        // Plugins that depend on each other cannot be planned, but are not
+        // scheduled either when left out.
+        let cyclic = || {
+            let mut manager = manager();
+            manager.register(Box::new(Stub::new("Left", &["Right"]))).unwrap();
+            manager.register(Box::new(Stub::new("Right", &["Left"]))).unwrap();
+            manager
+        };
+        let json = r#"{"stages":[[{"name":"Left"}],[{"name":"Right","after":["Left"]}]]}"#;
+        assert!(matches!(
+            Dispatcher::plan_from(&Plan::from_json(json).unwrap(), cyclic()),
+            Err(PlanError::Order { plugin, dependency }) if plugin == "Left" && dependency == "Right"
+        ));
+        assert_eq!(Dispatcher::plan_from(&plan, cyclic()).unwrap().plan(), plan);
+        assert!(matches!(
+            Plan::from_json(r#"{"stages":[[{"after":[]}]]}"#),
+            Err(PlanError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn explain() {
+        let mut manager = PluginManager::new();
+        for (name, dependencies) in [
+            ("Input", &[][..]),
+            ("Audio", &[]),
+            ("Physics", &["Input"]),
+            ("Render", &["Audio", "Physics"]),
+            ("Present", &["Render"]),
+            ("Ui", &["Physics"]),
+        ] {
+            manager.register(Box::new(Stub::new(name, dependencies))).unwrap();
+        }
+        let dispatcher = manager.into_dispatcher();
+
+        let render = dispatcher.explain("Render").unwrap();
+        assert_eq!(render.stage, 2);
+        assert_eq!(render.chain, ["Input", "Physics", "Render"]);
+        assert_eq!(render.blocks, ["Present"]);
+
+        let input = dispatcher.explain("Input").unwrap();
+        assert_eq!(input.chain, ["Input"]);
+        assert_eq!(input.blocks, ["Physics", "Render", "Ui", "Present"]);
+        assert_eq!(
+            input.to_string(),
+            "Input runs in stage 0, waiting for nothing\nit blocks Physics, Render, Ui, Present\n"
+        );
+        assert_eq!(
+            render.to_string(),
+            "Render runs in stage 2, after Input -> Physics -> Render\nit blocks Present\n"
+        );
+
+        assert_eq!(dispatcher.explain("Missing"), None);
+    }
+}
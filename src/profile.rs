@@ -0,0 +1,135 @@
+//! Per-plugin timings of [`Dispatcher::dispatch_par`], written in the Chrome
+//! trace event format, enabled by the `profile` feature.
+//!
+//! [`Dispatcher::dispatch_par`]: crate::Dispatcher::dispatch_par
+
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::report::push_json_string;
+use crate::{Dispatcher, SpanKind};
+
+/// The spans recorded by a dispatcher since it was built or last cleared.
+pub(crate) struct Profile {
+    epoch: Instant,
+    spans: Mutex<Vec<Span>>,
+}
+
+struct Span {
+    name: String,
+    kind: SpanKind,
+    /// The worker thread that ran a plugin.
+    thread: Option<usize>,
+    start: Duration,
+    duration: Duration,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self { epoch: Instant::now(), spans: Mutex::default() }
+    }
+}
+
+impl Profile {
+    /// Runs `f`, recording it as a span named `name`.
+    pub(crate) fn record<T>(&self, kind: SpanKind, name: String, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        let span = Span {
+            name,
+            kind,
+            thread: match kind {
+                SpanKind::Stage => None,
+                SpanKind::Plugin => crate::executor::current_thread_index(),
+            },
+            start: start - self.epoch,
+            duration: start.elapsed(),
+        };
+        self.spans.lock().unwrap().push(span);
+
+        result
+    }
+
+    fn to_json(&self) -> String {
+        let mut json = String::from("{\"traceEvents\":[");
+
+        for (index, span) in self.spans.lock().unwrap().iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            json.push_str("{\"name\":");
+            push_json_string(&mut json, &span.name);
+            // Stages are shown as thread 0, above the workers.
+            let (category, thread) = match span.kind {
+                SpanKind::Stage => ("stage", 0),
+                SpanKind::Plugin => ("plugin", span.thread.map_or(0, |thread| thread + 1)),
+            };
+            write!(
+                json,
+                ",\"cat\":\"{category}\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":1,\"tid\":\
+                 {thread}}}",
+                span.start.as_secs_f64() * 1e6,
+                span.duration.as_secs_f64() * 1e6,
+            )
+            .unwrap();
+        }
+
+        json.push_str("]}");
+        json
+    }
+}
+
+impl<L> Dispatcher<L> {
+    /// Returns the spans recorded by [`dispatch_par`](Self::dispatch_par)
+    /// as Chrome trace JSON, for `chrome://tracing` or Perfetto.
+    ///
+    /// Every plugin run is a span on the worker thread that ran it, and
+    /// every stage a span on thread 0.
+    pub fn trace_json(&self) -> String {
+        self.profile.to_json()
+    }
+
+    /// Writes [`trace_json`](Self::trace_json) to `path`, usually
+    /// `trace.json`.
+    pub fn write_trace(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.trace_json())
+    }
+
+    /// Discards the recorded spans.
+    pub fn clear_trace(&self) {
+        self.profile.spans.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Plugin, PluginManager, RunContext};
+
+    struct Sleep;
+
+    impl Plugin for Sleep {
+        fn run(&self, _: &RunContext) {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn trace_json() {
+        let mut manager = PluginManager::new();
+        manager.register(Box::new(Sleep)).unwrap();
+        let dispatcher = manager.into_dispatcher();
+
+        assert_eq!(dispatcher.trace_json(), "{\"traceEvents\":[]}");
+
+        dispatcher.dispatch_par();
+        let json = dispatcher.trace_json();
+        assert!(json.contains("{\"name\":\"Sleep\",\"cat\":\"plugin\",\"ph\":\"X\""));
+        assert!(json.contains("{\"name\":\"stage 0\",\"cat\":\"stage\",\"ph\":\"X\""));
+        assert!(json.contains("\"tid\":0}"));
+
+        dispatcher.clear_trace();
+        assert_eq!(dispatcher.trace_json(), "{\"traceEvents\":[]}");
+    }
+}
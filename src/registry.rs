@@ -0,0 +1,418 @@
+//! Registries that plugins are installed from: a folder with an index of
+//! the released bundles of every plugin.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::json::{self, Value};
+use crate::manifest::{is_version, parse_sha256};
+use crate::sha2::Sha256;
+use crate::{Loader, PluginManager};
+
+/// A plugin registry, described by the `index.json` at its root.
+///
+/// ```json
+/// {
+///   "plugins": {
+///     "Hello": [
+///       {
+///         "version": "0.1.0",
+///         "bundle": "hello/hello-0.1.0.sora",
+///         "sha256": "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+///       }
+///     ]
+///   }
+/// }
+/// ```
+///
+/// `bundle` is the path of the [bundle](crate::PluginManager::load_bundle)
+/// relative to the root, and `sha256` its digest. Only registries on a
+/// file system, such as a mounted share, are supported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registry {
+    root: PathBuf,
+    plugins: BTreeMap<String, Vec<Release>>,
+}
+
+/// A version of a plugin in a [`Registry`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Release {
+    pub name: String,
+    pub version: String,
+    /// The path of the bundle, relative to the root of the registry.
+    pub bundle: PathBuf,
+    pub sha256: [u8; 32],
+}
+
+impl Registry {
+    pub const INDEX: &'static str = "index.json";
+
+    /// Reads the index of the registry at `location`, a path or a `file://`
+    /// URL.
+    pub fn open(location: &str) -> Result<Self, RegistryError> {
+        let root = match location.split_once("://") {
+            None => PathBuf::from(location),
+            Some(("file", path)) => PathBuf::from(path),
+            Some((scheme, _)) => return Err(RegistryError::UnsupportedScheme(scheme.to_owned())),
+        };
+
+        let source = std::fs::read_to_string(root.join(Self::INDEX)).map_err(RegistryError::Io)?;
+        Self::parse(root, &source)
+    }
+
+    /// Parses the index of the registry at `root`.
+    pub fn parse(root: impl Into<PathBuf>, source: &str) -> Result<Self, RegistryError> {
+        let index = json::parse(source).map_err(|error| RegistryError::Syntax {
+            offset: error.offset,
+            message: error.message,
+        })?;
+
+        let invalid = RegistryError::Invalid;
+        let Value::Object(mut index) = index else {
+            return Err(invalid(format!(
+                "the index must be an object, found {}",
+                index.type_name()
+            )));
+        };
+        let plugins = match index.remove("plugins") {
+            Some(Value::Object(plugins)) => plugins,
+            Some(value) => {
+                return Err(invalid(format!(
+                    "`plugins` must be an object, found {}",
+                    value.type_name()
+                )));
+            }
+            None => return Err(invalid("missing field `plugins`".to_owned())),
+        };
+
+        let plugins = plugins
+            .into_iter()
+            .map(|(name, releases)| {
+                let Value::Array(releases) = releases else {
+                    return Err(invalid(format!("the releases of `{name}` must be an array")));
+                };
+                let mut releases = releases
+                    .into_iter()
+                    .map(|release| parse_release(&name, release))
+                    .collect::<Result<Vec<_>, _>>()?;
+                releases.sort_by(|a, b| compare_versions(&a.version, &b.version));
+                Ok((name, releases))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { root: root.into(), plugins })
+    }
+
+    /// The releases of the plugin `name`, oldest first.
+    pub fn releases(&self, name: &str) -> &[Release] {
+        self.plugins.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    /// The release of `name` with `version`, or its latest release if no
+    /// version is given.
+    pub fn resolve(&self, name: &str, version: Option<&str>) -> Result<&Release, RegistryError> {
+        let releases = self.releases(name);
+        let release = match version {
+            Some(version) => releases.iter().find(|release| release.version == version),
+            None => releases.last(),
+        };
+
+        release.ok_or_else(|| RegistryError::NotFound {
+            name: name.to_owned(),
+            version: version.map(str::to_owned),
+        })
+    }
+
+    /// Copies the bundle of `release` into `dir`, as
+    /// `<name>-<version>.sora`, once its digest is checked, and removes the
+    /// other versions of the plugin installed there. Returns the path of
+    /// the installed bundle.
+    pub fn install(
+        &self,
+        release: &Release,
+        dir: impl AsRef<Path>,
+    ) -> Result<PathBuf, RegistryError> {
+        let dir = dir.as_ref();
+        let source = self.root.join(&release.bundle);
+        let bytes = std::fs::read(&source).map_err(RegistryError::Io)?;
+        if Sha256::digest(&bytes) != release.sha256 {
+            return Err(RegistryError::Checksum(source));
+        }
+
+        std::fs::create_dir_all(dir).map_err(RegistryError::Io)?;
+        let file_name = format!("{}-{}.sora", release.name, release.version);
+        let path = dir.join(&file_name);
+        // A partially written bundle is never left under the final name.
+        let partial = dir.join(format!(".{file_name}.partial"));
+        std::fs::write(&partial, &bytes)
+            .and_then(|()| std::fs::rename(&partial, &path))
+            .map_err(RegistryError::Io)?;
+
+        for (installed, other) in installed(dir).map_err(RegistryError::Io)? {
+            if installed.name == release.name && installed.version != release.version {
+                std::fs::remove_file(other).map_err(RegistryError::Io)?;
+            }
+        }
+
+        Ok(path)
+    }
+}
+
+/// A newer version of a loaded plugin, found by
+/// [`PluginManager::check_updates`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Update {
+    pub name: String,
+    pub installed: String,
+    pub latest: String,
+}
+
+impl<L: Loader> PluginManager<L> {
+    /// Compares the versions the plugins report against the releases in
+    /// `registry`, and returns those that have a newer one, without
+    /// installing it. Pre-releases are only offered to plugins that run
+    /// one. Plugins without a version, or missing from the registry, are
+    /// left out.
+    pub fn check_updates(&self, registry: &Registry) -> Vec<Update> {
+        self.metadata()
+            .iter()
+            .filter_map(|metadata| {
+                let installed = metadata.version.as_deref()?;
+                let latest = registry
+                    .releases(&metadata.name)
+                    .iter()
+                    .rev()
+                    .map(|release| release.version.as_str())
+                    .find(|version| !is_prerelease(version) || is_prerelease(installed))?;
+
+                (compare_versions(latest, installed) == Ordering::Greater).then(|| Update {
+                    name: metadata.name.clone(),
+                    installed: installed.to_owned(),
+                    latest: latest.to_owned(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// A bundle installed from a registry.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Installed {
+    pub(crate) name: String,
+    pub(crate) version: String,
+}
+
+/// The bundles installed in `dir`, named `<name>-<version>.sora`, with
+/// their paths.
+pub(crate) fn installed(dir: &Path) -> io::Result<Vec<(Installed, PathBuf)>> {
+    let mut installed = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "sora") {
+            continue;
+        }
+        let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+
+        // Names may contain `-` as well as versions.
+        let split = stem.match_indices('-').find(|&(index, _)| is_version(&stem[index + 1..]));
+        if let Some((index, _)) = split {
+            let (name, version) = (stem[..index].to_owned(), stem[index + 1..].to_owned());
+            installed.push((Installed { name, version }, path));
+        }
+    }
+
+    installed.sort();
+    Ok(installed)
+}
+
+fn parse_release(name: &str, release: Value) -> Result<Release, RegistryError> {
+    let invalid =
+        |message: &str| RegistryError::Invalid(format!("a release of `{name}` {message}"));
+    let Value::Object(mut release) = release else {
+        return Err(invalid("must be an object"));
+    };
+    let mut take = |field: &str| match release.remove(field) {
+        Some(Value::String(string)) => Ok(string),
+        Some(_) => Err(invalid(&format!("has a `{field}` that is not a string"))),
+        None => Err(invalid(&format!("has no `{field}`"))),
+    };
+
+    let version = take("version")?;
+    let bundle = PathBuf::from(take("bundle")?);
+    let sha256 = parse_sha256(&take("sha256")?).map_err(|_| invalid("has an invalid `sha256`"))?;
+
+    // Both end up in the name of the installed bundle.
+    if is_unsafe_file_name(name) {
+        return Err(invalid("cannot be installed under that name"));
+    }
+    if is_unsafe_file_name(&version) {
+        return Err(invalid(&format!("has version `{version}`, which cannot be a file name")));
+    }
+    if !is_version(&version) {
+        return Err(invalid(&format!("has version `{version}`, not `MAJOR.MINOR.PATCH`")));
+    }
+    if bundle.is_absolute() || bundle.components().any(|component| component.as_os_str() == "..") {
+        return Err(invalid("has a bundle outside of the registry"));
+    }
+
+    Ok(Release { name: name.to_owned(), version, bundle, sha256 })
+}
+
+/// Whether `name` could lead out of the folder it is joined to.
+fn is_unsafe_file_name(name: &str) -> bool {
+    name.contains(['/', '\\', '\0']) || name.contains("..")
+}
+
+/// Orders versions by their numbers, then pre-releases before releases.
+pub(crate) fn compare_versions(a: &str, b: &str) -> Ordering {
+    version_key(a).cmp(&version_key(b))
+}
+
+fn is_prerelease(version: &str) -> bool {
+    version.split('+').next().unwrap_or_default().contains('-')
+}
+
+fn version_key(version: &str) -> (Vec<u64>, bool, &str) {
+    let version = version.split('+').next().unwrap_or_default();
+    let (core, pre) =
+        version.split_once('-').map_or((version, None), |(core, pre)| (core, Some(pre)));
+    let numbers = core.split('.').map(|number| number.parse().unwrap_or(0)).collect();
+    (numbers, pre.is_none(), pre.unwrap_or_default())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RegistryError {
+    #[error("cannot read registry: {0}")]
+    Io(io::Error),
+    #[error("`{0}://` registries are not supported, only paths and `file://` URLs")]
+    UnsupportedScheme(String),
+    #[error("invalid index at byte {offset}: {message}")]
+    Syntax { offset: usize, message: &'static str },
+    #[error("invalid index: {0}")]
+    Invalid(String),
+    #[error("no release of `{name}`{} in the registry", version.as_ref().map(|version| format!(" {version}")).unwrap_or_default())]
+    NotFound { name: String, version: Option<String> },
+    #[error("{} does not match the digest in the index", .0.display())]
+    Checksum(PathBuf),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Registry, RegistryError, Update};
+    use crate::sha2::Sha256;
+    use crate::{Plugin, PluginManager, RunContext};
+
+    struct Hello(&'static str);
+
+    impl Plugin for Hello {
+        fn version(&self) -> Option<&str> {
+            Some(self.0)
+        }
+
+        fn run(&self, _: &RunContext) {}
+    }
+
+    fn index(sha256: &str) -> String {
+        format!(
+            r#"{{"plugins": {{"Hello": [
+                {{"version": "0.10.0", "bundle": "hello-0.10.0.sora", "sha256": "{sha256}"}},
+                {{"version": "0.9.0", "bundle": "hello-0.9.0.sora", "sha256": "{sha256}"}},
+                {{"version": "0.10.0-rc.1", "bundle": "rc.sora", "sha256": "{sha256}"}}
+            ]}}}}"#
+        )
+    }
+
+    #[test]
+    fn resolve() {
+        let registry = Registry::parse("registry", &index(&"0".repeat(64))).unwrap();
+        let versions: Vec<_> =
+            registry.releases("Hello").iter().map(|release| release.version.as_str()).collect();
+        assert_eq!(versions, ["0.9.0", "0.10.0-rc.1", "0.10.0"]);
+
+        assert_eq!(registry.resolve("Hello", None).unwrap().version, "0.10.0");
+        assert_eq!(registry.resolve("Hello", Some("0.9.0")).unwrap().version, "0.9.0");
+        assert_eq!(
+            registry.resolve("Hello", Some("1.0.0")).unwrap_err().to_string(),
+            "no release of `Hello` 1.0.0 in the registry"
+        );
+        assert!(registry.releases("Missing").is_empty());
+
+        let error = |source: &str| Registry::parse("registry", source).unwrap_err().to_string();
+        assert_eq!(error("{}"), "invalid index: missing field `plugins`");
+        assert_eq!(
+            error(r#"{"plugins": {"A": [{"version": "1", "bundle": "a", "sha256": ""}]}}"#),
+            "invalid index: a release of `A` has an invalid `sha256`"
+        );
+        assert_eq!(
+            error(&format!(
+                r#"{{"plugins": {{"../A": [{{"version": "1.0.0", "bundle": "a", "sha256": "{}"}}]}}}}"#,
+                "0".repeat(64)
+            )),
+            "invalid index: a release of `../A` cannot be installed under that name"
+        );
+        assert_eq!(
+            error(&format!(
+                r#"{{"plugins": {{"A": [{{"version": "1.0.0-x/../../b", "bundle": "a", "sha256": "{}"}}]}}}}"#,
+                "0".repeat(64)
+            )),
+            "invalid index: a release of `A` has version `1.0.0-x/../../b`, which cannot be a \
+             file name"
+        );
+        assert!(matches!(
+            Registry::open("https://plugins.example.com"),
+            Err(RegistryError::UnsupportedScheme(scheme)) if scheme == "https"
+        ));
+    }
+
+    #[test]
+    fn check_updates() {
+        let registry = Registry::parse("registry", &index(&"0".repeat(64))).unwrap();
+        let check = |version: &'static str| {
+            let mut manager = PluginManager::new();
+            manager.register(Box::new(Hello(version))).unwrap();
+            manager.check_updates(&registry)
+        };
+
+        let update = Update {
+            name: "Hello".to_owned(),
+            installed: "0.9.0".to_owned(),
+            latest: "0.10.0".to_owned(),
+        };
+        assert_eq!(check("0.9.0"), [update]);
+        assert_eq!(check("0.10.0"), []);
+        assert_eq!(check("1.0.0"), []);
+        assert_eq!(check("0.10.0-rc.0")[0].latest, "0.10.0");
+    }
+
+    #[test]
+    fn install() {
+        let root = std::env::temp_dir().join(format!("sora-registry-{}", std::process::id()));
+        let plugins = root.join("plugins");
+        std::fs::create_dir_all(&plugins).unwrap();
+        std::fs::write(root.join("hello-0.10.0.sora"), b"bundle").unwrap();
+        std::fs::write(root.join("hello-0.9.0.sora"), b"tampered").unwrap();
+        std::fs::write(plugins.join("Hello-0.8.0.sora"), b"old").unwrap();
+        std::fs::write(plugins.join("Other-1.0.0.sora"), b"other").unwrap();
+        let index = index(&crate::hex(&Sha256::digest(b"bundle")));
+        std::fs::write(root.join(Registry::INDEX), index).unwrap();
+
+        let registry = Registry::open(&format!("file://{}", root.display())).unwrap();
+        let path = registry.install(registry.resolve("Hello", None).unwrap(), &plugins).unwrap();
+        let error = registry.install(registry.resolve("Hello", Some("0.9.0")).unwrap(), &plugins);
+        let mut files: Vec<_> = std::fs::read_dir(&plugins)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        let installed = std::fs::read(&path).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(files, ["Hello-0.10.0.sora", "Other-1.0.0.sora"]);
+        assert_eq!(installed, b"bundle");
+        assert!(matches!(error, Err(RegistryError::Checksum(_))));
+    }
+}
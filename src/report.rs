@@ -0,0 +1,404 @@
+//! What happened during a dispatch, as returned by
+//! [`Dispatcher::dispatch_report`](crate::Dispatcher::dispatch_report).
+
+use std::fmt::Write as _;
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, Instant};
+
+use ahash::AHashMap;
+
+use crate::context::DispatchState;
+use crate::schedule::Schedule;
+use crate::{Plugin, hex, host, panic_hook};
+
+/// What a reporting dispatch, such as
+/// [`Dispatcher::dispatch_report`](crate::Dispatcher::dispatch_report), does
+/// after a plugin fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ErrorPolicy {
+    /// Skips every stage after the one in which the plugin failed.
+    FailFast,
+    /// Skips the plugins that depend on the failed plugin, directly or
+    /// transitively, and runs the others.
+    SkipDependents,
+    /// Runs every plugin regardless.
+    #[default]
+    Continue,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DispatchReport {
+    /// One entry per plugin, grouped by the stage it ran in.
+    pub stages: Vec<Vec<PluginReport>>,
+    pub duration: Duration,
+    /// The policy that decided which plugins were skipped.
+    pub policy: ErrorPolicy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginReport {
+    pub name: String,
+    /// Every attempt, including retries. See [`Plugin::retry`].
+    pub duration: Duration,
+    /// How often the plugin ran, `0` if it was skipped.
+    pub attempts: u32,
+    pub status: PluginStatus,
+    /// Where the plugin panicked, if it did.
+    pub panic: Option<PanicReport>,
+    /// What the plugin logged through the [`HostApi`](crate::HostApi) while
+    /// it ran, and in
+    /// [`dispatch_isolated`](crate::Dispatcher::dispatch_isolated) what it
+    /// wrote to its standard output and error too. Empty unless the
+    /// dispatcher [captures output](crate::DispatcherBuilder::capture_output).
+    pub output: String,
+    /// What the plugin passed to
+    /// [`RunContext::set_result`](crate::RunContext::set_result), if
+    /// anything.
+    pub result: Option<PluginResult>,
+}
+
+/// A machine-readable result of a plugin, for hosts that aggregate them,
+/// such as from the JSON of [`DispatchReport::to_json`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PluginResult {
+    /// A JSON document.
+    Json(String),
+    Bytes(Vec<u8>),
+}
+
+/// Where a plugin panicked during a reporting dispatch. The stage is the
+/// one of its [`PluginReport`].
+///
+/// While a reporting dispatch runs, the panics of its plugins are recorded
+/// here by a panic hook rather than printed. Other panics are passed on to
+/// the hook that was installed before.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PanicReport {
+    /// The name of the thread the plugin ran on, or its ID if unnamed.
+    pub thread: String,
+    /// The file, line and column of the panic, such as `src/lib.rs:10:5`.
+    pub location: Option<String>,
+    pub backtrace: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PluginStatus {
+    Succeeded,
+    /// The plugin panicked with the given message.
+    Panicked(String),
+    /// The plugin did not run, as the [`ErrorPolicy`] demands after the
+    /// given plugin failed.
+    Skipped(String),
+    /// The plugin took down the worker process it ran in, or its state
+    /// could not be brought back from it, for the given reason. Only
+    /// [`Dispatcher::dispatch_isolated`](crate::Dispatcher::dispatch_isolated)
+    /// reports this.
+    Crashed(String),
+    /// The plugin did not run, as its stage would not have finished before
+    /// the deadline of
+    /// [`Dispatcher::dispatch_with_deadline`](crate::Dispatcher::dispatch_with_deadline).
+    Deferred,
+}
+
+impl PluginReport {
+    /// Runs `plugin` as its [`RetryPolicy`](crate::RetryPolicy) allows,
+    /// catching a panic instead of unwinding into the dispatcher.
+    pub(crate) fn run(plugin: &dyn Plugin, state: &DispatchState) -> Self {
+        let start = Instant::now();
+        let context = state.context(plugin);
+        let _watch = state.watch(plugin.name());
+        let attempt =
+            || panic_hook::record(|| plugin.retry().attempt(&context, || plugin.run(&context)));
+        let (((attempts, result), panic), output) = match state.capture_output {
+            true => host::capture(attempt),
+            false => (attempt(), String::new()),
+        };
+        let report = Self::new(plugin.name(), start, attempts, result, panic);
+        Self { output, result: state.take_result(plugin.name()), ..report }
+    }
+
+    pub(crate) fn run_with(name: &str, run: impl FnOnce()) -> Self {
+        let start = Instant::now();
+        let (result, panic) =
+            panic_hook::record(|| std::panic::catch_unwind(AssertUnwindSafe(run)));
+        Self::new(name, start, 1, result, panic)
+    }
+
+    pub(crate) fn deferred(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            duration: Duration::ZERO,
+            attempts: 0,
+            status: PluginStatus::Deferred,
+            panic: None,
+            output: String::new(),
+            result: None,
+        }
+    }
+
+    fn skipped(name: &str, cause: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            duration: Duration::ZERO,
+            attempts: 0,
+            status: PluginStatus::Skipped(cause.to_owned()),
+            panic: None,
+            output: String::new(),
+            result: None,
+        }
+    }
+
+    fn new(
+        name: &str,
+        start: Instant,
+        attempts: u32,
+        result: std::thread::Result<()>,
+        panic: Option<PanicReport>,
+    ) -> Self {
+        let panic = panic.filter(|_| result.is_err());
+        let status = match result {
+            Ok(()) => PluginStatus::Succeeded,
+            Err(payload) => PluginStatus::Panicked(
+                payload
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "Box<dyn Any>".to_owned()),
+            ),
+        };
+
+        let duration = start.elapsed();
+        let output = String::new();
+        Self { name: name.to_owned(), duration, attempts, status, panic, output, result: None }
+    }
+}
+
+impl DispatchReport {
+    /// Returns `true` if every plugin succeeded, and so none was skipped or
+    /// deferred.
+    pub fn is_success(&self) -> bool {
+        self.plugins().all(|plugin| plugin.status == PluginStatus::Succeeded)
+    }
+
+    pub fn plugins(&self) -> impl Iterator<Item = &PluginReport> {
+        self.stages.iter().flatten()
+    }
+
+    /// Serializes the report as a single line of JSON. Durations are in
+    /// seconds.
+    ///
+    /// ```json
+    /// {"duration":0.0012,"stages":[[{"name":"Hello","duration":0.0011,"status":"succeeded"}]]}
+    /// ```
+    ///
+    /// A panicked plugin has `"status":"panicked"`, a `"message"` and, if
+    /// known, the `"location"` of the panic, a crashed one
+    /// `"status":"crashed"` and a `"message"`, and a retried plugin has the
+    /// number of `"attempts"`. A plugin that did not run has
+    /// `"status":"skipped"` and the failed plugin as its `"cause"`, or
+    /// `"status":"deferred"`. Captured output is in `"output"`, if any, and
+    /// a [`PluginResult`] in `"result"` if it is JSON, or in hexadecimal in
+    /// `"result_bytes"`.
+    pub fn to_json(&self) -> String {
+        let mut json = format!("{{\"duration\":{},\"stages\":[", self.duration.as_secs_f64());
+
+        for (index, stage) in self.stages.iter().enumerate() {
+            json.push_str(if index == 0 { "[" } else { ",[" });
+            for (index, plugin) in stage.iter().enumerate() {
+                if index > 0 {
+                    json.push(',');
+                }
+                json.push_str("{\"name\":");
+                push_json_string(&mut json, &plugin.name);
+                write!(json, ",\"duration\":{}", plugin.duration.as_secs_f64()).unwrap();
+                if plugin.attempts > 1 {
+                    write!(json, ",\"attempts\":{}", plugin.attempts).unwrap();
+                }
+                match &plugin.status {
+                    PluginStatus::Succeeded => json.push_str(",\"status\":\"succeeded\""),
+                    PluginStatus::Deferred => json.push_str(",\"status\":\"deferred\""),
+                    PluginStatus::Skipped(cause) => {
+                        json.push_str(",\"status\":\"skipped\",\"cause\":");
+                        push_json_string(&mut json, cause);
+                    }
+                    PluginStatus::Panicked(message) => {
+                        json.push_str(",\"status\":\"panicked\",\"message\":");
+                        push_json_string(&mut json, message);
+                    }
+                    PluginStatus::Crashed(message) => {
+                        json.push_str(",\"status\":\"crashed\",\"message\":");
+                        push_json_string(&mut json, message);
+                    }
+                }
+                if let Some(location) =
+                    plugin.panic.as_ref().and_then(|panic| panic.location.as_ref())
+                {
+                    json.push_str(",\"location\":");
+                    push_json_string(&mut json, location);
+                }
+                if !plugin.output.is_empty() {
+                    json.push_str(",\"output\":");
+                    push_json_string(&mut json, &plugin.output);
+                }
+                match &plugin.result {
+                    Some(PluginResult::Json(result)) => {
+                        // Valid JSON only has line breaks between tokens.
+                        let result = result.replace(['\n', '\r'], " ");
+                        write!(json, ",\"result\":{result}").unwrap();
+                    }
+                    Some(PluginResult::Bytes(bytes)) => {
+                        write!(json, ",\"result_bytes\":\"{}\"", hex(bytes)).unwrap();
+                    }
+                    None => {}
+                }
+                json.push('}');
+            }
+            json.push(']');
+        }
+
+        json.push_str("]}");
+        json
+    }
+}
+
+/// Applies an [`ErrorPolicy`] over the stages of a reporting dispatch.
+#[derive(Clone)]
+pub(crate) struct Failures {
+    policy: ErrorPolicy,
+    /// The slots of the plugins to skip, with the name of the plugin whose
+    /// failure they are skipped for.
+    skipped: AHashMap<usize, String>,
+    /// The plugin whose failure skips every remaining stage.
+    aborted: Option<String>,
+}
+
+impl Failures {
+    pub(crate) fn new(policy: ErrorPolicy) -> Self {
+        Self { policy, skipped: AHashMap::new(), aborted: None }
+    }
+
+    /// Whether the policy skips the plugin scheduled in `slot`.
+    pub(crate) fn skips(&self, slot: usize) -> bool {
+        self.cause(slot).is_some()
+    }
+
+    fn cause(&self, slot: usize) -> Option<&String> {
+        self.aborted.as_ref().or_else(|| self.skipped.get(&slot))
+    }
+
+    /// Runs `plugin`, scheduled in `slot`, unless the policy skips it.
+    pub(crate) fn run(
+        &self,
+        slot: usize,
+        plugin: &dyn Plugin,
+        state: &DispatchState,
+    ) -> PluginReport {
+        match self.cause(slot) {
+            Some(cause) => PluginReport::skipped(plugin.name(), cause),
+            None => PluginReport::run(plugin, state),
+        }
+    }
+
+    /// Takes note of the failures in `stage` of `schedule` once it has
+    /// finished, given the `reports` of its plugins.
+    pub(crate) fn record(
+        &mut self,
+        schedule: &Schedule,
+        stage: &[usize],
+        reports: &[PluginReport],
+    ) {
+        for (&slot, report) in stage.iter().zip(reports) {
+            if !matches!(report.status, PluginStatus::Panicked(_) | PluginStatus::Crashed(_)) {
+                continue;
+            }
+
+            match self.policy {
+                ErrorPolicy::FailFast => {
+                    self.aborted.get_or_insert_with(|| report.name.clone());
+                }
+                ErrorPolicy::SkipDependents => {
+                    for dependent in schedule.downstream(slot) {
+                        self.skipped.entry(dependent).or_insert_with(|| report.name.clone());
+                    }
+                }
+                ErrorPolicy::Continue => {}
+            }
+        }
+    }
+
+    pub(crate) fn policy(&self) -> ErrorPolicy {
+        self.policy
+    }
+}
+
+pub(crate) fn push_json_string(json: &mut String, string: &str) {
+    json.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        DispatchReport, ErrorPolicy, PanicReport, PluginReport, PluginResult, PluginStatus,
+    };
+
+    #[test]
+    fn to_json() {
+        let report = DispatchReport {
+            stages: vec![
+                vec![PluginReport {
+                    name: "A".to_owned(),
+                    duration: Duration::from_millis(500),
+                    attempts: 1,
+                    status: PluginStatus::Succeeded,
+                    panic: None,
+                    output: String::new(),
+                    result: None,
+                }],
+                vec![PluginReport {
+                    name: "B".to_owned(),
+                    duration: Duration::from_millis(250),
+                    attempts: 3,
+                    status: PluginStatus::Panicked("\"oops\"\n".to_owned()),
+                    panic: Some(PanicReport {
+                        thread: "main".to_owned(),
+                        location: Some("src/b.rs:1:1".to_owned()),
+                        backtrace: String::new(),
+                    }),
+                    output: "[Error] oops\n".to_owned(),
+                    result: Some(PluginResult::Json("{\"found\":\n[1,2]}".to_owned())),
+                }],
+                vec![PluginReport {
+                    name: "C".to_owned(),
+                    duration: Duration::ZERO,
+                    attempts: 0,
+                    status: PluginStatus::Skipped("B".to_owned()),
+                    panic: None,
+                    output: String::new(),
+                    result: None,
+                }],
+            ],
+            duration: Duration::from_secs(1),
+            policy: ErrorPolicy::SkipDependents,
+        };
+
+        assert!(!report.is_success());
+        assert_eq!(
+            report.to_json(),
+            r#"{"duration":1,"stages":[[{"name":"A","duration":0.5,"status":"succeeded"}],[{"name":"B","duration":0.25,"attempts":3,"status":"panicked","message":"\"oops\"\n","location":"src/b.rs:1:1","output":"[Error] oops\n","result":{"found": [1,2]}}],[{"name":"C","duration":0,"status":"skipped","cause":"B"}]]}"#
+        );
+    }
+}
@@ -0,0 +1,76 @@
+//! Values the host shares with plugins, looked up by type.
+
+use std::any::{Any, TypeId};
+use std::sync::Arc;
+
+use ahash::AHashMap;
+
+/// Shared values, such as database pools or HTTP clients, that plugins
+/// receive in [`Plugin::init`](crate::Plugin::init).
+///
+/// Each type is stored at most once. A plugin library must be built with
+/// the same compiler as the host, or types will not match.
+#[derive(Default, Clone)]
+pub struct Resources {
+    resources: AHashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ResourceError {
+    #[error("no resource of type `{0}` was provided by the host")]
+    Missing(&'static str),
+}
+
+impl Resources {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores `resource`, replacing and returning the previous one of the
+    /// same type.
+    pub fn insert<T: Any + Send + Sync>(&mut self, resource: Arc<T>) -> Option<Arc<T>> {
+        self.resources
+            .insert(TypeId::of::<T>(), resource)
+            .map(|previous| previous.downcast().unwrap())
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Result<Arc<T>, ResourceError> {
+        self.resources
+            .get(&TypeId::of::<T>())
+            .map(|resource| resource.clone().downcast().unwrap())
+            .ok_or(ResourceError::Missing(std::any::type_name::<T>()))
+    }
+
+    pub fn contains<T: Any + Send + Sync>(&self) -> bool {
+        self.resources.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<Arc<T>> {
+        self.resources.remove(&TypeId::of::<T>()).map(|resource| resource.downcast().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{ResourceError, Resources};
+
+    struct Pool(usize);
+
+    #[test]
+    fn resources() {
+        let mut resources = Resources::new();
+        assert!(
+            matches!(resources.get::<Pool>(), Err(ResourceError::Missing(name)) if name.ends_with("Pool"))
+        );
+
+        assert!(resources.insert(Arc::new(Pool(4))).is_none());
+        assert_eq!(resources.get::<Pool>().unwrap().0, 4);
+        assert_eq!(resources.insert(Arc::new(Pool(8))).unwrap().0, 4);
+        assert!(resources.contains::<Pool>());
+
+        assert_eq!(resources.remove::<Pool>().unwrap().0, 8);
+        assert!(!resources.contains::<Pool>());
+    }
+}
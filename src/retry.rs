@@ -0,0 +1,146 @@
+//! Running a plugin again after it fails.
+
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+
+use crate::context::DispatchState;
+use crate::{Plugin, RunContext};
+
+/// How often a plugin is run before its failure is reported, returned by
+/// [`Plugin::retry`].
+///
+/// A plugin fails by panicking. Retries are meant for transient failures,
+/// such as a network timeout or a locked file, so the plugin must leave
+/// consistent state behind when it panics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// The number of runs, including the first. `0` is treated as `1`.
+    pub max_attempts: u32,
+    /// How long to wait before the first retry.
+    pub delay: Duration,
+    /// What the delay is multiplied by after every retry.
+    pub backoff: f64,
+}
+
+impl RetryPolicy {
+    /// Runs the plugin once.
+    pub const NEVER: Self = Self { max_attempts: 1, delay: Duration::ZERO, backoff: 1.0 };
+
+    /// Runs the plugin up to `max_attempts` times, without waiting in
+    /// between.
+    pub const fn attempts(max_attempts: u32) -> Self {
+        Self { max_attempts, ..Self::NEVER }
+    }
+
+    pub const fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub const fn backoff(mut self, backoff: f64) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Calls `run` until it returns, the attempts are exhausted, or
+    /// `context` is cancelled. Returns the number of attempts and the
+    /// outcome of the last one.
+    pub(crate) fn attempt(
+        &self,
+        context: &RunContext,
+        mut run: impl FnMut(),
+    ) -> (u32, std::thread::Result<()>) {
+        let mut delay = self.delay;
+        let mut attempts = 1;
+        loop {
+            let result = std::panic::catch_unwind(AssertUnwindSafe(&mut run));
+            if result.is_ok() || attempts >= self.max_attempts || context.is_cancelled() {
+                return (attempts, result);
+            }
+
+            std::thread::sleep(delay);
+            delay = delay.mul_f64(self.backoff);
+            attempts += 1;
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::NEVER
+    }
+}
+
+/// Runs `plugin` as its [`RetryPolicy`] allows, resuming the last panic if
+/// every attempt fails.
+pub(crate) fn run(plugin: &dyn Plugin, state: &DispatchState) {
+    let context = state.context(plugin);
+    let _watch = state.watch(plugin.name());
+    let retry = plugin.retry();
+    if retry.max_attempts <= 1 {
+        return plugin.run(&context);
+    }
+
+    if let (_, Err(payload)) = retry.attempt(&context, || plugin.run(&context)) {
+        std::panic::resume_unwind(payload);
+    }
+}
+
+/// Like [`run`], but through [`Plugin::run_mut`].
+pub(crate) fn run_mut(plugin: &mut dyn Plugin, state: &DispatchState) {
+    let _watch = state.watch(plugin.name());
+    state.with_context_mut(plugin, |plugin, context| {
+        let retry = plugin.retry();
+        if retry.max_attempts <= 1 {
+            return plugin.run_mut(context);
+        }
+
+        if let (_, Err(payload)) = retry.attempt(context, || plugin.run_mut(context)) {
+            std::panic::resume_unwind(payload);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+
+    use super::RetryPolicy;
+    use crate::context::DispatchState;
+    use crate::{Plugin, RunContext};
+
+    struct Flaky;
+
+    impl Plugin for Flaky {
+        fn run(&self, _: &RunContext) {}
+    }
+
+    #[test]
+    fn attempt() {
+        let runs = AtomicU32::new(0);
+        let flaky = || {
+            if runs.fetch_add(1, Ordering::SeqCst) < 2 {
+                panic!("locked");
+            }
+        };
+
+        let retry = RetryPolicy::attempts(3).delay(Duration::from_millis(1)).backoff(2.0);
+        let (attempts, result) = retry.attempt(&RunContext::detached(), flaky);
+        assert_eq!(attempts, 3);
+        assert!(result.is_ok());
+
+        runs.store(0, Ordering::SeqCst);
+        let (attempts, result) = RetryPolicy::attempts(2).attempt(&RunContext::detached(), flaky);
+        assert_eq!(attempts, 2);
+        assert!(result.is_err());
+
+        let state = DispatchState::default();
+        state.cancel();
+        let context = state.context(&Flaky);
+        runs.store(0, Ordering::SeqCst);
+        let (attempts, result) = RetryPolicy::attempts(3).attempt(&context, flaky);
+        assert_eq!(attempts, 1);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,374 @@
+//! Assigns plugins to stages, so that every plugin runs one stage after the
+//! last of its dependencies.
+
+use std::collections::{BTreeSet, HashMap};
+
+use ahash::{AHashMap, AHashSet};
+use petgraph::Direction;
+use petgraph::algo::toposort;
+use petgraph::graph::DiGraph;
+
+use crate::Plugin;
+
+/// Groups plugins, given by name and the dependencies they wait for, into
+/// stages. Returns indices into `plugins`.
+///
+/// # Panics
+///
+/// If the dependencies form a cycle or a dependency is not in `plugins`.
+pub(crate) fn schedule(plugins: &[(&str, Vec<&str>)]) -> Vec<Vec<usize>> {
+    let mut graph = DiGraph::new();
+    let mut node_indices = HashMap::new();
+    let mut node = |graph: &mut DiGraph<_, ()>, name| {
+        *node_indices.entry(name).or_insert_with(|| graph.add_node(name))
+    };
+
+    for (name, dependencies) in plugins {
+        let master = node(&mut graph, *name);
+
+        for &dependency in dependencies {
+            let dependency = node(&mut graph, dependency);
+
+            graph.add_edge(dependency, master, ());
+        }
+    }
+
+    let index_of_plugin: HashMap<_, _> =
+        plugins.iter().enumerate().map(|(index, (name, _))| (*name, index)).collect();
+    let nodes = toposort(&graph, None).unwrap();
+    let mut stage_of_node = vec![0; graph.node_count()];
+    let mut stages: Vec<Vec<_>> = Vec::new();
+
+    // A plugin runs one stage after the last of its dependencies, so
+    // plugins within a stage are independent of each other.
+    for node in nodes {
+        let stage = graph
+            .neighbors_directed(node, Direction::Incoming)
+            .map(|dependency| stage_of_node[dependency.index()] + 1)
+            .max()
+            .unwrap_or(0);
+        stage_of_node[node.index()] = stage;
+
+        if stages.len() <= stage {
+            stages.resize_with(stage + 1, Vec::new);
+        }
+        stages[stage].push(index_of_plugin[graph[node]]);
+    }
+
+    stages
+}
+
+/// Decides which stage every plugin runs in when a dispatcher is built. Set
+/// with [`DispatcherBuilder::scheduler`](crate::DispatcherBuilder::scheduler).
+///
+/// Plugins enabled or added once the dispatcher is running are placed by
+/// the dispatcher itself, right after what they wait for.
+pub trait Scheduler {
+    /// Groups `plugins` into stages, returning indices into `plugins`.
+    ///
+    /// `dependencies[index]` holds the plugins that `plugins[index]` waits
+    /// for: its dependencies, and the plugins ordered before it because
+    /// they access the same blackboard keys. Every plugin must be in exactly
+    /// one stage, after all of those it waits for.
+    fn schedule(&self, plugins: &[&dyn Plugin], dependencies: &[Vec<usize>]) -> Vec<Vec<usize>>;
+}
+
+/// The default [`Scheduler`]: every plugin runs in the stage right after
+/// the last of those it waits for, so stages are as few as possible.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TopologicalScheduler;
+
+impl Scheduler for TopologicalScheduler {
+    fn schedule(&self, plugins: &[&dyn Plugin], dependencies: &[Vec<usize>]) -> Vec<Vec<usize>> {
+        let plugins: Vec<_> = plugins
+            .iter()
+            .zip(dependencies)
+            .map(|(plugin, dependencies)| {
+                let dependencies = dependencies.iter().map(|&index| plugins[index].name());
+                (plugin.name(), dependencies.collect())
+            })
+            .collect();
+        schedule(&plugins)
+    }
+}
+
+/// Checks that `stages`, as returned by a [`Scheduler`], hold every plugin
+/// once and after all of its `dependencies`.
+///
+/// # Panics
+///
+/// If they do not.
+pub(crate) fn check_stages(stages: &[Vec<usize>], dependencies: &[Vec<usize>]) {
+    if let Err(message) = verify_stages(stages, dependencies) {
+        panic!("{message}");
+    }
+}
+
+/// Like [`check_stages`], but returns what is wrong instead of panicking.
+pub(crate) fn verify_stages(
+    stages: &[Vec<usize>],
+    dependencies: &[Vec<usize>],
+) -> Result<(), String> {
+    let mut stage_of_plugin = vec![None; dependencies.len()];
+    for (stage, plugins) in stages.iter().enumerate() {
+        for &index in plugins {
+            match stage_of_plugin.get_mut(index) {
+                Some(slot @ None) => *slot = Some(stage),
+                _ => return Err(format!("scheduler placed plugin {index} twice or out of range")),
+            }
+        }
+    }
+
+    for (index, dependencies) in dependencies.iter().enumerate() {
+        let Some(stage) = stage_of_plugin[index] else {
+            return Err(format!("scheduler left out plugin {index}"));
+        };
+        for &dependency in dependencies {
+            if stage_of_plugin[dependency] >= Some(stage) {
+                return Err(format!(
+                    "scheduler placed plugin {index} no later than {dependency}, which it waits \
+                     for"
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The blackboard keys a plugin reads and writes.
+#[derive(Clone, Copy)]
+pub(crate) struct Access<'a> {
+    pub(crate) reads: &'a [&'a str],
+    pub(crate) writes: &'a [&'a str],
+}
+
+impl<'a> Access<'a> {
+    pub(crate) fn of(plugin: &'a dyn Plugin) -> Self {
+        Self { reads: plugin.reads(), writes: plugin.writes() }
+    }
+
+    /// Whether running both plugins at once could race: one of them writes
+    /// a key that the other reads or writes.
+    pub(crate) fn conflicts(&self, other: &Access) -> bool {
+        let touches =
+            |access: &Access, key| access.reads.contains(key) || access.writes.contains(key);
+        self.writes.iter().any(|key| touches(other, key))
+            || other.writes.iter().any(|key| touches(self, key))
+    }
+}
+
+/// Makes plugins whose [`Access`] conflicts depend on each other, so that
+/// they never share a stage. `access` holds the access of each of `plugins`.
+///
+/// Conflicting plugins keep the order in which [`schedule`] runs them
+/// without the added dependencies, so explicit dependencies take precedence
+/// and no cycle is introduced. Every plugin depends on all earlier ones it
+/// conflicts with, not just the last one, so that they stay apart when one
+/// of them is removed.
+pub(crate) fn order_access<'a>(plugins: &mut [(&'a str, Vec<&'a str>)], access: &[Access]) {
+    let order = schedule(plugins).concat();
+    for (position, &later) in order.iter().enumerate() {
+        for &earlier in &order[..position] {
+            let name = plugins[earlier].0;
+            if access[later].conflicts(&access[earlier]) && !plugins[later].1.contains(&name) {
+                plugins[later].1.push(name);
+            }
+        }
+    }
+}
+
+/// The stages of a dispatcher, kept up to date as plugins are inserted and
+/// removed.
+///
+/// Plugins are identified by their slot in the dispatcher. Changes only
+/// revisit the transitive dependents of the plugin that changed, and stop
+/// at those whose stage stays the same.
+#[derive(Default)]
+pub(crate) struct Schedule {
+    nodes: AHashMap<usize, Node>,
+    stages: Vec<Vec<usize>>,
+}
+
+#[derive(Default)]
+struct Node {
+    stage: usize,
+    dependencies: Vec<usize>,
+    dependents: Vec<usize>,
+}
+
+impl Schedule {
+    /// Takes over `stages` as computed by [`schedule`], together with the
+    /// edges from each dependency to its dependent.
+    pub(crate) fn new(
+        stages: Vec<Vec<usize>>,
+        edges: impl IntoIterator<Item = (usize, usize)>,
+    ) -> Self {
+        let mut nodes = AHashMap::new();
+        for (stage, slots) in stages.iter().enumerate() {
+            for &slot in slots {
+                nodes.insert(slot, Node { stage, ..Node::default() });
+            }
+        }
+
+        for (dependency, dependent) in edges {
+            nodes.get_mut(&dependency).unwrap().dependents.push(dependent);
+            nodes.get_mut(&dependent).unwrap().dependencies.push(dependency);
+        }
+
+        Self { nodes, stages }
+    }
+
+    pub(crate) fn stages(&self) -> &[Vec<usize>] {
+        &self.stages
+    }
+
+    pub(crate) fn contains(&self, slot: usize) -> bool {
+        self.nodes.contains_key(&slot)
+    }
+
+    /// The stage `slot` runs in.
+    pub(crate) fn stage(&self, slot: usize) -> usize {
+        self.nodes[&slot].stage
+    }
+
+    /// The scheduled plugins `slot` waits for.
+    pub(crate) fn dependencies(&self, slot: usize) -> &[usize] {
+        &self.nodes[&slot].dependencies
+    }
+
+    /// The transitive dependents of `slot`, not including itself.
+    pub(crate) fn downstream(&self, slot: usize) -> AHashSet<usize> {
+        let mut downstream = AHashSet::new();
+        let mut queue = vec![slot];
+        while let Some(slot) = queue.pop() {
+            for &dependent in &self.nodes[&slot].dependents {
+                if downstream.insert(dependent) {
+                    queue.push(dependent);
+                }
+            }
+        }
+        downstream
+    }
+
+    /// Schedules `slot` after `dependencies` and before `dependents`, which
+    /// must already be scheduled. The new edges must not form a cycle.
+    pub(crate) fn insert(&mut self, slot: usize, dependencies: Vec<usize>, dependents: Vec<usize>) {
+        for dependency in &dependencies {
+            self.nodes.get_mut(dependency).unwrap().dependents.push(slot);
+        }
+        for dependent in &dependents {
+            self.nodes.get_mut(dependent).unwrap().dependencies.push(slot);
+        }
+
+        let stage = self.level(&dependencies);
+        self.nodes.insert(slot, Node { stage, dependencies, dependents: dependents.clone() });
+        self.place(slot, stage);
+        self.update(dependents);
+    }
+
+    /// Unschedules `slot`. Its dependents no longer wait for it.
+    pub(crate) fn remove(&mut self, slot: usize) {
+        let Some(node) = self.nodes.remove(&slot) else {
+            return;
+        };
+
+        self.stages[node.stage].retain(|&other| other != slot);
+        for dependency in &node.dependencies {
+            self.nodes.get_mut(dependency).unwrap().dependents.retain(|&other| other != slot);
+        }
+        for dependent in &node.dependents {
+            self.nodes.get_mut(dependent).unwrap().dependencies.retain(|&other| other != slot);
+        }
+
+        self.update(node.dependents);
+    }
+
+    /// The stage a plugin with `dependencies` belongs in.
+    fn level(&self, dependencies: &[usize]) -> usize {
+        dependencies.iter().map(|dependency| self.nodes[dependency].stage + 1).max().unwrap_or(0)
+    }
+
+    fn place(&mut self, slot: usize, stage: usize) {
+        if self.stages.len() <= stage {
+            self.stages.resize_with(stage + 1, Vec::new);
+        }
+        self.stages[stage].push(slot);
+    }
+
+    /// Moves `slots` to the stages their dependencies now call for, and
+    /// then the dependents of every plugin that moved.
+    fn update(&mut self, slots: Vec<usize>) {
+        // Visiting plugins by stage settles dependencies before their
+        // dependents.
+        let mut queue: BTreeSet<_> =
+            slots.into_iter().map(|slot| (self.nodes[&slot].stage, slot)).collect();
+
+        while let Some((_, slot)) = queue.pop_first() {
+            let old = self.nodes[&slot].stage;
+            let stage = self.level(&self.nodes[&slot].dependencies);
+            if stage == old {
+                continue;
+            }
+
+            self.stages[old].retain(|&other| other != slot);
+            self.nodes.get_mut(&slot).unwrap().stage = stage;
+            self.place(slot, stage);
+
+            for dependent in &self.nodes[&slot].dependents {
+                queue.insert((self.nodes[dependent].stage, *dependent));
+            }
+        }
+
+        while self.stages.last().is_some_and(Vec::is_empty) {
+            self.stages.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Access, Schedule, order_access, schedule};
+
+    #[test]
+    fn access() {
+        let access = |reads, writes| Access { reads, writes };
+
+        // B depends on A, and all three write `score`.
+        let mut plugins = vec![("B", vec!["A"]), ("A", vec![]), ("C", vec![])];
+        let score = access(&[], &["score"]);
+        order_access(&mut plugins, &[score, score, access(&[], &["score", "time"])]);
+
+        assert_eq!(plugins, [("B", vec!["A", "C"]), ("A", vec!["C"]), ("C", vec![])]);
+        assert_eq!(schedule(&plugins), [vec![2], vec![1], vec![0]]);
+
+        // Readers share a stage, but not with the writer.
+        let mut plugins = vec![("Log", vec![]), ("Render", vec![]), ("Physics", vec![])];
+        let reader = access(&["time"], &[]);
+        order_access(&mut plugins, &[reader, reader, access(&[], &["time"])]);
+        assert_eq!(schedule(&plugins).len(), 2);
+        assert_eq!(plugins[0].1, plugins[1].1);
+    }
+
+    #[test]
+    fn incremental() {
+        // 0 -> 1 -> 2, and 0 -> 3
+        let mut schedule =
+            Schedule::new(vec![vec![0], vec![1, 3], vec![2]], [(0, 1), (1, 2), (0, 3)]);
+        assert_eq!(schedule.downstream(0), [1, 2, 3].into_iter().collect());
+        assert_eq!(schedule.downstream(1), [2].into_iter().collect());
+
+        schedule.remove(0);
+        assert_eq!(schedule.stages(), [vec![1, 3], vec![2]]);
+
+        schedule.remove(1);
+        assert_eq!(schedule.stages(), [vec![3, 2]]);
+
+        schedule.insert(1, vec![3], vec![2]);
+        assert_eq!(schedule.stages(), [vec![3], vec![1], vec![2]]);
+
+        schedule.insert(0, vec![], vec![3]);
+        assert_eq!(schedule.stages(), [vec![0], vec![3], vec![1], vec![2]]);
+        assert!(schedule.contains(0));
+    }
+}
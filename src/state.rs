@@ -0,0 +1,260 @@
+//! Saving the state of plugins, so that it survives a restart of the host.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::{Dispatcher, Loader, Plugin, PluginManager};
+
+/// The error a plugin returns from [`Plugin::load_state`].
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StateError {
+    #[error("cannot access the state file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("the state file is corrupt")]
+    Corrupt,
+    #[error("plugin `{plugin}` cannot load its state: {source}")]
+    Plugin { plugin: String, source: BoxError },
+}
+
+/// The saved state of every plugin that has any, by plugin name.
+///
+/// The file starts with [`Snapshot::MAGIC`], followed by one entry per
+/// plugin: the length of the name as a little-endian `u32`, the name, the
+/// length of the state as a little-endian `u64`, and the state.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub states: BTreeMap<String, Vec<u8>>,
+}
+
+impl Snapshot {
+    pub const MAGIC: &'static [u8] = b"sora-state\0\x01";
+
+    /// Calls [`Plugin::save_state`] on each of `plugins`.
+    pub fn save<'a>(plugins: impl IntoIterator<Item = &'a dyn Plugin>) -> Self {
+        let states = plugins
+            .into_iter()
+            .filter_map(|plugin| Some((plugin.name().to_owned(), plugin.save_state()?)))
+            .collect();
+
+        Self { states }
+    }
+
+    /// Calls [`Plugin::load_state`] on each of `plugins` that has a saved
+    /// state. A plugin that fails keeps its initial state, the others are
+    /// restored regardless, and the first failure is returned. Saved states
+    /// of plugins that are not among `plugins` are ignored.
+    pub fn restore<'a>(
+        &self,
+        plugins: impl IntoIterator<Item = &'a dyn Plugin>,
+    ) -> Result<(), StateError> {
+        let mut result = Ok(());
+        for plugin in plugins {
+            if let Some(state) = self.states.get(plugin.name()) {
+                if let Err(source) = plugin.load_state(state) {
+                    let error = StateError::Plugin { plugin: plugin.name().to_owned(), source };
+                    result = result.and(Err(error));
+                }
+            }
+        }
+
+        result
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Self::MAGIC.to_vec();
+        for (name, state) in &self.states {
+            bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(name.as_bytes());
+            bytes.extend_from_slice(&(state.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(state);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, StateError> {
+        let mut bytes = bytes.strip_prefix(Self::MAGIC).ok_or(StateError::Corrupt)?;
+
+        let mut states = BTreeMap::new();
+        while !bytes.is_empty() {
+            let len = u32::from_le_bytes(take(&mut bytes, 4)?.try_into().unwrap());
+            let name = take(&mut bytes, len as usize)?;
+            let name = String::from_utf8(name.to_vec()).map_err(|_| StateError::Corrupt)?;
+            let len = u64::from_le_bytes(take(&mut bytes, 8)?.try_into().unwrap());
+            let len = usize::try_from(len).map_err(|_| StateError::Corrupt)?;
+            states.insert(name, take(&mut bytes, len)?.to_vec());
+        }
+
+        Ok(Self { states })
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, StateError> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// Writes the snapshot to a temporary file next to `path` first, so
+    /// that a crash does not leave a truncated file behind.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), StateError> {
+        let path = path.as_ref();
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+
+        std::fs::write(&temporary, self.to_bytes())?;
+        std::fs::rename(&temporary, path)?;
+        Ok(())
+    }
+}
+
+/// Splits the first `len` bytes off `bytes`.
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], StateError> {
+    let (taken, rest) = bytes.split_at_checked(len).ok_or(StateError::Corrupt)?;
+    *bytes = rest;
+    Ok(taken)
+}
+
+/// Reads the snapshot at `path`, or an empty one if there is no such file.
+fn read_or_default(path: impl AsRef<Path>) -> Result<Snapshot, StateError> {
+    match Snapshot::read(path) {
+        Err(StateError::Io(error)) if error.kind() == std::io::ErrorKind::NotFound => {
+            Ok(Snapshot::default())
+        }
+        snapshot => snapshot,
+    }
+}
+
+impl<L: Loader> PluginManager<L> {
+    /// The state of every registered plugin.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::save(self.plugins.iter().map(|plugin| &**plugin))
+    }
+
+    /// Hands the states in `snapshot` to the registered plugins, for
+    /// example to carry them over to freshly reloaded libraries. See
+    /// [`Snapshot::restore`].
+    pub fn restore(&self, snapshot: &Snapshot) -> Result<(), StateError> {
+        snapshot.restore(self.plugins.iter().map(|plugin| &**plugin))
+    }
+
+    /// Saves the state of every registered plugin to `path`. See
+    /// [`Snapshot`].
+    pub fn save_state(&self, path: impl AsRef<Path>) -> Result<(), StateError> {
+        self.snapshot().write(path)
+    }
+
+    /// Restores the state saved by [`save_state`](Self::save_state), for
+    /// example on the next startup. A missing file restores nothing.
+    pub fn restore_state(&self, path: impl AsRef<Path>) -> Result<(), StateError> {
+        self.restore(&read_or_default(path)?)
+    }
+}
+
+impl<L> Dispatcher<L> {
+    /// Like [`PluginManager::snapshot`], for the plugins of the dispatcher,
+    /// including disabled ones.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::save(self.plugins.iter().flatten().map(|plugin| &**plugin))
+    }
+
+    /// Like [`PluginManager::restore`].
+    pub fn restore(&self, snapshot: &Snapshot) -> Result<(), StateError> {
+        snapshot.restore(self.plugins.iter().flatten().map(|plugin| &**plugin))
+    }
+
+    /// Like [`PluginManager::save_state`].
+    pub fn save_state(&self, path: impl AsRef<Path>) -> Result<(), StateError> {
+        self.snapshot().write(path)
+    }
+
+    /// Like [`PluginManager::restore_state`].
+    pub fn restore_state(&self, path: impl AsRef<Path>) -> Result<(), StateError> {
+        self.restore(&read_or_default(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::{BoxError, Snapshot, StateError};
+    use crate::{Plugin, PluginManager, RunContext};
+
+    #[derive(Default)]
+    struct Counter(AtomicU32);
+
+    impl Plugin for Counter {
+        fn save_state(&self) -> Option<Vec<u8>> {
+            Some(self.0.load(Ordering::Relaxed).to_le_bytes().to_vec())
+        }
+
+        fn load_state(&self, state: &[u8]) -> Result<(), BoxError> {
+            self.0.store(u32::from_le_bytes(state.try_into()?), Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn run(&self, _: &RunContext) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    struct Stateless;
+
+    impl Plugin for Stateless {
+        fn run(&self, _: &RunContext) {}
+    }
+
+    #[test]
+    fn snapshot() {
+        let counter = Counter(AtomicU32::new(7));
+        let snapshot = Snapshot::save([&counter as &dyn Plugin, &Stateless]);
+        assert_eq!(snapshot.states.keys().collect::<Vec<_>>(), ["Counter"]);
+
+        let bytes = snapshot.to_bytes();
+        assert_eq!(Snapshot::from_bytes(&bytes).unwrap(), snapshot);
+        assert!(matches!(
+            Snapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(StateError::Corrupt)
+        ));
+        assert!(matches!(Snapshot::from_bytes(b"state"), Err(StateError::Corrupt)));
+
+        let mut corrupt = snapshot.clone();
+        corrupt.states.insert("Counter".to_owned(), vec![1]);
+        let error = corrupt.restore([&counter as &dyn Plugin]).unwrap_err();
+        assert!(matches!(error, StateError::Plugin { ref plugin, .. } if plugin == "Counter"));
+    }
+
+    #[test]
+    fn reload() {
+        let mut manager = PluginManager::new();
+        manager.register(Box::new(Counter::default())).unwrap();
+        let dispatcher = manager.into_dispatcher();
+        dispatcher.dispatch();
+
+        let mut manager = PluginManager::new();
+        manager.register(Box::new(Counter::default())).unwrap();
+        manager.restore(&dispatcher.snapshot()).unwrap();
+        drop(dispatcher);
+
+        assert_eq!(manager.snapshot().states["Counter"], 1_u32.to_le_bytes());
+    }
+
+    #[test]
+    fn restart() {
+        let path = std::env::temp_dir().join(format!("sora-state-{}", std::process::id()));
+
+        let mut manager = PluginManager::new();
+        manager.register(Box::new(Counter::default())).unwrap();
+        manager.restore_state(&path).unwrap();
+        let dispatcher = manager.into_dispatcher();
+        dispatcher.dispatch();
+        dispatcher.dispatch();
+        dispatcher.save_state(&path).unwrap();
+
+        let mut manager = PluginManager::new();
+        manager.register(Box::new(Counter::default())).unwrap();
+        manager.restore_state(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(manager.snapshot().states["Counter"], 2_u32.to_le_bytes());
+    }
+}
@@ -0,0 +1,173 @@
+//! Stand-ins for plugin libraries, so that hosts can test how their plugins
+//! are scheduled and dispatched without building any cdylib. Enabled by the
+//! `testing` feature.
+//!
+//! ```ignore
+//! sora::define_plugins! {
+//!     Physics { run: { step() } },
+//!     Render { run: { draw() }, dependencies: ["Physics"] }
+//! }
+//!
+//! let mut manager: PluginManager<PluginLoader> = PluginManager::default();
+//! unsafe { manager.load_plugin("Physics")? };
+//! unsafe { manager.load_plugin("Render")? };
+//! ```
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::Path;
+
+use crate::{LoadResult, Loader, Plugin, PluginLoadError, Plugins};
+
+/// Defines a plugin type for each name, which runs the given block and
+/// depends on the given plugins, and a `PluginLoader` that creates the one
+/// named by the file name of the path it loads.
+#[macro_export]
+macro_rules! define_plugins {
+    ($($name:ident { run: $run_block:block $(, dependencies: [$($deps:expr),*] )? }),+ $(,)?) => {
+        $(
+            struct $name;
+
+            impl $crate::Plugin for $name {
+                fn run(&self, _: &$crate::RunContext) {
+                    $run_block
+                }
+
+                fn dependencies(&self) -> &'static [&'static str] {
+                    &[$($($deps),*)?]
+                }
+            }
+        )+
+
+        #[derive(Default)]
+        pub struct PluginLoader;
+
+        impl $crate::Loader for PluginLoader {
+            type Library = ();
+            type Error = $crate::PluginLoadError;
+
+            fn opens_files(&self) -> bool {
+                false
+            }
+
+            unsafe fn load(
+                &self,
+                filename: impl ::std::convert::AsRef<::std::ffi::OsStr>,
+            ) -> $crate::LoadResult<Self> {
+                let path = ::std::path::Path::new(filename.as_ref());
+                let plugin: ::std::boxed::Box<dyn $crate::Plugin> =
+                    match path.file_name().and_then(|name| name.to_str()) {
+                        $( Some(stringify!($name)) => ::std::boxed::Box::new($name), )+
+                        _ => {
+                            let error = ::std::io::ErrorKind::NotFound.into();
+                            return Err($crate::PluginLoadError::Io(error));
+                        }
+                    };
+
+                Ok(((), plugin))
+            }
+        }
+    };
+}
+
+type CreateFn = Box<dyn Fn() -> Plugins + Send + Sync>;
+
+/// A [`Loader`] that creates plugins with closures rather than opening
+/// libraries. A path is looked up by its file name, and fails to load with
+/// [`std::io::ErrorKind::NotFound`] if no library of that name was added.
+///
+/// ```ignore
+/// let loader = MockLoader::new().plugin("physics.so", || Box::new(Physics));
+/// let mut manager = PluginManager::with_loader(loader);
+/// unsafe { manager.load_plugin("plugins/physics.so")? };
+/// ```
+#[derive(Default)]
+pub struct MockLoader {
+    libraries: HashMap<String, CreateFn>,
+}
+
+impl MockLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a library called `name` that provides the plugin `create`
+    /// returns.
+    pub fn plugin(
+        self,
+        name: impl Into<String>,
+        create: impl Fn() -> Box<dyn Plugin> + Send + Sync + 'static,
+    ) -> Self {
+        self.library(name, move || vec![create()])
+    }
+
+    /// Adds a library called `name` that provides every plugin `create`
+    /// returns, as one built with [`export_plugins!`](crate::export_plugins)
+    /// does.
+    pub fn library(
+        mut self,
+        name: impl Into<String>,
+        create: impl Fn() -> Plugins + Send + Sync + 'static,
+    ) -> Self {
+        self.libraries.insert(name.into(), Box::new(create));
+        self
+    }
+
+    fn create(&self, filename: &OsStr) -> Result<Plugins, PluginLoadError> {
+        let name = Path::new(filename).file_name().and_then(OsStr::to_str);
+        let create = name.and_then(|name| self.libraries.get(name));
+        let create = create.ok_or(PluginLoadError::Io(std::io::ErrorKind::NotFound.into()))?;
+        Ok(create())
+    }
+}
+
+impl Loader for MockLoader {
+    type Library = ();
+    type Error = PluginLoadError;
+
+    /// Libraries are found by file name.
+    fn opens_files(&self) -> bool {
+        false
+    }
+
+    /// Loads the first plugin of the library.
+    unsafe fn load(&self, filename: impl AsRef<OsStr>) -> LoadResult<Self> {
+        let plugin = self.create(filename.as_ref())?.into_iter().next();
+        Ok(((), plugin.ok_or(PluginLoadError::Io(std::io::ErrorKind::NotFound.into()))?))
+    }
+
+    unsafe fn load_plugins(
+        &self,
+        filename: impl AsRef<OsStr>,
+        _: &[&str],
+    ) -> Result<((), Plugins), PluginLoadError> {
+        Ok(((), self.create(filename.as_ref())?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MockLoader;
+    use crate::PluginManager;
+    use crate::fixture::Stub;
+
+    #[test]
+    fn mock_loader() {
+        let loader = MockLoader::new()
+            .plugin("render.so", || Box::new(Stub::new("Render", &["Physics"])))
+            .library("core.so", || {
+                vec![Box::new(Stub::new("Physics", &[])), Box::new(Stub::new("Audio", &["Render"]))]
+            });
+        let mut manager = PluginManager::with_loader(loader);
+        unsafe { manager.load_plugin("plugins/render.so").unwrap() };
+        unsafe { manager.load_plugin("plugins/core.so").unwrap() };
+        assert!(unsafe { manager.load_plugin("plugins/missing.so") }.is_err());
+
+        let dispatcher = manager.into_dispatcher();
+        let plan = dispatcher.plan();
+        assert_eq!(
+            plan.to_string(),
+            "stage 0: Physics\nstage 1: Render (after Physics)\nstage 2: Audio (after Render)\n"
+        );
+    }
+}
@@ -0,0 +1,239 @@
+//! A parser for the subset of TOML used by sora's manifests: `[table]`
+//! headers, bare keys, and string, integer, boolean and single-line array
+//! values.
+
+use std::collections::BTreeMap;
+
+/// A TOML table, such as the per-plugin settings of a [`Config`].
+///
+/// [`Config`]: crate::Config
+pub type Table = BTreeMap<String, Value>;
+
+/// A TOML value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+impl Value {
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Boolean(_) => "boolean",
+            Value::Array(_) => "array",
+            Value::Table(_) => "table",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ParseError {
+    pub(crate) line: usize,
+    pub(crate) message: String,
+}
+
+pub(crate) fn parse(source: &str) -> Result<Table, ParseError> {
+    let mut root = Table::new();
+    let mut path: Vec<String> = Vec::new();
+
+    for (index, line) in source.lines().enumerate() {
+        let error = |message: &str| ParseError { line: index + 1, message: message.to_owned() };
+        let mut cursor = Cursor { rest: line.trim() };
+
+        if cursor.is_done() {
+            continue;
+        }
+
+        if cursor.eat('[') {
+            let header =
+                cursor.take_until(']').ok_or_else(|| error("unterminated table header"))?;
+            path = header.split('.').map(|key| key.trim().to_owned()).collect();
+            if path.iter().any(|key| !is_bare_key(key)) {
+                return Err(error("invalid table name"));
+            }
+            if !cursor.is_done() {
+                return Err(error("unexpected characters after table header"));
+            }
+
+            table_at(&mut root, &path).ok_or_else(|| error("key is already defined"))?;
+            continue;
+        }
+
+        let key = cursor.take_until('=').ok_or_else(|| error("expected `key = value`"))?.trim();
+        if !is_bare_key(key) {
+            return Err(error("invalid key"));
+        }
+
+        let value = cursor.value().map_err(error)?;
+        if !cursor.is_done() {
+            return Err(error("unexpected characters after value"));
+        }
+
+        let table = table_at(&mut root, &path).ok_or_else(|| error("key is already defined"))?;
+        if table.insert(key.to_owned(), value).is_some() {
+            return Err(error("duplicate key"));
+        }
+    }
+
+    Ok(root)
+}
+
+fn table_at<'a>(mut table: &'a mut Table, path: &[String]) -> Option<&'a mut Table> {
+    for key in path {
+        let value = table.entry(key.clone()).or_insert_with(|| Value::Table(Table::new()));
+        table = match value {
+            Value::Table(table) => table,
+            _ => return None,
+        };
+    }
+
+    Some(table)
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+struct Cursor<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Cursor<'a> {
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    fn is_done(&mut self) -> bool {
+        self.skip_whitespace();
+        self.rest.is_empty() || self.rest.starts_with('#')
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        match self.rest.strip_prefix(c) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn take_until(&mut self, c: char) -> Option<&'a str> {
+        let (taken, rest) = self.rest.split_once(c)?;
+        self.rest = rest;
+        Some(taken)
+    }
+
+    fn value(&mut self) -> Result<Value, &'static str> {
+        self.skip_whitespace();
+
+        if self.eat('"') {
+            return self.string().map(Value::String);
+        }
+
+        if self.eat('[') {
+            let mut values = Vec::new();
+            while !self.eat(']') {
+                values.push(self.value()?);
+                if !self.eat(',') {
+                    if self.eat(']') {
+                        break;
+                    }
+                    return Err("expected `,` or `]` in array");
+                }
+            }
+            return Ok(Value::Array(values));
+        }
+
+        let end = self
+            .rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '_')))
+            .unwrap_or(self.rest.len());
+        let (token, rest) = self.rest.split_at(end);
+        self.rest = rest;
+
+        match token {
+            "true" => Ok(Value::Boolean(true)),
+            "false" => Ok(Value::Boolean(false)),
+            "" => Err("expected a value"),
+            token => {
+                token.replace('_', "").parse().map(Value::Integer).map_err(|_| "invalid value")
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, &'static str> {
+        let mut string = String::new();
+        let mut chars = self.rest.char_indices();
+
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[index + 1..];
+                    return Ok(string);
+                }
+                '\\' => string.push(match chars.next().map(|(_, c)| c) {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    _ => return Err("invalid escape sequence"),
+                }),
+                c => string.push(c),
+            }
+        }
+
+        Err("unterminated string")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Table, Value, parse};
+
+    #[test]
+    fn values_and_tables() {
+        let table = parse(
+            r#"
+            # comment
+            name = "a \"quoted\" name" # trailing comment
+            count = 1_000
+            enabled = false
+            list = ["a", "b",]
+
+            [plugin.hello]
+            threads = -2
+            "#,
+        )
+        .unwrap();
+
+        let mut hello = Table::new();
+        hello.insert("threads".into(), Value::Integer(-2));
+        let mut plugin = Table::new();
+        plugin.insert("hello".into(), Value::Table(hello));
+
+        assert_eq!(table["name"], Value::String("a \"quoted\" name".into()));
+        assert_eq!(table["count"], Value::Integer(1000));
+        assert_eq!(table["enabled"], Value::Boolean(false));
+        assert_eq!(
+            table["list"],
+            Value::Array(vec![Value::String("a".into()), Value::String("b".into())])
+        );
+        assert_eq!(table["plugin"], Value::Table(plugin));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse("a = 1\na = 2").unwrap_err().line, 2);
+        assert_eq!(parse("a = \"open").unwrap_err().message, "unterminated string");
+        assert_eq!(parse("a = 1\n[a]").unwrap_err().message, "key is already defined");
+        assert_eq!(parse("a b = 1").unwrap_err().message, "invalid key");
+    }
+}
@@ -0,0 +1,167 @@
+//! Flagging plugins that run for too long. See
+//! [`DispatcherBuilder::watchdog`](crate::DispatcherBuilder::watchdog).
+
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use ahash::AHashMap;
+
+type OverdueFn = Box<dyn Fn(&str, Duration) + Send + Sync>;
+
+/// A thread that calls a function for every plugin still running past a
+/// deadline. Plugins cannot be stopped, so they run on regardless.
+pub(crate) struct Watchdog {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+    /// The process the thread runs in, which a forked worker is not.
+    process: u32,
+}
+
+struct Shared {
+    deadline: Duration,
+    overdue: OverdueFn,
+    running: Mutex<Running>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct Running {
+    next_id: u64,
+    /// The name of every running plugin and when it started, by ID, for
+    /// those not flagged yet.
+    plugins: AHashMap<u64, (String, Instant)>,
+    stopped: bool,
+}
+
+impl Watchdog {
+    pub(crate) fn start(deadline: Duration, overdue: OverdueFn) -> Self {
+        let shared = Arc::new(Shared {
+            deadline,
+            overdue,
+            running: Mutex::default(),
+            changed: Condvar::new(),
+        });
+        let thread = std::thread::Builder::new()
+            .name("sora-watchdog".to_owned())
+            .spawn({
+                let shared = Arc::clone(&shared);
+                move || shared.watch()
+            })
+            .expect("cannot spawn the watchdog thread");
+
+        Self { shared, thread: Some(thread), process: std::process::id() }
+    }
+
+    /// Watches the plugin called `name` until the returned guard is
+    /// dropped.
+    pub(crate) fn run(&self, name: &str) -> Option<Watch<'_>> {
+        if std::process::id() != self.process {
+            return None;
+        }
+
+        let mut running = self.shared.lock();
+        let id = running.next_id;
+        running.next_id += 1;
+        running.plugins.insert(id, (name.to_owned(), Instant::now()));
+        self.shared.changed.notify_one();
+        Some(Watch { shared: &self.shared, id })
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.shared.lock().stopped = true;
+        self.shared.changed.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Running> {
+        self.running.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn watch(&self) {
+        let mut running = self.lock();
+        while !running.stopped {
+            let now = Instant::now();
+            let mut overdue = Vec::new();
+            running.plugins.retain(|_, (name, start)| {
+                let elapsed = now - *start;
+                if elapsed >= self.deadline {
+                    overdue.push((std::mem::take(name), elapsed));
+                }
+                elapsed < self.deadline
+            });
+
+            if !overdue.is_empty() {
+                drop(running);
+                for (name, elapsed) in overdue {
+                    (self.overdue)(&name, elapsed);
+                }
+                running = self.lock();
+                continue;
+            }
+
+            let next = running.plugins.values().map(|&(_, start)| start + self.deadline).min();
+            running = match next {
+                Some(next) => {
+                    let timeout = next.saturating_duration_since(now);
+                    self.changed
+                        .wait_timeout(running, timeout)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                None => self.changed.wait(running).unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+    }
+}
+
+/// Stops watching a plugin when it finishes.
+pub(crate) struct Watch<'a> {
+    shared: &'a Shared,
+    id: u64,
+}
+
+impl Drop for Watch<'_> {
+    fn drop(&mut self) {
+        self.shared.lock().plugins.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use crate::PluginManager;
+    use crate::fixture::Stub;
+
+    #[test]
+    fn watchdog() {
+        let mut manager = PluginManager::new();
+        let slow = Stub::new("Slow", &[]).sleep(Duration::from_millis(300));
+        manager.register(Box::new(slow)).unwrap();
+        manager.register(Box::new(Stub::new("Fast", &[]))).unwrap();
+
+        let flagged = Arc::new(Mutex::new(Vec::new()));
+        let dispatcher = manager
+            .into_dispatcher_builder()
+            .watchdog(Duration::from_millis(100), {
+                let flagged = Arc::clone(&flagged);
+                move |name, elapsed| flagged.lock().unwrap().push((name.to_owned(), elapsed))
+            })
+            .build();
+
+        dispatcher.dispatch();
+        dispatcher.dispatch_report();
+        let flagged = flagged.lock().unwrap();
+        let names: Vec<_> = flagged.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["Slow", "Slow"]);
+        assert!(flagged.iter().all(|&(_, elapsed)| elapsed >= Duration::from_millis(100)));
+    }
+}